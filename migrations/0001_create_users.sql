@@ -0,0 +1,9 @@
+-- Sample users table. UUID primary keys because Aurora DSQL does not support SERIAL,
+-- TIMESTAMPTZ so creation times carry their time zone.
+CREATE TABLE IF NOT EXISTS users (
+    id UUID PRIMARY KEY,
+    name VARCHAR(100) NOT NULL,
+    email VARCHAR(100) UNIQUE NOT NULL,
+    role VARCHAR(50) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -1,6 +1,14 @@
+use crate::config::DsqlConfig;
+use crate::metrics;
 use aws_config::{BehaviorVersion, Region};
 use aws_sdk_dsql::auth_token::{AuthTokenGenerator, Config};
+use serde::Serialize;
 use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
 
 /// Generate an authentication token for Aurora DSQL
 ///
@@ -16,32 +24,76 @@ pub async fn generate_auth_token(
     region: &str,
     admin_user: bool,
 ) -> Result<String, Box<dyn Error + Send + Sync>> {
-    // Load AWS configuration
-    let sdk_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-
-    // Create the AuthTokenGenerator with the cluster endpoint and region
-    let signer = AuthTokenGenerator::new(
-        Config::builder()
-            .hostname(cluster_endpoint)
-            .region(Region::new(region.to_string()))
-            .build()
-            .map_err(|e| e as Box<dyn Error + Send + Sync>)?,
-    );
-
-    // Generate the appropriate token based on whether we're connecting as admin or not
-    let token = if admin_user {
-        signer.db_connect_admin_auth_token(&sdk_config).await
-    } else {
-        signer.db_connect_auth_token(&sdk_config).await
-    };
-
-    // Handle result and convert to string
-    match token {
-        Ok(token) => Ok(token.to_string()),
-        Err(e) => Err(e as Box<dyn Error + Send + Sync>),
+    IamTokenSource::new(cluster_endpoint, region, admin_user)
+        .fetch_token()
+        .await
+}
+
+/// A generated token together with the connection details it is valid for
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenInfo {
+    pub token: String,
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub database: String,
+    pub region: String,
+    pub profile: Option<String>,
+    /// Whether this is an admin token
+    pub admin: bool,
+    /// When the token stops being accepted for new connections
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl TokenInfo {
+    /// libpq environment variables for connecting with this token
+    ///
+    /// `psql` and other libpq-based clients read these, so the token never has to be typed
+    /// in or passed on a command line, where other users could see it in `ps`.
+    pub fn libpq_env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("PGHOST", self.host.clone()),
+            ("PGPORT", self.port.to_string()),
+            ("PGUSER", self.user.clone()),
+            ("PGDATABASE", self.database.clone()),
+            ("PGPASSWORD", self.token.clone()),
+            ("PGSSLMODE", "require".to_string()),
+        ]
     }
 }
 
+/// Generate an authentication token for the configured cluster
+///
+/// Args:
+///   config: Resolved connection settings
+///   admin_user: Whether to generate a token for the admin user
+///
+/// Returns:
+///   The token with its expiry time and the settings it was generated for
+pub async fn generate_token_info(
+    config: &DsqlConfig,
+    admin_user: bool,
+) -> Result<TokenInfo, Box<dyn Error + Send + Sync>> {
+    let source = IamTokenSource::new(&config.host, &config.region, admin_user);
+
+    // Tokens are signed with the current time, so measuring before generating errs on
+    // the side of an earlier expiry
+    let issued_at = chrono::Utc::now();
+    let token = source.fetch_token().await?;
+
+    Ok(TokenInfo {
+        token,
+        host: config.host.clone(),
+        port: config.port,
+        user: config.user.clone(),
+        database: config.database.clone(),
+        region: config.region.clone(),
+        profile: config.profile.clone(),
+        admin: admin_user,
+        expires_at: issued_at + chrono::Duration::from_std(source.ttl())?,
+    })
+}
+
 /// Generate a database connection string with authentication token
 ///
 /// Args:
@@ -77,3 +129,243 @@ pub async fn get_connection_string(
         user, encoded_token, host, port, database
     ))
 }
+
+/// Lifetime requested for tokens minted by [`IamTokenSource`] (the DSQL maximum is 15 minutes)
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(900);
+
+/// How long before expiry a cached token is considered stale and gets renewed
+pub const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(120);
+
+/// Boxed future returned by [`TokenSource::fetch_token`]
+pub type TokenFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<String, Box<dyn Error + Send + Sync>>> + Send + 'a>>;
+
+/// Something that can mint database passwords on demand
+///
+/// The production implementation is [`IamTokenSource`]; tests plug in fake sources, e.g.
+/// one rotating the password of a role on a local Postgres instance.
+pub trait TokenSource: Send + Sync {
+    /// Mint a new token
+    fn fetch_token(&self) -> TokenFuture<'_>;
+
+    /// How long a freshly minted token stays valid
+    fn ttl(&self) -> Duration {
+        DEFAULT_TOKEN_TTL
+    }
+}
+
+/// Token source backed by the AWS SDK's DSQL auth token generator
+#[derive(Debug, Clone)]
+pub struct IamTokenSource {
+    hostname: String,
+    region: String,
+    admin_user: bool,
+    expires_in: Duration,
+}
+
+impl IamTokenSource {
+    /// Create a token source for the given cluster endpoint and region
+    pub fn new(hostname: impl Into<String>, region: impl Into<String>, admin_user: bool) -> Self {
+        Self {
+            hostname: hostname.into(),
+            region: region.into(),
+            admin_user,
+            expires_in: DEFAULT_TOKEN_TTL,
+        }
+    }
+}
+
+impl TokenSource for IamTokenSource {
+    fn fetch_token(&self) -> TokenFuture<'_> {
+        Box::pin(async move {
+            let sdk_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+
+            let signer = AuthTokenGenerator::new(
+                Config::builder()
+                    .hostname(&self.hostname)
+                    .region(Region::new(self.region.clone()))
+                    .expires_in(self.expires_in.as_secs())
+                    .build()
+                    .map_err(|e| e as Box<dyn Error + Send + Sync>)?,
+            );
+
+            let token = if self.admin_user {
+                signer.db_connect_admin_auth_token(&sdk_config).await
+            } else {
+                signer.db_connect_auth_token(&sdk_config).await
+            };
+
+            match token {
+                Ok(token) => Ok(token.to_string()),
+                Err(e) => Err(e as Box<dyn Error + Send + Sync>),
+            }
+        })
+    }
+
+    fn ttl(&self) -> Duration {
+        self.expires_in
+    }
+}
+
+/// A token together with the instant after which it should no longer be handed out
+#[derive(Clone)]
+struct CachedToken {
+    token: String,
+    refresh_at: Instant,
+}
+
+/// Caches tokens from a [`TokenSource`] and renews them ahead of expiry
+///
+/// Concurrent callers share a single in-flight refresh, so opening many connections at
+/// once only mints one token.
+pub struct TokenCache {
+    source: Arc<dyn TokenSource>,
+    refresh_margin: Duration,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+impl TokenCache {
+    /// Create a cache that renews tokens [`DEFAULT_REFRESH_MARGIN`] before they expire
+    pub fn new(source: Arc<dyn TokenSource>) -> Self {
+        Self::with_refresh_margin(source, DEFAULT_REFRESH_MARGIN)
+    }
+
+    /// Create a cache with a custom renewal margin
+    ///
+    /// The margin is clamped to half of the source's TTL so a token is always used for a while.
+    pub fn with_refresh_margin(source: Arc<dyn TokenSource>, refresh_margin: Duration) -> Self {
+        let refresh_margin = refresh_margin.min(source.ttl() / 2);
+        Self {
+            source,
+            refresh_margin,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Return the cached token, minting a new one if it is missing or close to expiry
+    pub async fn token(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let mut cached = self.cached.lock().await;
+
+        if let Some(entry) = cached.as_ref() {
+            if Instant::now() < entry.refresh_at {
+                return Ok(entry.token.clone());
+            }
+        }
+
+        let entry = self.mint().await?;
+        let token = entry.token.clone();
+        *cached = Some(entry);
+        Ok(token)
+    }
+
+    /// Mint a new token unconditionally, replacing whatever is cached
+    pub async fn refresh(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let mut cached = self.cached.lock().await;
+        let entry = self.mint().await?;
+        let token = entry.token.clone();
+        *cached = Some(entry);
+        Ok(token)
+    }
+
+    /// Time remaining until the cached token should be renewed (zero if nothing is cached)
+    pub async fn time_until_refresh(&self) -> Duration {
+        match self.cached.lock().await.as_ref() {
+            Some(entry) => entry.refresh_at.saturating_duration_since(Instant::now()),
+            None => Duration::ZERO,
+        }
+    }
+
+    async fn mint(&self) -> Result<CachedToken, Box<dyn Error + Send + Sync>> {
+        let issued_at = Instant::now();
+        let token = self.source.fetch_token().await;
+        metrics::observe_token_refresh(token.is_ok());
+        let token = token?;
+        let lifetime = self.source.ttl().saturating_sub(self.refresh_margin);
+        Ok(CachedToken {
+            token,
+            refresh_at: issued_at + lifetime,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Hands out `token-1`, `token-2`, ... and counts the calls
+    struct CountingSource {
+        minted: AtomicU32,
+        ttl: Duration,
+    }
+
+    impl CountingSource {
+        fn new(ttl: Duration) -> Arc<Self> {
+            Arc::new(Self {
+                minted: AtomicU32::new(0),
+                ttl,
+            })
+        }
+    }
+
+    impl TokenSource for CountingSource {
+        fn fetch_token(&self) -> TokenFuture<'_> {
+            Box::pin(async move {
+                // Slow enough for concurrent callers to overlap
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                let n = self.minted.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok(format!("token-{}", n))
+            })
+        }
+
+        fn ttl(&self) -> Duration {
+            self.ttl
+        }
+    }
+
+    #[tokio::test]
+    async fn reuses_a_fresh_token() {
+        let source = CountingSource::new(DEFAULT_TOKEN_TTL);
+        let cache = TokenCache::new(source.clone());
+
+        assert_eq!(cache.token().await.unwrap(), "token-1");
+        assert_eq!(cache.token().await.unwrap(), "token-1");
+        assert_eq!(source.minted.load(Ordering::SeqCst), 1);
+        assert!(cache.time_until_refresh().await > DEFAULT_TOKEN_TTL - DEFAULT_REFRESH_MARGIN * 2);
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_mint() {
+        let source = CountingSource::new(DEFAULT_TOKEN_TTL);
+        let cache = TokenCache::new(source.clone());
+
+        let (a, b, c) = tokio::join!(cache.token(), cache.token(), cache.token());
+        assert_eq!(
+            [a.unwrap(), b.unwrap(), c.unwrap()],
+            ["token-1", "token-1", "token-1"]
+        );
+        assert_eq!(source.minted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn renews_a_token_close_to_expiry() {
+        // The margin is clamped to half the TTL, so tokens are renewed after 50 ms
+        let source = CountingSource::new(Duration::from_millis(100));
+        let cache = TokenCache::with_refresh_margin(source.clone(), Duration::from_secs(60));
+
+        assert_eq!(cache.token().await.unwrap(), "token-1");
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(cache.time_until_refresh().await, Duration::ZERO);
+        assert_eq!(cache.token().await.unwrap(), "token-2");
+    }
+
+    #[tokio::test]
+    async fn refresh_replaces_the_cached_token() {
+        let source = CountingSource::new(DEFAULT_TOKEN_TTL);
+        let cache = TokenCache::new(source.clone());
+
+        assert_eq!(cache.token().await.unwrap(), "token-1");
+        assert_eq!(cache.refresh().await.unwrap(), "token-2");
+        assert_eq!(cache.token().await.unwrap(), "token-2");
+    }
+}
@@ -0,0 +1,580 @@
+use crate::config::DsqlConfig;
+use crate::stress::{OperationSummary, StressConfig, StressReport};
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Version of the result file layout, bumped on incompatible changes
+pub const RESULT_FORMAT_VERSION: u32 = 1;
+
+/// Directory result files are written to unless another one is given
+pub const DEFAULT_RESULTS_DIR: &str = "bench-results";
+
+/// Environment variable overriding the git commit recorded in result files
+pub const GIT_SHA_ENV: &str = "RUST_DSQL_GIT_SHA";
+
+/// Everything recorded about one stress test run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchResult {
+    pub format_version: u32,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    /// Commit the benchmark was run from, with a `-dirty` suffix for uncommitted changes
+    pub git_sha: Option<String>,
+    pub config: RunConfig,
+    pub environment: Environment,
+    pub elapsed_seconds: f64,
+    /// Summary rows: "total" first, then one per operation type
+    pub operations: Vec<OperationSummary>,
+    /// Number of operations by retries needed, per operation type
+    pub retry_depths: BTreeMap<String, BTreeMap<u64, u64>>,
+    /// Failed operations by kind of error, per operation type
+    pub error_kinds: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Latency histogram per operation type
+    pub latency_histograms: BTreeMap<String, Vec<HistogramBucket>>,
+    /// Messages of the first failed operations
+    pub error_samples: Vec<String>,
+}
+
+/// Stress test parameters of a run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunConfig {
+    pub workload: String,
+    /// Relative weight per operation type
+    pub weights: BTreeMap<String, u32>,
+    pub hot_keys: Option<usize>,
+    /// Number of operations, unless the run was limited by duration
+    pub total_operations: Option<usize>,
+    pub duration_seconds: Option<f64>,
+    pub concurrency: usize,
+    /// Target operations per second
+    pub rate: Option<f64>,
+    /// Users inserted per insert operation
+    #[serde(default = "single_row")]
+    pub batch_size: usize,
+    /// Size of the connection pool the workers shared (not recorded by older versions)
+    #[serde(default)]
+    pub max_connections: Option<u32>,
+}
+
+/// Batch size of runs recorded before batched inserts existed
+fn single_row() -> usize {
+    1
+}
+
+/// Where a run was made from and against
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Environment {
+    pub host: String,
+    pub region: String,
+    pub database: String,
+    pub user: String,
+    pub profile: Option<String>,
+    /// Version of this tool
+    pub client_version: String,
+    pub os: String,
+    pub arch: String,
+    pub cpus: usize,
+}
+
+/// Number of operations whose latency was recorded as (at most) `micros`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    pub micros: u64,
+    pub count: u64,
+}
+
+impl BenchResult {
+    /// Collect the result of a stress test run
+    ///
+    /// Args:
+    ///   report: Measurements of the run
+    ///   config: Stress test parameters
+    ///   db: Connection settings the run used
+    ///   started_at: When the run started
+    pub fn new(
+        report: &StressReport,
+        config: &StressConfig,
+        db: &DsqlConfig,
+        started_at: chrono::DateTime<chrono::Utc>,
+    ) -> Self {
+        let total = report.total();
+        let per_operation = std::iter::once(("total".to_string(), &total)).chain(
+            report
+                .operations
+                .iter()
+                .map(|(operation, stats)| (operation.to_string(), stats)),
+        );
+
+        let mut retry_depths = BTreeMap::new();
+        let mut error_kinds = BTreeMap::new();
+        let mut latency_histograms = BTreeMap::new();
+        for (name, stats) in per_operation {
+            let buckets = stats
+                .latency
+                .iter_recorded()
+                .map(|bucket| HistogramBucket {
+                    micros: bucket.value_iterated_to(),
+                    count: bucket.count_at_value(),
+                })
+                .collect();
+
+            retry_depths.insert(name.clone(), stats.retry_depths.clone());
+            error_kinds.insert(name.clone(), stats.error_kinds.clone());
+            latency_histograms.insert(name, buckets);
+        }
+
+        Self {
+            format_version: RESULT_FORMAT_VERSION,
+            started_at,
+            git_sha: git_sha(),
+            config: RunConfig {
+                workload: config.workload.name.clone(),
+                weights: config
+                    .workload
+                    .weights
+                    .iter()
+                    .map(|(operation, weight)| (operation.to_string(), *weight))
+                    .collect(),
+                hot_keys: config.workload.hot_keys,
+                total_operations: config.duration.is_none().then_some(config.total_operations),
+                duration_seconds: config.duration.map(|d| d.as_secs_f64()),
+                concurrency: config.concurrency,
+                rate: config.rate,
+                batch_size: config.batch_size,
+                max_connections: Some(db.pool.max_connections),
+            },
+            environment: Environment {
+                host: db.host.clone(),
+                region: db.region.clone(),
+                database: db.database.clone(),
+                user: db.user.clone(),
+                profile: db.profile.clone(),
+                client_version: env!("CARGO_PKG_VERSION").to_string(),
+                os: std::env::consts::OS.to_string(),
+                arch: std::env::consts::ARCH.to_string(),
+                cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            },
+            elapsed_seconds: report.elapsed.as_secs_f64(),
+            operations: report.summary(),
+            retry_depths,
+            error_kinds,
+            latency_histograms,
+            error_samples: report.errors.clone(),
+        }
+    }
+
+    /// Write the result as JSON into `dir`, named after its start time and workload
+    ///
+    /// Workloads read from a file can have any name, so characters other than ASCII
+    /// letters, digits, `_` and `-` are replaced by `_` in the file name.
+    ///
+    /// Returns:
+    ///   The path of the written file
+    pub fn save(&self, dir: &Path) -> Result<PathBuf, BenchError> {
+        let write_error = |path: &Path, source| BenchError::WriteFile {
+            path: path.to_path_buf(),
+            source,
+        };
+
+        fs::create_dir_all(dir).map_err(|e| write_error(dir, e))?;
+        let path = dir.join(format!(
+            "{}-{}.json",
+            self.started_at.format("%Y%m%dT%H%M%SZ"),
+            file_name_part(&self.config.workload)
+        ));
+        let json = serde_json::to_string_pretty(self).expect("results serialize to JSON");
+        fs::write(&path, json + "\n").map_err(|e| write_error(&path, e))?;
+
+        Ok(path)
+    }
+
+    /// Read a result file written by [`BenchResult::save`]
+    pub fn load(path: &Path) -> Result<Self, BenchError> {
+        let contents = fs::read_to_string(path).map_err(|source| BenchError::ReadFile {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        let version = serde_json::from_str::<serde_json::Value>(&contents)
+            .ok()
+            .and_then(|value| value.get("format_version")?.as_u64());
+        if let Some(version) = version.filter(|v| *v != u64::from(RESULT_FORMAT_VERSION)) {
+            return Err(BenchError::UnsupportedVersion {
+                path: path.to_path_buf(),
+                version,
+            });
+        }
+
+        serde_json::from_str(&contents).map_err(|source| BenchError::ParseFile {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Summary row for an operation type, or "total"
+    pub fn operation(&self, operation: &str) -> Option<&OperationSummary> {
+        self.operations
+            .iter()
+            .find(|row| row.operation == operation)
+    }
+}
+
+/// `name` with every character that is unsafe in a file name replaced by `_`
+fn file_name_part(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// The commit being benchmarked: `$RUST_DSQL_GIT_SHA`, else asked from git
+fn git_sha() -> Option<String> {
+    if let Ok(sha) = std::env::var(GIT_SHA_ENV) {
+        return Some(sha).filter(|sha| !sha.is_empty());
+    }
+
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    let sha = git(&["rev-parse", "HEAD"])?;
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .is_some_and(|status| !status.is_empty());
+    Some(if dirty { format!("{}-dirty", sha) } else { sha })
+}
+
+/// Whether a metric improves when it goes up or when it goes down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Better {
+    Higher,
+    Lower,
+}
+
+/// Reads one metric from a summary row
+type Metric = fn(&OperationSummary) -> f64;
+
+/// Metrics compared by [`compare`]
+const COMPARED_METRICS: [(&str, Better, Metric); 8] = [
+    ("rate", Better::Higher, |row| row.rate),
+    ("row_rate", Better::Higher, |row| row.row_rate),
+    ("latency_p50_ms", Better::Lower, |row| row.latency_p50_ms),
+    ("latency_p90_ms", Better::Lower, |row| row.latency_p90_ms),
+    ("latency_p99_ms", Better::Lower, |row| row.latency_p99_ms),
+    ("latency_p999_ms", Better::Lower, |row| row.latency_p999_ms),
+    ("error_rate", Better::Lower, |row| {
+        let total = row.successful + row.failed;
+        if total == 0 {
+            0.0
+        } else {
+            row.failed as f64 / total as f64
+        }
+    }),
+    ("conflict_rate", Better::Lower, |row| row.conflict_rate),
+];
+
+/// Change of one metric between two runs
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricDelta {
+    /// Operation type, or "total"
+    pub operation: String,
+    pub metric: String,
+    pub better: Better,
+    pub baseline: f64,
+    pub candidate: f64,
+    /// Relative change in percent (absent when the baseline is zero)
+    pub change_percent: Option<f64>,
+    /// Whether the metric got worse by more than the threshold
+    pub regression: bool,
+}
+
+/// Compare every metric of the operation types present in both runs
+///
+/// A metric regresses when it moves in the wrong direction by more than
+/// `threshold_percent` of its baseline value. A metric whose baseline is zero regresses
+/// as soon as it gets worse at all, e.g. the first errors in a previously clean run.
+pub fn compare(
+    baseline: &BenchResult,
+    candidate: &BenchResult,
+    threshold_percent: f64,
+) -> Vec<MetricDelta> {
+    let mut deltas = Vec::new();
+
+    for base in &baseline.operations {
+        let Some(cand) = candidate.operation(&base.operation) else {
+            continue;
+        };
+
+        for (metric, better, value) in COMPARED_METRICS {
+            let (before, after) = (value(base), value(cand));
+            let change_percent = (before != 0.0).then(|| (after - before) / before * 100.0);
+            let worse_by = match (change_percent, better) {
+                (Some(change), Better::Higher) => -change,
+                (Some(change), Better::Lower) => change,
+                (None, Better::Higher) if after < before => f64::INFINITY,
+                (None, Better::Lower) if after > before => f64::INFINITY,
+                (None, _) => 0.0,
+            };
+
+            deltas.push(MetricDelta {
+                operation: base.operation.clone(),
+                metric: metric.to_string(),
+                better,
+                baseline: before,
+                candidate: after,
+                change_percent,
+                regression: worse_by > threshold_percent,
+            });
+        }
+    }
+
+    deltas
+}
+
+/// Problems reading or writing result files
+#[derive(Debug)]
+pub enum BenchError {
+    /// A result file could not be read
+    ReadFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// A result file is not valid JSON or is missing fields
+    ParseFile {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// A result file was written by an incompatible version
+    UnsupportedVersion { path: PathBuf, version: u64 },
+    /// A result file could not be written
+    WriteFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+impl fmt::Display for BenchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BenchError::ReadFile { path, source } => {
+                write!(f, "cannot read result file {}: {}", path.display(), source)
+            }
+            BenchError::ParseFile { path, source } => {
+                write!(f, "invalid result file {}: {}", path.display(), source)
+            }
+            BenchError::UnsupportedVersion { path, version } => write!(
+                f,
+                "result file {} has format version {}, expected {}",
+                path.display(),
+                version,
+                RESULT_FORMAT_VERSION
+            ),
+            BenchError::WriteFile { path, source } => {
+                write!(f, "cannot write result file {}: {}", path.display(), source)
+            }
+        }
+    }
+}
+
+impl Error for BenchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BenchError::ReadFile { source, .. } => Some(source),
+            BenchError::ParseFile { source, .. } => Some(source),
+            BenchError::WriteFile { source, .. } => Some(source),
+            BenchError::UnsupportedVersion { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::types::chrono::TimeZone;
+
+    fn row(operation: &str, rate: f64, latency_p99_ms: f64, failed: u64) -> OperationSummary {
+        OperationSummary {
+            operation: operation.to_string(),
+            successful: 1000,
+            failed,
+            rate,
+            attempts: 1000 + failed,
+            attempt_rate: rate,
+            latency_mean_ms: 5.0,
+            latency_p50_ms: 5.0,
+            latency_p90_ms: 8.0,
+            latency_p99_ms,
+            latency_p999_ms: 20.0,
+            latency_max_ms: 25.0,
+            retries: 0,
+            retries_per_operation: 0.0,
+            max_retries: 0,
+            conflicts: 0,
+            conflict_rate: 0.0,
+            rows: 1000,
+            row_rate: rate,
+            statements: 1000,
+            statement_rate: rate,
+        }
+    }
+
+    fn result(workload: &str, operations: Vec<OperationSummary>) -> BenchResult {
+        BenchResult {
+            format_version: RESULT_FORMAT_VERSION,
+            started_at: chrono::Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 5).unwrap(),
+            git_sha: Some("abc123".to_string()),
+            config: RunConfig {
+                workload: workload.to_string(),
+                weights: BTreeMap::new(),
+                hot_keys: None,
+                total_operations: Some(1000),
+                duration_seconds: None,
+                concurrency: 10,
+                rate: None,
+                batch_size: 1,
+                max_connections: Some(10),
+            },
+            environment: Environment {
+                host: "localhost".to_string(),
+                region: "us-east-1".to_string(),
+                database: "postgres".to_string(),
+                user: "admin".to_string(),
+                profile: None,
+                client_version: "0.1.0".to_string(),
+                os: "linux".to_string(),
+                arch: "x86_64".to_string(),
+                cpus: 4,
+            },
+            elapsed_seconds: 10.0,
+            operations,
+            retry_depths: BTreeMap::new(),
+            error_kinds: BTreeMap::new(),
+            latency_histograms: BTreeMap::new(),
+            error_samples: Vec::new(),
+        }
+    }
+
+    fn delta<'a>(deltas: &'a [MetricDelta], operation: &str, metric: &str) -> &'a MetricDelta {
+        deltas
+            .iter()
+            .find(|delta| delta.operation == operation && delta.metric == metric)
+            .unwrap()
+    }
+
+    fn scratch_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("rust_dsql_bench_{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn slower_runs_regress_beyond_the_threshold() {
+        let baseline = result("mixed", vec![row("total", 100.0, 10.0, 0)]);
+        let candidate = result("mixed", vec![row("total", 85.0, 10.5, 0)]);
+
+        let deltas = compare(&baseline, &candidate, 10.0);
+        let rate = delta(&deltas, "total", "rate");
+        assert_eq!(rate.change_percent, Some(-15.0));
+        assert!(rate.regression);
+
+        // 5% worse is within the threshold
+        let p99 = delta(&deltas, "total", "latency_p99_ms");
+        assert_eq!(p99.change_percent, Some(5.0));
+        assert!(!p99.regression);
+        assert!(compare(&baseline, &candidate, 20.0)
+            .iter()
+            .all(|delta| !delta.regression));
+    }
+
+    #[test]
+    fn improvements_are_not_regressions() {
+        let baseline = result("mixed", vec![row("total", 100.0, 10.0, 10)]);
+        let candidate = result("mixed", vec![row("total", 150.0, 5.0, 0)]);
+
+        let deltas = compare(&baseline, &candidate, 0.0);
+        assert_eq!(delta(&deltas, "total", "rate").change_percent, Some(50.0));
+        assert_eq!(
+            delta(&deltas, "total", "latency_p99_ms").change_percent,
+            Some(-50.0)
+        );
+        assert!(deltas.iter().all(|delta| !delta.regression));
+    }
+
+    #[test]
+    fn first_errors_regress_whatever_the_threshold() {
+        let baseline = result("mixed", vec![row("total", 100.0, 10.0, 0)]);
+        let candidate = result("mixed", vec![row("total", 100.0, 10.0, 1)]);
+
+        let deltas = compare(&baseline, &candidate, 1000.0);
+        let errors = delta(&deltas, "total", "error_rate");
+        assert_eq!(errors.change_percent, None);
+        assert!(errors.regression);
+    }
+
+    #[test]
+    fn operations_missing_from_either_run_are_skipped() {
+        let baseline = result(
+            "mixed",
+            vec![row("total", 100.0, 10.0, 0), row("insert", 50.0, 10.0, 0)],
+        );
+        let candidate = result(
+            "mixed",
+            vec![row("total", 100.0, 10.0, 0), row("read", 50.0, 10.0, 0)],
+        );
+
+        let deltas = compare(&baseline, &candidate, 10.0);
+        assert_eq!(deltas.len(), COMPARED_METRICS.len());
+        assert!(deltas.iter().all(|delta| delta.operation == "total"));
+    }
+
+    #[test]
+    fn saved_results_load_back() {
+        let dir = scratch_dir();
+        let saved = result("mixed", vec![row("total", 100.0, 10.0, 0)]);
+        let path = saved.save(&dir).unwrap();
+        assert_eq!(path, dir.join("20260102T030405Z-mixed.json"));
+
+        let loaded = BenchResult::load(&path).unwrap();
+        assert_eq!(loaded.operations, saved.operations);
+        assert_eq!(loaded.config.workload, "mixed");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn workload_names_are_sanitised_in_file_names() {
+        let dir = scratch_dir();
+        let path = result("../hot keys/v2", Vec::new()).save(&dir).unwrap();
+        assert_eq!(path, dir.join("20260102T030405Z-___hot_keys_v2.json"));
+        assert!(path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_format_versions_are_rejected() {
+        let dir = scratch_dir();
+        let mut newer = result("mixed", Vec::new());
+        newer.format_version = RESULT_FORMAT_VERSION + 1;
+        let path = newer.save(&dir).unwrap();
+
+        match BenchResult::load(&path) {
+            Err(BenchError::UnsupportedVersion { version, .. }) => {
+                assert_eq!(version, u64::from(RESULT_FORMAT_VERSION + 1))
+            }
+            other => panic!("expected an unsupported version, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
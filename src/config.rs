@@ -0,0 +1,803 @@
+use crate::endpoint::{self, EndpointError};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Port used when no source specifies one
+pub const DEFAULT_PORT: u16 = 5432;
+
+/// User used when no source specifies one
+pub const DEFAULT_USER: &str = "admin";
+
+/// Database used when no source specifies one
+pub const DEFAULT_DATABASE: &str = "postgres";
+
+/// Pool size used when no source specifies one
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+
+/// How long to wait for a pooled connection when no source specifies it
+pub const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a connection may sit idle in the pool when no source specifies it
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Age at which pooled connections are replaced when no source specifies it
+///
+/// Kept below [`DSQL_MAX_CONNECTION_AGE`] so the pool retires connections itself instead
+/// of having DSQL close them while they are in use.
+pub const DEFAULT_MAX_LIFETIME: Duration = Duration::from_secs(55 * 60);
+
+/// DSQL closes every connection after one hour
+pub const DSQL_MAX_CONNECTION_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Environment variable overriding the location of the config file
+pub const CONFIG_PATH_ENV: &str = "RUST_DSQL_CONFIG";
+
+/// Environment variable selecting a profile from the config file
+pub const PROFILE_ENV: &str = "DSQL_PROFILE";
+
+/// Fully resolved connection settings shared by every subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DsqlConfig {
+    /// Cluster endpoint
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub database: String,
+    /// AWS region used to sign auth tokens
+    pub region: String,
+    /// Name of the config file profile that was applied, if any
+    pub profile: Option<String>,
+    /// Connection pool tuning
+    pub pool: PoolConfig,
+}
+
+/// Connection pool settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Connections kept open even when idle
+    pub min_connections: u32,
+    /// Most connections open at once; more concurrent operations wait for one
+    pub max_connections: u32,
+    /// How long an operation waits for a connection before failing
+    pub acquire_timeout: Duration,
+    /// Idle connections above `min_connections` are closed after this long
+    pub idle_timeout: Duration,
+    /// Connections are closed once this old, which must be less than DSQL's one hour
+    pub max_lifetime: Duration,
+    /// Check that a connection is alive with a ping before handing it out
+    pub test_before_acquire: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_connections: 0,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            acquire_timeout: DEFAULT_ACQUIRE_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_lifetime: DEFAULT_MAX_LIFETIME,
+            test_before_acquire: true,
+        }
+    }
+}
+
+impl DsqlConfig {
+    /// Whether tokens should be generated for the DSQL `admin` role
+    pub fn is_admin(&self) -> bool {
+        self.user.eq_ignore_ascii_case("admin")
+    }
+
+    /// Resolve the configuration from all sources
+    ///
+    /// Each setting is taken from the first source that provides it:
+    ///   1. `cli` (command line flags)
+    ///   2. environment variables (`DB_HOST`, `DB_PORT`, `DB_USER`, `DB_NAME`,
+    ///      `DB_REGION`), including those loaded from `.env`
+    ///   3. the selected profile of the config file
+    ///   4. built-in defaults (port 5432, user `admin`, database `postgres`, and the
+    ///      [`PoolConfig`] defaults)
+    ///
+    /// Pool settings have no environment variables and come from flags or the profile's
+    /// `pool` table.
+    ///
+    /// If no region is found in any source, `AWS_REGION` is used, else the region is
+    /// derived from the cluster endpoint. `AWS_REGION` is often set for other tools, so
+    /// unlike `DB_REGION` it does not override a profile's region.
+    /// The profile is selected with `--profile`, then `DSQL_PROFILE`, then the file's
+    /// `default_profile`, then a profile named `default`.
+    ///
+    /// Args:
+    ///   cli: Settings given on the command line
+    ///   profile: Profile requested on the command line
+    ///
+    /// Returns:
+    ///   A Result containing the resolved configuration or a description of what is wrong
+    pub fn load(cli: ConfigOverrides, profile: Option<&str>) -> Result<Self, ConfigError> {
+        // Load environment variables from .env file (does not override the real environment)
+        dotenv::dotenv().ok();
+
+        let env_overrides = ConfigOverrides::from_env()?;
+        let profile = profile
+            .map(str::to_string)
+            .or_else(|| env::var(PROFILE_ENV).ok().filter(|p| !p.is_empty()));
+
+        let path = config_path();
+        let file = match &path {
+            Some(path) if path.exists() => Some(ConfigFile::read(path)?),
+            _ => None,
+        };
+
+        let (file_overrides, profile) = match (file, path) {
+            (Some(file), Some(path)) => file.select_profile(profile, &path)?,
+            (_, path) => match profile {
+                Some(name) => {
+                    return Err(ConfigError::NoConfigFile {
+                        profile: name,
+                        path,
+                    })
+                }
+                None => (ConfigOverrides::default(), None),
+            },
+        };
+
+        cli.or(env_overrides).or(file_overrides).resolve(profile)
+    }
+}
+
+/// Settings from a single source; unset fields fall through to lower-precedence sources
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigOverrides {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub database: Option<String>,
+    pub region: Option<String>,
+    #[serde(default)]
+    pub pool: PoolOverrides,
+}
+
+impl ConfigOverrides {
+    /// Read settings from the environment
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+
+        let port = match var("DB_PORT") {
+            Some(value) => Some(parse_port(&value, "DB_PORT")?),
+            None => None,
+        };
+
+        Ok(Self {
+            host: var("DB_HOST"),
+            port,
+            user: var("DB_USER"),
+            database: var("DB_NAME"),
+            region: var("DB_REGION"),
+            pool: PoolOverrides::default(),
+        })
+    }
+
+    /// Fill fields that are unset here from `lower`
+    pub fn or(self, lower: ConfigOverrides) -> Self {
+        Self {
+            host: self.host.or(lower.host),
+            port: self.port.or(lower.port),
+            user: self.user.or(lower.user),
+            database: self.database.or(lower.database),
+            region: self.region.or(lower.region),
+            pool: self.pool.or(lower.pool),
+        }
+    }
+
+    /// Apply defaults and validate, producing the final configuration
+    fn resolve(self, profile: Option<String>) -> Result<DsqlConfig, ConfigError> {
+        let host = self
+            .host
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .ok_or(ConfigError::MissingHost)?;
+
+        let region = endpoint::resolve_region(self.region.as_deref(), &host)?;
+        let pool = self.pool.resolve()?;
+
+        Ok(DsqlConfig {
+            port: self.port.unwrap_or(DEFAULT_PORT),
+            user: self.user.unwrap_or_else(|| DEFAULT_USER.to_string()),
+            database: self
+                .database
+                .unwrap_or_else(|| DEFAULT_DATABASE.to_string()),
+            host,
+            region,
+            profile,
+            pool,
+        })
+    }
+}
+
+/// Pool settings from a single source; unset fields fall through like [`ConfigOverrides`]
+///
+/// In the config file durations are strings such as `"30s"` or `"50m"`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolOverrides {
+    pub min_connections: Option<u32>,
+    pub max_connections: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub acquire_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub idle_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub max_lifetime: Option<Duration>,
+    pub test_before_acquire: Option<bool>,
+}
+
+impl PoolOverrides {
+    /// Fill fields that are unset here from `lower`
+    pub fn or(self, lower: PoolOverrides) -> Self {
+        Self {
+            min_connections: self.min_connections.or(lower.min_connections),
+            max_connections: self.max_connections.or(lower.max_connections),
+            acquire_timeout: self.acquire_timeout.or(lower.acquire_timeout),
+            idle_timeout: self.idle_timeout.or(lower.idle_timeout),
+            max_lifetime: self.max_lifetime.or(lower.max_lifetime),
+            test_before_acquire: self.test_before_acquire.or(lower.test_before_acquire),
+        }
+    }
+
+    /// Apply defaults and check the settings fit together and within DSQL's limits
+    fn resolve(self) -> Result<PoolConfig, ConfigError> {
+        let defaults = PoolConfig::default();
+        let pool = PoolConfig {
+            min_connections: self.min_connections.unwrap_or(defaults.min_connections),
+            max_connections: self.max_connections.unwrap_or(defaults.max_connections),
+            acquire_timeout: self.acquire_timeout.unwrap_or(defaults.acquire_timeout),
+            idle_timeout: self.idle_timeout.unwrap_or(defaults.idle_timeout),
+            max_lifetime: self.max_lifetime.unwrap_or(defaults.max_lifetime),
+            test_before_acquire: self
+                .test_before_acquire
+                .unwrap_or(defaults.test_before_acquire),
+        };
+
+        if pool.max_connections == 0 {
+            return Err(ConfigError::InvalidPool(
+                "max_connections must be at least 1".to_string(),
+            ));
+        }
+        if pool.min_connections > pool.max_connections {
+            return Err(ConfigError::InvalidPool(format!(
+                "min_connections ({}) is greater than max_connections ({})",
+                pool.min_connections, pool.max_connections
+            )));
+        }
+        if pool.max_lifetime >= DSQL_MAX_CONNECTION_AGE {
+            return Err(ConfigError::InvalidPool(format!(
+                "max_lifetime ({}) must be less than {}, after which DSQL closes connections",
+                humantime::format_duration(pool.max_lifetime),
+                humantime::format_duration(DSQL_MAX_CONNECTION_AGE)
+            )));
+        }
+
+        Ok(pool)
+    }
+}
+
+/// Read an optional duration written like `"30s"` or `"5m"`
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// On-disk layout of `config.toml`
+///
+/// ```toml
+/// default_profile = "dev"
+///
+/// [profiles.dev]
+/// host = "abcdefghijklmnopqrstuvwxyz.dsql.eu-west-1.on.aws"
+/// user = "admin"
+///
+/// [profiles.dev.pool]
+/// max_connections = 20
+/// acquire_timeout = "10s"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, ConfigOverrides>,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::ReadFile {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        toml::from_str(&contents).map_err(|source| ConfigError::ParseFile {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Pick the requested profile, else the file's `default_profile`, else a profile
+    /// named `default`, and return its settings
+    fn select_profile(
+        mut self,
+        requested: Option<String>,
+        path: &Path,
+    ) -> Result<(ConfigOverrides, Option<String>), ConfigError> {
+        let name = match requested.or(self.default_profile.take()) {
+            Some(name) => name,
+            None if self.profiles.contains_key("default") => "default".to_string(),
+            None => return Ok((ConfigOverrides::default(), None)),
+        };
+
+        match self.profiles.remove(&name) {
+            Some(profile) => Ok((profile, Some(name))),
+            None => Err(ConfigError::UnknownProfile {
+                name,
+                path: path.to_path_buf(),
+            }),
+        }
+    }
+}
+
+/// Location of the config file: `$RUST_DSQL_CONFIG`, else `~/.config/rust-dsql/config.toml`
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(CONFIG_PATH_ENV).filter(|p| !p.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(|home| PathBuf::from(home).join(".config/rust-dsql/config.toml"))
+}
+
+fn parse_port(value: &str, origin: &str) -> Result<u16, ConfigError> {
+    value
+        .trim()
+        .parse::<u16>()
+        .map_err(|_| ConfigError::InvalidPort {
+            value: value.to_string(),
+            origin: origin.to_string(),
+        })
+}
+
+/// Problems found while resolving the configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    /// No source provided the cluster endpoint
+    MissingHost,
+    /// A port value is not a number between 0 and 65535
+    InvalidPort { value: String, origin: String },
+    /// The config file exists but could not be read
+    ReadFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The config file is not valid TOML or contains unknown keys
+    ParseFile {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    /// The requested profile is not defined in the config file
+    UnknownProfile { name: String, path: PathBuf },
+    /// A profile was requested but there is no config file
+    NoConfigFile {
+        profile: String,
+        path: Option<PathBuf>,
+    },
+    /// The endpoint or region is invalid
+    Endpoint(EndpointError),
+    /// The pool settings are inconsistent or exceed DSQL's limits
+    InvalidPool(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingHost => write!(
+                f,
+                "no cluster endpoint configured; pass --host, set DB_HOST (e.g. in .env) or add `host` to a config profile"
+            ),
+            ConfigError::InvalidPort { value, origin } => {
+                write!(f, "invalid port '{}' from {}", value, origin)
+            }
+            ConfigError::ReadFile { path, source } => {
+                write!(f, "cannot read config file {}: {}", path.display(), source)
+            }
+            ConfigError::ParseFile { path, source } => {
+                write!(f, "invalid config file {}: {}", path.display(), source)
+            }
+            ConfigError::UnknownProfile { name, path } => {
+                write!(f, "profile '{}' is not defined in {}", name, path.display())
+            }
+            ConfigError::NoConfigFile { profile, path } => match path {
+                Some(path) => write!(
+                    f,
+                    "profile '{}' requested but {} does not exist",
+                    profile,
+                    path.display()
+                ),
+                None => write!(
+                    f,
+                    "profile '{}' requested but no config file location is known; set {}",
+                    profile, CONFIG_PATH_ENV
+                ),
+            },
+            ConfigError::Endpoint(err) => write!(f, "{}", err),
+            ConfigError::InvalidPool(reason) => write!(f, "invalid pool settings: {}", reason),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::ReadFile { source, .. } => Some(source),
+            ConfigError::ParseFile { source, .. } => Some(source),
+            ConfigError::Endpoint(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<EndpointError> for ConfigError {
+    fn from(err: EndpointError) -> Self {
+        ConfigError::Endpoint(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+    use uuid::Uuid;
+
+    const HOST: &str = "abcdefghijklmnopqrstuvwxyz.dsql.eu-west-1.on.aws";
+
+    fn overrides(host: Option<&str>, user: Option<&str>, region: Option<&str>) -> ConfigOverrides {
+        ConfigOverrides {
+            host: host.map(str::to_string),
+            user: user.map(str::to_string),
+            region: region.map(str::to_string),
+            ..ConfigOverrides::default()
+        }
+    }
+
+    fn pool_error(pool: PoolOverrides) -> String {
+        match pool.resolve() {
+            Err(ConfigError::InvalidPool(reason)) => reason,
+            other => panic!("expected invalid pool settings, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn higher_sources_win_field_by_field() {
+        let cli = overrides(None, Some("cli"), None);
+        let env = overrides(None, Some("env"), Some("us-east-2"));
+        let profile = ConfigOverrides {
+            port: Some(6543),
+            ..overrides(Some(HOST), Some("profile"), Some("eu-west-1"))
+        };
+
+        let merged = cli.or(env).or(profile);
+        assert_eq!(merged.host.as_deref(), Some(HOST));
+        assert_eq!(merged.user.as_deref(), Some("cli"));
+        assert_eq!(merged.region.as_deref(), Some("us-east-2"));
+        assert_eq!(merged.port, Some(6543));
+
+        let pool = PoolOverrides {
+            max_connections: Some(4),
+            ..PoolOverrides::default()
+        }
+        .or(PoolOverrides {
+            max_connections: Some(20),
+            min_connections: Some(2),
+            ..PoolOverrides::default()
+        });
+        assert_eq!(pool.max_connections, Some(4));
+        assert_eq!(pool.min_connections, Some(2));
+    }
+
+    #[test]
+    fn defaults_fill_the_rest() {
+        let config = ConfigOverrides {
+            region: Some("eu-west-1".to_string()),
+            ..overrides(Some(HOST), None, None)
+        }
+        .resolve(None)
+        .unwrap();
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.user, DEFAULT_USER);
+        assert_eq!(config.database, DEFAULT_DATABASE);
+        assert_eq!(config.region, "eu-west-1");
+        assert_eq!(config.pool, PoolConfig::default());
+        assert!(config.is_admin());
+
+        assert!(matches!(
+            overrides(Some("  "), None, None).resolve(None),
+            Err(ConfigError::MissingHost)
+        ));
+    }
+
+    #[test]
+    fn pool_limits() {
+        assert_eq!(
+            PoolOverrides::default().resolve().unwrap(),
+            PoolConfig::default()
+        );
+
+        let reason = pool_error(PoolOverrides {
+            max_connections: Some(0),
+            ..PoolOverrides::default()
+        });
+        assert!(reason.contains("at least 1"), "{}", reason);
+
+        let reason = pool_error(PoolOverrides {
+            min_connections: Some(5),
+            max_connections: Some(4),
+            ..PoolOverrides::default()
+        });
+        assert!(
+            reason.contains("greater than max_connections"),
+            "{}",
+            reason
+        );
+        PoolOverrides {
+            min_connections: Some(4),
+            max_connections: Some(4),
+            ..PoolOverrides::default()
+        }
+        .resolve()
+        .unwrap();
+
+        let reason = pool_error(PoolOverrides {
+            max_lifetime: Some(DSQL_MAX_CONNECTION_AGE),
+            ..PoolOverrides::default()
+        });
+        assert!(reason.contains("must be less than 1h"), "{}", reason);
+        PoolOverrides {
+            max_lifetime: Some(Duration::from_secs(59 * 60)),
+            ..PoolOverrides::default()
+        }
+        .resolve()
+        .unwrap();
+    }
+
+    #[test]
+    fn profiles_are_selected_by_name_then_default() {
+        let file = |contents: &str| toml::from_str::<ConfigFile>(contents).unwrap();
+        let path = Path::new("config.toml");
+        let contents = r#"
+            default_profile = "dev"
+
+            [profiles.dev]
+            user = "dev"
+
+            [profiles.prod]
+            user = "prod"
+
+            [profiles.prod.pool]
+            max_connections = 32
+            acquire_timeout = "10s"
+        "#;
+
+        let (prod, name) = file(contents)
+            .select_profile(Some("prod".to_string()), path)
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("prod"));
+        assert_eq!(prod.user.as_deref(), Some("prod"));
+        assert_eq!(prod.pool.max_connections, Some(32));
+        assert_eq!(prod.pool.acquire_timeout, Some(Duration::from_secs(10)));
+
+        let (dev, name) = file(contents).select_profile(None, path).unwrap();
+        assert_eq!(
+            (dev.user.as_deref(), name.as_deref()),
+            (Some("dev"), Some("dev"))
+        );
+
+        let (default, name) = file("[profiles.default]\nuser = \"d\"")
+            .select_profile(None, path)
+            .unwrap();
+        assert_eq!(
+            (default.user.as_deref(), name.as_deref()),
+            (Some("d"), Some("default"))
+        );
+
+        let (none, name) = file("").select_profile(None, path).unwrap();
+        assert_eq!((none, name), (ConfigOverrides::default(), None));
+
+        assert!(matches!(
+            file(contents).select_profile(Some("staging".to_string()), path),
+            Err(ConfigError::UnknownProfile { name, .. }) if name == "staging"
+        ));
+        assert!(toml::from_str::<ConfigFile>("[profiles.dev]\nhots = \"x\"").is_err());
+    }
+
+    // The tests below change environment variables and the working directory, so each
+    // holds `ENV` while it runs
+
+    const VARS: &[&str] = &[
+        "DB_HOST",
+        "DB_PORT",
+        "DB_USER",
+        "DB_NAME",
+        "DB_REGION",
+        "AWS_REGION",
+        PROFILE_ENV,
+        CONFIG_PATH_ENV,
+    ];
+
+    const CONFIG: &str = r#"
+default_profile = "dev"
+
+[profiles.dev]
+host = "abcdefghijklmnopqrstuvwxyz.dsql.eu-west-1.on.aws"
+port = 6000
+user = "profile"
+database = "profile_db"
+region = "ap-northeast-1"
+
+[profiles.prod]
+host = "zyxwvutsrqponmlkjihgfedcba.dsql.us-east-1.on.aws"
+"#;
+
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// A clean environment with `CONFIG` as the config file, restored when dropped
+    struct Env {
+        dir: PathBuf,
+        cwd: PathBuf,
+        _guard: MutexGuard<'static, ()>,
+    }
+
+    impl Env {
+        fn new() -> Self {
+            let guard = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            for var in VARS {
+                env::remove_var(var);
+            }
+
+            let dir = env::temp_dir().join(format!("rust_dsql_config_{}", Uuid::new_v4().simple()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("config.toml"), CONFIG).unwrap();
+            env::set_var(CONFIG_PATH_ENV, dir.join("config.toml"));
+
+            // No `.env` of the checkout or its parents must leak in
+            let cwd = env::current_dir().unwrap();
+            env::set_current_dir(&dir).unwrap();
+
+            Self {
+                dir,
+                cwd,
+                _guard: guard,
+            }
+        }
+    }
+
+    impl Drop for Env {
+        fn drop(&mut self) {
+            env::set_current_dir(&self.cwd).unwrap();
+            for var in VARS {
+                env::remove_var(var);
+            }
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn flags_then_environment_then_profile() {
+        let _env = Env::new();
+        env::set_var("DB_USER", "env");
+        env::set_var("DB_NAME", "env_db");
+
+        let config = DsqlConfig::load(overrides(None, Some("cli"), None), None).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("dev"));
+        assert_eq!(config.host, HOST);
+        assert_eq!(config.port, 6000);
+        assert_eq!(config.user, "cli");
+        assert_eq!(config.database, "env_db");
+        assert_eq!(config.region, "ap-northeast-1");
+
+        env::set_var("DB_PORT", "7000");
+        env::set_var("DB_REGION", "us-east-2");
+        let config = DsqlConfig::load(overrides(None, None, Some("eu-central-1")), None).unwrap();
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.user, "env");
+        assert_eq!(config.region, "eu-central-1");
+
+        let config = DsqlConfig::load(ConfigOverrides::default(), None).unwrap();
+        assert_eq!(config.region, "us-east-2");
+    }
+
+    #[test]
+    fn defaults_and_the_endpoint_fill_the_rest() {
+        let _env = Env::new();
+        env::set_var(PROFILE_ENV, "prod");
+
+        let config = DsqlConfig::load(ConfigOverrides::default(), None).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("prod"));
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.user, "admin");
+        assert_eq!(config.database, "postgres");
+        assert_eq!(config.region, "us-east-1");
+
+        // --profile wins over DSQL_PROFILE
+        let config = DsqlConfig::load(ConfigOverrides::default(), Some("dev")).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("dev"));
+    }
+
+    #[test]
+    fn aws_region_does_not_override_a_profile() {
+        let _env = Env::new();
+        env::set_var("AWS_REGION", "us-west-2");
+
+        let config = DsqlConfig::load(ConfigOverrides::default(), Some("dev")).unwrap();
+        assert_eq!(config.region, "ap-northeast-1");
+
+        // Without a region in any source it still beats the endpoint's
+        let config = DsqlConfig::load(ConfigOverrides::default(), Some("prod")).unwrap();
+        assert_eq!(config.region, "us-west-2");
+
+        env::set_var("DB_REGION", "us-east-2");
+        let config = DsqlConfig::load(ConfigOverrides::default(), Some("dev")).unwrap();
+        assert_eq!(config.region, "us-east-2");
+    }
+
+    #[test]
+    fn dot_env_fills_in_for_the_environment() {
+        let env = Env::new();
+        fs::write(
+            env.dir.join(".env"),
+            "DB_USER=dot_env\nDB_NAME=dot_env_db\nDB_REGION=eu-west-2\n",
+        )
+        .unwrap();
+        env::set_var("DB_NAME", "env_db");
+
+        let config = DsqlConfig::load(ConfigOverrides::default(), None).unwrap();
+        assert_eq!(config.user, "dot_env");
+        assert_eq!(config.database, "env_db");
+        assert_eq!(config.region, "eu-west-2");
+    }
+
+    #[test]
+    fn missing_profiles_and_files_are_errors() {
+        let env = Env::new();
+
+        assert!(matches!(
+            DsqlConfig::load(ConfigOverrides::default(), Some("staging")),
+            Err(ConfigError::UnknownProfile { name, .. }) if name == "staging"
+        ));
+
+        env::set_var(CONFIG_PATH_ENV, env.dir.join("missing.toml"));
+        assert!(matches!(
+            DsqlConfig::load(ConfigOverrides::default(), Some("dev")),
+            Err(ConfigError::NoConfigFile { profile, .. }) if profile == "dev"
+        ));
+        assert!(matches!(
+            DsqlConfig::load(ConfigOverrides::default(), None),
+            Err(ConfigError::MissingHost)
+        ));
+
+        env::set_var("DB_HOST", HOST);
+        env::set_var("DB_PORT", "not-a-port");
+        assert!(matches!(
+            DsqlConfig::load(ConfigOverrides::default(), None),
+            Err(ConfigError::InvalidPort { .. })
+        ));
+    }
+}
@@ -0,0 +1,324 @@
+use crate::auth::{IamTokenSource, TokenCache};
+use crate::config::{DsqlConfig, PoolConfig};
+use crate::metrics;
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions, PgSslMode, Postgres};
+use std::error::Error;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// How long to wait before trying again when a background token refresh fails
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// A connection pool together with the task renewing its credentials
+///
+/// Derefs to the [`PgPool`], so it is used like one, and `pool.clone()` hands out
+/// ordinary pool handles. Dropping this handle stops the renewal, as does closing the
+/// pool: keep it for as long as the pool is in use, since connections opened afterwards
+/// would use an expired token.
+#[derive(Debug)]
+pub struct RefreshingPool {
+    pool: PgPool,
+    refresher: JoinHandle<()>,
+}
+
+impl Deref for RefreshingPool {
+    type Target = PgPool;
+
+    fn deref(&self) -> &PgPool {
+        &self.pool
+    }
+}
+
+impl Drop for RefreshingPool {
+    fn drop(&mut self) {
+        // The task holds a pool handle, so it would otherwise keep the pool open forever
+        self.refresher.abort();
+    }
+}
+
+/// Connection settings for the configured cluster, without a password
+pub fn connect_options(config: &DsqlConfig) -> PgConnectOptions {
+    PgConnectOptions::new()
+        .host(&config.host)
+        .port(config.port)
+        .username(&config.user)
+        .database(&config.database)
+        .ssl_mode(PgSslMode::Require)
+}
+
+/// Pool options for the configured pool size, timeouts and connection lifetime
+pub fn pool_options(pool: &PoolConfig) -> PgPoolOptions {
+    PgPoolOptions::new()
+        .min_connections(pool.min_connections)
+        .max_connections(pool.max_connections)
+        .acquire_timeout(pool.acquire_timeout)
+        .idle_timeout(pool.idle_timeout)
+        .max_lifetime(pool.max_lifetime)
+        .test_before_acquire(pool.test_before_acquire)
+}
+
+/// Create a database connection pool for the configured cluster
+///
+/// Tokens expire after 15 minutes, so the pool mints fresh ones for new connections
+/// (see [`create_refreshing_pool`]). The pool is sized and tuned by `config.pool`.
+pub async fn connect(config: &DsqlConfig) -> Result<RefreshingPool, Box<dyn Error + Send + Sync>> {
+    let token_source = IamTokenSource::new(&config.host, &config.region, config.is_admin());
+    let tokens = Arc::new(TokenCache::new(Arc::new(token_source)));
+
+    create_refreshing_pool(pool_options(&config.pool), connect_options(config), tokens).await
+}
+
+/// Create a connection pool whose credentials are renewed before the IAM token expires
+///
+/// The pool is opened with a token from `tokens`, then a background task swaps in a fresh
+/// token shortly before the current one expires. Connections the pool opens later (after
+/// idle reaping, errors or growth) therefore always authenticate with a valid token.
+/// The task stops once the pool is closed or the returned [`RefreshingPool`] is dropped.
+///
+/// Args:
+///   pool_options: Pool settings (connection limits, timeouts, ...)
+///   connect_options: Connection settings without a password
+///   tokens: Cache providing the password for new connections
+///
+/// Returns:
+///   A Result containing the connected pool and its renewal task
+pub async fn create_refreshing_pool(
+    pool_options: PgPoolOptions,
+    connect_options: PgConnectOptions,
+    tokens: Arc<TokenCache>,
+) -> Result<RefreshingPool, Box<dyn Error + Send + Sync>> {
+    let token = tokens.token().await?;
+    let pool = pool_options
+        .connect_with(connect_options.clone().password(&token))
+        .await?;
+
+    let refresher = tokio::spawn(refresh_credentials(pool.clone(), connect_options, tokens));
+
+    Ok(RefreshingPool { pool, refresher })
+}
+
+/// Take a connection from the pool, recording how long it took in the metrics
+///
+/// A slow acquire means the pool is too small for the workload or connections are being
+/// held too long.
+pub async fn acquire(pool: &PgPool) -> Result<PoolConnection<Postgres>, sqlx::Error> {
+    let started = Instant::now();
+    let conn = pool.acquire().await;
+    metrics::observe_acquire_wait(started.elapsed());
+    conn
+}
+
+/// Keep the pool's connect options supplied with a valid token until the pool closes
+async fn refresh_credentials(
+    pool: PgPool,
+    connect_options: PgConnectOptions,
+    tokens: Arc<TokenCache>,
+) {
+    let close_event = pool.close_event();
+    tokio::pin!(close_event);
+
+    loop {
+        let wait = tokens.time_until_refresh().await;
+
+        tokio::select! {
+            _ = &mut close_event => break,
+            _ = tokio::time::sleep(wait) => {}
+        }
+
+        match tokens.refresh().await {
+            Ok(token) => {
+                debug!("refreshed auth token");
+                pool.set_connect_options(connect_options.clone().password(&token))
+            }
+            Err(e) => {
+                warn!(retry_in = ?REFRESH_RETRY_DELAY, "failed to refresh auth token: {}", e);
+                tokio::select! {
+                    _ = &mut close_event => break,
+                    _ = tokio::time::sleep(REFRESH_RETRY_DELAY) => {}
+                }
+            }
+        }
+    }
+}
+
+/// The database that tests needing one run against, named by `DATABASE_URL`
+///
+/// For example a local Postgres started with
+/// `docker run -e POSTGRES_HOST_AUTH_METHOD=trust -p 5432:5432 postgres`.
+///
+/// Returns:
+///   The database's URL and a pool for it, or `None` to skip the test when
+///   `DATABASE_URL` is not set
+#[cfg(test)]
+pub(crate) async fn test_database() -> Option<(String, PgPool)> {
+    let url = match std::env::var("DATABASE_URL") {
+        Ok(url) if !url.is_empty() => url,
+        _ => {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return None;
+        }
+    };
+    let pool = PgPool::connect(&url)
+        .await
+        .expect("connect to DATABASE_URL");
+    Some((url, pool))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{TokenFuture, TokenSource};
+    use sqlx::types::uuid::Uuid;
+    use sqlx::ConnectOptions;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    /// Mints a new password for `role` on every call, like IAM tokens that expire
+    struct RotatingSource {
+        admin: PgPool,
+        role: String,
+        minted: AtomicU32,
+        tokens: Mutex<Vec<String>>,
+    }
+
+    impl RotatingSource {
+        fn new(admin: &PgPool, role: &str) -> Arc<Self> {
+            Arc::new(Self {
+                admin: admin.clone(),
+                role: role.to_string(),
+                minted: AtomicU32::new(0),
+                tokens: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn minted(&self) -> u32 {
+            self.minted.load(Ordering::SeqCst)
+        }
+    }
+
+    impl TokenSource for RotatingSource {
+        fn fetch_token(&self) -> TokenFuture<'_> {
+            Box::pin(async move {
+                let token = format!("token-{}", Uuid::new_v4().simple());
+                sqlx::query(&format!("ALTER ROLE {} PASSWORD '{}'", self.role, token))
+                    .execute(&self.admin)
+                    .await?;
+                self.tokens.lock().unwrap().push(token.clone());
+                self.minted.fetch_add(1, Ordering::SeqCst);
+                Ok(token)
+            })
+        }
+
+        /// Short enough that the pool renews the token every second
+        fn ttl(&self) -> Duration {
+            Duration::from_secs(2)
+        }
+    }
+
+    async fn wait_for_tokens(source: &RotatingSource, count: u32) {
+        for _ in 0..100 {
+            if source.minted() >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("only {} tokens minted", source.minted());
+    }
+
+    #[tokio::test]
+    async fn pool_connects_with_rotated_tokens_until_dropped() {
+        let Some((url, admin)) = test_database().await else {
+            return;
+        };
+        let role = format!("rust_dsql_rotating_{}", Uuid::new_v4().simple());
+        sqlx::query(&format!("CREATE ROLE {} LOGIN PASSWORD 'initial'", role))
+            .execute(&admin)
+            .await
+            .unwrap();
+
+        let source = RotatingSource::new(&admin, &role);
+        let tokens = Arc::new(TokenCache::with_refresh_margin(
+            source.clone(),
+            Duration::from_secs(1),
+        ));
+        let options = url.parse::<PgConnectOptions>().unwrap().username(&role);
+
+        let pool = create_refreshing_pool(
+            PgPoolOptions::new().max_connections(1),
+            options.clone(),
+            tokens,
+        )
+        .await
+        .unwrap();
+        assert_eq!(source.minted(), 1);
+
+        wait_for_tokens(&source, 3).await;
+
+        // Replace the pooled connection, so the next query logs in with the current token
+        pool.acquire().await.unwrap().close().await.unwrap();
+        let user: String = sqlx::query_scalar("SELECT current_user::text")
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(user, role);
+
+        // Only meaningful where the server checks passwords (not with `trust` auth)
+        let enforces_passwords = options.clone().password("wrong").connect().await.is_err();
+        if enforces_passwords {
+            let first = source.tokens.lock().unwrap()[0].clone();
+            assert!(options.clone().password(&first).connect().await.is_err());
+        }
+
+        // Dropping the handle stops the renewal even though a clone of the pool lives on
+        let inner = (*pool).clone();
+        drop(pool);
+        let minted = source.minted();
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(source.minted(), minted);
+
+        inner.close().await;
+        let mut conn = admin.acquire().await.unwrap();
+        sqlx::query(&format!("DROP ROLE {}", role))
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        conn.close().await.unwrap();
+        admin.close().await;
+    }
+
+    #[tokio::test]
+    async fn closing_the_pool_stops_the_renewal() {
+        let Some((url, admin)) = test_database().await else {
+            return;
+        };
+        let role = format!("rust_dsql_rotating_{}", Uuid::new_v4().simple());
+        sqlx::query(&format!("CREATE ROLE {} LOGIN", role))
+            .execute(&admin)
+            .await
+            .unwrap();
+
+        let source = RotatingSource::new(&admin, &role);
+        let tokens = Arc::new(TokenCache::with_refresh_margin(
+            source.clone(),
+            Duration::from_secs(1),
+        ));
+        let options = url.parse::<PgConnectOptions>().unwrap().username(&role);
+        let pool = create_refreshing_pool(PgPoolOptions::new(), options, tokens)
+            .await
+            .unwrap();
+
+        pool.close().await;
+        let minted = source.minted();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(source.minted(), minted);
+
+        sqlx::query(&format!("DROP ROLE {}", role))
+            .execute(&admin)
+            .await
+            .unwrap();
+    }
+}
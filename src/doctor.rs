@@ -0,0 +1,648 @@
+use crate::auth::{IamTokenSource, TokenSource};
+use crate::config::{config_path, ConfigError, DsqlConfig};
+use crate::connection::connect_options;
+use crate::endpoint::ClusterEndpoint;
+use aws_config::ecs::EcsCredentialsProvider;
+use aws_config::environment::credentials::EnvironmentVariableCredentialsProvider;
+use aws_config::imds::credentials::ImdsCredentialsProvider;
+use aws_config::profile::ProfileFileCredentialsProvider;
+use aws_config::provider_config::ProviderConfig;
+use aws_config::web_identity_token::WebIdentityTokenCredentialsProvider;
+use aws_credential_types::provider::error::CredentialsError;
+use aws_sdk_dsql::config::{Credentials, ProvideCredentials};
+use aws_sdk_dsql::error::DisplayErrorContext;
+use serde::Serialize;
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::types::chrono;
+use sqlx::Connection;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use x509_cert::der::Decode;
+
+/// How long each network step may take before it counts as failed
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Postgres SSLRequest message: length 8, then the request code 80877103
+const SSL_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+
+/// Warn when the server certificate expires within this many days
+const CERTIFICATE_WARNING_DAYS: i64 = 14;
+
+const CONFIGURATION: &str = "Configuration";
+const ENDPOINT: &str = "Endpoint";
+const CREDENTIALS: &str = "AWS credentials";
+const TOKEN: &str = "Auth token";
+const DNS: &str = "DNS";
+const TCP: &str = "TCP";
+const TLS: &str = "TLS";
+const AUTHENTICATION: &str = "Postgres auth";
+const ROUND_TRIP: &str = "SELECT 1";
+const DATABASE: &str = "Database";
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    /// Works, but something looks off
+    Warn,
+    Fail,
+    /// Not run because a check it depends on did not pass
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+            Status::Skip => "SKIP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One step of the diagnosis
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    /// What was found, or why the check failed
+    pub detail: String,
+    /// What to do about a warning or failure
+    pub hint: Option<String>,
+    /// How long the check took, in milliseconds
+    pub elapsed_ms: f64,
+}
+
+/// Results of a diagnosis, in the order the checks ran
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Number of failed checks
+    pub fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == Status::Fail)
+            .count()
+    }
+
+    /// Whether no check failed (warnings and skips aside)
+    pub fn is_healthy(&self) -> bool {
+        self.failures() == 0
+    }
+
+    /// Run a check and record its outcome, returning the value it produced
+    async fn run<T>(
+        &mut self,
+        name: &'static str,
+        step: impl Future<Output = Step<T>>,
+    ) -> Option<T> {
+        let started = Instant::now();
+        let step = step.await;
+        self.checks.push(Check {
+            name,
+            status: step.status,
+            detail: step.detail,
+            hint: step.hint,
+            elapsed_ms: started.elapsed().as_secs_f64() * 1000.0,
+        });
+        step.value
+    }
+
+    /// Record a check that could not run because `dependency` did not pass
+    fn skip(&mut self, name: &'static str, dependency: &str) {
+        self.checks.push(Check {
+            name,
+            status: Status::Skip,
+            detail: format!("needs a passing {} check", dependency),
+            hint: None,
+            elapsed_ms: 0.0,
+        });
+    }
+}
+
+/// What a check found, and the value later checks build on
+struct Step<T> {
+    status: Status,
+    detail: String,
+    hint: Option<String>,
+    value: Option<T>,
+}
+
+impl<T> Step<T> {
+    fn pass(value: T, detail: String) -> Self {
+        Step {
+            status: Status::Pass,
+            detail,
+            hint: None,
+            value: Some(value),
+        }
+    }
+
+    fn warn(value: T, detail: String, hint: String) -> Self {
+        Step {
+            status: Status::Warn,
+            detail,
+            hint: Some(hint),
+            value: Some(value),
+        }
+    }
+
+    fn fail(detail: String, hint: String) -> Self {
+        Step {
+            status: Status::Fail,
+            detail,
+            hint: Some(hint),
+            value: None,
+        }
+    }
+}
+
+/// Check every step needed to talk to the cluster, from configuration to a query
+///
+/// Checks run in order: configuration, endpoint, AWS credentials, token generation, DNS,
+/// TCP, TLS, Postgres authentication and a `SELECT 1` round trip. A check whose inputs
+/// are missing because an earlier one failed is reported as skipped.
+///
+/// Args:
+///   config: The result of resolving the configuration, so a failure there is reported
+///     like any other check
+pub async fn diagnose(config: Result<DsqlConfig, ConfigError>) -> Report {
+    let mut report = Report::default();
+
+    let Some(config) = report
+        .run(CONFIGURATION, async { check_config(config) })
+        .await
+    else {
+        for name in [
+            ENDPOINT,
+            CREDENTIALS,
+            TOKEN,
+            DNS,
+            TCP,
+            TLS,
+            AUTHENTICATION,
+            ROUND_TRIP,
+        ] {
+            report.skip(name, CONFIGURATION);
+        }
+        return report;
+    };
+
+    report
+        .run(ENDPOINT, async { check_endpoint(&config) })
+        .await;
+
+    let token = match report.run(CREDENTIALS, check_credentials()).await {
+        Some(()) => report.run(TOKEN, check_token(&config)).await,
+        None => {
+            report.skip(TOKEN, CREDENTIALS);
+            None
+        }
+    };
+
+    let stream = match report.run(DNS, check_dns(&config)).await {
+        Some(addresses) => report.run(TCP, check_tcp(&addresses)).await,
+        None => {
+            report.skip(TCP, DNS);
+            None
+        }
+    };
+    let reachable = stream.is_some();
+    match stream {
+        Some(stream) => {
+            report.run(TLS, check_tls(stream, &config.host)).await;
+        }
+        None => report.skip(TLS, TCP),
+    }
+
+    // Authentication opens its own connection, so a certificate the TLS check could not
+    // verify doesn't stop it (sqlx requires TLS but doesn't verify the certificate)
+    let conn = match (reachable, token) {
+        (true, Some(token)) => {
+            report
+                .run(AUTHENTICATION, check_auth(&config, &token))
+                .await
+        }
+        (false, _) => {
+            report.skip(AUTHENTICATION, TCP);
+            None
+        }
+        (true, None) => {
+            report.skip(AUTHENTICATION, TOKEN);
+            None
+        }
+    };
+
+    match conn {
+        Some(mut conn) => {
+            report.run(ROUND_TRIP, check_round_trip(&mut conn)).await;
+            let _ = conn.close().await;
+        }
+        None => report.skip(ROUND_TRIP, AUTHENTICATION),
+    }
+
+    report
+}
+
+/// Check that `pool` can hand out a connection that answers `SELECT 1`
+///
+/// This is the readiness check of the REST API.
+pub async fn check_pool(pool: &PgPool) -> Report {
+    let mut report = Report::default();
+    report
+        .run(DATABASE, async {
+            let started = Instant::now();
+            let result = tokio::time::timeout(NETWORK_TIMEOUT, async {
+                let mut conn = pool.acquire().await?;
+                sqlx::query_scalar::<_, i32>("SELECT 1")
+                    .fetch_one(&mut *conn)
+                    .await
+            })
+            .await;
+
+            match result {
+                Ok(Ok(_)) => Step::pass(
+                    (),
+                    format!(
+                        "round trip took {:.1} ms",
+                        started.elapsed().as_secs_f64() * 1000.0
+                    ),
+                ),
+                Ok(Err(e)) => Step::fail(
+                    e.to_string(),
+                    "Run `rust-dsql doctor` for a step-by-step diagnosis".to_string(),
+                ),
+                Err(_) => Step::fail(
+                    format!("no answer within {:?}", NETWORK_TIMEOUT),
+                    "The pool may be exhausted or the cluster unreachable".to_string(),
+                ),
+            }
+        })
+        .await;
+    report
+}
+
+fn check_config(config: Result<DsqlConfig, ConfigError>) -> Step<DsqlConfig> {
+    match config {
+        Ok(config) => {
+            let profile = config
+                .profile
+                .as_deref()
+                .map(|name| format!(" (profile {})", name))
+                .unwrap_or_default();
+            let detail = format!(
+                "{}@{}:{}/{} in {}{}",
+                config.user, config.host, config.port, config.database, config.region, profile
+            );
+            Step::pass(config, detail)
+        }
+        Err(e) => {
+            let file = config_path()
+                .map(|path| format!(" or in {}", path.display()))
+                .unwrap_or_default();
+            Step::fail(
+                e.to_string(),
+                format!(
+                    "Settings are read from the command line, the environment, .env{}",
+                    file
+                ),
+            )
+        }
+    }
+}
+
+fn check_endpoint(config: &DsqlConfig) -> Step<()> {
+    match config.host.parse::<ClusterEndpoint>() {
+        Ok(endpoint) if endpoint.region() != config.region => Step::warn(
+            (),
+            format!(
+                "cluster {} is in {}, but tokens are signed for {}",
+                endpoint.cluster_id(),
+                endpoint.region(),
+                config.region
+            ),
+            format!(
+                "Set --region or DB_REGION to {}, or the cluster will reject the token",
+                endpoint.region()
+            ),
+        ),
+        Ok(endpoint) => Step::pass(
+            (),
+            format!(
+                "cluster {} in {}",
+                endpoint.cluster_id(),
+                endpoint.region()
+            ),
+        ),
+        Err(e) => Step::warn(
+            (),
+            e.to_string(),
+            "Tokens are only accepted for the cluster's own endpoint; use <cluster_id>.dsql.<region>.on.aws rather than an alias".to_string(),
+        ),
+    }
+}
+
+async fn check_credentials() -> Step<()> {
+    let hint = "Configure credentials with `aws configure` or `aws sso login`, or set AWS_PROFILE or AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY".to_string();
+
+    match default_chain_credentials().await {
+        Ok((provider, credentials)) => {
+            let key = credentials.access_key_id();
+            let suffix = &key[key.len().saturating_sub(4)..];
+            let expiry = credentials
+                .expiry()
+                .map(|expiry| {
+                    format!(
+                        ", expiring {}",
+                        chrono::DateTime::<chrono::Utc>::from(expiry)
+                            .format("%Y-%m-%d %H:%M:%S UTC")
+                    )
+                })
+                .unwrap_or_default();
+            Step::pass(
+                (),
+                format!(
+                    "access key ...{} from the {} provider{}",
+                    suffix, provider, expiry
+                ),
+            )
+        }
+        Err(e) => Step::fail(DisplayErrorContext(&e).to_string(), hint),
+    }
+}
+
+/// Credentials from the providers of the SDK's default chain, tried in the same order,
+/// along with the name of the provider that supplied them
+///
+/// Like the chain, a provider without credentials passes on to the next one and any
+/// other error stops the search.
+async fn default_chain_credentials() -> Result<(&'static str, Credentials), CredentialsError> {
+    let conf = ProviderConfig::with_default_region().await;
+    let providers: [(&'static str, Box<dyn ProvideCredentials>); 5] = [
+        (
+            "Environment",
+            Box::new(EnvironmentVariableCredentialsProvider::new()),
+        ),
+        (
+            "Profile",
+            Box::new(
+                ProfileFileCredentialsProvider::builder()
+                    .configure(&conf)
+                    .build(),
+            ),
+        ),
+        (
+            "WebIdentityToken",
+            Box::new(
+                WebIdentityTokenCredentialsProvider::builder()
+                    .configure(&conf)
+                    .build(),
+            ),
+        ),
+        (
+            "EcsContainer",
+            Box::new(EcsCredentialsProvider::builder().configure(&conf).build()),
+        ),
+        (
+            "Ec2InstanceMetadata",
+            Box::new(ImdsCredentialsProvider::builder().configure(&conf).build()),
+        ),
+    ];
+
+    for (name, provider) in providers {
+        match provider.provide_credentials().await {
+            Ok(credentials) => return Ok((name, credentials)),
+            Err(CredentialsError::CredentialsNotLoaded(_)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(CredentialsError::not_loaded(
+        "no provider in the default chain supplied credentials",
+    ))
+}
+
+async fn check_token(config: &DsqlConfig) -> Step<String> {
+    let admin = config.is_admin();
+    let source = IamTokenSource::new(&config.host, &config.region, admin);
+
+    match source.fetch_token().await {
+        Ok(token) => Step::pass(
+            token,
+            format!(
+                "{} token for {}, valid for {} minutes",
+                if admin { "admin" } else { "non-admin" },
+                config.user,
+                source.ttl().as_secs() / 60
+            ),
+        ),
+        Err(e) => Step::fail(
+            e.to_string(),
+            "Check the region and that the credentials can sign requests".to_string(),
+        ),
+    }
+}
+
+async fn check_dns(config: &DsqlConfig) -> Step<Vec<SocketAddr>> {
+    let hint =
+        "Check the endpoint for typos; private (VPC) endpoints only resolve from inside the VPC"
+            .to_string();
+    let lookup = tokio::net::lookup_host((config.host.as_str(), config.port));
+
+    match tokio::time::timeout(NETWORK_TIMEOUT, lookup).await {
+        Ok(Ok(addresses)) => {
+            let addresses: Vec<SocketAddr> = addresses.collect();
+            if addresses.is_empty() {
+                return Step::fail(format!("{} has no addresses", config.host), hint);
+            }
+            let shown: Vec<String> = addresses.iter().map(|a| a.ip().to_string()).collect();
+            Step::pass(addresses, format!("resolved to {}", shown.join(", ")))
+        }
+        Ok(Err(e)) => Step::fail(format!("cannot resolve {}: {}", config.host, e), hint),
+        Err(_) => Step::fail(
+            format!("no answer within {:?}", NETWORK_TIMEOUT),
+            "Check that DNS servers are reachable".to_string(),
+        ),
+    }
+}
+
+async fn check_tcp(addresses: &[SocketAddr]) -> Step<TcpStream> {
+    let mut errors = Vec::new();
+
+    for address in addresses {
+        match tokio::time::timeout(NETWORK_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => return Step::pass(stream, format!("connected to {}", address)),
+            Ok(Err(e)) => errors.push(format!("{}: {}", address, e)),
+            Err(_) => errors.push(format!(
+                "{}: no answer within {:?}",
+                address, NETWORK_TIMEOUT
+            )),
+        }
+    }
+
+    Step::fail(
+        errors.join("; "),
+        format!(
+            "Check that firewalls, security groups and VPNs allow outbound TCP to port {}",
+            addresses[0].port()
+        ),
+    )
+}
+
+async fn check_tls(mut stream: TcpStream, host: &str) -> Step<()> {
+    let hint = "A proxy or firewall may be intercepting the connection".to_string();
+
+    // Postgres negotiates TLS inside its own protocol: ask first, then hand over to TLS
+    let mut answer = [0u8; 1];
+    let negotiated = tokio::time::timeout(NETWORK_TIMEOUT, async {
+        stream.write_all(&SSL_REQUEST).await?;
+        stream.read_exact(&mut answer).await
+    })
+    .await;
+    match negotiated {
+        Ok(Ok(_)) if answer[0] == b'S' => {}
+        Ok(Ok(_)) => {
+            return Step::fail(
+                "the server refused to start TLS".to_string(),
+                "Aurora DSQL always accepts TLS; check that the endpoint is a DSQL cluster"
+                    .to_string(),
+            )
+        }
+        Ok(Err(e)) => return Step::fail(format!("TLS negotiation failed: {}", e), hint),
+        Err(_) => {
+            return Step::fail(format!("no answer within {:?}", NETWORK_TIMEOUT), hint);
+        }
+    }
+
+    let Ok(server_name) = rustls::ServerName::try_from(host) else {
+        return Step::fail(format!("'{}' is not a valid TLS server name", host), hint);
+    };
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config()));
+
+    let tls =
+        match tokio::time::timeout(NETWORK_TIMEOUT, connector.connect(server_name, stream)).await {
+            Ok(Ok(tls)) => tls,
+            Ok(Err(e)) => return Step::fail(format!("TLS handshake failed: {}", e), hint),
+            Err(_) => return Step::fail(format!("no answer within {:?}", NETWORK_TIMEOUT), hint),
+        };
+
+    let (_, session) = tls.get_ref();
+    let protocol = session
+        .protocol_version()
+        .map(|version| format!("{:?}", version))
+        .unwrap_or_default();
+    let cipher = session
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:?}", suite.suite()))
+        .unwrap_or_default();
+    let certificate = session
+        .peer_certificates()
+        .and_then(|chain| chain.first())
+        .and_then(|cert| x509_cert::Certificate::from_der(&cert.0).ok());
+
+    let Some(certificate) = certificate else {
+        return Step::pass((), format!("{} {}", protocol, cipher));
+    };
+
+    let tbs = &certificate.tbs_certificate;
+    let not_after = tbs.validity.not_after.to_unix_duration().as_secs() as i64;
+    let expires = chrono::DateTime::<chrono::Utc>::from_timestamp(not_after, 0).unwrap_or_default();
+    let days_left = (expires - chrono::Utc::now()).num_days();
+    let detail = format!(
+        "{} {}; certificate {} issued by {}, expires {} ({} days)",
+        protocol,
+        cipher,
+        tbs.subject,
+        tbs.issuer,
+        expires.format("%Y-%m-%d"),
+        days_left
+    );
+
+    if days_left < CERTIFICATE_WARNING_DAYS {
+        Step::warn(
+            (),
+            detail,
+            "The server certificate expires soon; clients that verify it will start failing"
+                .to_string(),
+        )
+    } else {
+        Step::pass((), detail)
+    }
+}
+
+/// TLS settings trusting the Mozilla root certificates
+fn tls_config() -> rustls::ClientConfig {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+
+    rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+async fn check_auth(config: &DsqlConfig, token: &str) -> Step<PgConnection> {
+    let options = connect_options(config).password(token);
+
+    match tokio::time::timeout(NETWORK_TIMEOUT, PgConnection::connect_with(&options)).await {
+        Ok(Ok(conn)) => Step::pass(conn, format!("logged in as {}", config.user)),
+        Ok(Err(e)) => {
+            let rejected = e
+                .as_database_error()
+                .and_then(|db_err| db_err.code())
+                .is_some_and(|code| code.starts_with("28"));
+            let hint = if rejected {
+                format!(
+                    "The token was rejected. Check that the IAM identity may call {} on this cluster and that the region is right{}",
+                    if config.is_admin() { "dsql:DbConnectAdmin" } else { "dsql:DbConnect" },
+                    if config.is_admin() { "" } else { ", and that the database role is mapped to it with AWS IAM GRANT" }
+                )
+            } else {
+                "Check the database name and that the endpoint is an Aurora DSQL cluster"
+                    .to_string()
+            };
+            Step::fail(e.to_string(), hint)
+        }
+        Err(_) => Step::fail(
+            format!("no answer within {:?}", NETWORK_TIMEOUT),
+            "The cluster accepted the connection but did not finish logging in".to_string(),
+        ),
+    }
+}
+
+async fn check_round_trip(conn: &mut PgConnection) -> Step<()> {
+    let started = Instant::now();
+    match sqlx::query_scalar::<_, i32>("SELECT 1")
+        .fetch_one(conn)
+        .await
+    {
+        Ok(_) => Step::pass(
+            (),
+            format!(
+                "answered in {:.1} ms",
+                started.elapsed().as_secs_f64() * 1000.0
+            ),
+        ),
+        Err(e) => Step::fail(
+            e.to_string(),
+            "The connection works but queries fail; check the cluster's status in the AWS console"
+                .to_string(),
+        ),
+    }
+}
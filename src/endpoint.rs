@@ -0,0 +1,353 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A parsed Aurora DSQL cluster endpoint
+///
+/// Endpoints have the form `<cluster_id>.dsql.<region>.<domain_suffix>`,
+/// e.g. `abcdefghijklmnopqrstuvwxyz.dsql.eu-west-1.on.aws`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterEndpoint {
+    cluster_id: String,
+    region: String,
+    domain_suffix: String,
+}
+
+/// Reasons a host name is not a valid DSQL cluster endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EndpointError {
+    /// The host name is empty
+    Empty,
+    /// The host does not follow the `<cluster_id>.dsql.<region>.<domain>` layout
+    Malformed { host: String },
+    /// The cluster id label contains characters other than lowercase letters and digits
+    InvalidClusterId { host: String, cluster_id: String },
+    /// The region label does not look like an AWS region (e.g. `us-east-1`)
+    InvalidRegion { host: String, region: String },
+    /// No region was given and none could be derived from the host
+    NoRegion {
+        host: String,
+        reason: Box<EndpointError>,
+    },
+}
+
+impl fmt::Display for EndpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndpointError::Empty => write!(f, "cluster endpoint is empty"),
+            EndpointError::Malformed { host } => write!(
+                f,
+                "'{}' is not a DSQL cluster endpoint (expected <cluster_id>.dsql.<region>.on.aws)",
+                host
+            ),
+            EndpointError::InvalidClusterId { host, cluster_id } => write!(
+                f,
+                "invalid cluster id '{}' in endpoint '{}' (expected lowercase letters and digits)",
+                cluster_id, host
+            ),
+            EndpointError::InvalidRegion { host, region } => write!(
+                f,
+                "invalid region '{}' for endpoint '{}' (expected something like 'us-east-1')",
+                region, host
+            ),
+            EndpointError::NoRegion { host, reason } => write!(
+                f,
+                "cannot determine the AWS region for '{}': {}; set DB_REGION or AWS_REGION",
+                host, reason
+            ),
+        }
+    }
+}
+
+impl Error for EndpointError {}
+
+impl ClusterEndpoint {
+    /// The cluster identifier (first label of the host)
+    pub fn cluster_id(&self) -> &str {
+        &self.cluster_id
+    }
+
+    /// The AWS region the cluster lives in
+    pub fn region(&self) -> &str {
+        &self.region
+    }
+
+    /// Everything after the region label (usually `on.aws`)
+    pub fn domain_suffix(&self) -> &str {
+        &self.domain_suffix
+    }
+}
+
+impl FromStr for ClusterEndpoint {
+    type Err = EndpointError;
+
+    fn from_str(host: &str) -> Result<Self, Self::Err> {
+        let host = host.trim().trim_end_matches('.');
+        if host.is_empty() {
+            return Err(EndpointError::Empty);
+        }
+
+        let malformed = || EndpointError::Malformed {
+            host: host.to_string(),
+        };
+
+        let labels: Vec<&str> = host.split('.').collect();
+        if labels.len() < 4 || labels[1] != "dsql" || labels.iter().any(|l| l.is_empty()) {
+            return Err(malformed());
+        }
+
+        let cluster_id = labels[0];
+        if !cluster_id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        {
+            return Err(EndpointError::InvalidClusterId {
+                host: host.to_string(),
+                cluster_id: cluster_id.to_string(),
+            });
+        }
+
+        let region = labels[2];
+        if !is_valid_region(region) {
+            return Err(EndpointError::InvalidRegion {
+                host: host.to_string(),
+                region: region.to_string(),
+            });
+        }
+
+        Ok(ClusterEndpoint {
+            cluster_id: cluster_id.to_string(),
+            region: region.to_string(),
+            domain_suffix: labels[3..].join("."),
+        })
+    }
+}
+
+impl fmt::Display for ClusterEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.dsql.{}.{}",
+            self.cluster_id, self.region, self.domain_suffix
+        )
+    }
+}
+
+/// Check that a string looks like an AWS region: a two-letter prefix, one or more
+/// lowercase words and a number, separated by dashes (e.g. `ap-northeast-1`, `us-gov-west-1`)
+pub fn is_valid_region(region: &str) -> bool {
+    let parts: Vec<&str> = region.split('-').collect();
+    if parts.len() < 3 {
+        return false;
+    }
+
+    let (prefix, rest) = parts.split_first().unwrap();
+    let (number, words) = rest.split_last().unwrap();
+
+    prefix.len() == 2
+        && prefix.chars().all(|c| c.is_ascii_lowercase())
+        && words
+            .iter()
+            .all(|w| !w.is_empty() && w.chars().all(|c| c.is_ascii_lowercase()))
+        && !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Determine the AWS region to sign tokens for
+///
+/// The region is taken from the first available source:
+///   1. `explicit` (e.g. a `--region` flag)
+///   2. the `DB_REGION` environment variable
+///   3. the `AWS_REGION` environment variable
+///   4. the region label of the cluster endpoint
+///
+/// Args:
+///   explicit: A region given explicitly by the caller
+///   host: The cluster endpoint
+///
+/// Returns:
+///   A Result containing the region, or an error explaining why the host could not be parsed
+pub fn resolve_region(explicit: Option<&str>, host: &str) -> Result<String, EndpointError> {
+    resolve_region_with(explicit, host, |name| env::var(name).ok())
+}
+
+/// [`resolve_region`] reading environment variables through `var`
+fn resolve_region_with(
+    explicit: Option<&str>,
+    host: &str,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<String, EndpointError> {
+    let non_empty = |value: String| {
+        let value = value.trim().to_string();
+        (!value.is_empty()).then_some(value)
+    };
+
+    let overridden = explicit
+        .map(str::to_string)
+        .and_then(non_empty)
+        .or_else(|| var("DB_REGION").and_then(non_empty))
+        .or_else(|| var("AWS_REGION").and_then(non_empty));
+
+    if let Some(region) = overridden {
+        if !is_valid_region(&region) {
+            return Err(EndpointError::InvalidRegion {
+                host: host.to_string(),
+                region,
+            });
+        }
+        return Ok(region);
+    }
+
+    host.parse::<ClusterEndpoint>()
+        .map(|endpoint| endpoint.region)
+        .map_err(|reason| EndpointError::NoRegion {
+            host: host.to_string(),
+            reason: Box::new(reason),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "abcdefghijklmnopqrstuvwxyz.dsql.eu-west-1.on.aws";
+
+    fn vars<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn parses_endpoints_in_any_region() {
+        for (host, region) in [
+            (HOST, "eu-west-1"),
+            (
+                "abcdefghijklmnopqrstuvwxyz.dsql.ap-northeast-1.on.aws",
+                "ap-northeast-1",
+            ),
+            (
+                "abcdefghijklmnopqrstuvwxyz.dsql.us-gov-west-1.on.aws",
+                "us-gov-west-1",
+            ),
+        ] {
+            let endpoint: ClusterEndpoint = host.parse().unwrap();
+            assert_eq!(endpoint.cluster_id(), "abcdefghijklmnopqrstuvwxyz");
+            assert_eq!(endpoint.region(), region);
+            assert_eq!(endpoint.domain_suffix(), "on.aws");
+            assert_eq!(endpoint.to_string(), host);
+        }
+    }
+
+    #[test]
+    fn trims_whitespace_and_the_root_dot() {
+        let endpoint: ClusterEndpoint = format!("  {}.\n", HOST).parse().unwrap();
+        assert_eq!(endpoint.to_string(), HOST);
+    }
+
+    #[test]
+    fn rejects_hosts_that_are_not_dsql_endpoints() {
+        assert_eq!("".parse::<ClusterEndpoint>(), Err(EndpointError::Empty));
+        assert_eq!(" ".parse::<ClusterEndpoint>(), Err(EndpointError::Empty));
+
+        for host in [
+            "localhost",
+            "db.example.com",
+            "mycluster.cluster-abc.us-east-1.rds.amazonaws.com",
+            "abc.dsql.us-east-1",
+            "abc..us-east-1.on.aws",
+            "abc.dsql..on.aws",
+        ] {
+            assert!(
+                matches!(
+                    host.parse::<ClusterEndpoint>(),
+                    Err(EndpointError::Malformed { .. })
+                ),
+                "{}",
+                host
+            );
+        }
+
+        assert!(matches!(
+            "ABC_1.dsql.us-east-1.on.aws".parse::<ClusterEndpoint>(),
+            Err(EndpointError::InvalidClusterId { cluster_id, .. }) if cluster_id == "ABC_1"
+        ));
+        assert!(matches!(
+            "abc.dsql.useast1.on.aws".parse::<ClusterEndpoint>(),
+            Err(EndpointError::InvalidRegion { region, .. }) if region == "useast1"
+        ));
+    }
+
+    #[test]
+    fn validates_region_names() {
+        for region in [
+            "us-east-1",
+            "eu-west-1",
+            "ap-northeast-1",
+            "us-gov-west-1",
+            "eu-central-2",
+        ] {
+            assert!(is_valid_region(region), "{}", region);
+        }
+        for region in [
+            "",
+            "us-east",
+            "useast1",
+            "US-EAST-1",
+            "usa-east-1",
+            "us--1",
+            "us-east-",
+            "us-east-1a",
+            "us-east-one",
+        ] {
+            assert!(!is_valid_region(region), "{}", region);
+        }
+    }
+
+    #[test]
+    fn region_precedence() {
+        let all = [("DB_REGION", "us-east-2"), ("AWS_REGION", "us-west-2")];
+        let resolve = |explicit, env: &[(&str, &str)]| {
+            resolve_region_with(explicit, HOST, vars(env)).unwrap()
+        };
+
+        assert_eq!(resolve(Some("ap-south-1"), &all), "ap-south-1");
+        assert_eq!(resolve(None, &all), "us-east-2");
+        assert_eq!(resolve(None, &all[1..]), "us-west-2");
+        assert_eq!(resolve(None, &[]), "eu-west-1");
+
+        // Blank values are skipped
+        assert_eq!(
+            resolve(Some(" "), &[("DB_REGION", ""), ("AWS_REGION", "us-west-2")]),
+            "us-west-2"
+        );
+    }
+
+    #[test]
+    fn region_errors() {
+        assert!(matches!(
+            resolve_region_with(Some("nowhere"), HOST, vars(&[])),
+            Err(EndpointError::InvalidRegion { region, .. }) if region == "nowhere"
+        ));
+        assert!(matches!(
+            resolve_region_with(None, HOST, vars(&[("DB_REGION", "bogus")])),
+            Err(EndpointError::InvalidRegion { .. })
+        ));
+
+        let err = resolve_region_with(None, "localhost", vars(&[])).unwrap_err();
+        assert!(matches!(
+            &err,
+            EndpointError::NoRegion { reason, .. } if matches!(**reason, EndpointError::Malformed { .. })
+        ));
+        assert!(err.to_string().contains("set DB_REGION or AWS_REGION"));
+
+        // An explicit region makes the host's layout irrelevant
+        assert_eq!(
+            resolve_region_with(Some("us-east-1"), "localhost", vars(&[])).unwrap(),
+            "us-east-1"
+        );
+    }
+}
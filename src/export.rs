@@ -0,0 +1,525 @@
+use crate::users::{RepositoryError, User, UserQuery, UserRepository};
+use arrow_array::{ArrayRef, RecordBatch, StringArray, TimestampMicrosecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use flate2::write::GzEncoder;
+use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::types::{chrono, uuid::Uuid};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{instrument, warn};
+
+/// Users fetched per page unless configured otherwise
+pub const DEFAULT_PAGE_SIZE: i64 = 10_000;
+
+/// Columns of every export, in order
+pub const EXPORT_COLUMNS: [&str; 5] = ["id", "name", "email", "role", "created_at"];
+
+/// Layout of an export file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// Comma-separated values with a header row
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+    /// Apache Parquet, compressed per column
+    Parquet,
+}
+
+/// Compression applied to an export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl ExportFormat {
+    /// Guess the format and compression from a file name such as `users.csv.gz`
+    pub fn from_path(path: &Path) -> Option<(Self, Compression)> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        let (name, compression) = if let Some(name) = name.strip_suffix(".gz") {
+            (name, Compression::Gzip)
+        } else if let Some(name) = name
+            .strip_suffix(".zst")
+            .or_else(|| name.strip_suffix(".zstd"))
+        {
+            (name, Compression::Zstd)
+        } else {
+            (name.as_str(), Compression::None)
+        };
+
+        let format = match Path::new(name).extension()?.to_str()? {
+            "csv" => ExportFormat::Csv,
+            "ndjson" | "jsonl" => ExportFormat::Ndjson,
+            "parquet" => ExportFormat::Parquet,
+            _ => return None,
+        };
+        Some((format, compression))
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Parquet => "parquet",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Settings of an export
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Compression of the whole file for CSV and NDJSON, of each column for Parquet
+    pub compression: Compression,
+    /// Users fetched per query
+    pub page_size: i64,
+    /// Which users to export, in which order; `limit` caps the whole export
+    pub query: UserQuery,
+}
+
+/// Description of a finished export, written next to it for audits
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    /// Name of the export file, relative to the manifest
+    pub file: String,
+    pub format: ExportFormat,
+    pub compression: Compression,
+    pub columns: Vec<String>,
+    /// Number of users exported
+    pub rows: u64,
+    /// Number of pages (queries) the users were fetched in
+    pub pages: u64,
+    /// Size of the export file in bytes
+    pub bytes: u64,
+    /// SHA-256 of the export file, hex encoded
+    pub sha256: String,
+    /// Id of the last exported user, to continue with `--after`
+    pub last_id: Option<Uuid>,
+    pub query: UserQuery,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub finished_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Manifest {
+    /// Where the manifest of the export file `path` is written
+    pub fn path_for(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".manifest.json");
+        PathBuf::from(name)
+    }
+}
+
+/// Export users to a file
+///
+/// Users are fetched page by page with keyset pagination: every page is a single query
+/// continuing after the sort key and id of the last user of the previous one (which need
+/// not exist any more), so it reads one consistent snapshot and no transaction stays open
+/// long enough to hit Aurora DSQL's transaction time limit.
+/// Pages are separate snapshots, so rows changed while the export runs may appear in
+/// their old or new state.
+///
+/// The export is written to `<path>.partial` and renamed once complete, or removed if
+/// the export fails. Then a manifest with the row count and SHA-256 checksum of the file
+/// is written to `<path>.manifest.json`.
+///
+/// Args:
+///   repo: Repository to read from
+///   path: File to write
+///   options: Format, compression, page size and which users to export
+///   on_page: Called with the number of users exported so far after every page
+#[instrument(level = "debug", skip_all, fields(path = %path.display()))]
+pub async fn export_users(
+    repo: &UserRepository,
+    path: &Path,
+    options: &ExportOptions,
+    mut on_page: impl FnMut(u64),
+) -> Result<Manifest, ExportError> {
+    let started_at = chrono::Utc::now();
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| ExportError::Io { path, source }
+    };
+
+    let written = write_export(repo, &partial, options, &mut on_page)
+        .await
+        .and_then(|written| {
+            fs::rename(&partial, path).map_err(io_error(path))?;
+            Ok(written)
+        });
+    let (rows, pages, last_id) = match written {
+        Ok(written) => written,
+        Err(err) => {
+            // Leave nothing behind that could be mistaken for a complete export
+            if let Err(e) = fs::remove_file(&partial) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("cannot remove {}: {}", partial.display(), e);
+                }
+            }
+            return Err(err);
+        }
+    };
+
+    let (bytes, sha256) = checksum(path).map_err(io_error(path))?;
+    let manifest = Manifest {
+        file: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        format: options.format,
+        compression: options.compression,
+        columns: EXPORT_COLUMNS.iter().map(|c| c.to_string()).collect(),
+        rows,
+        pages,
+        bytes,
+        sha256,
+        last_id,
+        query: options.query.clone(),
+        started_at,
+        finished_at: chrono::Utc::now(),
+    };
+
+    let manifest_path = Manifest::path_for(path);
+    let json = serde_json::to_string_pretty(&manifest).expect("manifests serialize to JSON");
+    fs::write(&manifest_path, json + "\n").map_err(io_error(&manifest_path))?;
+
+    Ok(manifest)
+}
+
+/// Write every page of the export to `partial`
+///
+/// Returns:
+///   The number of rows and pages written and the id of the last user
+async fn write_export(
+    repo: &UserRepository,
+    partial: &Path,
+    options: &ExportOptions,
+    on_page: &mut impl FnMut(u64),
+) -> Result<(u64, u64, Option<Uuid>), ExportError> {
+    let file = File::create(partial).map_err(|source| ExportError::Io {
+        path: partial.to_path_buf(),
+        source,
+    })?;
+    let mut writer = UserWriter::new(BufWriter::new(file), options).map_err(|e| e.at(partial))?;
+
+    let mut rows = 0;
+    let mut pages = 0;
+    let mut last_id = None;
+    let mut page = Vec::new();
+    let mut page_query = options.query.clone();
+    loop {
+        let remaining = options.query.limit.map(|limit| limit - rows as i64);
+        let page_size = remaining.map_or(options.page_size, |r| r.min(options.page_size));
+        if page_size <= 0 {
+            break;
+        }
+        page_query.limit = Some(page_size);
+
+        let listed = repo
+            .for_each(&page_query, |user| {
+                page.push(user);
+                Ok::<_, RepositoryError>(())
+            })
+            .await?;
+        if page.is_empty() {
+            break;
+        }
+
+        writer.write_page(&page).map_err(|e| e.at(partial))?;
+        rows += page.len() as u64;
+        pages += 1;
+        last_id = page.last().map(|user| user.id);
+        page.clear();
+        on_page(rows);
+
+        match listed.next_after {
+            Some(after) => {
+                page_query.after = Some(after);
+                page_query.after_key = listed.next_key;
+            }
+            None => break,
+        }
+    }
+
+    writer.finish().map_err(|e| e.at(partial))?;
+    Ok((rows, pages, last_id))
+}
+
+/// Size and hex-encoded SHA-256 of a file
+fn checksum(path: &Path) -> io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let bytes = io::copy(&mut File::open(path)?, &mut hasher)?;
+    let digest = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok((bytes, digest))
+}
+
+/// Export file, before compression
+type Output = BufWriter<File>;
+
+/// CSV or NDJSON output, compressed as a whole
+enum Compressed {
+    None(Output),
+    Gzip(GzEncoder<Output>),
+    Zstd(zstd::Encoder<'static, Output>),
+}
+
+impl Compressed {
+    fn new(output: Output, compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Compressed::None(output),
+            Compression::Gzip => {
+                Compressed::Gzip(GzEncoder::new(output, flate2::Compression::default()))
+            }
+            Compression::Zstd => Compressed::Zstd(zstd::Encoder::new(output, 0)?),
+        })
+    }
+
+    /// Write the end of the compressed stream and return the file
+    fn finish(self) -> io::Result<Output> {
+        match self {
+            Compressed::None(output) => Ok(output),
+            Compressed::Gzip(encoder) => encoder.finish(),
+            Compressed::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for Compressed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Compressed::None(output) => output.write(buf),
+            Compressed::Gzip(encoder) => encoder.write(buf),
+            Compressed::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Compressed::None(output) => output.flush(),
+            Compressed::Gzip(encoder) => encoder.flush(),
+            Compressed::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writes pages of users in the export format
+enum UserWriter {
+    Csv(Box<csv::Writer<Compressed>>),
+    Ndjson(Compressed),
+    Parquet(Box<ArrowWriter<Output>>),
+}
+
+impl UserWriter {
+    fn new(output: Output, options: &ExportOptions) -> Result<Self, WriteError> {
+        Ok(match options.format {
+            ExportFormat::Csv => {
+                let compressed = Compressed::new(output, options.compression)?;
+                UserWriter::Csv(Box::new(csv::Writer::from_writer(compressed)))
+            }
+            ExportFormat::Ndjson => {
+                UserWriter::Ndjson(Compressed::new(output, options.compression)?)
+            }
+            ExportFormat::Parquet => {
+                let compression = match options.compression {
+                    Compression::None => parquet::basic::Compression::UNCOMPRESSED,
+                    Compression::Gzip => parquet::basic::Compression::GZIP(Default::default()),
+                    Compression::Zstd => parquet::basic::Compression::ZSTD(Default::default()),
+                };
+                let properties = WriterProperties::builder()
+                    .set_compression(compression)
+                    .build();
+                UserWriter::Parquet(Box::new(ArrowWriter::try_new(
+                    output,
+                    parquet_schema(),
+                    Some(properties),
+                )?))
+            }
+        })
+    }
+
+    fn write_page(&mut self, users: &[User]) -> Result<(), WriteError> {
+        match self {
+            UserWriter::Csv(writer) => {
+                for user in users {
+                    writer.serialize(user)?;
+                }
+            }
+            UserWriter::Ndjson(writer) => {
+                for user in users {
+                    serde_json::to_writer(&mut *writer, user).map_err(io::Error::from)?;
+                    writeln!(writer)?;
+                }
+            }
+            UserWriter::Parquet(writer) => writer.write(&record_batch(users)?)?,
+        }
+        Ok(())
+    }
+
+    /// Complete the file and flush it to disk
+    fn finish(self) -> Result<(), WriteError> {
+        let output = match self {
+            UserWriter::Csv(writer) => writer.into_inner().map_err(|e| e.into_error())?.finish()?,
+            UserWriter::Ndjson(writer) => writer.finish()?,
+            UserWriter::Parquet(writer) => writer.into_inner()?,
+        };
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(())
+    }
+}
+
+/// Arrow schema of exported users: the id as text and `created_at` in UTC microseconds
+fn parquet_schema() -> Arc<Schema> {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("email", DataType::Utf8, false),
+        Field::new("role", DataType::Utf8, false),
+        Field::new(
+            "created_at",
+            DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+            false,
+        ),
+    ]))
+}
+
+fn record_batch(users: &[User]) -> Result<RecordBatch, WriteError> {
+    let text = |field: fn(&User) -> String| -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(users.iter().map(field)))
+    };
+    let created_at = TimestampMicrosecondArray::from_iter_values(
+        users.iter().map(|user| user.created_at.timestamp_micros()),
+    )
+    .with_timezone("UTC");
+
+    let batch = RecordBatch::try_new(
+        parquet_schema(),
+        vec![
+            text(|user| user.id.to_string()),
+            text(|user| user.name.clone()),
+            text(|user| user.email.clone()),
+            text(|user| user.role.clone()),
+            Arc::new(created_at),
+        ],
+    )
+    .map_err(|e| WriteError::Parquet(e.into()))?;
+    Ok(batch)
+}
+
+/// A failure writing the export file, before the path is known to the caller
+#[derive(Debug)]
+enum WriteError {
+    Io(io::Error),
+    Csv(csv::Error),
+    Parquet(ParquetError),
+}
+
+impl WriteError {
+    fn at(self, path: &Path) -> ExportError {
+        let path = path.to_path_buf();
+        match self {
+            WriteError::Io(source) => ExportError::Io { path, source },
+            WriteError::Csv(source) => ExportError::Io {
+                path,
+                source: source.into(),
+            },
+            WriteError::Parquet(source) => ExportError::Parquet { path, source },
+        }
+    }
+}
+
+impl From<io::Error> for WriteError {
+    fn from(err: io::Error) -> Self {
+        WriteError::Io(err)
+    }
+}
+
+impl From<csv::Error> for WriteError {
+    fn from(err: csv::Error) -> Self {
+        WriteError::Csv(err)
+    }
+}
+
+impl From<ParquetError> for WriteError {
+    fn from(err: ParquetError) -> Self {
+        WriteError::Parquet(err)
+    }
+}
+
+/// Errors that stop an export
+#[derive(Debug)]
+pub enum ExportError {
+    /// The export or manifest file could not be written
+    Io { path: PathBuf, source: io::Error },
+    /// The Parquet encoder failed
+    Parquet { path: PathBuf, source: ParquetError },
+    /// Fetching a page of users failed, even after retrying
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            ExportError::Parquet { path, source } => {
+                write!(
+                    f,
+                    "cannot write Parquet file {}: {}",
+                    path.display(),
+                    source
+                )
+            }
+            ExportError::Repository(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ExportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExportError::Io { source, .. } => Some(source),
+            ExportError::Parquet { source, .. } => Some(source),
+            ExportError::Repository(err) => Some(err),
+        }
+    }
+}
+
+impl From<RepositoryError> for ExportError {
+    fn from(err: RepositoryError) -> Self {
+        ExportError::Repository(err)
+    }
+}
@@ -0,0 +1,545 @@
+use crate::users::{batch_chunks, NewUser, RepositoryError, UserRepository, MAX_TRANSACTION_ROWS};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::types::uuid::Uuid;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::instrument;
+
+/// Records read between checkpoints unless configured otherwise
+pub const DEFAULT_CHUNK_SIZE: usize = 1000;
+
+/// Role given to imported users whose record has none, as `add-user` does
+pub const DEFAULT_ROLE: &str = "User";
+
+/// Layout of an import file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// Comma-separated values with a header row naming the columns
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+impl ImportFormat {
+    /// Guess the format from a file extension (`.csv`, `.ndjson` or `.jsonl`)
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "csv" => Some(ImportFormat::Csv),
+            "ndjson" | "jsonl" => Some(ImportFormat::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ImportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportFormat::Csv => write!(f, "csv"),
+            ImportFormat::Ndjson => write!(f, "ndjson"),
+        }
+    }
+}
+
+/// Settings of an import run
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub format: ImportFormat,
+    /// Records read between checkpoints; valid ones are inserted in statements of at most
+    /// this many rows (capped at DSQL's 3,000 rows per transaction)
+    pub chunk_size: usize,
+    /// NDJSON file receiving every rejected record with the reason
+    pub rejects: PathBuf,
+    /// File tracking progress, removed once the import completes
+    pub checkpoint: PathBuf,
+    /// Continue after the records recorded in the checkpoint
+    pub resume: bool,
+}
+
+impl ImportOptions {
+    /// Options writing rejects and the checkpoint next to `file`
+    pub fn for_file(file: &Path, format: ImportFormat) -> Self {
+        let sibling = |suffix: &str| {
+            let mut name = file.as_os_str().to_owned();
+            name.push(suffix);
+            PathBuf::from(name)
+        };
+
+        Self {
+            format,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            rejects: sibling(".rejects.ndjson"),
+            checkpoint: sibling(".checkpoint.json"),
+            resume: false,
+        }
+    }
+}
+
+/// Progress of an import, kept across resumed runs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportSummary {
+    /// Records read from the file, valid or not
+    pub records: u64,
+    pub inserted: u64,
+    /// Valid records skipped because a user with the email or id already exists
+    pub duplicates: u64,
+    /// Records that could not be parsed or failed validation
+    pub invalid: u64,
+    /// INSERT statements run
+    pub statements: u64,
+    /// Statement attempts beyond the first, e.g. after OCC conflicts
+    pub retries: u64,
+    /// Records that a previous, interrupted run had already processed
+    pub resumed_after: u64,
+}
+
+/// A user record in an import file
+///
+/// Other fields, such as `created_at` in files written by `list-users`, are ignored.
+#[derive(Debug, Deserialize)]
+struct ImportRecord {
+    /// Primary key; a random one is generated if missing
+    #[serde(default)]
+    id: Option<Uuid>,
+    name: String,
+    email: String,
+    #[serde(default)]
+    role: Option<String>,
+}
+
+impl ImportRecord {
+    fn into_user(self) -> Result<NewUser, String> {
+        let user = NewUser {
+            id: self.id.unwrap_or_else(Uuid::new_v4),
+            name: self.name,
+            email: self.email,
+            role: self
+                .role
+                .filter(|role| !role.is_empty())
+                .unwrap_or_else(|| DEFAULT_ROLE.to_string()),
+        };
+        user.validate()?;
+        Ok(user)
+    }
+}
+
+/// One record as read from the file
+struct SourceRecord {
+    /// Line the record starts on
+    line: u64,
+    /// The record as written, for the reject file
+    raw: Value,
+    /// The user it describes, or why it doesn't describe one
+    user: Result<NewUser, String>,
+}
+
+/// Reads records one at a time from an import file
+enum RecordReader {
+    Csv {
+        reader: csv::Reader<BufReader<File>>,
+        headers: csv::StringRecord,
+    },
+    Ndjson {
+        lines: io::Lines<BufReader<File>>,
+        line: u64,
+    },
+}
+
+impl RecordReader {
+    fn open(path: &Path, format: ImportFormat) -> Result<Self, ImportError> {
+        let io_error = |source| ImportError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let file = BufReader::new(File::open(path).map_err(io_error)?);
+
+        Ok(match format {
+            ImportFormat::Csv => {
+                let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(file);
+                let headers = reader.headers().map_err(|e| io_error(e.into()))?.clone();
+                RecordReader::Csv { reader, headers }
+            }
+            ImportFormat::Ndjson => RecordReader::Ndjson {
+                lines: file.lines(),
+                line: 0,
+            },
+        })
+    }
+
+    /// The next record, or `None` at the end of the file
+    ///
+    /// Malformed records are returned with the parse error as the reason they are
+    /// invalid; only I/O errors end the import.
+    fn next_record(&mut self) -> Result<Option<SourceRecord>, io::Error> {
+        match self {
+            RecordReader::Csv { reader, headers } => {
+                let mut record = csv::StringRecord::new();
+                match reader.read_record(&mut record) {
+                    Ok(false) => Ok(None),
+                    Ok(true) => {
+                        let line = record.position().map_or(0, |position| position.line());
+                        let (raw, user) = if record.len() == headers.len() {
+                            let raw = headers
+                                .iter()
+                                .zip(record.iter())
+                                .map(|(header, field)| (header.to_string(), Value::from(field)))
+                                .collect::<serde_json::Map<_, _>>()
+                                .into();
+                            let user = record
+                                .deserialize::<ImportRecord>(Some(headers))
+                                .map_err(|e| invalid_record(&e))
+                                .and_then(ImportRecord::into_user);
+                            (raw, user)
+                        } else {
+                            let raw = record.iter().map(Value::from).collect();
+                            let reason = format!(
+                                "expected {} fields, found {}",
+                                headers.len(),
+                                record.len()
+                            );
+                            (raw, Err(reason))
+                        };
+                        Ok(Some(SourceRecord { line, raw, user }))
+                    }
+                    Err(e) if e.is_io_error() => Err(e.into()),
+                    Err(e) => {
+                        let line = e.position().map_or(0, |position| position.line());
+                        Ok(Some(SourceRecord {
+                            line,
+                            raw: Value::Null,
+                            user: Err(invalid_record(&e)),
+                        }))
+                    }
+                }
+            }
+            RecordReader::Ndjson { lines, line } => loop {
+                let Some(text) = lines.next().transpose()? else {
+                    return Ok(None);
+                };
+                *line += 1;
+                if text.trim().is_empty() {
+                    continue;
+                }
+
+                let (raw, user) = match serde_json::from_str::<Value>(&text) {
+                    Ok(raw) => {
+                        let user = ImportRecord::deserialize(&raw)
+                            .map_err(|e| invalid_record(&e))
+                            .and_then(ImportRecord::into_user);
+                        (raw, user)
+                    }
+                    Err(e) => (Value::String(text), Err(invalid_record(&e))),
+                };
+                return Ok(Some(SourceRecord {
+                    line: *line,
+                    raw,
+                    user,
+                }));
+            },
+        }
+    }
+}
+
+fn invalid_record(err: &dyn Error) -> String {
+    format!("invalid record: {}", err)
+}
+
+/// Progress saved after every chunk so an interrupted import can be resumed
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    file: PathBuf,
+    format: ImportFormat,
+    summary: ImportSummary,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> Result<Option<Self>, ImportError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => {
+                return Err(ImportError::Io {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|source| ImportError::InvalidCheckpoint {
+                path: path.to_path_buf(),
+                reason: source.to_string(),
+            })
+    }
+
+    /// Replace the checkpoint file, via a temporary file so it is never left half-written
+    fn save(&self, path: &Path) -> Result<(), ImportError> {
+        let io_error = |source| ImportError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let json = serde_json::to_string_pretty(self).expect("checkpoints serialize to JSON");
+        fs::write(&temporary, json + "\n").map_err(io_error)?;
+        fs::rename(&temporary, path).map_err(io_error)
+    }
+}
+
+/// A record written to the reject file
+#[derive(Serialize)]
+struct Reject<'a> {
+    line: u64,
+    reason: &'a str,
+    record: &'a Value,
+}
+
+/// Import users from a CSV or NDJSON file
+///
+/// The file is streamed `chunk_size` records at a time. Records that cannot be parsed or
+/// would not fit the `users` table are written to the reject file with the reason. The
+/// valid users of a chunk are inserted with multi-row statements, each retried on its own
+/// after OCC conflicts; users whose email or id is taken are rejected as duplicates.
+///
+/// After every chunk the progress is saved to the checkpoint file. If the import is
+/// interrupted, running it again with `resume` skips the records already processed and
+/// appends to the reject file. Without `resume`, an existing checkpoint is an error, so
+/// an unfinished import is never silently restarted. The checkpoint is removed once the
+/// whole file has been imported.
+///
+/// Args:
+///   repo: Repository to insert through
+///   path: File to import
+///   options: Format, chunk size and where to write rejects and the checkpoint
+///   on_progress: Called with the running totals after every chunk
+#[instrument(level = "debug", skip_all, fields(path = %path.display()))]
+pub async fn import_users(
+    repo: &UserRepository,
+    path: &Path,
+    options: &ImportOptions,
+    mut on_progress: impl FnMut(&ImportSummary),
+) -> Result<ImportSummary, ImportError> {
+    let chunk_size = options.chunk_size.clamp(1, MAX_TRANSACTION_ROWS);
+
+    let mut summary = match Checkpoint::load(&options.checkpoint)? {
+        Some(_) if !options.resume => {
+            return Err(ImportError::CheckpointExists(options.checkpoint.clone()))
+        }
+        Some(checkpoint) => {
+            if checkpoint.file != path || checkpoint.format != options.format {
+                return Err(ImportError::InvalidCheckpoint {
+                    path: options.checkpoint.clone(),
+                    reason: format!(
+                        "it belongs to the {} import of {}",
+                        checkpoint.format,
+                        checkpoint.file.display()
+                    ),
+                });
+            }
+            ImportSummary {
+                resumed_after: checkpoint.summary.records,
+                ..checkpoint.summary
+            }
+        }
+        None => ImportSummary::default(),
+    };
+
+    let mut reader = RecordReader::open(path, options.format)?;
+    let mut rejects = RejectWriter::open(&options.rejects, options.resume)?;
+    let read_error = |source| ImportError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    // Skip what the interrupted run already processed
+    for _ in 0..summary.resumed_after {
+        if reader.next_record().map_err(read_error)?.is_none() {
+            break;
+        }
+    }
+
+    let mut chunk = Vec::with_capacity(chunk_size);
+    loop {
+        let record = reader.next_record().map_err(read_error)?;
+        let at_end = record.is_none();
+        if let Some(record) = record {
+            chunk.push(record);
+        }
+
+        if chunk.len() == chunk_size || (at_end && !chunk.is_empty()) {
+            import_chunk(repo, &mut chunk, &mut rejects, &mut summary).await?;
+            rejects.flush()?;
+            Checkpoint {
+                file: path.to_path_buf(),
+                format: options.format,
+                summary: summary.clone(),
+            }
+            .save(&options.checkpoint)?;
+            on_progress(&summary);
+        }
+
+        if at_end {
+            break;
+        }
+    }
+
+    rejects.flush()?;
+    fs::remove_file(&options.checkpoint).or_else(|e| match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(ImportError::Io {
+            path: options.checkpoint.clone(),
+            source: e,
+        }),
+    })?;
+
+    Ok(summary)
+}
+
+/// Insert the valid users of a chunk of records and reject the others
+async fn import_chunk(
+    repo: &UserRepository,
+    chunk: &mut Vec<SourceRecord>,
+    rejects: &mut RejectWriter,
+    summary: &mut ImportSummary,
+) -> Result<(), ImportError> {
+    let mut users = Vec::with_capacity(chunk.len());
+    let mut valid = Vec::with_capacity(chunk.len());
+    for record in chunk.drain(..) {
+        summary.records += 1;
+        match record.user {
+            Ok(ref user) => {
+                users.push(user.clone());
+                valid.push(record);
+            }
+            Err(ref reason) => {
+                summary.invalid += 1;
+                rejects.write(record.line, reason, &record.raw)?;
+            }
+        }
+    }
+
+    let mut inserted = Vec::with_capacity(users.len());
+    for statement in batch_chunks(&users, users.len()) {
+        let result = repo.insert_chunk_retried(statement).await?;
+        summary.statements += 1;
+        summary.retries += u64::from(result.attempts - 1);
+        inserted.extend(result.value);
+    }
+
+    summary.inserted += inserted.len() as u64;
+    // Each id is inserted at most once; a later record with the same id is a duplicate
+    let mut inserted: std::collections::HashSet<Uuid> = inserted.into_iter().collect();
+    for (record, user) in valid.iter().zip(&users) {
+        if !inserted.remove(&user.id) {
+            summary.duplicates += 1;
+            let reason = format!(
+                "a user with id '{}' or email '{}' already exists",
+                user.id, user.email
+            );
+            rejects.write(record.line, &reason, &record.raw)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes rejected records to the reject file
+struct RejectWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl RejectWriter {
+    /// Create or truncate the reject file, or append to it when resuming
+    fn open(path: &Path, append: bool) -> Result<Self, ImportError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(|source| ImportError::Io {
+                path: path.to_path_buf(),
+                source,
+            })?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn write(&mut self, line: u64, reason: &str, record: &Value) -> Result<(), ImportError> {
+        let reject = Reject {
+            line,
+            reason,
+            record,
+        };
+        serde_json::to_writer(&mut self.writer, &reject)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(self.writer))
+            .map_err(|source| ImportError::Io {
+                path: self.path.clone(),
+                source,
+            })
+    }
+
+    fn flush(&mut self) -> Result<(), ImportError> {
+        self.writer.flush().map_err(|source| ImportError::Io {
+            path: self.path.clone(),
+            source,
+        })
+    }
+}
+
+/// Errors that stop an import
+#[derive(Debug)]
+pub enum ImportError {
+    /// The import, reject or checkpoint file could not be read or written
+    Io { path: PathBuf, source: io::Error },
+    /// A checkpoint from an unfinished import exists, but resuming was not requested
+    CheckpointExists(PathBuf),
+    /// The checkpoint file is unreadable or belongs to another import
+    InvalidCheckpoint { path: PathBuf, reason: String },
+    /// Inserting a chunk failed, even after retrying
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            ImportError::CheckpointExists(path) => write!(
+                f,
+                "an unfinished import left the checkpoint {}; pass --resume to continue it or delete the file to start over",
+                path.display()
+            ),
+            ImportError::InvalidCheckpoint { path, reason } => {
+                write!(f, "cannot resume from checkpoint {}: {}", path.display(), reason)
+            }
+            ImportError::Repository(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ImportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImportError::Io { source, .. } => Some(source),
+            ImportError::Repository(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<RepositoryError> for ImportError {
+    fn from(err: RepositoryError) -> Self {
+        ImportError::Repository(err)
+    }
+}
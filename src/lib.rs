@@ -0,0 +1,27 @@
+//! Building blocks for talking to Amazon Aurora DSQL from Rust: IAM token generation,
+//! configuration, a token-refreshing connection pool, OCC-aware retries and typed
+//! access to the sample `users` table.
+
+pub mod auth;
+pub mod bench;
+pub mod config;
+pub mod connection;
+pub mod doctor;
+pub mod endpoint;
+pub mod export;
+pub mod import;
+pub mod logging;
+pub mod metrics;
+pub mod migrate;
+pub mod output;
+pub mod query;
+pub mod retry;
+pub mod script;
+pub mod seed;
+pub mod server;
+pub mod shell;
+pub mod sql;
+pub mod stats;
+pub mod stress;
+pub mod users;
+pub mod workload;
@@ -0,0 +1,263 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::error::Error;
+use std::fmt;
+use std::io::IsTerminal;
+use tracing::{Level, Subscriber};
+use tracing_subscriber::filter::{filter_fn, EnvFilter, Targets};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// How diagnostics are written to stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines (the default)
+    #[default]
+    Text,
+    /// One JSON object per event, with the enclosing span's fields
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How much is logged when `RUST_LOG` is not set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Warnings and errors only (`-q`)
+    Quiet,
+    /// Progress messages (the default)
+    #[default]
+    Normal,
+    /// Database operations and retry attempts (`-v`)
+    Verbose,
+    /// Everything, including the SQL sqlx sends (`-vv`)
+    Trace,
+}
+
+impl Verbosity {
+    /// Verbosity from the `-q` flag and the number of `-v` flags
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Trace,
+        }
+    }
+
+    /// Filter directives for this verbosity
+    ///
+    /// Other crates only report warnings, except that the AWS SDK's warnings about
+    /// probing EC2 instance metadata off EC2 are left out unless tracing.
+    fn directives(self) -> &'static str {
+        match self {
+            Verbosity::Quiet => "warn,aws_config=error",
+            Verbosity::Normal => "warn,aws_config=error,rust_dsql=info",
+            Verbosity::Verbose => "warn,aws_config=error,rust_dsql=debug",
+            Verbosity::Trace => "info,rust_dsql=trace,sqlx=debug",
+        }
+    }
+}
+
+/// Service name spans are exported under
+const SERVICE_NAME: &str = "rust-dsql";
+
+/// Handle on the trace exporter installed by [`init`]
+///
+/// Spans are exported in batches in the background; call [`Telemetry::shutdown`] before
+/// the process exits so the last batch is not lost.
+#[must_use = "call shutdown before exiting to flush the exported spans"]
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Export the spans not sent yet and stop the exporter
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("failed to export traces: {}", e);
+            }
+        }
+    }
+}
+
+/// Send diagnostics to stderr, keeping stdout for command results
+///
+/// `RUST_LOG` (e.g. `RUST_LOG=rust_dsql::retry=debug`) takes precedence over
+/// `verbosity`. Text output adds timestamps, targets and span context from `-v` on, and
+/// is only colored when stderr is a terminal. Messages the `log` crate emits (as sqlx does for queries) are
+/// forwarded too. Call once, before anything logs, from within the Tokio runtime.
+///
+/// When `otlp_endpoint` is given, or `OTEL_EXPORTER_OTLP_ENDPOINT` is set, the
+/// command, operation and attempt spans are also exported over OTLP/gRPC (e.g. to an
+/// OpenTelemetry Collector or Jaeger at `http://localhost:4317`), whatever the
+/// verbosity. Other `OTEL_*` variables configure the exporter as usual.
+///
+/// Returns:
+///   A Result containing the handle to shut the exporter down with
+pub fn init(
+    verbosity: Verbosity,
+    format: LogFormat,
+    otlp_endpoint: Option<&str>,
+) -> Result<Telemetry, Box<dyn Error + Send + Sync>> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(verbosity.directives()));
+
+    // From -v on, report when each span (command, operation, attempt) ends and how long
+    // it took
+    let span_events = if verbosity >= Verbosity::Verbose {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+
+    let layer = match format {
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_span_events(span_events)
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(std::io::stderr)
+            .boxed(),
+        LogFormat::Text => {
+            let text = tracing_subscriber::fmt::layer()
+                .with_span_events(span_events)
+                .with_target(verbosity >= Verbosity::Verbose)
+                .with_ansi(std::io::stderr().is_terminal())
+                .with_writer(std::io::stderr);
+            if verbosity >= Verbosity::Verbose {
+                text.boxed()
+            } else {
+                // Plain messages: leave out the span context (command, attempt, ...)
+                text.without_time()
+                    .with_filter(filter_fn(|metadata| metadata.is_event()))
+                    .boxed()
+            }
+        }
+    };
+
+    let provider = match otlp_endpoint {
+        Some(endpoint) => Some(tracer_provider(Some(endpoint))?),
+        None if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some() => {
+            Some(tracer_provider(None)?)
+        }
+        None => None,
+    };
+    let otel = provider.as_ref().map(otel_layer);
+
+    // Only fails if a subscriber is already installed, which then keeps working
+    let _ = tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .with(otel)
+        .try_init();
+
+    Ok(Telemetry { provider })
+}
+
+/// Layer turning this crate's spans into OpenTelemetry spans for `provider`
+///
+/// Only this crate's spans: the exporter's own gRPC client must not trace itself.
+fn otel_layer<S>(provider: &SdkTracerProvider) -> impl Layer<S> + Send + Sync
+where
+    S: Subscriber + Send + Sync + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(SERVICE_NAME))
+        .with_filter(Targets::new().with_target("rust_dsql", Level::DEBUG))
+}
+
+/// Tracer provider exporting spans in batches over OTLP/gRPC
+///
+/// Without an endpoint the exporter reads `OTEL_EXPORTER_OTLP_ENDPOINT`.
+fn tracer_provider(
+    endpoint: Option<&str>,
+) -> Result<SdkTracerProvider, Box<dyn Error + Send + Sync>> {
+    let mut exporter = opentelemetry_otlp::SpanExporter::builder().with_tonic();
+    if let Some(endpoint) = endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+
+    Ok(SdkTracerProvider::builder()
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .with_batch_exporter(exporter.build()?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::{retry_as, RetryPolicy};
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+    use std::time::Duration;
+    use tracing::instrument::WithSubscriber;
+    use tracing::Instrument;
+
+    #[tokio::test]
+    async fn spans_reach_the_exporter() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(otel_layer(&provider));
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            multiplier: 1.0,
+            jitter: false,
+        };
+
+        async {
+            let mut failed = false;
+            retry_as(&policy, "logging_test", || {
+                let fail = !std::mem::replace(&mut failed, true);
+                async move {
+                    if fail {
+                        Err(sqlx::Error::PoolTimedOut)
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .instrument(tracing::info_span!("command", name = "test"))
+            .await
+            .unwrap();
+            // Spans of other crates, like the exporter's gRPC client, are left out
+            tracing::info_span!(target: "h2", "frame").in_scope(|| {});
+        }
+        .with_subscriber(subscriber)
+        .await;
+        provider.force_flush().unwrap();
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let names: Vec<&str> = spans.iter().map(|span| span.name.as_ref()).collect();
+        assert_eq!(names, ["attempt", "attempt", "command"]);
+
+        let command = spans[2].span_context.span_id();
+        for (number, span) in spans[..2].iter().enumerate() {
+            assert_eq!(span.parent_span_id, command);
+            let attribute = |key: &str| {
+                span.attributes
+                    .iter()
+                    .find(|kv| kv.key.as_str() == key)
+                    .map(|kv| kv.value.as_str().into_owned())
+            };
+            assert_eq!(attribute("attempt"), Some((number + 1).to_string()));
+            assert!(attribute("latency_ms").is_some());
+        }
+    }
+}
@@ -1,60 +1,426 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
 use dialoguer::{Confirm, Input};
-use dotenv::dotenv;
-use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
-use sqlx::postgres::{PgPool, PgPoolOptions};
+use rust_dsql::auth::{self, TokenInfo};
+use rust_dsql::bench::{self, BenchResult, MetricDelta};
+use rust_dsql::config::{ConfigError, ConfigOverrides, DsqlConfig, PoolOverrides};
+use rust_dsql::connection::{self, RefreshingPool};
+use rust_dsql::doctor::{self, Report};
+use rust_dsql::endpoint::ClusterEndpoint;
+use rust_dsql::export::{self, Compression, ExportFormat, ExportOptions, Manifest};
+use rust_dsql::import::{self, ImportFormat, ImportOptions, ImportSummary};
+use rust_dsql::logging::{self, LogFormat, Verbosity};
+use rust_dsql::metrics;
+use rust_dsql::migrate::{self, AppliedMigration, MigrationKind};
+use rust_dsql::output::{self, OutputFormat, RecordWriter};
+use rust_dsql::query::Record;
+use rust_dsql::retry::RetryPolicy;
+use rust_dsql::script::{self, Script, StatementOutcome};
+use rust_dsql::seed;
+use rust_dsql::server;
+use rust_dsql::shell::{self, ShellOptions};
+use rust_dsql::stats::{self, UserStats};
+use rust_dsql::stress::{self, StressConfig, StressReport};
+use rust_dsql::users::{User, UserOrder, UserQuery, UserRepository, UserUpdate};
+use rust_dsql::workload::{Workload, WorkloadProfile};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
 use sqlx::types::{chrono, uuid::Uuid};
-use sqlx::Row;
-use std::env;
 use std::error::Error;
-use std::thread;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-
-// Add the auth module
-mod auth;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Output format for command results; progress messages always go to stderr
+    #[arg(short, long, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+
+    /// Log more: -v adds database operations and retry attempts, -vv the SQL sent
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log warnings and errors
+    #[arg(short, long, global = true, default_value_t = false)]
+    quiet: bool,
+
+    /// Format of the log messages written to stderr (RUST_LOG overrides the level)
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Export traces over OTLP/gRPC to this collector (e.g. http://localhost:4317);
+    /// defaults to OTEL_EXPORTER_OTLP_ENDPOINT when that is set
+    #[arg(long, global = true, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Serve Prometheus metrics on http://ADDR/metrics while the command runs
+    #[arg(long, global = true, value_name = "ADDR")]
+    metrics_listen: Option<SocketAddr>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Connection settings shared by all subcommands (override env vars and the config file)
+#[derive(Args)]
+struct ConnectionArgs {
+    /// Cluster endpoint (overrides DB_HOST)
+    #[arg(short = 'e', long, global = true, visible_alias = "endpoint")]
+    host: Option<String>,
+
+    /// Database port (overrides DB_PORT)
+    #[arg(long, global = true)]
+    port: Option<u16>,
+
+    /// Database user (overrides DB_USER)
+    #[arg(long, global = true)]
+    user: Option<String>,
+
+    /// Database name (overrides DB_NAME)
+    #[arg(long, global = true)]
+    database: Option<String>,
+
+    /// AWS region used to sign tokens (overrides DB_REGION/AWS_REGION)
+    #[arg(short, long, global = true)]
+    region: Option<String>,
+
+    /// Profile from ~/.config/rust-dsql/config.toml (overrides DSQL_PROFILE)
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    #[command(flatten)]
+    pool: PoolArgs,
+}
+
+/// Connection pool tuning (overrides the profile's `pool` table)
+#[derive(Args)]
+struct PoolArgs {
+    /// Connections to keep open even when idle (default: 0)
+    #[arg(long, global = true, help_heading = "Connection pool")]
+    min_connections: Option<u32>,
+
+    /// Most connections to open at once (default: 10)
+    #[arg(long, global = true, help_heading = "Connection pool")]
+    max_connections: Option<u32>,
+
+    /// How long to wait for a free connection, e.g. 10s (default: 30s)
+    #[arg(long, global = true, value_parser = humantime::parse_duration, help_heading = "Connection pool")]
+    acquire_timeout: Option<Duration>,
+
+    /// Close connections idle for this long, e.g. 5m (default: 10m)
+    #[arg(long, global = true, value_parser = humantime::parse_duration, help_heading = "Connection pool")]
+    idle_timeout: Option<Duration>,
+
+    /// Replace connections once this old; must be under DSQL's 1h limit (default: 55m)
+    #[arg(long, global = true, value_parser = humantime::parse_duration, help_heading = "Connection pool")]
+    max_lifetime: Option<Duration>,
+
+    /// Whether to ping connections before handing them out (default: true)
+    #[arg(
+        long,
+        global = true,
+        value_name = "BOOL",
+        help_heading = "Connection pool"
+    )]
+    test_before_acquire: Option<bool>,
+}
+
+impl PoolArgs {
+    fn overrides(&self) -> PoolOverrides {
+        PoolOverrides {
+            min_connections: self.min_connections,
+            max_connections: self.max_connections,
+            acquire_timeout: self.acquire_timeout,
+            idle_timeout: self.idle_timeout,
+            max_lifetime: self.max_lifetime,
+            test_before_acquire: self.test_before_acquire,
+        }
+    }
+}
+
+impl ConnectionArgs {
+    /// Resolve the full configuration, with these flags taking precedence
+    fn load_config(&self) -> Result<DsqlConfig, ConfigError> {
+        let overrides = ConfigOverrides {
+            host: self.host.clone(),
+            port: self.port,
+            user: self.user.clone(),
+            database: self.database.clone(),
+            region: self.region.clone(),
+            pool: self.pool.overrides(),
+        };
+        DsqlConfig::load(overrides, self.profile.as_deref())
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    /// Repopulate the database (WARNING: drops existing users table)
-    Repopulate,
+    /// Upsert the sample users, optionally clearing existing users first
+    Seed {
+        /// Delete all existing users, in batches, before seeding (the table is kept)
+        #[arg(long, default_value_t = false)]
+        truncate: bool,
+
+        /// Drop and recreate the users table before seeding (irreversible)
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with = "truncate",
+            requires = "i_know_what_im_doing"
+        )]
+        drop: bool,
+
+        /// Required with --drop, which also asks you to type the cluster id
+        #[arg(
+            long = "i-know-what-im-doing",
+            default_value_t = false,
+            requires = "drop"
+        )]
+        i_know_what_im_doing: bool,
+
+        /// Rows deleted per transaction with --truncate
+        #[arg(long, default_value_t = seed::DEFAULT_DELETE_BATCH_SIZE)]
+        batch_size: i64,
+
+        /// Skip the confirmation prompt for --truncate
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+    },
 
-    /// List all users in the database
-    ListUsers,
+    /// List users, optionally filtered, streaming them as they are read
+    ListUsers {
+        #[command(flatten)]
+        query: UserQueryArgs,
+    },
+
+    /// Export users to a CSV, NDJSON or Parquet file, with a manifest for audits
+    Export {
+        /// File to write; the manifest goes to <FILE>.manifest.json
+        file: PathBuf,
+
+        /// File format (default: guessed from the extension, e.g. users.csv.gz)
+        #[arg(short, long, value_enum)]
+        format: Option<ExportFormat>,
+
+        /// Compression (default: guessed from a .gz or .zst extension, else none)
+        #[arg(long, value_enum)]
+        compression: Option<Compression>,
+
+        /// Users fetched per query
+        #[arg(long, default_value_t = export::DEFAULT_PAGE_SIZE, value_parser = clap::value_parser!(i64).range(1..))]
+        page_size: i64,
+
+        #[command(flatten)]
+        query: UserQueryArgs,
+    },
 
     /// Add a new user interactively
     AddUser,
 
-    /// Stress test the database with parallel inserts
+    /// Show a single user, looked up by ID or email
+    #[command(group(ArgGroup::new("lookup").required(true).args(["id", "email"])))]
+    GetUser {
+        /// The user's ID
+        #[arg(long)]
+        id: Option<Uuid>,
+
+        /// The user's email address
+        #[arg(long)]
+        email: Option<String>,
+    },
+
+    /// Change a user's name and/or role (prompts for both if neither is given)
+    UpdateUser {
+        /// The user's ID
+        #[arg(long)]
+        id: Uuid,
+
+        /// New name
+        #[arg(long)]
+        name: Option<String>,
+
+        /// New role
+        #[arg(long)]
+        role: Option<String>,
+    },
+
+    /// Delete a user
+    DeleteUser {
+        /// The user's ID
+        #[arg(long)]
+        id: Uuid,
+
+        /// Don't ask for confirmation
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+    },
+
+    /// Import users from a CSV or NDJSON file
+    ///
+    /// Records need `name` and `email` fields, and may have `id` and `role` (default
+    /// "User"). Invalid records and duplicate emails are written to a reject file.
+    Import {
+        /// File to import
+        file: PathBuf,
+
+        /// File format (default: guessed from the extension)
+        #[arg(short, long, value_enum)]
+        format: Option<ImportFormat>,
+
+        /// Records per checkpoint, inserted with multi-row statements (at most 3,000)
+        #[arg(long, default_value_t = import::DEFAULT_CHUNK_SIZE as u32, value_parser = clap::value_parser!(u32).range(1..=3000))]
+        batch_size: u32,
+
+        /// Where to write rejected records (default: <FILE>.rejects.ndjson)
+        #[arg(long)]
+        rejects: Option<PathBuf>,
+
+        /// Where to keep progress for resuming (default: <FILE>.checkpoint.json)
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+
+        /// Continue an interrupted import from its checkpoint
+        #[arg(long, default_value_t = false)]
+        resume: bool,
+    },
+
+    /// Stress test the database with concurrent operations (inserts by default)
     StressTest {
-        /// Number of users to insert (default: 100)
-        #[arg(short, long, default_value_t = 100)]
+        /// Number of operations to run (default: 100)
+        #[arg(
+            short,
+            long,
+            visible_alias = "operations",
+            default_value_t = 100,
+            conflicts_with = "duration"
+        )]
         users: usize,
 
-        /// Number of concurrent inserts (default: 10)
+        /// Number of concurrent workers (default: 10)
         #[arg(short, long, default_value_t = 10)]
         concurrency: usize,
+
+        /// Keep running for this long instead of a fixed number of operations (e.g. 30s, 5m)
+        #[arg(short, long, value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+
+        /// Target operations per second across all workers (default: as fast as possible)
+        #[arg(long)]
+        rps: Option<f64>,
+
+        /// Built-in mix of operations to run
+        #[arg(short, long, value_enum, default_value_t = WorkloadProfile::InsertOnly)]
+        workload: WorkloadProfile,
+
+        /// TOML file defining a custom mix of operations (overrides --workload)
+        #[arg(long, conflicts_with = "workload")]
+        workload_file: Option<PathBuf>,
+
+        /// Rotate the roles of a few hot users in explicit transactions to provoke OCC
+        /// conflicts (replaces the workload)
+        #[arg(long, default_value_t = false, conflicts_with_all = ["workload", "workload_file"])]
+        contention: bool,
+
+        /// Number of hot users updated with --contention
+        #[arg(long, default_value_t = 5, requires = "contention")]
+        hot_rows: usize,
+
+        /// Users inserted per insert operation, with multi-row statements chunked to
+        /// DSQL's 3,000 rows per transaction (default: 1, one row per statement)
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        batch_size: u32,
+
+        /// Directory to write the JSON result file to, for `bench compare`
+        #[arg(long, default_value = bench::DEFAULT_RESULTS_DIR)]
+        results_dir: PathBuf,
+
+        /// Don't write a result file
+        #[arg(long, default_value_t = false)]
+        no_save: bool,
+    },
+
+    /// Work with saved stress test results
+    Bench {
+        #[command(subcommand)]
+        action: BenchAction,
     },
 
     /// Display statistics about users in the database
     UserStats,
 
-    /// Generate an authentication token for Aurora DSQL
-    GenerateToken {
-        /// The AWS region (e.g., "us-east-1")
+    /// Manage the database schema
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+
+    /// Serve the user operations as a JSON REST API
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+    },
+
+    /// Run SQL statements from a file or the command line, e.g. in CI scripts
+    ///
+    /// Statements run one at a time and are retried after OCC conflicts; statements
+    /// between BEGIN and COMMIT run as one transaction and are retried as a whole.
+    /// Exits non-zero if any statement fails.
+    #[command(group(ArgGroup::new("source").required(true).args(["file", "command"])))]
+    Exec {
+        /// SQL script to run ("-" reads standard input)
         #[arg(short, long)]
-        region: Option<String>,
+        file: Option<PathBuf>,
 
-        /// The cluster endpoint
+        /// SQL to run, e.g. "SELECT count(*) FROM users"
         #[arg(short, long)]
-        endpoint: Option<String>,
+        command: Option<String>,
+    },
+
+    /// Open an interactive SQL shell, authenticating with automatically refreshed tokens
+    Shell {
+        /// File to keep line history in (default: ~/.rust_dsql_history)
+        #[arg(long)]
+        history: Option<PathBuf>,
+
+        /// Don't read or write a history file
+        #[arg(long, default_value_t = false, conflicts_with = "history")]
+        no_history: bool,
 
+        /// Report how long each statement took (toggle with \timing)
+        #[arg(long, default_value_t = false)]
+        timing: bool,
+    },
+
+    /// Run psql (or another libpq client) with a fresh token in its environment
+    ///
+    /// The token is passed in PGPASSWORD to the client only; it is never printed.
+    Connect {
+        /// Client program to run instead of psql
+        #[arg(long, default_value = "psql")]
+        client: String,
+
+        /// Arguments for the client, after `--` (e.g. `-- -c 'SELECT 1'`)
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+
+    /// Print `export` lines setting PGHOST, PGPASSWORD, ... for `eval "$(rust-dsql env)"`
+    Env,
+
+    /// Check configuration, credentials, DNS, TLS and login step by step
+    ///
+    /// Exits non-zero if any check fails.
+    Doctor,
+
+    /// Generate an authentication token for Aurora DSQL
+    GenerateToken {
         /// Generate a token for the admin user (default: true)
         #[arg(short, long, default_value_t = true)]
         admin: bool,
@@ -65,748 +431,1432 @@ enum Commands {
     },
 }
 
-/// Create a database connection pool using parameters from .env file
-async fn create_connection_pool() -> Result<PgPool, Box<dyn Error + Send + Sync>> {
-    // Load environment variables from .env file
-    dotenv().ok();
+/// Filters, ordering and keyset pagination for listing users
+#[derive(Args)]
+struct UserQueryArgs {
+    /// Only users with this role
+    #[arg(long)]
+    role: Option<String>,
 
-    // Get database connection details from environment variables
-    let db_host = env::var("DB_HOST").expect("DB_HOST must be set in .env file");
-    let db_port = env::var("DB_PORT").expect("DB_PORT must be set in .env file");
-    let db_user = env::var("DB_USER").expect("DB_USER must be set in .env file");
-    let db_name = env::var("DB_NAME").expect("DB_NAME must be set in .env file");
+    /// Only users whose email matches this SQL LIKE pattern (e.g. '%@example.com')
+    #[arg(long)]
+    email_like: Option<String>,
 
-    // Extract region from host
-    let region = String::from("us-east-1");
+    /// Only users created at or after this time (RFC 3339 or YYYY-MM-DD, UTC)
+    #[arg(long, value_parser = parse_timestamp)]
+    created_since: Option<chrono::DateTime<chrono::Utc>>,
 
-    println!("Generating auth token for connection...");
+    /// Only users created before this time (RFC 3339 or YYYY-MM-DD, UTC)
+    #[arg(long, value_parser = parse_timestamp)]
+    created_until: Option<chrono::DateTime<chrono::Utc>>,
 
-    // Determine if we should use admin auth based on the username
-    let admin_auth = db_user.to_lowercase() == "admin";
+    /// Column to sort by (ties are broken by id)
+    #[arg(long, value_enum, default_value_t = UserOrder::Id)]
+    order_by: UserOrder,
 
-    // Generate the authentication token
-    let auth_token = auth::generate_auth_token(&db_host, &region, admin_auth).await?;
+    /// Sort in descending order
+    #[arg(long, default_value_t = false)]
+    desc: bool,
 
-    // URL encode the token to handle special characters
-    let encoded_token = utf8_percent_encode(&auth_token, NON_ALPHANUMERIC).to_string();
+    /// Continue after the user with this ID (the last ID of the previous page)
+    #[arg(long)]
+    after: Option<Uuid>,
 
-    // Construct the database URL with the encoded token
-    let database_url = format!(
-        "postgres://{}:{}@{}:{}/{}?sslmode=require",
-        db_user, encoded_token, db_host, db_port, db_name
-    );
+    /// Maximum number of users to return
+    #[arg(long, value_parser = clap::value_parser!(i64).range(1..))]
+    limit: Option<i64>,
+}
 
-    println!("Database URL constructed from parameters");
+impl UserQueryArgs {
+    fn into_query(self) -> UserQuery {
+        UserQuery {
+            role: self.role,
+            email_like: self.email_like,
+            created_since: self.created_since,
+            created_until: self.created_until,
+            order_by: self.order_by,
+            descending: self.desc,
+            after: self.after,
+            after_key: None,
+            limit: self.limit,
+        }
+    }
+}
 
-    // Create a connection pool
-    println!("Connecting to database...");
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await?;
+/// Parse an RFC 3339 timestamp, or a date meaning midnight UTC
+fn parse_timestamp(value: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    if let Ok(timestamp) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&chrono::Utc));
+    }
+
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+        .map_err(|_| {
+            format!(
+                "'{}' is not a date (YYYY-MM-DD) or RFC 3339 timestamp",
+                value
+            )
+        })
+}
+
+#[derive(Subcommand)]
+enum MigrateAction {
+    /// Apply all pending migrations
+    Up {
+        /// Validate and show pending migrations without applying them
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Show which migrations have been applied
+    Status,
+}
+
+#[derive(Subcommand)]
+enum BenchAction {
+    /// Compare two stress test result files, failing if the candidate regressed
+    Compare {
+        /// Result file of the reference run
+        baseline: PathBuf,
+
+        /// Result file of the run to check
+        candidate: PathBuf,
+
+        /// Percentage by which a metric may get worse before it counts as a regression
+        #[arg(long, default_value_t = 10.0)]
+        threshold: f64,
+    },
+}
 
-    println!("Connected successfully!");
+/// Create a database connection pool using the resolved configuration
+async fn create_connection_pool(
+    config: &DsqlConfig,
+) -> Result<RefreshingPool, Box<dyn Error + Send + Sync>> {
+    info!(host = %config.host, user = %config.user, "connecting");
+    let pool = connection::connect(config).await?;
+    debug!("connected");
 
     Ok(pool)
 }
 
-/// Repopulate the database with sample data
-async fn repopulate_database(pool: &PgPool) -> Result<(), Box<dyn Error + Send + Sync>> {
-    // Confirm with the user before proceeding
-    let confirmed = Confirm::new()
-        .with_prompt("WARNING: This will drop the existing users table and all its data. Continue?")
-        .default(false)
-        .interact()?;
+/// Close the connection pool, reporting progress on stderr
+async fn close_connection_pool(pool: RefreshingPool) {
+    debug!("closing connection pool");
+    pool.close().await;
+}
 
-    if !confirmed {
-        println!("Operation cancelled");
-        return Ok(());
+/// Print a single result
+///
+/// JSON and NDJSON get the whole value; CSV gets `csv_rows`, a flat view of it, since
+/// nested values cannot be represented as CSV columns.
+fn print_value<T: Serialize, R: Serialize>(
+    format: OutputFormat,
+    value: &T,
+    csv_rows: impl FnOnce(&T) -> Vec<R>,
+    print_table: impl FnOnce(&T),
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let stdout = std::io::stdout();
+    match format {
+        OutputFormat::Table => {
+            print_table(value);
+            Ok(())
+        }
+        OutputFormat::Json => output::write_json(stdout, value),
+        OutputFormat::Ndjson => output::write_ndjson(stdout, [value]),
+        OutputFormat::Csv => output::write_csv(stdout, csv_rows(value)),
     }
+}
 
-    // Drop and recreate table with retry mechanism
-    let max_retries = 3;
-    let mut attempt = 0;
+/// How `seed` clears existing data before upserting the sample users
+enum SeedMode {
+    /// Keep existing users
+    Upsert,
+    /// Delete all users in batches, keeping the table
+    Truncate { batch_size: i64 },
+    /// Drop and recreate the users table
+    Drop,
+}
 
-    loop {
-        attempt += 1;
-        println!(
-            "Attempt {}/{}: Dropping existing users table if it exists...",
-            attempt, max_retries
-        );
+/// Fail unless stdin is an interactive terminal
+///
+/// Destructive operations are confirmed by prompting, so they refuse to run from scripts
+/// or pipes where nobody can answer.
+fn require_terminal(operation: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if std::io::stdin().is_terminal() {
+        Ok(())
+    } else {
+        Err(format!(
+            "refusing to {} without an interactive terminal to confirm on",
+            operation
+        )
+        .into())
+    }
+}
 
-        let result = sqlx::query("DROP TABLE IF EXISTS users")
-            .execute(pool)
-            .await;
+/// Ask the user to type the cluster id before dropping the users table
+///
+/// Returns:
+///   True if the typed id matched
+fn confirm_drop(config: &DsqlConfig) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    require_terminal("drop the users table")?;
+
+    // Endpoints that are not DSQL cluster endpoints (e.g. a local Postgres) are identified
+    // by their full host name instead
+    let cluster_id = config
+        .host
+        .parse::<ClusterEndpoint>()
+        .map(|endpoint| endpoint.cluster_id().to_string())
+        .unwrap_or_else(|_| config.host.clone());
+
+    eprintln!(
+        "WARNING: This will DROP the users table on {} and permanently delete all its data.",
+        config.host
+    );
+    let typed: String = Input::new()
+        .with_prompt(format!("Type the cluster id ({}) to confirm", cluster_id))
+        .allow_empty(true)
+        .interact_text()?;
 
-        if let Err(err) = result {
-            println!("Error dropping table: {}", err);
-            if attempt >= max_retries {
-                return Err(err.into());
-            }
-            thread::sleep(Duration::from_millis(500));
-            continue;
+    Ok(typed.trim() == cluster_id)
+}
+
+/// Upsert the sample users, after clearing existing data if requested
+async fn seed_database(
+    repo: &UserRepository,
+    mode: SeedMode,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let pool = repo.pool();
+    let policy = RetryPolicy::default();
+
+    match mode {
+        SeedMode::Upsert => {}
+        SeedMode::Truncate { batch_size } => {
+            info!("deleting existing users in batches of {}", batch_size);
+            let deleted = seed::truncate_users(pool, &policy, batch_size, |total| {
+                info!("{} users deleted", total)
+            })
+            .await?;
+            info!("deleted {} users", deleted);
+        }
+        SeedMode::Drop => {
+            info!("dropping and recreating the users table");
+            seed::drop_and_recreate_users(pool, &policy).await?;
+            info!("table 'users' recreated");
         }
+    }
 
-        println!(
-            "Attempt {}/{}: Creating users table with UUID primary key...",
-            attempt, max_retries
+    // Make sure the schema exists before writing to it
+    for applied in migrate::up(pool, &policy, false).await? {
+        info!(
+            "applied migration {:04}_{}",
+            applied.migration.version, applied.migration.name
         );
+    }
 
-        let result = sqlx::query(
-            r#"
-            CREATE TABLE users (
-                id UUID PRIMARY KEY,
-                name VARCHAR(100) NOT NULL,
-                email VARCHAR(100) UNIQUE NOT NULL,
-                role VARCHAR(50) NOT NULL,
-                created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-        )
-        .execute(pool)
-        .await;
+    info!("upserting sample users");
+    for seeded in seed::seed_sample_users(repo).await? {
+        let action = if seeded.created {
+            "inserted"
+        } else {
+            "updated"
+        };
+        info!(id = %seeded.user.id, "user '{}' {}", seeded.user.name, action);
+    }
 
-        match result {
-            Ok(_) => {
-                println!("Table 'users' successfully created");
-                break;
-            }
-            Err(err) => {
-                println!("Error creating table: {}", err);
-                if attempt >= max_retries {
-                    return Err(err.into());
-                }
-                thread::sleep(Duration::from_millis(500));
+    info!("database seeded");
+
+    Ok(())
+}
+
+/// List users matching the query, printing each one as it is read
+async fn list_users(
+    repo: &UserRepository,
+    query: &UserQuery,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    debug!("querying users");
+
+    let summary = match RecordWriter::new(std::io::stdout().lock(), format) {
+        Some(mut writer) => {
+            let summary = repo.for_each(query, |user| writer.write(&user)).await?;
+            writer.finish()?;
+            summary
+        }
+        None => {
+            let summary = repo
+                .for_each(query, |user| {
+                    println!(
+                        "ID: {}, Name: {}, Email: {}, Role: {}, Created at: {}",
+                        user.id, user.name, user.email, user.role, user.created_at
+                    );
+                    Ok::<_, Box<dyn Error + Send + Sync>>(())
+                })
+                .await?;
+
+            if summary.count == 0 {
+                println!("No users found in the database.");
+            } else {
+                println!("\nListed {} users", summary.count);
             }
+            summary
         }
+    };
+
+    if let Some(next) = summary.next_after {
+        info!("more users available; continue with --after {}", next);
     }
 
-    // Sample data to insert
-    let sample_users = vec![
-        ("John Doe", "john.doe@example.com", "Admin"),
-        ("Jane Smith", "jane.smith@example.com", "User"),
-        ("Bob Johnson", "bob.johnson@example.com", "User"),
-        ("Alice Williams", "alice.williams@example.com", "Manager"),
-        ("Charlie Brown", "charlie.brown@example.com", "User"),
-    ];
+    Ok(())
+}
 
-    println!("Inserting sample users...");
+/// Export users to a file, printing progress to stderr
+async fn export_file(
+    repo: &UserRepository,
+    file: &Path,
+    options: &ExportOptions,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("exporting users to {}", file.display());
 
-    // Insert sample users with retry for each
-    for (name, email, role) in sample_users {
-        let user_id = Uuid::new_v4(); // Generate a new UUID for each user
+    let manifest = export::export_users(repo, file, options, |rows| {
+        info!("exported {} users", rows);
+    })
+    .await?;
 
-        match insert_user(pool, user_id, name, email, role).await {
-            Ok(_) => println!("User '{}' inserted with ID: {}", name, user_id),
-            Err(e) => println!("Failed to insert user '{}': {}", name, e),
-        }
+    /// The manifest without its nested query, for CSV output
+    #[derive(Serialize)]
+    struct ExportedFile {
+        file: String,
+        format: ExportFormat,
+        compression: Compression,
+        rows: u64,
+        pages: u64,
+        bytes: u64,
+        sha256: String,
     }
 
-    println!("Database has been repopulated successfully");
+    print_value(
+        format,
+        &manifest,
+        |manifest| {
+            vec![ExportedFile {
+                file: manifest.file.clone(),
+                format: manifest.format,
+                compression: manifest.compression,
+                rows: manifest.rows,
+                pages: manifest.pages,
+                bytes: manifest.bytes,
+                sha256: manifest.sha256.clone(),
+            }]
+        },
+        |manifest| {
+            println!(
+                "Exported {} users in {} pages to {} ({} bytes, {}, compression: {})",
+                manifest.rows,
+                manifest.pages,
+                file.display(),
+                manifest.bytes,
+                manifest.format,
+                manifest.compression
+            );
+            println!("SHA-256: {}", manifest.sha256);
+            println!("Manifest: {}", Manifest::path_for(file).display());
+        },
+    )
+}
+
+/// Add a new user interactively
+async fn add_user_interactive(repo: &UserRepository) -> Result<(), Box<dyn Error + Send + Sync>> {
+    eprintln!("Adding a new user. Please provide the following information:");
+
+    let name: String = Input::new().with_prompt("Name").interact_text()?;
+
+    let email: String = Input::new().with_prompt("Email").interact_text()?;
+
+    let role: String = Input::new()
+        .with_prompt("Role (Admin/User/Manager)")
+        .default("User".into())
+        .interact_text()?;
+
+    let user_id = Uuid::new_v4();
 
+    let user = repo.insert(user_id, &name, &email, &role).await?;
+    println!("User added successfully!");
+    print_user(&user);
     Ok(())
 }
 
-/// Insert a new user into the database
-async fn insert_user(
-    pool: &PgPool,
-    user_id: Uuid,
-    name: &str,
-    email: &str,
-    role: &str,
+/// Print a single user's details
+fn print_user(user: &User) {
+    println!("User ID: {}", user.id);
+    println!("Name: {}", user.name);
+    println!("Email: {}", user.email);
+    println!("Role: {}", user.role);
+    println!("Created at: {}", user.created_at);
+}
+
+/// Look up a user by ID or email and print it
+async fn get_user(
+    repo: &UserRepository,
+    id: Option<Uuid>,
+    email: Option<String>,
+    format: OutputFormat,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut insert_attempt = 0;
-    let max_insert_retries = 3;
-
-    loop {
-        insert_attempt += 1;
-
-        let result = sqlx::query(
-            r#"
-            INSERT INTO users (id, name, email, role) 
-            VALUES ($1, $2, $3, $4)
-            ON CONFLICT (email) DO NOTHING
-            "#,
-        )
-        .bind(user_id)
-        .bind(name)
-        .bind(email)
-        .bind(role)
-        .execute(pool)
-        .await;
-
-        match result {
-            Ok(result) => {
-                if result.rows_affected() > 0 {
-                    return Ok(());
-                } else {
-                    return Err(format!("User with email '{}' already exists", email).into());
-                }
-            }
-            Err(err) => {
-                println!(
-                    "Error inserting user '{}' (attempt {}/{}): {}",
-                    name, insert_attempt, max_insert_retries, err
-                );
+    let user = match (id, email) {
+        (Some(id), _) => repo.get_by_id(id).await?,
+        (None, Some(email)) => repo.get_by_email(&email).await?,
+        (None, None) => return Err("either --id or --email is required".into()),
+    };
 
-                if insert_attempt >= max_insert_retries {
-                    return Err(err.into());
-                }
+    match user {
+        Some(user) => print_value(format, &user, |user| vec![user.clone()], print_user),
+        None => Err("User not found".into()),
+    }
+}
 
-                thread::sleep(Duration::from_millis(500));
-            }
+/// Update a user's name and/or role, prompting for both if no changes were given
+async fn update_user(
+    repo: &UserRepository,
+    id: Uuid,
+    update: UserUpdate,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let update = if update.is_empty() {
+        let current = repo
+            .get_by_id(id)
+            .await?
+            .ok_or_else(|| format!("User with ID '{}' not found", id))?;
+
+        eprintln!("Updating user. Press enter to keep the current value:");
+
+        let name: String = Input::new()
+            .with_prompt("Name")
+            .default(current.name.clone())
+            .interact_text()?;
+
+        let role: String = Input::new()
+            .with_prompt("Role (Admin/User/Manager)")
+            .default(current.role.clone())
+            .interact_text()?;
+
+        UserUpdate {
+            name: (name != current.name).then_some(name),
+            role: (role != current.role).then_some(role),
         }
+    } else {
+        update
+    };
+
+    if update.is_empty() {
+        eprintln!("Nothing to update");
+        return Ok(());
     }
+
+    let user = repo.update(id, &update).await?;
+    println!("User updated successfully!");
+    print_user(&user);
+
+    Ok(())
 }
 
-/// List all users in the database
-async fn list_users(pool: &PgPool) -> Result<(), Box<dyn Error + Send + Sync>> {
-    println!("Querying all users...");
+/// Delete a user after confirmation
+async fn delete_user(
+    repo: &UserRepository,
+    id: Uuid,
+    skip_confirmation: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let user = repo
+        .get_by_id(id)
+        .await?
+        .ok_or_else(|| format!("User with ID '{}' not found", id))?;
 
-    let mut query_attempt = 0;
-    let max_query_retries = 3;
+    if !skip_confirmation {
+        let confirmed = Confirm::new()
+            .with_prompt(format!("Delete user '{}' <{}>?", user.name, user.email))
+            .default(false)
+            .interact()?;
 
-    let users = loop {
-        query_attempt += 1;
+        if !confirmed {
+            eprintln!("Operation cancelled");
+            return Ok(());
+        }
+    }
 
-        let result = sqlx::query(
-            r#"
-            SELECT id, name, email, role, created_at FROM users
-            "#,
-        )
-        .fetch_all(pool)
-        .await;
+    repo.delete(id).await?;
+    println!("User '{}' deleted", user.name);
 
-        match result {
-            Ok(result) => {
-                break result;
-            }
-            Err(err) => {
-                println!(
-                    "Error querying users (attempt {}/{}): {}",
-                    query_attempt, max_query_retries, err
-                );
+    Ok(())
+}
 
-                if query_attempt >= max_query_retries {
-                    return Err(err.into());
-                }
+/// Import users from a file, printing progress to stderr
+async fn import_file(
+    repo: &UserRepository,
+    file: &Path,
+    options: &ImportOptions,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Make sure the users table exists
+    for applied in migrate::up(repo.pool(), &RetryPolicy::default(), false).await? {
+        info!(
+            "applied migration {:04}_{}",
+            applied.migration.version, applied.migration.name
+        );
+    }
 
-                thread::sleep(Duration::from_millis(500));
-            }
-        }
+    if options.resume {
+        info!("resuming import of {}", file.display());
+    } else {
+        info!("importing {}", file.display());
+    }
+
+    let summary = import::import_users(repo, file, options, |progress| {
+        info!(
+            "{} records read: {} inserted, {} duplicates, {} invalid",
+            progress.records, progress.inserted, progress.duplicates, progress.invalid
+        );
+    })
+    .await?;
+
+    print_value(
+        format,
+        &summary,
+        |summary| vec![summary.clone()],
+        |summary| print_import_summary(summary, &options.rejects),
+    )
+}
+
+/// Print the totals of an import
+fn print_import_summary(summary: &ImportSummary, rejects: &Path) {
+    println!("\nImport Results:");
+    println!("---------------");
+    if summary.resumed_after > 0 {
+        println!(
+            "Resumed after {} records processed by an earlier run",
+            summary.resumed_after
+        );
+    }
+    println!("Records read: {}", summary.records);
+    println!("Inserted: {}", summary.inserted);
+    println!("Duplicates: {}", summary.duplicates);
+    println!("Invalid records: {}", summary.invalid);
+    println!(
+        "Statements: {} ({} retries)",
+        summary.statements, summary.retries
+    );
+    if summary.duplicates + summary.invalid > 0 {
+        println!("Rejected records written to {}", rejects.display());
+    }
+}
+
+/// Stress test the database with a workload of concurrent operations
+///
+/// Unless `results_dir` is `None`, the results are also saved there as JSON.
+async fn stress_test_database(
+    repo: &UserRepository,
+    config: &StressConfig,
+    db: &DsqlConfig,
+    results_dir: Option<&Path>,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let rate = match config.rate {
+        Some(rate) => format!(" at up to {} operations/second", rate),
+        None => String::new(),
     };
+    match config.duration {
+        Some(duration) => info!(
+            "starting {} {} stress test with {} workers{}",
+            humantime::format_duration(duration),
+            config.workload.name,
+            config.concurrency,
+            rate
+        ),
+        None => info!(
+            "starting {} stress test with {} operations at concurrency level {}{}",
+            config.workload.name, config.total_operations, config.concurrency, rate
+        ),
+    }
+    if config.batch_size > 1 {
+        info!("inserting {} users per insert operation", config.batch_size);
+    }
+    if config.concurrency > db.pool.max_connections as usize {
+        warn!(
+            "concurrency {} exceeds the pool size of {} connections, so workers will wait for connections; raise --max-connections to run them all at once",
+            config.concurrency, db.pool.max_connections
+        );
+    }
+
+    // Make sure the users table exists
+    for applied in migrate::up(repo.pool(), &RetryPolicy::default(), false).await? {
+        info!(
+            "applied migration {:04}_{}",
+            applied.migration.version, applied.migration.name
+        );
+    }
 
-    println!("Found {} users in database", users.len());
+    let started_at = chrono::Utc::now();
+    let report = stress::run_stress_test(repo, config).await?;
 
-    if users.is_empty() {
-        println!("No users found in the database.");
-        return Ok(());
+    print_value(format, &report, StressReport::summary, print_stress_report)?;
+
+    if let Some(dir) = results_dir {
+        let path = BenchResult::new(&report, config, db, started_at).save(dir)?;
+        info!("results saved to {}", path.display());
     }
 
-    println!("\nUsers in database:");
-    for user in users {
-        // Use DateTime<Utc> instead of NaiveDateTime to match the TIMESTAMPTZ type
+    Ok(())
+}
+
+/// Print the results of a stress test, with one row per operation type
+fn print_stress_report(report: &StressReport) {
+    let total = report.total();
+
+    for error in &report.errors {
+        println!("Failed operation: {}", error);
+    }
+    if total.failed > report.errors.len() as u64 {
         println!(
-            "ID: {}, Name: {}, Email: {}, Role: {}, Created at: {}",
-            user.get::<Uuid, _>("id"),
-            user.get::<String, _>("name"),
-            user.get::<String, _>("email"),
-            user.get::<String, _>("role"),
-            user.get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+            "... and {} more failed operations",
+            total.failed - report.errors.len() as u64
         );
     }
 
+    println!("\nStress Test Results ({}):", report.workload);
+    println!("--------------------");
+    println!("Total time: {:.2} seconds", report.elapsed.as_secs_f64());
+    println!("Successful operations: {}", total.successful);
+    println!("Failed operations: {}", total.failed);
+    println!(
+        "Operation rate: {:.2} operations/second",
+        report.operation_rate()
+    );
+    println!(
+        "Rows: {} ({:.2} rows/second), statements: {} ({:.2} statements/second)",
+        total.rows,
+        total.rows as f64 / report.elapsed.as_secs_f64(),
+        total.statements,
+        total.statements as f64 / report.elapsed.as_secs_f64()
+    );
+    println!(
+        "Retries: {} ({:.3} per operation), OCC conflicts: {}",
+        total.retries,
+        total.retries_per_operation(),
+        total.conflicts
+    );
+
+    if total.retries > 0 {
+        let elapsed = report.elapsed.as_secs_f64();
+        println!(
+            "Commit-time conflict rate: {:.1}% of attempts",
+            total.conflict_rate() * 100.0
+        );
+        println!(
+            "Throughput: {:.2} attempts/second, goodput: {:.2} operations/second ({:.1}% of attempts succeeded)",
+            total.attempts as f64 / elapsed,
+            total.successful as f64 / elapsed,
+            total.successful as f64 / total.attempts as f64 * 100.0
+        );
+        println!("Retry depth distribution:");
+        for (retries, count) in &total.retry_depths {
+            println!(
+                "- {} retries: {} operations ({:.1}%)",
+                retries,
+                count,
+                *count as f64 / total.total() as f64 * 100.0
+            );
+        }
+    }
+
+    println!("\nLatency of successful operations (ms):");
+    println!(
+        "{:<12} {:>8} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9} {:>8} {:>9}",
+        "operation", "ok", "failed", "p50", "p90", "p99", "p999", "max", "retries", "conflicts"
+    );
+    for row in report.summary() {
+        println!(
+            "{:<12} {:>8} {:>7} {:>9.2} {:>9.2} {:>9.2} {:>9.2} {:>9.2} {:>8} {:>9}",
+            row.operation,
+            row.successful,
+            row.failed,
+            row.latency_p50_ms,
+            row.latency_p90_ms,
+            row.latency_p99_ms,
+            row.latency_p999_ms,
+            row.latency_max_ms,
+            row.retries,
+            row.conflicts
+        );
+    }
+}
+
+/// Compare two saved stress test runs
+///
+/// Returns an error, and so a non-zero exit code, if any metric regressed by more than
+/// `threshold` percent.
+fn bench_compare(
+    baseline: &Path,
+    candidate: &Path,
+    threshold: f64,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let baseline = BenchResult::load(baseline)?;
+    let candidate = BenchResult::load(candidate)?;
+
+    if baseline.config.workload != candidate.config.workload {
+        warn!(
+            "comparing different workloads ({} and {})",
+            baseline.config.workload, candidate.config.workload
+        );
+    }
+
+    let deltas = bench::compare(&baseline, &candidate, threshold);
+    print_value(
+        format,
+        &deltas,
+        |deltas| deltas.clone(),
+        |deltas| print_bench_comparison(&baseline, &candidate, deltas),
+    )?;
+
+    let regressions = deltas.iter().filter(|delta| delta.regression).count();
+    if regressions > 0 {
+        return Err(format!(
+            "{} metric(s) regressed by more than {}%",
+            regressions, threshold
+        )
+        .into());
+    }
+
     Ok(())
 }
 
-/// Add a new user interactively
-async fn add_user_interactive(pool: &PgPool) -> Result<(), Box<dyn Error + Send + Sync>> {
-    println!("Adding a new user. Please provide the following information:");
+/// Print the change of every compared metric, marking regressions
+fn print_bench_comparison(baseline: &BenchResult, candidate: &BenchResult, deltas: &[MetricDelta]) {
+    let describe = |result: &BenchResult| {
+        format!(
+            "{} at {} (commit {})",
+            result.config.workload,
+            result.started_at.format("%Y-%m-%d %H:%M:%S UTC"),
+            result.git_sha.as_deref().unwrap_or("unknown")
+        )
+    };
+    println!("Baseline:  {}", describe(baseline));
+    println!("Candidate: {}", describe(candidate));
 
-    let name: String = Input::new().with_prompt("Name").interact_text()?;
+    println!(
+        "\n{:<16} {:<16} {:>12} {:>12} {:>9}",
+        "operation", "metric", "baseline", "candidate", "change"
+    );
+    for delta in deltas {
+        let change = match delta.change_percent {
+            Some(change) => format!("{:+.1}%", change),
+            None if delta.candidate == delta.baseline => "0.0%".to_string(),
+            None => "new".to_string(),
+        };
+        println!(
+            "{:<16} {:<16} {:>12.3} {:>12.3} {:>9}{}",
+            delta.operation,
+            delta.metric,
+            delta.baseline,
+            delta.candidate,
+            change,
+            if delta.regression { "  REGRESSION" } else { "" }
+        );
+    }
+}
 
-    let email: String = Input::new().with_prompt("Email").interact_text()?;
+/// Run the REST API until Ctrl-C
+async fn serve(
+    repo: UserRepository,
+    listen: SocketAddr,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Make sure the users table exists
+    for applied in migrate::up(repo.pool(), &RetryPolicy::default(), false).await? {
+        info!(
+            "applied migration {:04}_{}",
+            applied.migration.version, applied.migration.name
+        );
+    }
 
-    let role: String = Input::new()
-        .with_prompt("Role (Admin/User/Manager)")
-        .default("User".into())
-        .interact_text()?;
+    info!("listening on http://{} (Ctrl-C to stop)", listen);
+    server::serve(repo, listen, async {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("shutting down");
+    })
+    .await?;
 
-    let user_id = Uuid::new_v4();
+    Ok(())
+}
 
-    match insert_user(pool, user_id, &name, &email, &role).await {
-        Ok(_) => {
-            println!("User added successfully!");
-            println!("User ID: {}", user_id);
-            println!("Name: {}", name);
-            println!("Email: {}", email);
-            println!("Role: {}", role);
-            Ok(())
-        }
-        Err(e) => {
-            println!("Failed to add user: {}", e);
-            Err(e)
+/// A statement run by `exec`, as printed in JSON and NDJSON output
+#[derive(Serialize)]
+struct ExecutedStatement<'a> {
+    statement: &'a str,
+    /// Column names, for statements that return rows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    columns: Option<Vec<&'a str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rows: Option<Vec<Record<'a>>>,
+    rows_affected: u64,
+    attempts: u32,
+}
+
+impl<'a> From<&'a StatementOutcome> for ExecutedStatement<'a> {
+    fn from(outcome: &'a StatementOutcome) -> Self {
+        let result = &outcome.result;
+        let returns_rows = result.returns_rows();
+        ExecutedStatement {
+            statement: &outcome.statement,
+            columns: returns_rows.then(|| result.columns.iter().map(|c| c.name.as_str()).collect()),
+            rows: returns_rows.then(|| result.records().collect()),
+            rows_affected: result.rows_affected,
+            attempts: outcome.attempts,
         }
     }
 }
 
-/// Stress test the database with parallel user inserts
-async fn stress_test_database(pool: &PgPool, total_users: usize, concurrency: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
-    println!("Starting stress test with {} users at concurrency level {}", total_users, concurrency);
-    
-    // Ensure the users table exists - fixed query to properly check table existence
-    let table_exists = sqlx::query("SELECT EXISTS (SELECT FROM information_schema.tables WHERE table_schema = 'public' AND table_name = 'users')")
-        .fetch_one(pool)
-        .await?
-        .get::<bool, _>(0);
-    
-    if !table_exists {
-        println!("The users table doesn't exist. Creating it...");
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS users (
-                id UUID PRIMARY KEY,
-                name VARCHAR(100) NOT NULL,
-                email VARCHAR(100) UNIQUE NOT NULL,
-                role VARCHAR(50) NOT NULL,
-                created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
-            )
-            "#,
-        )
-        .execute(pool)
-        .await?;
-        println!("Table 'users' created");
-    }
-    
-    // Track performance metrics
-    let start_time = std::time::Instant::now();
-    let mut successful_inserts = 0;
-    let mut failed_inserts = 0;
-    
-    // Generate random roles for variety
-    let roles = vec!["User", "Admin", "Manager", "Guest", "Developer"];
-    
-    // Medieval first names
-    let medieval_first_names = vec![
-        "Aelfric", "Aldwin", "Baldwin", "Cedric", "Edmund", "Godfrey", "Harold", "Leofric",
-        "Oswald", "Wilfrid", "Adelina", "Beatrice", "Cecily", "Eleanor", "Guinevere", "Isolde",
-        "Matilda", "Rohesia", "Sybil", "Yvonne", "William", "Richard", "Robert", "Hugh", "Roland",
-        "Giles", "Walter", "Henry", "Thomas", "John", "Agnes", "Alice", "Elaine", "Emma", "Joan",
-        "Margaret", "Marian", "Edith", "Godiva", "Maud"
-    ];
-    
-    // Shakespearean last names
-    let shakespearean_last_names = vec![
-        "Montague", "Capulet", "Othello", "Hamlet", "Macbeth", "Lear", "Prospero", "Oberon",
-        "Puck", "Lysander", "Demetrius", "Titania", "Portia", "Shylock", "Malvolio", "Orsino",
-        "Orlando", "Rosalind", "Falstaff", "Petruchio", "Ariel", "Caliban", "Polonius", "Laertes",
-        "Ophelia", "Macduff", "Banquo", "Desdemona", "Cordelia", "Goneril", "Regan", "Kent",
-        "Gloucester", "Albany", "Cornwall", "Feste", "Viola", "Sebastian", "Antonio", "Benvolio",
-        "Mercutio", "Tybalt", "Horatio", "Fortinbras", "Bottom"
-    ];
-    
-    // Process in batches of concurrency size
-    for batch_idx in 0..(total_users / concurrency + 1) {
-        let start_idx = batch_idx * concurrency;
-        let end_idx = std::cmp::min(start_idx + concurrency, total_users);
-        
-        if start_idx >= total_users {
-            break;
-        }
-        
-        println!("Processing batch {}: users {}-{}", batch_idx + 1, start_idx + 1, end_idx);
-        
-        // Create a vector to hold our join handles
-        let mut handles = Vec::new();
-        
-        // Start concurrent tasks
-        for i in start_idx..end_idx {
-            // Create unique test user data
-            let user_id = Uuid::new_v4();
-            
-            // Generate random name from medieval first name and Shakespearean last name
-            let first_name_idx = i % medieval_first_names.len();
-            let last_name_idx = (i * 7) % shakespearean_last_names.len(); // Use a different multiplier to vary combinations
-            let first_name = medieval_first_names[first_name_idx];
-            let last_name = shakespearean_last_names[last_name_idx];
-            let name = format!("{} {}", first_name, last_name);
-            
-            // Create email using the name with a unique identifier to avoid conflicts
-            let email = format!("{}.{}.{}@kingdommail.com", 
-                first_name.to_lowercase(), 
-                last_name.to_lowercase(),
-                user_id.simple());
-            
-            let role = roles[i % roles.len()];
-            
-            // Clone the pool for each task
-            let pool = pool.clone();
-            
-            // Spawn a new task for this insert
-            let handle = tokio::spawn(async move {
-                let result = insert_user(&pool, user_id, &name, &email, role).await;
-                (i, user_id, name, result)
-            });
-            
-            handles.push(handle);
-        }
-        
-        // Wait for all inserts in this batch to complete
-        for handle in handles {
-            match handle.await {
-                Ok((i, user_id, name, result)) => {
-                    match result {
-                        Ok(_) => {
-                            println!("Successfully inserted user #{} '{}' with ID: {}", i + 1, name, user_id);
-                            successful_inserts += 1;
-                        }
-                        Err(e) => {
-                            println!("Failed to insert user #{} '{}': {}", i + 1, name, e);
-                            failed_inserts += 1;
-                        }
-                    }
-                }
-                Err(e) => {
-                    println!("Task joining error: {}", e);
-                    failed_inserts += 1;
+/// Run a script step by step, printing each statement's result as soon as it completes
+///
+/// JSON and NDJSON get one record per statement. CSV only has room for rows, so it gets
+/// the rows of each statement that returns any, each with its own header.
+async fn exec_script(
+    pool: &PgPool,
+    script: &Script,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let policy = RetryPolicy::default();
+    let stdout = std::io::stdout();
+    let mut writer = match format {
+        OutputFormat::Json | OutputFormat::Ndjson => RecordWriter::new(stdout.lock(), format),
+        OutputFormat::Table | OutputFormat::Csv => None,
+    };
+    let mut completed = 0;
+
+    for step in &script.steps {
+        let outcomes = match script::run_step(pool, &policy, step).await {
+            Ok(outcomes) => outcomes,
+            Err(e) => {
+                if let Some(writer) = writer {
+                    writer.finish()?;
                 }
+                warn!(
+                    "{} of {} statements completed before the failure",
+                    completed,
+                    script.statement_count()
+                );
+                return Err(e.into());
+            }
+        };
+
+        for outcome in &outcomes {
+            let result = &outcome.result;
+            match &mut writer {
+                Some(writer) => writer.write(&ExecutedStatement::from(outcome))?,
+                None if result.returns_rows() => result.write(stdout.lock(), format)?,
+                None if format.is_table() => println!("{}", result.command_tag(&outcome.statement)),
+                None => {}
             }
         }
+        completed += outcomes.len();
     }
-    
-    let elapsed = start_time.elapsed();
-    let total_seconds = elapsed.as_secs_f64();
-    let rate = successful_inserts as f64 / total_seconds;
-    
-    println!("\nStress Test Results:");
-    println!("--------------------");
-    println!("Total time: {:.2} seconds", total_seconds);
-    println!("Successful inserts: {}", successful_inserts);
-    println!("Failed inserts: {}", failed_inserts);
-    println!("Insert rate: {:.2} users/second", rate);
-    
+
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+    info!("ran {} statements", completed);
+
     Ok(())
 }
 
-/// Get statistics about users in the database
-async fn get_user_statistics(pool: &PgPool) -> Result<(), Box<dyn Error + Send + Sync>> {
-    println!("Gathering user statistics...");
+/// Apply pending migrations, or show what would be applied
+async fn migrate_up(pool: &PgPool, dry_run: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let applied = migrate::up(pool, &RetryPolicy::default(), dry_run).await?;
 
-    // Total user count
-    let total_count = sqlx::query("SELECT COUNT(*) as count FROM users")
-        .fetch_one(pool)
-        .await?
-        .get::<i64, _>("count");
-
-    // Count by role
-    let roles = sqlx::query(
-        r#"
-        SELECT role, COUNT(*) as count 
-        FROM users 
-        GROUP BY role 
-        ORDER BY count DESC
-        "#,
-    )
-    .fetch_all(pool)
-    .await?;
+    if applied.is_empty() {
+        println!("Schema is up to date");
+        return Ok(());
+    }
 
-    // Get newest and oldest user
-    let newest_user = sqlx::query(
-        r#"
-        SELECT name, email, created_at 
-        FROM users 
-        ORDER BY created_at DESC 
-        LIMIT 1
-        "#,
-    )
-    .fetch_optional(pool)
-    .await?;
+    for AppliedMigration { migration, plan } in &applied {
+        let kind = match plan.kind {
+            MigrationKind::Ddl => "DDL",
+            MigrationKind::Dml => "DML",
+        };
+
+        if dry_run {
+            println!(
+                "Would apply {:04}_{} ({}, {} statement(s)):",
+                migration.version,
+                migration.name,
+                kind,
+                plan.statements.len()
+            );
+            for statement in &plan.statements {
+                println!("{};\n", statement);
+            }
+        } else {
+            println!(
+                "Applied {:04}_{} ({})",
+                migration.version, migration.name, kind
+            );
+        }
+    }
 
-    let oldest_user = sqlx::query(
-        r#"
-        SELECT name, email, created_at 
-        FROM users 
-        ORDER BY created_at ASC 
-        LIMIT 1
-        "#,
-    )
-    .fetch_optional(pool)
-    .await?;
+    if dry_run {
+        println!("Dry run: {} migration(s) pending", applied.len());
+    } else {
+        println!("{} migration(s) applied", applied.len());
+    }
 
-    // Get popular name prefixes
-    let popular_names = sqlx::query(
-        r#"
-        SELECT LEFT(name, POSITION(' ' IN name)) as first_name, COUNT(*) as count
-        FROM users
-        WHERE POSITION(' ' IN name) > 0
-        GROUP BY first_name
-        ORDER BY count DESC
-        LIMIT 5
-        "#,
-    )
-    .fetch_all(pool)
-    .await?;
+    Ok(())
+}
 
-    // Get user creation trends (users created by day)
-    let creation_trends = sqlx::query(
-        r#"
-        SELECT 
-            DATE(created_at) as date,
-            COUNT(*) as count
-        FROM users
-        GROUP BY date
-        ORDER BY date DESC
-        LIMIT 7
-        "#,
-    )
-    .fetch_all(pool)
-    .await?;
-    
-    // Get creation time distribution (by hour of day)
-    let hour_distribution = sqlx::query(
-        r#"
-        SELECT 
-            EXTRACT(HOUR FROM created_at)::INT as hour,
-            COUNT(*) as count
-        FROM users
-        GROUP BY hour
-        ORDER BY hour
-        "#,
-    )
-    .fetch_all(pool)
-    .await?;
-    
-    // Get longest and shortest names
-    let name_extremes = sqlx::query(
-        r#"
-        SELECT 
-            (SELECT name FROM users ORDER BY LENGTH(name) DESC LIMIT 1) as longest_name,
-            (SELECT name FROM users ORDER BY LENGTH(name) ASC LIMIT 1) as shortest_name,
-            (SELECT AVG(LENGTH(name))::FLOAT8 FROM users) as avg_length
-        "#,
-    )
-    .fetch_one(pool)
-    .await?;
+/// Print the status of every migration
+async fn migrate_status(pool: &PgPool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for status in migrate::status(pool, &RetryPolicy::default()).await? {
+        let state = match status.applied_at {
+            Some(_) if !status.checksum_matches => "MODIFIED after being applied".to_string(),
+            Some(applied_at) => format!("applied at {}", applied_at),
+            None => match status.migration.validate() {
+                Ok(_) => "pending".to_string(),
+                Err(e) => format!("pending, INVALID: {}", e),
+            },
+        };
 
-    // Email domain statistics
-    let email_domains = sqlx::query(
-        r#"
-        SELECT 
-            SUBSTRING(email FROM POSITION('@' IN email) + 1) as domain,
-            COUNT(*) as count
-        FROM users
-        GROUP BY domain
-        ORDER BY count DESC
-        LIMIT 5
-        "#,
-    )
-    .fetch_all(pool)
-    .await?;
+        println!(
+            "{:04}_{}: {}",
+            status.migration.version, status.migration.name, state
+        );
+    }
+
+    Ok(())
+}
 
-    // Print statistics
+/// Print statistics about users in the database
+fn print_user_statistics(stats: &UserStats) {
     println!("\n----- User Statistics -----");
-    println!("Total users: {}", total_count);
-    
+    println!("Total users: {}", stats.total_users);
+
     println!("\nDistribution by role:");
-    for role in roles {
+    for role in &stats.roles {
         println!(
             "- {}: {} users ({}%)",
-            role.get::<String, _>("role"),
-            role.get::<i64, _>("count"),
-            (role.get::<i64, _>("count") as f64 / total_count as f64 * 100.0).round()
+            role.role,
+            role.count,
+            stats.percentage(role.count).round()
         );
     }
 
-    if let Some(newest) = newest_user {
+    if let Some(newest) = &stats.newest_user {
         println!(
             "\nNewest user: {} ({}) - Created: {}",
-            newest.get::<String, _>("name"),
-            newest.get::<String, _>("email"),
-            newest.get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+            newest.name, newest.email, newest.created_at
         );
     }
 
-    if let Some(oldest) = oldest_user {
+    if let Some(oldest) = &stats.oldest_user {
         println!(
             "Oldest user: {} ({}) - Created: {}",
-            oldest.get::<String, _>("name"),
-            oldest.get::<String, _>("email"),
-            oldest.get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+            oldest.name, oldest.email, oldest.created_at
         );
     }
 
     // Print user creation trends
     println!("\nUser creation trends (last 7 days):");
-    for trend in creation_trends {
-        println!(
-            "- {}: {} users",
-            trend.get::<chrono::NaiveDate, _>("date"),
-            trend.get::<i64, _>("count")
-        );
+    for trend in &stats.creation_trends {
+        println!("- {}: {} users", trend.date, trend.count);
     }
-    
+
     // Print creation time distribution
     println!("\nCreation time distribution (by hour of day):");
-    for hour in hour_distribution {
-        let hour_val = hour.get::<i32, _>("hour");
-        let count = hour.get::<i64, _>("count");
-        let bar = "█".repeat((count as f64 / total_count as f64 * 50.0) as usize);
+    for hour in &stats.hour_distribution {
+        let bar = "█".repeat((stats.percentage(hour.count) / 2.0) as usize);
+        println!("- {:02}:00: {:4} users {}", hour.hour, hour.count, bar);
+    }
+
+    // Print name length information
+    if let Some(names) = &stats.name_lengths {
+        println!("\nName length statistics:");
         println!(
-            "- {:02}:00: {:4} users {}",
-            hour_val,
-            count,
-            bar
+            "- Longest name: {} ({} chars)",
+            names.longest_name,
+            names.longest_name.len()
+        );
+        println!(
+            "- Shortest name: {} ({} chars)",
+            names.shortest_name,
+            names.shortest_name.len()
+        );
+        println!(
+            "- Average name length: {:.1} characters",
+            names.average_length
         );
     }
-    
-    // Print name length information
-    println!("\nName length statistics:");
-    println!(
-        "- Longest name: {} ({} chars)",
-        name_extremes.get::<String, _>("longest_name"),
-        name_extremes.get::<String, _>("longest_name").len()
-    );
-    println!(
-        "- Shortest name: {} ({} chars)",
-        name_extremes.get::<String, _>("shortest_name"),
-        name_extremes.get::<String, _>("shortest_name").len()
-    );
-    println!(
-        "- Average name length: {:.1} characters",
-        name_extremes.get::<f64, _>("avg_length")
-    );
 
     println!("\nMost common first names:");
-    for name in popular_names {
-        println!(
-            "- {}: {} users",
-            name.get::<String, _>("first_name"),
-            name.get::<i64, _>("count")
-        );
+    for name in &stats.popular_first_names {
+        println!("- {}: {} users", name.first_name, name.count);
     }
 
     println!("\nMost common email domains:");
-    for domain in email_domains {
+    for domain in &stats.email_domains {
         println!(
             "- {}: {} users ({}%)",
-            domain.get::<String, _>("domain"),
-            domain.get::<i64, _>("count"),
-            (domain.get::<i64, _>("count") as f64 / total_count as f64 * 100.0).round()
+            domain.domain,
+            domain.count,
+            stats.percentage(domain.count).round()
         );
     }
-    
+
     println!("\n---------------------------");
+}
 
-    Ok(())
+/// Run a database client with the token's libpq variables in its environment, returning
+/// its exit code
+async fn run_client(
+    client: &str,
+    args: &[String],
+    info: &TokenInfo,
+) -> Result<i32, Box<dyn Error + Send + Sync>> {
+    let mut child = tokio::process::Command::new(client)
+        .args(args)
+        .envs(info.libpq_env())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => format!(
+                "could not find `{}`; install it, choose another client with --client, or use `rust-dsql shell`",
+                client
+            ),
+            _ => format!("could not run `{}`: {}", client, e),
+        })?;
+
+    // Ctrl-C is meant for the client (psql uses it to cancel a query), so don't let it
+    // terminate us and leave the client running without its parent
+    let status = loop {
+        tokio::select! {
+            status = child.wait() => break status?,
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    };
+
+    // A client killed by a signal has no exit code
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Quote a value for a POSIX shell
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Print a token with its connection details and a sample psql command
+fn print_token(info: &TokenInfo) {
+    println!("Authentication token generated successfully!");
+    println!("Host:     {}", info.host);
+    println!("Port:     {}", info.port);
+    println!("User:     {}", info.user);
+    println!("Database: {}", info.database);
+    println!("Region:   {}", info.region);
+    if let Some(profile) = &info.profile {
+        println!("Profile:  {}", profile);
+    }
+    println!("Admin:    {}", if info.admin { "Yes" } else { "No" });
+    println!("Expires:  {}", info.expires_at);
+    println!("\nToken: {}", info.token);
+
+    // Print a sample connection command
+    println!("\nSample connection command:");
+    println!(
+        "PGSSLMODE=require psql \"postgresql://{}@{}:{}/{}\" -W",
+        info.user, info.host, info.port, info.database
+    );
+    println!("When prompted for password, use the token shown above.");
+    println!("\nOr let rust-dsql pass the token without showing it: rust-dsql connect");
+}
+
+fn print_doctor_report(report: &Report) {
+    for check in &report.checks {
+        println!(
+            "[{}] {:<15} {} ({:.0} ms)",
+            check.status, check.name, check.detail, check.elapsed_ms
+        );
+        if let Some(hint) = &check.hint {
+            println!("       {}", hint);
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let cli = Cli::parse();
+async fn main() {
+    let matches = Cli::command().get_matches();
+    let command = matches.subcommand_name().unwrap_or_default().to_string();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    let telemetry = logging::init(
+        Verbosity::from_flags(cli.quiet, cli.verbose),
+        cli.log_format,
+        cli.otlp_endpoint.as_deref(),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Error: failed to set up trace export: {}", e);
+        std::process::exit(1);
+    });
+
+    // Report errors with their Display message rather than the Debug dump `main` would print
+    let span = info_span!("cli", command = %command);
+    let result = run(cli).instrument(span.clone()).await;
+    if let Err(e) = &result {
+        span.in_scope(|| error!("{}", e));
+    }
+    // Close the command's span so it is exported with the rest
+    drop(span);
+    telemetry.shutdown();
+
+    // Only exit once the pool is closed and the spans are flushed
+    let code = result.unwrap_or(1);
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
+
+/// Run the selected subcommand
+///
+/// Returns:
+///   A Result containing the process exit code: 0, or the client's for `connect`
+async fn run(cli: Cli) -> Result<i32, Box<dyn Error + Send + Sync>> {
+    // Comparing result files doesn't touch the database, so needs no configuration
+    if let Commands::Bench { action } = &cli.command {
+        return match action {
+            BenchAction::Compare {
+                baseline,
+                candidate,
+                threshold,
+            } => bench_compare(baseline, candidate, *threshold, cli.output).map(|()| 0),
+        };
+    }
+
+    // Diagnosing a bad configuration is the point, so report it as a check instead
+    if let Commands::Doctor = &cli.command {
+        let report = doctor::diagnose(cli.connection.load_config()).await;
+        print_value(
+            cli.output,
+            &report,
+            |r| r.checks.clone(),
+            print_doctor_report,
+        )?;
+        return match report.failures() {
+            0 => Ok(0),
+            failures => {
+                Err(format!("{} of {} checks failed", failures, report.checks.len()).into())
+            }
+        };
+    }
+
+    if let Some(addr) = cli.metrics_listen {
+        info!("serving metrics on http://{}/metrics", addr);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, std::future::pending()).await {
+                error!("failed to serve metrics on {}: {}", addr, e);
+            }
+        });
+    }
+
+    let config = cli.connection.load_config()?;
 
     // Execute the appropriate command
     match cli.command {
-        Commands::Repopulate => {
+        Commands::Seed {
+            truncate,
+            drop,
+            i_know_what_im_doing: _,
+            batch_size,
+            yes,
+        } => {
+            // Confirm destructive modes before connecting
+            let mode = if drop {
+                if !confirm_drop(&config)? {
+                    eprintln!("Cluster id did not match; operation cancelled");
+                    return Ok(0);
+                }
+                SeedMode::Drop
+            } else if truncate {
+                if !yes {
+                    require_terminal("delete all users")?;
+                    let confirmed = Confirm::new()
+                        .with_prompt(format!("Delete ALL users on {}?", config.host))
+                        .default(false)
+                        .interact()?;
+                    if !confirmed {
+                        eprintln!("Operation cancelled");
+                        return Ok(0);
+                    }
+                }
+                SeedMode::Truncate { batch_size }
+            } else {
+                SeedMode::Upsert
+            };
+
             // Create the database connection pool
-            let pool = create_connection_pool().await?;
-            repopulate_database(&pool).await?;
-            // Close the connection pool
-            println!("Closing connection pool...");
-            pool.close().await;
-            println!("Connection closed");
-        }
-        Commands::ListUsers => {
+            let pool = create_connection_pool(&config).await?;
+            let result = seed_database(&UserRepository::new(pool.clone()), mode).await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::ListUsers { query } => {
             // Create the database connection pool
-            let pool = create_connection_pool().await?;
-            list_users(&pool).await?;
-            // Close the connection pool
-            println!("Closing connection pool...");
-            pool.close().await;
-            println!("Connection closed");
+            let pool = create_connection_pool(&config).await?;
+            let result = list_users(
+                &UserRepository::new(pool.clone()),
+                &query.into_query(),
+                cli.output,
+            )
+            .await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::Export {
+            file,
+            format,
+            compression,
+            page_size,
+            query,
+        } => {
+            let guessed = ExportFormat::from_path(&file);
+            let options = ExportOptions {
+                format: format
+                    .or(guessed.map(|(format, _)| format))
+                    .ok_or("cannot tell the file format from its extension; pass --format")?,
+                compression: compression
+                    .or(guessed.map(|(_, compression)| compression))
+                    .unwrap_or_default(),
+                page_size,
+                query: query.into_query(),
+            };
+
+            let pool = create_connection_pool(&config).await?;
+            let result = export_file(
+                &UserRepository::new(pool.clone()),
+                &file,
+                &options,
+                cli.output,
+            )
+            .await;
+            close_connection_pool(pool).await;
+            result?;
         }
         Commands::AddUser => {
             // Create the database connection pool
-            let pool = create_connection_pool().await?;
-            add_user_interactive(&pool).await?;
-            // Close the connection pool
-            println!("Closing connection pool...");
-            pool.close().await;
-            println!("Connection closed");
-        }
-        Commands::StressTest { users, concurrency } => {
-            // Create the database connection pool
-            let pool = create_connection_pool().await?;
-            stress_test_database(&pool, users, concurrency).await?;
-            // Close the connection pool
-            println!("Closing connection pool...");
-            pool.close().await;
-            println!("Connection closed");
+            let pool = create_connection_pool(&config).await?;
+            let result = add_user_interactive(&UserRepository::new(pool.clone())).await;
+            close_connection_pool(pool).await;
+            result?;
         }
-        Commands::UserStats => {
-            let pool = create_connection_pool().await?;
-            get_user_statistics(&pool).await?;
-            pool.close().await;
-        }
-        Commands::GenerateToken {
-            region,
-            endpoint,
-            admin,
-            token_only,
+        Commands::GetUser { id, email } => {
+            let pool = create_connection_pool(&config).await?;
+            let result = get_user(&UserRepository::new(pool.clone()), id, email, cli.output).await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::UpdateUser { id, name, role } => {
+            let pool = create_connection_pool(&config).await?;
+            let update = UserUpdate { name, role };
+            let result = update_user(&UserRepository::new(pool.clone()), id, update).await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::DeleteUser { id, yes } => {
+            let pool = create_connection_pool(&config).await?;
+            let result = delete_user(&UserRepository::new(pool.clone()), id, yes).await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::Import {
+            file,
+            format,
+            batch_size,
+            rejects,
+            checkpoint,
+            resume,
         } => {
-            // Load environment variables
-            dotenv().ok();
+            let format = format
+                .or_else(|| ImportFormat::from_path(&file))
+                .ok_or("cannot tell the file format from its extension; pass --format")?;
+            let mut options = ImportOptions::for_file(&file, format);
+            options.chunk_size = batch_size as usize;
+            options.resume = resume;
+            if let Some(rejects) = rejects {
+                options.rejects = rejects;
+            }
+            if let Some(checkpoint) = checkpoint {
+                options.checkpoint = checkpoint;
+            }
 
-            // Use provided values or fall back to environment variables
-            let region = region.unwrap_or_else(|| {
-                let host = env::var("DB_HOST").expect("DB_HOST must be set in .env file");
-                // Extract region from host - assuming format "<cluster_id>.dsql.<region>.on.aws"
-                host.split('.').nth(2).unwrap_or("us-east-1").to_string()
-            });
+            let pool = create_connection_pool(&config).await?;
+            let result = import_file(
+                &UserRepository::new(pool.clone()),
+                &file,
+                &options,
+                cli.output,
+            )
+            .await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::StressTest {
+            users,
+            concurrency,
+            duration,
+            rps,
+            workload,
+            workload_file,
+            contention,
+            hot_rows,
+            batch_size,
+            results_dir,
+            no_save,
+        } => {
+            let workload = match workload_file {
+                _ if contention => Workload::contention(hot_rows),
+                Some(path) => Workload::from_file(&path)?,
+                None => workload.workload(),
+            };
+            let stress_config = StressConfig {
+                total_operations: users,
+                concurrency,
+                duration,
+                rate: rps,
+                workload,
+                batch_size: batch_size as usize,
+            };
+            // Create the database connection pool
+            let pool = create_connection_pool(&config).await?;
+            let result = stress_test_database(
+                &UserRepository::new(pool.clone()),
+                &stress_config,
+                &config,
+                (!no_save).then_some(results_dir.as_path()),
+                cli.output,
+            )
+            .await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::UserStats => {
+            let pool = create_connection_pool(&config).await?;
+            debug!("gathering user statistics");
+            let result = stats::user_statistics(&pool).await;
+            close_connection_pool(pool).await;
+            print_value(cli.output, &result?, UserStats::rows, print_user_statistics)?;
+        }
+        Commands::Migrate { action } => {
+            let pool = create_connection_pool(&config).await?;
+            let result = match action {
+                MigrateAction::Up { dry_run } => migrate_up(&pool, dry_run).await,
+                MigrateAction::Status => migrate_status(&pool).await,
+            };
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::Serve { listen } => {
+            let pool = create_connection_pool(&config).await?;
+            let result = serve(UserRepository::new(pool.clone()), listen).await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::Exec { file, command } => {
+            let sql = match (file, command) {
+                (_, Some(command)) => command,
+                (Some(path), None) if path == Path::new("-") => {
+                    std::io::read_to_string(std::io::stdin())?
+                }
+                (Some(path), None) => std::fs::read_to_string(&path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?,
+                (None, None) => unreachable!("clap requires --file or --command"),
+            };
+
+            // Check the whole script before connecting, so nothing runs if part of it is invalid
+            let script = Script::parse(&sql)?;
+            if script.steps.is_empty() {
+                return Err("no SQL statements to run".into());
+            }
 
-            let endpoint = endpoint
-                .unwrap_or_else(|| env::var("DB_HOST").expect("DB_HOST must be set in .env file"));
+            let pool = create_connection_pool(&config).await?;
+            let result = exec_script(&pool, &script, cli.output).await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::Shell {
+            history,
+            no_history,
+            timing,
+        } => {
+            let options = ShellOptions {
+                prompt: config.database.clone(),
+                history: if no_history {
+                    None
+                } else {
+                    history.or_else(shell::history_path)
+                },
+                format: cli.output,
+                timing,
+            };
+
+            let pool = create_connection_pool(&config).await?;
+            let result = shell::run(&pool, options).await;
+            close_connection_pool(pool).await;
+            result?;
+        }
+        Commands::Bench { .. } | Commands::Doctor => {
+            unreachable!("handled before loading the configuration")
+        }
+        Commands::Connect { client, args } => {
+            let info = auth::generate_token_info(&config, config.is_admin()).await?;
+            return run_client(&client, &args, &info).await;
+        }
+        Commands::Env => {
+            // The point is to keep the token off the screen, so only write it to a pipe
+            if std::io::stdout().is_terminal() {
+                return Err(
+                    "refusing to print a token to the terminal; run eval \"$(rust-dsql env)\" instead"
+                        .into(),
+                );
+            }
 
+            let info = auth::generate_token_info(&config, config.is_admin()).await?;
+            println!("# Token expires at {}", info.expires_at);
+            for (name, value) in info.libpq_env() {
+                println!("export {}={}", name, shell_quote(&value));
+            }
+        }
+        Commands::GenerateToken { admin, token_only } => {
             // Generate the token
-            let token = auth::generate_auth_token(&endpoint, &region, admin).await?;
+            let info = auth::generate_token_info(&config, admin).await?;
 
             if token_only {
-                // Just print the token
-                println!("{}", token);
+                // Just print the token, whatever the output format
+                println!("{}", info.token);
             } else {
-                // Get user and database name from env or use defaults
-                let user = env::var("DB_USER").unwrap_or_else(|_| {
-                    if admin {
-                        "admin".to_string()
-                    } else {
-                        "postgres".to_string()
-                    }
-                });
-                let database = env::var("DB_NAME").unwrap_or_else(|_| "postgres".to_string());
-                let port = env::var("DB_PORT")
-                    .unwrap_or_else(|_| "5432".to_string())
-                    .parse::<u16>()
-                    .unwrap_or(5432);
-
-                // Print connection details
-                println!("Authentication token generated successfully!");
-                println!("Host:     {}", endpoint);
-                println!("Port:     {}", port);
-                println!("User:     {}", user);
-                println!("Database: {}", database);
-                println!("Region:   {}", region);
-                println!("Admin:    {}", if admin { "Yes" } else { "No" });
-                println!("\nToken: {}", token);
-
-                // Print a sample connection command
-                println!("\nSample connection command:");
-                println!(
-                    "PGSSLMODE=require psql \"postgresql://{}@{}:{}/{}\" -W",
-                    user, endpoint, port, database
-                );
-                println!("When prompted for password, use the token shown above.");
+                print_value(cli.output, &info, |info| vec![info.clone()], print_token)?;
             }
         }
     }
 
-    Ok(())
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn generate_token_keeps_its_short_flags() {
+        let host = "abcdefghijklmnopqrstuvwxyz.dsql.us-east-1.on.aws";
+        let cli =
+            Cli::try_parse_from(["rust-dsql", "generate-token", "-r", "us-east-1", "-e", host])
+                .unwrap();
+        assert_eq!(cli.connection.region.as_deref(), Some("us-east-1"));
+        assert_eq!(cli.connection.host.as_deref(), Some(host));
+        assert!(matches!(cli.command, Commands::GenerateToken { .. }));
+    }
 }
@@ -0,0 +1,354 @@
+use crate::retry::ErrorClass;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// Latency buckets in seconds, from 1 ms (a cached read) to 10 s (a retried bulk insert)
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters and histograms for database activity, exported in the Prometheus text format
+///
+/// Operations are named after what they do (`insert_user`, `get_user`, ...); statements
+/// run through plain [`retry`](crate::retry::retry) are counted as `query`, transactions
+/// as `transaction`.
+struct Metrics {
+    registry: Registry,
+    /// Finished operations by outcome: `ok`, `conflict`, `connection` or `error`
+    operations: IntCounterVec,
+    /// Time from the first attempt until the operation succeeded or gave up, backoff included
+    operation_duration: HistogramVec,
+    /// Latency of each attempt
+    query_duration: HistogramVec,
+    /// Attempts that failed with a retryable error and were tried again
+    retries: IntCounterVec,
+    /// Attempts that failed with an OCC conflict, by SQLSTATE
+    occ_conflicts: IntCounterVec,
+    /// Tokens minted for new connections, by outcome
+    token_refreshes: IntCounterVec,
+    /// Time spent waiting for a pooled connection
+    pool_acquire_wait: Histogram,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new();
+    let counter = |name: &str, help: &str, labels: &[&str]| {
+        let counter =
+            IntCounterVec::new(Opts::new(name, help), labels).expect("metric options are valid");
+        registry
+            .register(Box::new(counter.clone()))
+            .expect("metric names are unique");
+        counter
+    };
+    let histogram = |name: &str, help: &str, labels: &[&str]| {
+        let opts = HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec());
+        let histogram = HistogramVec::new(opts, labels).expect("metric options are valid");
+        registry
+            .register(Box::new(histogram.clone()))
+            .expect("metric names are unique");
+        histogram
+    };
+
+    Metrics {
+        operations: counter(
+            "dsql_operations_total",
+            "Database operations by outcome, after retries",
+            &["operation", "outcome"],
+        ),
+        operation_duration: histogram(
+            "dsql_operation_duration_seconds",
+            "Duration of database operations including retries and backoff",
+            &["operation"],
+        ),
+        query_duration: histogram(
+            "dsql_query_duration_seconds",
+            "Latency of each attempt of a database operation",
+            &["operation"],
+        ),
+        retries: counter(
+            "dsql_retries_total",
+            "Attempts that failed with a retryable error and were tried again",
+            &["operation", "class"],
+        ),
+        occ_conflicts: counter(
+            "dsql_occ_conflicts_total",
+            "Attempts that failed with an optimistic concurrency conflict",
+            &["operation", "sqlstate"],
+        ),
+        token_refreshes: counter(
+            "dsql_token_refreshes_total",
+            "IAM authentication tokens minted",
+            &["outcome"],
+        ),
+        pool_acquire_wait: {
+            let opts = HistogramOpts::new(
+                "dsql_pool_acquire_wait_seconds",
+                "Time spent waiting for a connection from the pool",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec());
+            let histogram = Histogram::with_opts(opts).expect("metric options are valid");
+            registry
+                .register(Box::new(histogram.clone()))
+                .expect("metric names are unique");
+            histogram
+        },
+        registry,
+    }
+});
+
+/// Name of an error class as used in metric labels
+fn class_label(class: ErrorClass) -> &'static str {
+    match class {
+        ErrorClass::Conflict => "conflict",
+        ErrorClass::Connection => "connection",
+        ErrorClass::Fatal => "error",
+    }
+}
+
+pub(crate) fn observe_attempt(operation: &str, latency: Duration) {
+    METRICS
+        .query_duration
+        .with_label_values(&[operation])
+        .observe(latency.as_secs_f64());
+}
+
+/// Count a failed attempt; `retried` is whether it will be tried again
+pub(crate) fn observe_failure(
+    operation: &str,
+    class: ErrorClass,
+    sqlstate: Option<&str>,
+    retried: bool,
+) {
+    if retried {
+        METRICS
+            .retries
+            .with_label_values(&[operation, class_label(class)])
+            .inc();
+    }
+    if class == ErrorClass::Conflict {
+        METRICS
+            .occ_conflicts
+            .with_label_values(&[operation, sqlstate.unwrap_or("")])
+            .inc();
+    }
+}
+
+/// Count a finished operation; `failure` is the class of the error it gave up with
+pub(crate) fn observe_operation(operation: &str, failure: Option<ErrorClass>, elapsed: Duration) {
+    let outcome = failure.map_or("ok", class_label);
+    METRICS
+        .operations
+        .with_label_values(&[operation, outcome])
+        .inc();
+    METRICS
+        .operation_duration
+        .with_label_values(&[operation])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub(crate) fn observe_token_refresh(succeeded: bool) {
+    let outcome = if succeeded { "ok" } else { "error" };
+    METRICS.token_refreshes.with_label_values(&[outcome]).inc();
+}
+
+pub(crate) fn observe_acquire_wait(wait: Duration) {
+    METRICS.pool_acquire_wait.observe(wait.as_secs_f64());
+}
+
+/// All metrics in the Prometheus text exposition format
+///
+/// Metrics are collected for the whole process, so tests can read them after running
+/// operations without starting a server.
+pub fn gather() -> String {
+    TextEncoder::new()
+        .encode_to_string(&METRICS.registry.gather())
+        .unwrap_or_default()
+}
+
+/// `GET /metrics` handler, also mounted by the REST API
+pub async fn metrics_handler() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], gather())
+}
+
+/// Serve `GET /metrics` on `addr` until `shutdown` completes
+///
+/// For commands other than `serve` (e.g. a long stress test) so Prometheus can scrape
+/// them while they run.
+pub async fn serve(
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let router = Router::new().route("/metrics", get(metrics_handler));
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::{retry_as, RetryPolicy, DSQL_OCC_DATA_CONFLICT};
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use std::borrow::Cow;
+    use std::fmt;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// The error DSQL returns when a commit loses an optimistic concurrency check
+    #[derive(Debug)]
+    struct OccConflict;
+
+    impl fmt::Display for OccConflict {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.message())
+        }
+    }
+
+    impl std::error::Error for OccConflict {}
+
+    impl DatabaseError for OccConflict {
+        fn message(&self) -> &str {
+            "change conflicts with another transaction"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(DSQL_OCC_DATA_CONFLICT))
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            multiplier: 1.0,
+            jitter: false,
+        }
+    }
+
+    /// Value of the sample of `metric` carrying all of `labels`, 0 if there is none
+    ///
+    /// Metrics are process-wide, so each test uses operation names of its own.
+    fn sample(metric: &str, labels: &[(&str, &str)]) -> f64 {
+        gather()
+            .lines()
+            .filter(|line| line.starts_with(&format!("{}{{", metric)))
+            .find(|line| {
+                labels
+                    .iter()
+                    .all(|(name, value)| line.contains(&format!("{}=\"{}\"", name, value)))
+            })
+            .and_then(|line| line.rsplit(' ').next()?.parse().ok())
+            .unwrap_or(0.0)
+    }
+
+    #[tokio::test]
+    async fn retried_operation_is_counted() {
+        let calls = AtomicU32::new(0);
+        let retried = retry_as(&policy(5), "metrics_test_retried", || {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match call {
+                    0 => Err(sqlx::Error::PoolTimedOut),
+                    1 => Err(sqlx::Error::Database(Box::new(OccConflict))),
+                    _ => Ok(call),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!((retried.attempts, retried.conflicts), (3, 1));
+
+        let op = ("operation", "metrics_test_retried");
+        assert_eq!(
+            sample("dsql_retries_total", &[op, ("class", "connection")]),
+            1.0
+        );
+        assert_eq!(
+            sample("dsql_retries_total", &[op, ("class", "conflict")]),
+            1.0
+        );
+        assert_eq!(
+            sample(
+                "dsql_occ_conflicts_total",
+                &[op, ("sqlstate", DSQL_OCC_DATA_CONFLICT)]
+            ),
+            1.0
+        );
+        assert_eq!(
+            sample("dsql_operations_total", &[op, ("outcome", "ok")]),
+            1.0
+        );
+        assert_eq!(sample("dsql_operation_duration_seconds_count", &[op]), 1.0);
+        assert_eq!(sample("dsql_query_duration_seconds_count", &[op]), 3.0);
+        assert_eq!(
+            sample("dsql_query_duration_seconds_bucket", &[op, ("le", "+Inf")]),
+            3.0
+        );
+    }
+
+    #[tokio::test]
+    async fn exhausted_and_fatal_operations_are_counted() {
+        let err = retry_as(&policy(2), "metrics_test_exhausted", || async {
+            Err::<(), _>(sqlx::Error::PoolTimedOut)
+        })
+        .await
+        .unwrap_err();
+        assert!(err.is_exhausted());
+
+        let op = ("operation", "metrics_test_exhausted");
+        // The last attempt is not retried
+        assert_eq!(
+            sample("dsql_retries_total", &[op, ("class", "connection")]),
+            1.0
+        );
+        assert_eq!(
+            sample("dsql_operations_total", &[op, ("outcome", "connection")]),
+            1.0
+        );
+        assert_eq!(
+            sample("dsql_operations_total", &[op, ("outcome", "ok")]),
+            0.0
+        );
+        assert_eq!(sample("dsql_query_duration_seconds_count", &[op]), 2.0);
+
+        retry_as(&policy(5), "metrics_test_fatal", || async {
+            Err::<(), _>(sqlx::Error::RowNotFound)
+        })
+        .await
+        .unwrap_err();
+
+        let op = ("operation", "metrics_test_fatal");
+        assert_eq!(sample("dsql_retries_total", &[op]), 0.0);
+        assert_eq!(
+            sample("dsql_operations_total", &[op, ("outcome", "error")]),
+            1.0
+        );
+        assert_eq!(sample("dsql_operation_duration_seconds_count", &[op]), 1.0);
+    }
+}
@@ -0,0 +1,499 @@
+use crate::retry::{retry, retry_transaction, RetryError, RetryPolicy};
+use crate::sql::{self, StatementKind};
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgPool;
+use sqlx::types::chrono;
+use sqlx::Row;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use tracing::{debug, instrument};
+
+/// A schema migration embedded in the binary
+#[derive(Debug)]
+pub struct Migration {
+    /// Ordering key; migrations are applied in ascending version order
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// All migrations, in the order they are applied
+pub static MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    name: "create_users",
+    sql: include_str!("../migrations/0001_create_users.sql"),
+}];
+
+/// Table recording which migrations have been applied
+const CREATE_TRACKING_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS schema_migrations (
+        version BIGINT PRIMARY KEY,
+        name VARCHAR(255) NOT NULL,
+        checksum VARCHAR(64) NOT NULL,
+        applied_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )
+"#;
+
+/// Look up an embedded migration by name
+pub fn find(name: &str) -> Option<&'static Migration> {
+    MIGRATIONS.iter().find(|migration| migration.name == name)
+}
+
+impl Migration {
+    /// SHA-256 of the migration's SQL, used to detect migrations edited after being applied
+    pub fn checksum(&self) -> String {
+        Sha256::digest(self.sql.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Check the migration against Aurora DSQL's transaction rules
+    ///
+    /// Each migration runs in its own transaction, and DSQL allows only one DDL statement
+    /// per transaction and no mixing of DDL with DML. A migration must therefore be either
+    /// a single DDL statement or any number of DML statements. Transaction control
+    /// statements are rejected because the runner manages transactions, and indexes must be
+    /// built with `CREATE INDEX ASYNC`.
+    ///
+    /// Because the DDL statement and the row recording it in `schema_migrations` commit
+    /// separately, DDL migrations should be idempotent (`IF NOT EXISTS`).
+    pub fn validate(&self) -> Result<MigrationPlan, MigrationError> {
+        let invalid = |reason: String| MigrationError::Invalid {
+            version: self.version,
+            name: self.name,
+            reason,
+        };
+
+        let statements = sql::split_statements(self.sql);
+        if statements.is_empty() {
+            return Err(invalid("migration contains no statements".to_string()));
+        }
+
+        let kinds: Vec<StatementKind> = statements.iter().map(|s| StatementKind::of(s)).collect();
+
+        if kinds.contains(&StatementKind::Transaction) {
+            return Err(invalid(
+                "transaction control statements are not allowed; each migration already runs in its own transaction".to_string(),
+            ));
+        }
+
+        let ddl_count = kinds.iter().filter(|&&k| k == StatementKind::Ddl).count();
+        if ddl_count > 1 {
+            return Err(invalid(format!(
+                "contains {} DDL statements, but Aurora DSQL allows only one DDL statement per transaction; split it into separate migrations",
+                ddl_count
+            )));
+        }
+        if ddl_count == 1 && statements.len() > 1 {
+            return Err(invalid(
+                "mixes DDL with other statements, which Aurora DSQL does not allow in one transaction".to_string(),
+            ));
+        }
+
+        if let Some(statement) = statements.iter().find(|s| is_sync_index_creation(s)) {
+            return Err(invalid(format!(
+                "indexes must be created with CREATE INDEX ASYNC on Aurora DSQL: {}",
+                statement
+            )));
+        }
+
+        Ok(MigrationPlan {
+            kind: if ddl_count == 1 {
+                MigrationKind::Ddl
+            } else {
+                MigrationKind::Dml
+            },
+            statements,
+        })
+    }
+}
+
+/// Whether a statement is `CREATE [UNIQUE] INDEX` without `ASYNC`
+fn is_sync_index_creation(statement: &str) -> bool {
+    let words = sql::keywords(statement);
+    let mut words = words.iter().map(String::as_str);
+
+    if words.next() != Some("CREATE") {
+        return false;
+    }
+    let mut next = words.next();
+    if next == Some("UNIQUE") {
+        next = words.next();
+    }
+    next == Some("INDEX") && words.next() != Some("ASYNC")
+}
+
+/// Whether a migration changes the schema or only data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationKind {
+    /// A single DDL statement, run on its own
+    Ddl,
+    /// One or more DML statements, run in a single transaction with the tracking row
+    Dml,
+}
+
+/// A validated migration, split into statements
+#[derive(Debug, Clone)]
+pub struct MigrationPlan {
+    pub kind: MigrationKind,
+    pub statements: Vec<String>,
+}
+
+/// A migration that was (or, in a dry run, would be) applied
+#[derive(Debug)]
+pub struct AppliedMigration {
+    pub migration: &'static Migration,
+    pub plan: MigrationPlan,
+}
+
+/// Whether a migration has been applied to the database
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub migration: &'static Migration,
+    /// When the migration was applied, if it has been
+    pub applied_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// False if the migration's SQL changed since it was applied
+    pub checksum_matches: bool,
+}
+
+/// Errors from validating or applying migrations
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The migration violates Aurora DSQL's rules
+    Invalid {
+        version: i64,
+        name: &'static str,
+        reason: String,
+    },
+    /// An applied migration's SQL no longer matches what was recorded
+    ChecksumMismatch { version: i64, name: &'static str },
+    /// A database operation failed
+    Database(RetryError),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Invalid {
+                version,
+                name,
+                reason,
+            } => write!(
+                f,
+                "migration {:04}_{} is invalid: {}",
+                version, name, reason
+            ),
+            MigrationError::ChecksumMismatch { version, name } => write!(
+                f,
+                "migration {:04}_{} was modified after it was applied",
+                version, name
+            ),
+            MigrationError::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for MigrationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MigrationError::Database(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<RetryError> for MigrationError {
+    fn from(err: RetryError) -> Self {
+        MigrationError::Database(err)
+    }
+}
+
+/// Report which migrations have been applied
+///
+/// Nothing is created in the database; if the tracking table does not exist yet, every
+/// migration is reported as pending.
+pub async fn status(
+    pool: &PgPool,
+    policy: &RetryPolicy,
+) -> Result<Vec<MigrationStatus>, MigrationError> {
+    let applied = applied_migrations(pool, policy).await?;
+
+    Ok(MIGRATIONS
+        .iter()
+        .map(|migration| match applied.get(&migration.version) {
+            Some((checksum, applied_at)) => MigrationStatus {
+                migration,
+                applied_at: Some(*applied_at),
+                checksum_matches: *checksum == migration.checksum(),
+            },
+            None => MigrationStatus {
+                migration,
+                applied_at: None,
+                checksum_matches: true,
+            },
+        })
+        .collect())
+}
+
+/// Apply all pending migrations in order
+///
+/// Every pending migration is validated before anything is applied, and applied
+/// migrations whose SQL has changed abort the run. Several processes may run this at
+/// once (e.g. `serve` instances starting together): a migration another run applies
+/// first is skipped and left out of the result.
+///
+/// Args:
+///   pool: Connection pool
+///   policy: Retry policy for each statement/transaction
+///   dry_run: Only validate and report what would be applied
+///
+/// Returns:
+///   The migrations that were applied (or would be, in a dry run)
+#[instrument(level = "debug", skip(pool, policy))]
+pub async fn up(
+    pool: &PgPool,
+    policy: &RetryPolicy,
+    dry_run: bool,
+) -> Result<Vec<AppliedMigration>, MigrationError> {
+    let mut pending = Vec::new();
+    for status in status(pool, policy).await? {
+        if !status.checksum_matches {
+            return Err(MigrationError::ChecksumMismatch {
+                version: status.migration.version,
+                name: status.migration.name,
+            });
+        }
+        if status.applied_at.is_none() {
+            pending.push(AppliedMigration {
+                migration: status.migration,
+                plan: status.migration.validate()?,
+            });
+        }
+    }
+
+    if dry_run || pending.is_empty() {
+        return Ok(pending);
+    }
+
+    // Creating the tracking table is DDL, so it gets a transaction of its own
+    already_applied(retry(policy, || sqlx::query(CREATE_TRACKING_TABLE).execute(pool)).await)?;
+
+    let mut applied = Vec::new();
+    for migration in pending {
+        if apply(pool, policy, &migration).await? {
+            applied.push(migration);
+        } else {
+            debug!(
+                version = migration.migration.version,
+                "migration was applied by another run"
+            );
+        }
+    }
+
+    Ok(applied)
+}
+
+/// Treat a unique violation as the work having been done by a concurrent run
+///
+/// Two runs creating the same table or inserting the same tracking row race on a unique
+/// index (the catalog's, or the primary key of `schema_migrations`); the loser gets
+/// SQLSTATE 23505. Returns whether this run did the work.
+fn already_applied<T>(result: Result<T, RetryError>) -> Result<bool, RetryError> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(err)
+            if err
+                .source
+                .as_database_error()
+                .is_some_and(|db_err| db_err.is_unique_violation()) =>
+        {
+            Ok(false)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Apply one validated migration and record it
+///
+/// Returns:
+///   Whether this run applied it, rather than a concurrent one
+async fn apply(
+    pool: &PgPool,
+    policy: &RetryPolicy,
+    applied: &AppliedMigration,
+) -> Result<bool, MigrationError> {
+    let migration = applied.migration;
+    let checksum = migration.checksum();
+
+    match applied.plan.kind {
+        MigrationKind::Ddl => {
+            // DDL and DML cannot share a transaction, so the tracking row is written
+            // separately after the schema change commits
+            let statement = &applied.plan.statements[0];
+            already_applied(retry(policy, || sqlx::query(statement).execute(pool)).await)?;
+            Ok(already_applied(
+                retry(policy, || record(migration, &checksum).execute(pool)).await,
+            )?)
+        }
+        MigrationKind::Dml => {
+            // A concurrent run recording the migration first makes this transaction fail
+            // on the tracking row, rolling back its statements
+            let statements = applied.plan.statements.clone();
+            let result = retry_transaction(pool, policy, move |conn| {
+                let statements = statements.clone();
+                let checksum = checksum.clone();
+                Box::pin(async move {
+                    for statement in &statements {
+                        sqlx::query(statement).execute(&mut *conn).await?;
+                    }
+                    record(migration, &checksum).execute(&mut *conn).await?;
+                    Ok(())
+                })
+            })
+            .await;
+            Ok(already_applied(result)?)
+        }
+    }
+}
+
+/// Statement inserting the tracking row for a migration
+fn record<'q>(
+    migration: &'static Migration,
+    checksum: &'q str,
+) -> sqlx::query::Query<'q, sqlx::Postgres, sqlx::postgres::PgArguments> {
+    sqlx::query("INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)")
+        .bind(migration.version)
+        .bind(migration.name)
+        .bind(checksum)
+}
+
+/// Applied migrations by version: (checksum, applied_at)
+async fn applied_migrations(
+    pool: &PgPool,
+    policy: &RetryPolicy,
+) -> Result<HashMap<i64, (String, chrono::DateTime<chrono::Utc>)>, MigrationError> {
+    let table_exists = retry(policy, || {
+        sqlx::query("SELECT EXISTS (SELECT FROM information_schema.tables WHERE table_schema = 'public' AND table_name = 'schema_migrations')")
+            .fetch_one(pool)
+    })
+    .await?
+    .value
+    .get::<bool, _>(0);
+
+    if !table_exists {
+        return Ok(HashMap::new());
+    }
+
+    let rows = retry(policy, || {
+        sqlx::query("SELECT version, checksum, applied_at FROM schema_migrations").fetch_all(pool)
+    })
+    .await?;
+
+    Ok(rows
+        .value
+        .iter()
+        .map(|row| {
+            (
+                row.get::<i64, _>("version"),
+                (row.get("checksum"), row.get("applied_at")),
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migration(sql: &'static str) -> Migration {
+        Migration {
+            version: 2,
+            name: "test",
+            sql,
+        }
+    }
+
+    fn invalid_reason(sql: &'static str) -> String {
+        match migration(sql).validate() {
+            Err(MigrationError::Invalid { reason, .. }) => reason,
+            other => panic!("expected an invalid migration, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn embedded_migrations_are_valid() {
+        for migration in MIGRATIONS {
+            migration.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn single_ddl_statement() {
+        let plan = migration("CREATE TABLE IF NOT EXISTS t (id UUID PRIMARY KEY);")
+            .validate()
+            .unwrap();
+        assert_eq!(plan.kind, MigrationKind::Ddl);
+        assert_eq!(plan.statements.len(), 1);
+    }
+
+    #[test]
+    fn several_dml_statements() {
+        let plan = migration(
+            "INSERT INTO t VALUES ('a'); UPDATE t SET id = 'b'; DELETE FROM t WHERE id = 'c';",
+        )
+        .validate()
+        .unwrap();
+        assert_eq!(plan.kind, MigrationKind::Dml);
+        assert_eq!(plan.statements.len(), 3);
+    }
+
+    #[test]
+    fn rejects_more_than_one_ddl_statement() {
+        let reason = invalid_reason("CREATE TABLE a (id INT); CREATE TABLE b (id INT);");
+        assert!(reason.contains("2 DDL statements"), "{}", reason);
+    }
+
+    #[test]
+    fn rejects_ddl_mixed_with_dml() {
+        let reason =
+            invalid_reason("ALTER TABLE users ADD COLUMN age INT; UPDATE users SET age = 0;");
+        assert!(reason.contains("mixes DDL"), "{}", reason);
+    }
+
+    #[test]
+    fn rejects_synchronous_index_creation() {
+        let reason = invalid_reason("CREATE INDEX users_role ON users (role);");
+        assert!(reason.contains("CREATE INDEX ASYNC"), "{}", reason);
+        let reason = invalid_reason("create unique index users_name on users (name);");
+        assert!(reason.contains("CREATE INDEX ASYNC"), "{}", reason);
+
+        migration("CREATE INDEX ASYNC users_role ON users (role);")
+            .validate()
+            .unwrap();
+        migration("CREATE UNIQUE INDEX ASYNC users_name ON users (name);")
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_transaction_control() {
+        let reason = invalid_reason("BEGIN; INSERT INTO t VALUES (1); COMMIT;");
+        assert!(reason.contains("transaction control"), "{}", reason);
+    }
+
+    #[test]
+    fn rejects_an_empty_migration() {
+        let reason = invalid_reason("-- nothing to do\n");
+        assert!(reason.contains("no statements"), "{}", reason);
+    }
+
+    #[test]
+    fn checksum_changes_with_the_sql() {
+        let a = migration("SELECT 1;").checksum();
+        assert_eq!(a.len(), 64);
+        assert_eq!(a, migration("SELECT 1;").checksum());
+        assert_ne!(a, migration("SELECT 2;").checksum());
+    }
+}
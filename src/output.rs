@@ -0,0 +1,240 @@
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+
+/// How command results are printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text (the default)
+    #[default]
+    Table,
+    /// A single pretty-printed JSON document
+    Json,
+    /// One compact JSON object per line
+    Ndjson,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+impl OutputFormat {
+    /// Whether this is the human-readable format
+    pub fn is_table(self) -> bool {
+        self == OutputFormat::Table
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputFormat::Table => "table",
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+            OutputFormat::Csv => "csv",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Write a value as pretty-printed JSON followed by a newline
+pub fn write_json<W: Write, T: Serialize + ?Sized>(
+    mut writer: W,
+    value: &T,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Write each record as one line of compact JSON
+pub fn write_ndjson<W: Write, T: Serialize>(
+    mut writer: W,
+    records: impl IntoIterator<Item = T>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for record in records {
+        serde_json::to_writer(&mut writer, &record)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Write records as CSV, with a header row taken from the first record's field names
+///
+/// Records must be flat: nested structs and sequences cannot be represented in CSV.
+pub fn write_csv<W: Write, T: Serialize>(
+    writer: W,
+    records: impl IntoIterator<Item = T>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for record in records {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes records one at a time in a machine-readable format
+///
+/// Unlike [`write_json`] and friends this does not need all records up front, so results
+/// can be printed while they are still being fetched.
+pub enum RecordWriter<W: Write> {
+    /// Elements of a JSON array; `first` is true until the first record is written
+    Json {
+        writer: W,
+        first: bool,
+    },
+    Ndjson(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RecordWriter<W> {
+    /// Create a writer for `format`, or `None` for the table format, which each command
+    /// renders itself
+    pub fn new(writer: W, format: OutputFormat) -> Option<Self> {
+        match format {
+            OutputFormat::Table => None,
+            OutputFormat::Json => Some(RecordWriter::Json {
+                writer,
+                first: true,
+            }),
+            OutputFormat::Ndjson => Some(RecordWriter::Ndjson(writer)),
+            OutputFormat::Csv => Some(RecordWriter::Csv(Box::new(csv::Writer::from_writer(
+                writer,
+            )))),
+        }
+    }
+
+    /// Write one record
+    pub fn write<T: Serialize>(&mut self, record: &T) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self {
+            RecordWriter::Json { writer, first } => {
+                writer.write_all(if *first { b"[\n" } else { b",\n" })?;
+                *first = false;
+                serde_json::to_writer_pretty(&mut *writer, record)?;
+            }
+            RecordWriter::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writeln!(writer)?;
+            }
+            RecordWriter::Csv(writer) => writer.serialize(record)?,
+        }
+        Ok(())
+    }
+
+    /// Terminate the output and flush it
+    pub fn finish(self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self {
+            RecordWriter::Json { mut writer, first } => {
+                writer.write_all(if first { b"[]\n" } else { b"\n]\n" })?;
+                writer.flush()?;
+            }
+            RecordWriter::Ndjson(mut writer) => writer.flush()?,
+            RecordWriter::Csv(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Row {
+        name: &'static str,
+        role: Option<&'static str>,
+        count: u32,
+    }
+
+    fn rows() -> Vec<Row> {
+        vec![
+            Row {
+                name: "Doe, Jane",
+                role: Some("Admin"),
+                count: 1,
+            },
+            Row {
+                name: "The \"Boss\"",
+                role: None,
+                count: 2,
+            },
+        ]
+    }
+
+    fn written(write: impl FnOnce(&mut Vec<u8>)) -> String {
+        let mut buffer = Vec::new();
+        write(&mut buffer);
+        String::from_utf8(buffer).unwrap()
+    }
+
+    /// Everything `RecordWriter` writes for `rows()` in `format`
+    fn streamed(format: OutputFormat) -> String {
+        written(|buffer| {
+            let mut writer = RecordWriter::new(buffer, format).unwrap();
+            for row in rows() {
+                writer.write(&row).unwrap();
+            }
+            writer.finish().unwrap();
+        })
+    }
+
+    #[test]
+    fn json_is_one_document_with_nulls() {
+        let json = written(|buffer| write_json(buffer, &rows()).unwrap());
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!([
+                { "name": "Doe, Jane", "role": "Admin", "count": 1 },
+                { "name": "The \"Boss\"", "role": null, "count": 2 },
+            ])
+        );
+        assert!(json.ends_with("]\n"));
+
+        let streamed: serde_json::Value =
+            serde_json::from_str(&streamed(OutputFormat::Json)).unwrap();
+        assert_eq!(streamed, value);
+    }
+
+    #[test]
+    fn empty_json_is_an_empty_array() {
+        let json = written(|buffer| {
+            RecordWriter::new(buffer, OutputFormat::Json)
+                .unwrap()
+                .finish()
+                .unwrap()
+        });
+        assert_eq!(json, "[]\n");
+    }
+
+    #[test]
+    fn ndjson_has_one_object_per_line() {
+        let ndjson = written(|buffer| write_ndjson(buffer, rows()).unwrap());
+        let lines: Vec<&str> = ndjson.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"name":"Doe, Jane","role":"Admin","count":1}"#,
+                r#"{"name":"The \"Boss\"","role":null,"count":2}"#,
+            ]
+        );
+        assert_eq!(streamed(OutputFormat::Ndjson), ndjson);
+    }
+
+    #[test]
+    fn csv_quotes_commas_and_quotes_and_leaves_nulls_empty() {
+        let csv = written(|buffer| write_csv(buffer, rows()).unwrap());
+        assert_eq!(
+            csv,
+            "name,role,count\n\"Doe, Jane\",Admin,1\n\"The \"\"Boss\"\"\",,2\n"
+        );
+        assert_eq!(streamed(OutputFormat::Csv), csv);
+    }
+
+    #[test]
+    fn table_is_left_to_the_command() {
+        assert!(RecordWriter::new(Vec::new(), OutputFormat::Table).is_none());
+        assert!(OutputFormat::Table.is_table());
+        assert_eq!(OutputFormat::Ndjson.to_string(), "ndjson");
+    }
+}
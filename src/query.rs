@@ -0,0 +1,270 @@
+use crate::output::OutputFormat;
+use crate::sql::{self, StatementKind};
+use futures_util::TryStreamExt;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use sqlx::postgres::{PgConnection, PgRow};
+use sqlx::{Column as _, Either, Executor, Row, Statement, TypeInfo, ValueRef};
+use std::error::Error;
+use std::io::Write;
+
+/// Lists user tables, for the shell's `\dt`
+const LIST_TABLES_SQL: &str = "SELECT table_schema AS schema, table_name AS name, \
+     table_type AS type \
+     FROM information_schema.tables \
+     WHERE table_schema NOT IN ('pg_catalog', 'information_schema') \
+     ORDER BY table_schema, table_name";
+
+/// A result column: its name and the name of its Postgres type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub type_name: String,
+}
+
+/// The outcome of running one statement, with every value in its text form
+///
+/// Statements are sent with the simple query protocol, so Postgres returns values as
+/// text and any column type can be shown without a Rust type to decode it into.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResultSet {
+    /// Result columns; empty for statements that return no rows
+    pub columns: Vec<Column>,
+    /// Values by row then column; `None` is SQL NULL
+    pub rows: Vec<Vec<Option<String>>>,
+    /// Rows changed by INSERT, UPDATE or DELETE (or returned, for queries)
+    pub rows_affected: u64,
+}
+
+impl ResultSet {
+    /// Whether the statement produced a result table (possibly with no rows)
+    pub fn returns_rows(&self) -> bool {
+        !self.columns.is_empty()
+    }
+
+    /// psql-style summary of a statement that returns no rows: its leading keyword, plus
+    /// the row count for DML (`UPDATE 3`)
+    pub fn command_tag(&self, statement: &str) -> String {
+        let keyword = sql::keywords(statement)
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        if StatementKind::of(statement) == StatementKind::Dml {
+            format!("{} {}", keyword, self.rows_affected)
+        } else {
+            keyword
+        }
+    }
+
+    /// Rows as name-to-value records, in column order
+    pub fn records(&self) -> impl Iterator<Item = Record<'_>> {
+        self.rows.iter().map(|values| Record {
+            columns: &self.columns,
+            values,
+        })
+    }
+
+    /// Write the rows in `format`; the table format is aligned like psql's output
+    pub fn write<W: Write>(
+        &self,
+        mut writer: W,
+        format: OutputFormat,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match format {
+            OutputFormat::Table => self.write_table(&mut writer)?,
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, &self.records().collect::<Vec<_>>())?;
+                writeln!(writer)?;
+            }
+            OutputFormat::Ndjson => {
+                for record in self.records() {
+                    serde_json::to_writer(&mut writer, &record)?;
+                    writeln!(writer)?;
+                }
+            }
+            OutputFormat::Csv => {
+                // Written field by field since csv cannot serialize maps
+                let mut writer = csv::Writer::from_writer(writer);
+                writer.write_record(self.columns.iter().map(|c| &c.name))?;
+                for row in &self.rows {
+                    writer.write_record(row.iter().map(|v| v.as_deref().unwrap_or("")))?;
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_table<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut widths: Vec<usize> = self
+            .columns
+            .iter()
+            .map(|c| c.name.chars().count())
+            .collect();
+        for row in &self.rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                let len = value.as_deref().map_or(0, |v| v.chars().count());
+                *width = (*width).max(len);
+            }
+        }
+
+        let header: Vec<String> = self
+            .columns
+            .iter()
+            .zip(&widths)
+            .map(|(c, &width)| format!(" {:^width$} ", c.name))
+            .collect();
+        writeln!(writer, "{}", header.join("|").trim_end())?;
+
+        let rule: Vec<String> = widths.iter().map(|&width| "-".repeat(width + 2)).collect();
+        writeln!(writer, "{}", rule.join("+"))?;
+
+        for row in &self.rows {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(value, &width)| format!(" {:<width$} ", value.as_deref().unwrap_or("")))
+                .collect();
+            writeln!(writer, "{}", cells.join("|").trim_end())?;
+        }
+
+        let count = self.rows.len();
+        writeln!(
+            writer,
+            "({} row{})",
+            count,
+            if count == 1 { "" } else { "s" }
+        )
+    }
+}
+
+/// One row of a [`ResultSet`], serialized as a map from column name to value
+pub struct Record<'a> {
+    columns: &'a [Column],
+    values: &'a [Option<String>],
+}
+
+impl Serialize for Record<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+        for (column, value) in self.columns.iter().zip(self.values) {
+            map.serialize_entry(&column.name, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Run a single SQL statement and collect its result
+///
+/// The statement is sent as-is, without parameters. A query returning no rows is
+/// prepared as well, so its columns can still be reported.
+pub async fn execute(conn: &mut PgConnection, statement: &str) -> Result<ResultSet, sqlx::Error> {
+    let mut result = ResultSet::default();
+
+    {
+        let mut stream = sqlx::raw_sql(statement).fetch_many(&mut *conn);
+        while let Some(item) = stream.try_next().await? {
+            match item {
+                Either::Left(done) => result.rows_affected += done.rows_affected(),
+                Either::Right(row) => {
+                    if result.columns.is_empty() {
+                        result.columns = columns(row.columns());
+                    }
+                    result.rows.push(text_values(&row)?);
+                }
+            }
+        }
+    }
+
+    if result.rows.is_empty() && StatementKind::of(statement) == StatementKind::Query {
+        // Describing may fail where running succeeded (e.g. SHOW); the columns are a nicety
+        if let Ok(prepared) = conn.prepare(statement).await {
+            result.columns = columns(prepared.columns());
+        }
+    }
+
+    Ok(result)
+}
+
+/// List the tables and views outside the system schemas
+pub async fn list_tables(conn: &mut PgConnection) -> Result<ResultSet, sqlx::Error> {
+    execute(conn, LIST_TABLES_SQL).await
+}
+
+/// Describe a table's columns and indexes
+///
+/// `name` may be qualified with a schema (`public.users`). Returns `None` when no such
+/// table exists.
+pub async fn describe_table(
+    conn: &mut PgConnection,
+    name: &str,
+) -> Result<Option<(ResultSet, ResultSet)>, sqlx::Error> {
+    let (schema_filter, table) = match name.split_once('.') {
+        Some((schema, table)) => (format!("= {}", quote_literal(schema)), table),
+        None => (
+            "NOT IN ('pg_catalog', 'information_schema')".to_string(),
+            name,
+        ),
+    };
+
+    let columns = execute(
+        conn,
+        &format!(
+            "SELECT column_name AS column, data_type AS type, is_nullable AS nullable, \
+             column_default AS default \
+             FROM information_schema.columns \
+             WHERE table_name = {} AND table_schema {} \
+             ORDER BY table_schema, ordinal_position",
+            quote_literal(table),
+            schema_filter
+        ),
+    )
+    .await?;
+    if columns.rows.is_empty() {
+        return Ok(None);
+    }
+
+    let indexes = execute(
+        conn,
+        &format!(
+            "SELECT indexname AS index, indexdef AS definition \
+             FROM pg_indexes \
+             WHERE tablename = {} AND schemaname {} \
+             ORDER BY indexname",
+            quote_literal(table),
+            schema_filter
+        ),
+    )
+    .await?;
+
+    Ok(Some((columns, indexes)))
+}
+
+/// Quote a value as a SQL string literal
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn columns(columns: &[sqlx::postgres::PgColumn]) -> Vec<Column> {
+    columns
+        .iter()
+        .map(|c| Column {
+            name: c.name().to_string(),
+            type_name: c.type_info().name().to_string(),
+        })
+        .collect()
+}
+
+fn text_values(row: &PgRow) -> Result<Vec<Option<String>>, sqlx::Error> {
+    (0..row.len())
+        .map(|i| {
+            let value = row.try_get_raw(i)?;
+            if value.is_null() {
+                return Ok(None);
+            }
+            value
+                .as_str()
+                .map(|text| Some(text.to_string()))
+                .map_err(sqlx::Error::Decode)
+        })
+        .collect()
+}
@@ -0,0 +1,715 @@
+use crate::connection;
+use crate::metrics;
+use rand::Rng;
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgConnection, PgPool, Postgres};
+use sqlx::Connection;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::{debug, debug_span, warn, Instrument, Span};
+
+/// SQLSTATE for serialization failures, raised by DSQL when optimistic concurrency control
+/// detects a conflicting commit
+pub const SERIALIZATION_FAILURE: &str = "40001";
+
+/// DSQL-specific SQLSTATEs for OCC conflicts on data (OC000) and schema (OC001) changes
+pub const DSQL_OCC_DATA_CONFLICT: &str = "OC000";
+pub const DSQL_OCC_SCHEMA_CONFLICT: &str = "OC001";
+
+/// How a failed database operation should be treated by the retry executor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Optimistic concurrency conflict or deadlock; the transaction can simply be re-run
+    Conflict,
+    /// The connection was lost or could not be acquired; a fresh connection may succeed
+    Connection,
+    /// Anything else (constraint violations, syntax errors, ...); retrying will not help
+    Fatal,
+}
+
+impl ErrorClass {
+    /// Whether an operation failing with this class of error should be attempted again
+    pub fn is_retryable(self) -> bool {
+        self != ErrorClass::Fatal
+    }
+}
+
+/// Classify a sqlx error as retryable or fatal
+///
+/// Database errors are classified by SQLSTATE: serialization failures (40001), deadlocks
+/// (40P01), the DSQL OCC codes (OC000/OC001), connection exceptions (class 08) and
+/// administrator shutdowns (57P01) are retryable. I/O errors and pool timeouts are treated
+/// as connection resets.
+pub fn classify(err: &sqlx::Error) -> ErrorClass {
+    match err {
+        sqlx::Error::Database(db_err) => match db_err.code().as_deref() {
+            Some(SERIALIZATION_FAILURE)
+            | Some("40P01")
+            | Some(DSQL_OCC_DATA_CONFLICT)
+            | Some(DSQL_OCC_SCHEMA_CONFLICT) => ErrorClass::Conflict,
+            Some(code) if code.starts_with("08") || code == "57P01" => ErrorClass::Connection,
+            _ => ErrorClass::Fatal,
+        },
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => {
+            ErrorClass::Connection
+        }
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// Exponential backoff settings for retrying database operations
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Backoff ceiling before the second attempt
+    pub initial_backoff: Duration,
+    /// Upper bound for any single backoff
+    pub max_backoff: Duration,
+    /// Factor the backoff ceiling grows by after each attempt
+    pub multiplier: f64,
+    /// Randomize each delay between zero and the current ceiling ("full jitter") so that
+    /// conflicting clients do not retry in lockstep
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay to wait after the given (1-based) failed attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(32) as i32;
+        let ceiling = self
+            .initial_backoff
+            .mul_f64(self.multiplier.powi(exponent))
+            .min(self.max_backoff);
+
+        if self.jitter {
+            ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+        } else {
+            ceiling
+        }
+    }
+}
+
+/// The value produced by a retried operation, along with how much effort it took
+#[derive(Debug, Clone, Copy)]
+pub struct Retried<T> {
+    pub value: T,
+    /// Number of attempts made, including the successful one
+    pub attempts: u32,
+    /// Number of failed attempts caused by OCC conflicts
+    pub conflicts: u32,
+}
+
+/// Error returned when a retried operation fails fatally or runs out of attempts
+#[derive(Debug)]
+pub struct RetryError {
+    /// The error from the last attempt
+    pub source: sqlx::Error,
+    /// Classification of the last error
+    pub class: ErrorClass,
+    /// Number of attempts made
+    pub attempts: u32,
+    /// Number of failed attempts caused by OCC conflicts
+    pub conflicts: u32,
+    /// The connection was lost after a write was sent, so it may have been applied; see
+    /// [`retry_write_as`]
+    pub ambiguous: bool,
+}
+
+impl RetryError {
+    /// Whether the operation kept failing with retryable errors until the policy gave up
+    pub fn is_exhausted(&self) -> bool {
+        self.class.is_retryable() && !self.ambiguous
+    }
+}
+
+impl fmt::Display for RetryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ambiguous {
+            write!(
+                f,
+                "{} (the write may or may not have been applied)",
+                self.source
+            )
+        } else if self.is_exhausted() {
+            write!(
+                f,
+                "{} (gave up after {} attempts)",
+                self.source, self.attempts
+            )
+        } else {
+            write!(f, "{}", self.source)
+        }
+    }
+}
+
+impl Error for RetryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Run an async operation, retrying it with backoff while it fails with retryable errors
+///
+/// The operation is a closure producing a new future for every attempt, e.g.
+/// `retry(&policy, || sqlx::query("...").bind(id).execute(pool))`. Its metrics are
+/// recorded as operation `query`; use [`retry_as`] to name it.
+///
+/// Args:
+///   policy: Backoff settings
+///   operation: Closure starting one attempt of the operation
+///
+/// Returns:
+///   A Result containing the operation's value and attempt counts
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, operation: F) -> Result<Retried<T>, RetryError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    retry_as(policy, "query", operation).await
+}
+
+/// Same as [`retry`], recording metrics under the given operation name (e.g. `insert_user`)
+pub async fn retry_as<T, F, Fut>(
+    policy: &RetryPolicy,
+    name: &'static str,
+    mut operation: F,
+) -> Result<Retried<T>, RetryError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempts = Attempts::new(policy, name);
+
+    loop {
+        let span = attempts.start();
+        match operation().instrument(span).await {
+            Ok(value) => return Ok(attempts.succeeded(value)),
+            Err(err) => attempts.failed(err).await?,
+        }
+    }
+}
+
+/// Same as [`retry_as`], for a write that is not idempotent, such as an insert or delete
+///
+/// Each attempt gets a connection from `pool`, and failing to get one is retried like any
+/// connection error. Once the statement has been sent, though, losing the connection
+/// leaves it unknown whether the write committed, and running it again could apply it
+/// twice. Such an error is returned at once with [`RetryError::ambiguous`] set. OCC
+/// conflicts are still retried, as the conflicting attempt was rolled back.
+pub async fn retry_write_as<T, F, Fut>(
+    pool: &PgPool,
+    policy: &RetryPolicy,
+    name: &'static str,
+    mut operation: F,
+) -> Result<Retried<T>, RetryError>
+where
+    F: FnMut(PoolConnection<Postgres>) -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempts = Attempts::new(policy, name);
+
+    loop {
+        let span = attempts.start();
+        let conn = match connection::acquire(pool).instrument(span.clone()).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                attempts.failed(err).await?;
+                continue;
+            }
+        };
+        match operation(conn).instrument(span).await {
+            Ok(value) => return Ok(attempts.succeeded(value)),
+            Err(err) if classify(&err) == ErrorClass::Connection => {
+                return Err(attempts.give_up_ambiguous(err));
+            }
+            Err(err) => attempts.failed(err).await?,
+        }
+    }
+}
+
+/// Boxed future returned by the closure passed to [`retry_transaction`]
+pub type TransactionFuture<'c, T> =
+    Pin<Box<dyn Future<Output = Result<T, sqlx::Error>> + Send + 'c>>;
+
+/// Run a closure inside a transaction, re-running the whole transaction on retryable errors
+///
+/// Under DSQL's optimistic concurrency control conflicts only surface at `COMMIT`, so the
+/// unit of retry has to be the full transaction rather than individual statements.
+/// Losing the connection while `COMMIT` is in flight is the exception: the transaction
+/// may have committed, so it is not re-run and the error has [`RetryError::ambiguous`]
+/// set.
+///
+/// The closure is called once per attempt with the transaction's connection; values it
+/// needs should be owned (cloned into the closure) so each attempt can reuse them:
+///
+/// ```ignore
+/// retry_transaction(pool, &policy, move |conn| {
+///     let name = name.clone();
+///     Box::pin(async move {
+///         sqlx::query("UPDATE users SET name = $1 WHERE id = $2")
+///             .bind(name)
+///             .bind(id)
+///             .execute(conn)
+///             .await
+///     })
+/// })
+/// ```
+pub async fn retry_transaction<T, F>(
+    pool: &PgPool,
+    policy: &RetryPolicy,
+    mut operation: F,
+) -> Result<Retried<T>, RetryError>
+where
+    F: for<'c> FnMut(&'c mut PgConnection) -> TransactionFuture<'c, T>,
+{
+    let mut attempts = Attempts::new(policy, "transaction");
+
+    loop {
+        let span = attempts.start();
+        match run_transaction(pool, &mut operation).instrument(span).await {
+            Ok(value) => return Ok(attempts.succeeded(value)),
+            Err(TransactionFailure::Committing(err))
+                if classify(&err) == ErrorClass::Connection =>
+            {
+                return Err(attempts.give_up_ambiguous(err));
+            }
+            Err(TransactionFailure::Running(err) | TransactionFailure::Committing(err)) => {
+                attempts.failed(err).await?
+            }
+        }
+    }
+}
+
+/// How an attempt of a transaction failed
+enum TransactionFailure {
+    /// Before `COMMIT` was sent, so the transaction did not commit
+    Running(sqlx::Error),
+    /// While committing
+    Committing(sqlx::Error),
+}
+
+/// Run one attempt of a transaction closure: begin, run, commit
+async fn run_transaction<T, F>(pool: &PgPool, operation: &mut F) -> Result<T, TransactionFailure>
+where
+    F: for<'c> FnMut(&'c mut PgConnection) -> TransactionFuture<'c, T>,
+{
+    let mut conn = connection::acquire(pool)
+        .await
+        .map_err(TransactionFailure::Running)?;
+    let mut tx = conn.begin().await.map_err(TransactionFailure::Running)?;
+    // Dropping the transaction on error rolls it back
+    let value = operation(&mut tx)
+        .await
+        .map_err(TransactionFailure::Running)?;
+    tx.commit().await.map_err(TransactionFailure::Committing)?;
+    Ok(value)
+}
+
+/// Attempt bookkeeping shared by the retry executors
+///
+/// Each attempt runs in an `attempt` span that records the attempt number, its latency
+/// and, when it fails, the SQLSTATE. Attempts, retries, conflicts and the operation's
+/// outcome are also counted in the [metrics](crate::metrics) under the operation's name.
+pub(crate) struct Attempts<'a> {
+    policy: &'a RetryPolicy,
+    operation: &'static str,
+    attempt: u32,
+    conflicts: u32,
+    span: Span,
+    began: Instant,
+    started: Instant,
+}
+
+impl<'a> Attempts<'a> {
+    pub(crate) fn new(policy: &'a RetryPolicy, operation: &'static str) -> Self {
+        let now = Instant::now();
+        Self {
+            policy,
+            operation,
+            attempt: 0,
+            conflicts: 0,
+            span: Span::none(),
+            began: now,
+            started: now,
+        }
+    }
+
+    /// Start the next attempt, returning the span to run it in
+    pub(crate) fn start(&mut self) -> Span {
+        self.attempt += 1;
+        self.started = Instant::now();
+        self.span = debug_span!(
+            "attempt",
+            attempt = self.attempt,
+            latency_ms = Empty,
+            sqlstate = Empty
+        );
+        self.span.clone()
+    }
+
+    /// Record the attempt's latency
+    fn finish(&self) {
+        let latency = self.started.elapsed();
+        self.span
+            .record("latency_ms", latency.as_secs_f64() * 1000.0);
+        metrics::observe_attempt(self.operation, latency);
+    }
+
+    /// Record a failed attempt's latency and SQLSTATE; `retried` is whether it will be
+    /// tried again
+    fn finish_failed(&self, err: &sqlx::Error, class: ErrorClass, retried: bool) {
+        let sqlstate = err.as_database_error().and_then(|db_err| db_err.code());
+        if let Some(code) = &sqlstate {
+            self.span.record("sqlstate", code.as_ref());
+        }
+        self.finish();
+        metrics::observe_failure(self.operation, class, sqlstate.as_deref(), retried);
+    }
+
+    pub(crate) fn succeeded<T>(&self, value: T) -> Retried<T> {
+        self.finish();
+        metrics::observe_operation(self.operation, None, self.began.elapsed());
+        if self.attempt > 1 {
+            self.span.in_scope(|| {
+                debug!(
+                    attempts = self.attempt,
+                    conflicts = self.conflicts,
+                    "succeeded after retrying"
+                )
+            });
+        }
+
+        Retried {
+            value,
+            attempts: self.attempt,
+            conflicts: self.conflicts,
+        }
+    }
+
+    /// Record a failed attempt, then either back off or give up
+    pub(crate) async fn failed(&mut self, err: sqlx::Error) -> Result<(), RetryError> {
+        let max_attempts = self.policy.max_attempts.max(1);
+        let class = classify(&err);
+        if !class.is_retryable() || self.attempt >= max_attempts {
+            return Err(self.give_up(err));
+        }
+        if class == ErrorClass::Conflict {
+            self.conflicts += 1;
+        }
+
+        self.finish_failed(&err, class, true);
+        let delay = self.policy.backoff(self.attempt);
+        self.span.in_scope(|| {
+            warn!(
+                max_attempts,
+                ?class,
+                backoff_ms = delay.as_secs_f64() * 1000.0,
+                "retryable error, retrying: {}",
+                err
+            )
+        });
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+
+    /// Record a failed attempt that must not be retried, e.g. because part of its result
+    /// was already consumed
+    pub(crate) fn give_up(&mut self, err: sqlx::Error) -> RetryError {
+        let class = classify(&err);
+        if class == ErrorClass::Conflict {
+            self.conflicts += 1;
+        }
+
+        self.finish_failed(&err, class, false);
+        metrics::observe_operation(self.operation, Some(class), self.began.elapsed());
+        self.span.in_scope(|| debug!(?class, "giving up: {}", err));
+
+        RetryError {
+            source: err,
+            class,
+            attempts: self.attempt,
+            conflicts: self.conflicts,
+            ambiguous: false,
+        }
+    }
+
+    /// Give up after the connection was lost while a write was in flight, which may or
+    /// may not have been applied
+    pub(crate) fn give_up_ambiguous(&mut self, err: sqlx::Error) -> RetryError {
+        RetryError {
+            ambiguous: true,
+            ..self.give_up(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::test_database;
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use sqlx::postgres::PgPoolOptions;
+    use sqlx::types::uuid::Uuid;
+    use std::borrow::Cow;
+    use std::io;
+
+    /// A database error with nothing but a SQLSTATE
+    #[derive(Debug)]
+    struct Sqlstate(&'static str);
+
+    impl fmt::Display for Sqlstate {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "error {}", self.0)
+        }
+    }
+
+    impl Error for Sqlstate {}
+
+    impl DatabaseError for Sqlstate {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn database_error(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(Sqlstate(code)))
+    }
+
+    fn policy(jitter: bool) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn conflicts() {
+        for code in ["40001", "40P01", "OC000", "OC001"] {
+            assert_eq!(
+                classify(&database_error(code)),
+                ErrorClass::Conflict,
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn connection_errors() {
+        for code in ["08000", "08003", "08006", "57P01"] {
+            assert_eq!(
+                classify(&database_error(code)),
+                ErrorClass::Connection,
+                "{}",
+                code
+            );
+        }
+        let reset = sqlx::Error::Io(io::ErrorKind::ConnectionReset.into());
+        assert_eq!(classify(&reset), ErrorClass::Connection);
+        assert_eq!(classify(&sqlx::Error::PoolTimedOut), ErrorClass::Connection);
+    }
+
+    #[test]
+    fn fatal_errors() {
+        for code in ["23505", "42601", "42P01", "57014"] {
+            assert_eq!(
+                classify(&database_error(code)),
+                ErrorClass::Fatal,
+                "{}",
+                code
+            );
+        }
+        assert_eq!(classify(&sqlx::Error::RowNotFound), ErrorClass::Fatal);
+        assert!(!ErrorClass::Fatal.is_retryable());
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_cap() {
+        let policy = policy(false);
+        let delays: Vec<u64> = (1..=6)
+            .map(|attempt| policy.backoff(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(policy.backoff(u32::MAX), policy.max_backoff);
+    }
+
+    #[test]
+    fn jitter_stays_below_the_ceiling() {
+        let policy = policy(true);
+        for attempt in 1..=6 {
+            let ceiling = RetryPolicy {
+                jitter: false,
+                ..policy.clone()
+            }
+            .backoff(attempt);
+            for _ in 0..100 {
+                assert!(policy.backoff(attempt) <= ceiling);
+            }
+        }
+    }
+
+    /// Retries quickly, for tests that run out of attempts
+    fn fast_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            multiplier: 1.0,
+            jitter: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_are_not_retried_after_losing_the_connection() {
+        let Some((_, pool)) = test_database().await else {
+            return;
+        };
+
+        // The statement went through, then the connection drops before the reply is read
+        let mut runs = 0;
+        let err = retry_write_as(&pool, &fast_policy(5), "test", |mut conn| {
+            runs += 1;
+            async move {
+                sqlx::query("SELECT 1").execute(&mut *conn).await?;
+                Err::<(), _>(sqlx::Error::Io(io::ErrorKind::ConnectionReset.into()))
+            }
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(runs, 1);
+        assert_eq!((err.class, err.attempts), (ErrorClass::Connection, 1));
+        assert!(err.ambiguous);
+        assert!(!err.is_exhausted());
+        assert!(err.to_string().contains("may or may not have been applied"));
+    }
+
+    #[tokio::test]
+    async fn writes_are_retried_after_conflicts() {
+        let Some((_, pool)) = test_database().await else {
+            return;
+        };
+
+        let mut runs = 0;
+        let retried = retry_write_as(&pool, &fast_policy(5), "test", |mut conn| {
+            runs += 1;
+            let statement = if runs < 3 {
+                "DO $$ BEGIN RAISE EXCEPTION 'conflict' USING ERRCODE = '40001'; END $$"
+            } else {
+                "SELECT 1"
+            };
+            async move { sqlx::query(statement).execute(&mut *conn).await }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!((retried.attempts, retried.conflicts), (3, 2));
+    }
+
+    #[tokio::test]
+    async fn writes_are_retried_while_no_connection_can_be_had() {
+        // Needs no database: nothing listens on port 1
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/postgres")
+            .unwrap();
+
+        let mut runs = 0;
+        let err = retry_write_as(&pool, &fast_policy(2), "test", |mut conn| {
+            runs += 1;
+            async move { sqlx::query("SELECT 1").execute(&mut *conn).await }
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(runs, 0);
+        assert_eq!(err.attempts, 2);
+        assert!(!err.ambiguous);
+        assert!(err.is_exhausted());
+    }
+
+    #[tokio::test]
+    async fn a_lost_connection_at_commit_is_not_retried() {
+        let Some((_, pool)) = test_database().await else {
+            return;
+        };
+        // A deferred trigger kills the backend while it commits
+        let table = format!("marks_{}", Uuid::new_v4().simple());
+        for statement in [
+            format!("CREATE TABLE {} (id INT)", table),
+            format!(
+                "CREATE FUNCTION {}_die() RETURNS trigger LANGUAGE plpgsql AS $$ \
+                 BEGIN PERFORM pg_terminate_backend(pg_backend_pid()); RETURN NULL; END $$",
+                table
+            ),
+            format!(
+                "CREATE CONSTRAINT TRIGGER die AFTER INSERT ON {0} \
+                 DEFERRABLE INITIALLY DEFERRED FOR EACH ROW EXECUTE FUNCTION {0}_die()",
+                table
+            ),
+        ] {
+            sqlx::query(&statement).execute(&pool).await.unwrap();
+        }
+
+        let mut runs = 0;
+        let insert = format!("INSERT INTO {} VALUES (1)", table);
+        let result = retry_transaction(&pool, &fast_policy(5), |conn| {
+            runs += 1;
+            let insert = insert.clone();
+            Box::pin(async move { sqlx::query(&insert).execute(conn).await })
+        })
+        .await;
+
+        for statement in [
+            format!("DROP TABLE {}", table),
+            format!("DROP FUNCTION {}_die()", table),
+        ] {
+            sqlx::query(&statement).execute(&pool).await.unwrap();
+        }
+
+        let err = result.unwrap_err();
+        assert_eq!(runs, 1);
+        assert_eq!((err.class, err.attempts), (ErrorClass::Connection, 1));
+        assert!(err.ambiguous);
+    }
+}
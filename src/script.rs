@@ -0,0 +1,412 @@
+use crate::connection;
+use crate::query::{self, ResultSet};
+use crate::retry::{retry, retry_transaction, retry_write_as, RetryError, RetryPolicy};
+use crate::sql::{self, StatementKind};
+use sqlx::postgres::PgPool;
+use std::error::Error;
+use std::fmt;
+use tracing::instrument;
+
+/// Longest statement excerpt quoted in error messages
+const EXCERPT_LENGTH: usize = 60;
+
+/// A unit of work in a script
+///
+/// `number` is the position of the step's first statement in the script, counting from 1
+/// (for a transaction block, the position of its `BEGIN`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// A statement outside any transaction block, committed on its own
+    Statement { number: usize, sql: String },
+    /// The statements between `BEGIN` and `COMMIT`, committed together
+    Transaction {
+        number: usize,
+        statements: Vec<String>,
+    },
+}
+
+impl Step {
+    /// The statements this step runs, without the transaction control around them
+    pub fn statements(&self) -> &[String] {
+        match self {
+            Step::Statement { sql, .. } => std::slice::from_ref(sql),
+            Step::Transaction { statements, .. } => statements,
+        }
+    }
+}
+
+/// A SQL script split into steps and checked against Aurora DSQL's transaction rules
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script {
+    pub steps: Vec<Step>,
+}
+
+impl Script {
+    /// Split `sql` into steps
+    ///
+    /// Statements outside `BEGIN ... COMMIT` run on their own, so any number of them may
+    /// be DDL. A transaction block may contain either one DDL statement or only DML and
+    /// queries, as DSQL requires; it must end with `COMMIT` (or `END`), and `ROLLBACK` and
+    /// savepoints are not supported since blocks are re-run as a whole after OCC conflicts.
+    pub fn parse(sql: &str) -> Result<Self, ScriptError> {
+        let mut steps = Vec::new();
+        let mut block: Option<(usize, Vec<String>)> = None;
+
+        for (i, statement) in sql::split_statements(sql).into_iter().enumerate() {
+            let number = i + 1;
+            let invalid = |reason: &str| ScriptError::Invalid {
+                statement: number,
+                excerpt: excerpt(&statement),
+                reason: reason.to_string(),
+            };
+
+            if StatementKind::of(&statement) != StatementKind::Transaction {
+                match &mut block {
+                    Some((_, statements)) => statements.push(statement),
+                    None => steps.push(Step::Statement {
+                        number,
+                        sql: statement,
+                    }),
+                }
+                continue;
+            }
+
+            let keyword = sql::keywords(&statement)
+                .into_iter()
+                .next()
+                .unwrap_or_default();
+            match (keyword.as_str(), block.take()) {
+                ("BEGIN" | "START", None) => block = Some((number, Vec::new())),
+                ("BEGIN" | "START", Some(_)) => {
+                    return Err(invalid("a transaction is already open"));
+                }
+                ("COMMIT" | "END", Some((begin, statements))) => {
+                    check_transaction(begin, &statements)?;
+                    steps.push(Step::Transaction {
+                        number: begin,
+                        statements,
+                    });
+                }
+                ("COMMIT" | "END", None) => return Err(invalid("no transaction is open")),
+                _ => {
+                    return Err(invalid(
+                        "ROLLBACK and savepoints are not supported; transaction blocks must end with COMMIT",
+                    ));
+                }
+            }
+        }
+
+        if let Some((begin, _)) = block {
+            return Err(ScriptError::UnterminatedTransaction { statement: begin });
+        }
+
+        Ok(Script { steps })
+    }
+
+    /// Total number of statements, excluding transaction control
+    pub fn statement_count(&self) -> usize {
+        self.steps.iter().map(|step| step.statements().len()).sum()
+    }
+}
+
+/// Check a transaction block against DSQL's one-DDL-per-transaction rule
+fn check_transaction(begin: usize, statements: &[String]) -> Result<(), ScriptError> {
+    let ddl_count = statements
+        .iter()
+        .filter(|s| StatementKind::of(s) == StatementKind::Ddl)
+        .count();
+
+    let reason = if ddl_count > 1 {
+        format!(
+            "contains {} DDL statements, but Aurora DSQL allows only one DDL statement per transaction",
+            ddl_count
+        )
+    } else if ddl_count == 1 && statements.len() > 1 {
+        "mixes DDL with other statements, which Aurora DSQL does not allow in one transaction"
+            .to_string()
+    } else {
+        return Ok(());
+    };
+
+    Err(ScriptError::InvalidTransaction {
+        statement: begin,
+        reason,
+    })
+}
+
+/// What running one statement produced
+#[derive(Debug, Clone)]
+pub struct StatementOutcome {
+    pub statement: String,
+    pub result: ResultSet,
+    /// Attempts made; for a transaction block, attempts of the whole block
+    pub attempts: u32,
+}
+
+/// Run one step, retrying it on OCC conflicts and lost connections
+///
+/// A statement is retried on its own; a transaction block is rolled back and re-run
+/// from the start, as conflicts only surface at `COMMIT`. Only queries are retried after
+/// losing the connection mid-statement: any other statement may already have been
+/// applied, so its error is returned with [`RetryError::ambiguous`] set instead.
+#[instrument(level = "debug", skip_all, fields(statements = step.statements().len()))]
+pub async fn run_step(
+    pool: &PgPool,
+    policy: &RetryPolicy,
+    step: &Step,
+) -> Result<Vec<StatementOutcome>, ScriptError> {
+    let retried = match step {
+        Step::Statement { number, sql } => match StatementKind::of(sql) {
+            StatementKind::Query => {
+                retry(policy, || async {
+                    let mut conn = connection::acquire(pool).await?;
+                    Ok(vec![query::execute(&mut conn, sql).await?])
+                })
+                .await
+            }
+            _ => {
+                retry_write_as(pool, policy, "query", |mut conn| async move {
+                    Ok(vec![query::execute(&mut conn, sql).await?])
+                })
+                .await
+            }
+        }
+        .map_err(|source| ScriptError::Failed {
+            statement: *number,
+            excerpt: excerpt(sql),
+            source,
+        })?,
+        Step::Transaction { number, statements } => {
+            let statements = statements.clone();
+            retry_transaction(pool, policy, move |conn| {
+                let statements = statements.clone();
+                Box::pin(async move {
+                    let mut results = Vec::with_capacity(statements.len());
+                    for statement in &statements {
+                        results.push(query::execute(&mut *conn, statement).await?);
+                    }
+                    Ok(results)
+                })
+            })
+            .await
+            .map_err(|source| ScriptError::TransactionFailed {
+                statement: *number,
+                source,
+            })?
+        }
+    };
+
+    Ok(step
+        .statements()
+        .iter()
+        .zip(retried.value)
+        .map(|(statement, result)| StatementOutcome {
+            statement: statement.clone(),
+            result,
+            attempts: retried.attempts,
+        })
+        .collect())
+}
+
+/// First line of code in a statement, shortened for error messages
+fn excerpt(statement: &str) -> String {
+    let mut lines = statement.lines().skip_while(|line| !sql::has_code(line));
+    let line = lines.next().unwrap_or_default();
+    if line.chars().count() > EXCERPT_LENGTH || lines.next().is_some() {
+        let short: String = line.chars().take(EXCERPT_LENGTH).collect();
+        format!("{}...", short.trim_end())
+    } else {
+        line.to_string()
+    }
+}
+
+/// Error from checking or running a script
+#[derive(Debug)]
+pub enum ScriptError {
+    /// A statement cannot be used where it appears (statements are numbered from 1)
+    Invalid {
+        statement: usize,
+        excerpt: String,
+        reason: String,
+    },
+    /// The transaction block starting at `statement` breaks DSQL's transaction rules
+    InvalidTransaction { statement: usize, reason: String },
+    /// The transaction started at `statement` is never committed
+    UnterminatedTransaction { statement: usize },
+    /// A statement outside a transaction block failed, even after retrying; earlier
+    /// steps stay committed
+    Failed {
+        statement: usize,
+        excerpt: String,
+        source: RetryError,
+    },
+    /// The transaction block starting at `statement` failed and was rolled back, even
+    /// after retrying; earlier steps stay committed
+    TransactionFailed {
+        statement: usize,
+        source: RetryError,
+    },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Invalid {
+                statement,
+                excerpt,
+                reason,
+            } => write!(f, "statement {} ({}): {}", statement, excerpt, reason),
+            ScriptError::InvalidTransaction { statement, reason } => write!(
+                f,
+                "transaction starting at statement {} {}; split it into separate transactions",
+                statement, reason
+            ),
+            ScriptError::UnterminatedTransaction { statement } => write!(
+                f,
+                "transaction started at statement {} is never committed; add COMMIT",
+                statement
+            ),
+            ScriptError::Failed {
+                statement,
+                excerpt,
+                source,
+            } => write!(
+                f,
+                "statement {} ({}) failed: {}",
+                statement, excerpt, source
+            ),
+            ScriptError::TransactionFailed { statement, source } => write!(
+                f,
+                "transaction starting at statement {} failed and was rolled back: {}",
+                statement, source
+            ),
+        }
+    }
+}
+
+impl Error for ScriptError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScriptError::Failed { source, .. } | ScriptError::TransactionFailed { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statement(number: usize, sql: &str) -> Step {
+        Step::Statement {
+            number,
+            sql: sql.to_string(),
+        }
+    }
+
+    #[test]
+    fn statements_outside_blocks_run_on_their_own() {
+        let script = Script::parse(
+            "CREATE TABLE a (id int); CREATE TABLE b (id int); INSERT INTO a VALUES (1);",
+        )
+        .unwrap();
+        assert_eq!(
+            script.steps,
+            [
+                statement(1, "CREATE TABLE a (id int)"),
+                statement(2, "CREATE TABLE b (id int)"),
+                statement(3, "INSERT INTO a VALUES (1)"),
+            ]
+        );
+        assert_eq!(script.statement_count(), 3);
+    }
+
+    #[test]
+    fn blocks_become_transactions() {
+        let script = Script::parse(
+            "SELECT 1;\nBEGIN;\nINSERT INTO a VALUES (1);\nUPDATE a SET id = 2;\nCOMMIT;\nstart transaction; CREATE INDEX ASYNC i ON a (id); end;",
+        )
+        .unwrap();
+        assert_eq!(
+            script.steps,
+            [
+                statement(1, "SELECT 1"),
+                Step::Transaction {
+                    number: 2,
+                    statements: vec![
+                        "INSERT INTO a VALUES (1)".to_string(),
+                        "UPDATE a SET id = 2".to_string(),
+                    ],
+                },
+                Step::Transaction {
+                    number: 6,
+                    statements: vec!["CREATE INDEX ASYNC i ON a (id)".to_string()],
+                },
+            ]
+        );
+        assert_eq!(script.statement_count(), 4);
+    }
+
+    #[test]
+    fn quoted_transaction_keywords_are_not_control_statements() {
+        let script =
+            Script::parse("BEGIN; INSERT INTO log VALUES ('COMMIT;'), ($$ROLLBACK;$$); COMMIT;")
+                .unwrap();
+        assert_eq!(script.steps.len(), 1);
+        assert_eq!(script.statement_count(), 1);
+    }
+
+    #[test]
+    fn blocks_follow_dsql_ddl_rules() {
+        for (sql, reason) in [
+            (
+                "BEGIN; CREATE TABLE a (id int); CREATE TABLE b (id int); COMMIT;",
+                "2 DDL statements",
+            ),
+            (
+                "BEGIN; CREATE TABLE a (id int); INSERT INTO a VALUES (1); COMMIT;",
+                "mixes DDL",
+            ),
+        ] {
+            match Script::parse(sql) {
+                Err(ScriptError::InvalidTransaction {
+                    statement,
+                    reason: found,
+                }) => {
+                    assert_eq!(statement, 1);
+                    assert!(found.contains(reason), "{}", found);
+                }
+                other => panic!("expected an invalid transaction, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unbalanced_and_unsupported_control() {
+        assert!(matches!(
+            Script::parse("SELECT 1; BEGIN; INSERT INTO a VALUES (1);"),
+            Err(ScriptError::UnterminatedTransaction { statement: 2 })
+        ));
+
+        for (sql, number, reason) in [
+            ("COMMIT;", 1, "no transaction is open"),
+            ("BEGIN; BEGIN;", 2, "already open"),
+            ("BEGIN; INSERT INTO a VALUES (1); ROLLBACK;", 3, "ROLLBACK"),
+            ("BEGIN; SAVEPOINT s; COMMIT;", 2, "savepoints"),
+        ] {
+            match Script::parse(sql) {
+                Err(ScriptError::Invalid {
+                    statement,
+                    reason: found,
+                    ..
+                }) => {
+                    assert_eq!(statement, number, "{}", sql);
+                    assert!(found.contains(reason), "{}", found);
+                }
+                other => panic!("expected an invalid statement, got {:?}", other),
+            }
+        }
+    }
+}
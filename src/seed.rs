@@ -0,0 +1,158 @@
+use crate::migrate;
+use crate::retry::{retry, RetryError, RetryPolicy};
+use crate::users::{RepositoryError, User, UserRepository};
+use sqlx::postgres::PgPool;
+use sqlx::types::uuid::Uuid;
+use std::error::Error;
+use std::fmt;
+
+/// Sample users written by [`seed_sample_users`]: (name, email, role)
+pub const SAMPLE_USERS: [(&str, &str, &str); 5] = [
+    ("John Doe", "john.doe@example.com", "Admin"),
+    ("Jane Smith", "jane.smith@example.com", "User"),
+    ("Bob Johnson", "bob.johnson@example.com", "User"),
+    ("Alice Williams", "alice.williams@example.com", "Manager"),
+    ("Charlie Brown", "charlie.brown@example.com", "User"),
+];
+
+/// Rows deleted per transaction by [`truncate_users`]
+///
+/// Aurora DSQL limits how many rows a single transaction may modify (3,000), so rows are
+/// deleted in batches well below that.
+pub const DEFAULT_DELETE_BATCH_SIZE: i64 = 1000;
+
+/// Result of seeding one sample user
+#[derive(Debug)]
+pub struct SeededUser {
+    pub user: User,
+    /// True if the user was inserted, false if an existing user was updated
+    pub created: bool,
+}
+
+/// Errors from seeding or clearing the users table
+#[derive(Debug)]
+pub enum SeedError {
+    /// A repository operation failed
+    Repository(RepositoryError),
+    /// A migration could not be applied
+    Migration(migrate::MigrationError),
+    /// A database operation failed
+    Database(RetryError),
+}
+
+impl fmt::Display for SeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeedError::Repository(err) => write!(f, "{}", err),
+            SeedError::Migration(err) => write!(f, "{}", err),
+            SeedError::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for SeedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SeedError::Repository(err) => Some(err),
+            SeedError::Migration(err) => Some(err),
+            SeedError::Database(err) => Some(err),
+        }
+    }
+}
+
+impl From<RepositoryError> for SeedError {
+    fn from(err: RepositoryError) -> Self {
+        SeedError::Repository(err)
+    }
+}
+
+impl From<migrate::MigrationError> for SeedError {
+    fn from(err: migrate::MigrationError) -> Self {
+        SeedError::Migration(err)
+    }
+}
+
+impl From<RetryError> for SeedError {
+    fn from(err: RetryError) -> Self {
+        SeedError::Database(err)
+    }
+}
+
+/// Upsert the sample users
+///
+/// Users are matched by email, so running this repeatedly never creates duplicates and
+/// never touches other rows.
+pub async fn seed_sample_users(repo: &UserRepository) -> Result<Vec<SeededUser>, SeedError> {
+    let mut seeded = Vec::with_capacity(SAMPLE_USERS.len());
+
+    for (name, email, role) in SAMPLE_USERS {
+        let user_id = Uuid::new_v4();
+        let user = repo.upsert(user_id, name, email, role).await?;
+        seeded.push(SeededUser {
+            created: user.id == user_id,
+            user,
+        });
+    }
+
+    Ok(seeded)
+}
+
+/// Delete every row of the users table, keeping the table itself
+///
+/// Rows are deleted in batches of `batch_size`, each in its own transaction, so large
+/// tables stay within DSQL's per-transaction row limit and a conflict only retries one
+/// batch.
+///
+/// Args:
+///   pool: Connection pool
+///   policy: Retry policy for each batch
+///   batch_size: Maximum rows deleted per transaction
+///   on_batch: Called with the running total after each batch
+///
+/// Returns:
+///   The number of rows deleted
+pub async fn truncate_users(
+    pool: &PgPool,
+    policy: &RetryPolicy,
+    batch_size: i64,
+    mut on_batch: impl FnMut(u64),
+) -> Result<u64, SeedError> {
+    let batch_size = batch_size.max(1);
+    let mut deleted = 0;
+
+    loop {
+        let result = retry(policy, || {
+            sqlx::query("DELETE FROM users WHERE id IN (SELECT id FROM users LIMIT $1)")
+                .bind(batch_size)
+                .execute(pool)
+        })
+        .await?;
+
+        let rows = result.value.rows_affected();
+        if rows == 0 {
+            return Ok(deleted);
+        }
+        deleted += rows;
+        on_batch(deleted);
+    }
+}
+
+/// Drop the users table and recreate it, empty, from its migration
+///
+/// This is irreversible; callers are responsible for confirming it with the user.
+pub async fn drop_and_recreate_users(pool: &PgPool, policy: &RetryPolicy) -> Result<(), SeedError> {
+    let create_users = migrate::find("create_users")
+        .expect("create_users migration is embedded")
+        .validate()?;
+
+    // DROP and CREATE are both DDL, so each runs in its own transaction
+    retry(policy, || {
+        sqlx::query("DROP TABLE IF EXISTS users").execute(pool)
+    })
+    .await?;
+    for statement in &create_users.statements {
+        retry(policy, || sqlx::query(statement).execute(pool)).await?;
+    }
+
+    Ok(())
+}
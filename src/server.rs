@@ -0,0 +1,334 @@
+use crate::doctor;
+use crate::metrics;
+use crate::retry::retry_as;
+use crate::stats::{self, UserStats};
+use crate::users::{
+    NewUser, RepositoryError, User, UserOrder, UserQuery, UserRepository, UserUpdate,
+    MAX_NAME_LENGTH, MAX_ROLE_LENGTH,
+};
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use sqlx::types::{chrono, uuid::Uuid};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tracing::{error, info, info_span, Instrument};
+
+/// Users returned by `GET /users` when the request sets no limit
+pub const DEFAULT_PAGE_LIMIT: i64 = 100;
+
+/// Most users `GET /users` returns per request; larger listings are paged with `after`
+pub const MAX_PAGE_LIMIT: i64 = 1000;
+
+/// Role given to users created without one
+const DEFAULT_ROLE: &str = "User";
+
+/// Seconds clients are asked to wait before retrying after a 503
+const RETRY_AFTER_SECONDS: &str = "1";
+
+/// Routes of the users API
+///
+/// - `GET /users`: list users, filtered and paged like `list-users`
+/// - `POST /users`: create a user (201, or 409 if the email or the given id is taken)
+/// - `GET /users/stats`: the `user-stats` report
+/// - `GET /users/{id}`, `PATCH /users/{id}`, `DELETE /users/{id}`: a single user
+/// - `GET /healthz`: 200 while the process is serving requests
+/// - `GET /readyz`: 200 if the database answers `SELECT 1`, 503 otherwise
+/// - `GET /metrics`: the [metrics](crate::metrics) in the Prometheus text format
+///
+/// Every operation is retried on OCC conflicts by the repository; if it still fails the
+/// response is 503 with a `Retry-After` header. Each request runs in a `request` span and
+/// is logged with its status and latency.
+pub fn router(repo: UserRepository) -> Router {
+    Router::new()
+        .route("/users", get(list_users).post(create_user))
+        .route("/users/stats", get(user_stats))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics::metrics_handler))
+        .route(
+            "/users/:id",
+            get(get_user).patch(update_user).delete(delete_user),
+        )
+        .layer(middleware::from_fn(log_request))
+        .with_state(repo)
+}
+
+/// Serve the users API on `addr` until `shutdown` completes
+///
+/// Requests in flight when `shutdown` completes are allowed to finish.
+pub async fn serve(
+    repo: UserRepository,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, router(repo))
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// Run a request in its own span and log how it went
+async fn log_request(request: Request, next: Next) -> Response {
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path()
+    );
+    let started = Instant::now();
+    let response = next.run(request).instrument(span.clone()).await;
+
+    span.in_scope(|| {
+        info!(
+            status = response.status().as_u16(),
+            latency_ms = started.elapsed().as_secs_f64() * 1000.0,
+            "handled request"
+        )
+    });
+    response
+}
+
+/// Query parameters of `GET /users`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ListParams {
+    role: Option<String>,
+    email_like: Option<String>,
+    created_since: Option<chrono::DateTime<chrono::Utc>>,
+    created_until: Option<chrono::DateTime<chrono::Utc>>,
+    order_by: UserOrder,
+    desc: bool,
+    after: Option<Uuid>,
+    limit: Option<i64>,
+}
+
+/// Response of `GET /users`
+#[derive(Debug, Serialize)]
+struct UserPage {
+    users: Vec<User>,
+    /// Pass as `after` to get the next page; absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_after: Option<Uuid>,
+}
+
+/// Body of `POST /users`
+#[derive(Debug, Deserialize)]
+struct CreateUser {
+    /// Generated when not given
+    id: Option<Uuid>,
+    name: String,
+    email: String,
+    /// `User` when not given
+    role: Option<String>,
+}
+
+/// Body of `PATCH /users/{id}`; absent fields are left unchanged
+#[derive(Debug, Deserialize)]
+struct PatchUser {
+    name: Option<String>,
+    role: Option<String>,
+}
+
+async fn list_users(
+    State(repo): State<UserRepository>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<UserPage>, ApiError> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if !(1..=MAX_PAGE_LIMIT).contains(&limit) {
+        return Err(ApiError::invalid(format!(
+            "limit must be between 1 and {}",
+            MAX_PAGE_LIMIT
+        )));
+    }
+
+    let query = UserQuery {
+        role: params.role,
+        email_like: params.email_like,
+        created_since: params.created_since,
+        created_until: params.created_until,
+        order_by: params.order_by,
+        descending: params.desc,
+        after: params.after,
+        after_key: None,
+        limit: Some(limit),
+    };
+
+    let mut users = Vec::new();
+    let summary = repo
+        .for_each(&query, |user| {
+            users.push(user);
+            Ok::<_, RepositoryError>(())
+        })
+        .await?;
+
+    Ok(Json(UserPage {
+        users,
+        next_after: summary.next_after,
+    }))
+}
+
+async fn create_user(
+    State(repo): State<UserRepository>,
+    Json(body): Json<CreateUser>,
+) -> Result<Response, ApiError> {
+    let user = NewUser {
+        id: body.id.unwrap_or_else(Uuid::new_v4),
+        name: body.name,
+        email: body.email,
+        role: body.role.unwrap_or_else(|| DEFAULT_ROLE.to_string()),
+    };
+    user.validate().map_err(ApiError::invalid)?;
+
+    let created = repo
+        .insert(user.id, &user.name, &user.email, &user.role)
+        .await?;
+    let location = format!("/users/{}", created.id);
+
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(created),
+    )
+        .into_response())
+}
+
+async fn get_user(
+    State(repo): State<UserRepository>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<User>, ApiError> {
+    match repo.get_by_id(id).await? {
+        Some(user) => Ok(Json(user)),
+        None => Err(RepositoryError::NotFound(id).into()),
+    }
+}
+
+async fn update_user(
+    State(repo): State<UserRepository>,
+    Path(id): Path<Uuid>,
+    Json(body): Json<PatchUser>,
+) -> Result<Json<User>, ApiError> {
+    let update = UserUpdate {
+        name: body.name,
+        role: body.role,
+    };
+    if update.is_empty() {
+        return Err(ApiError::invalid("nothing to update; set name and/or role"));
+    }
+
+    let mut problems = Vec::new();
+    for (field, value, max) in [
+        ("name", &update.name, MAX_NAME_LENGTH),
+        ("role", &update.role, MAX_ROLE_LENGTH),
+    ] {
+        match value {
+            Some(value) if value.trim().is_empty() => problems.push(format!("{} is empty", field)),
+            Some(value) if value.chars().count() > max => problems.push(format!(
+                "{} is {} characters long, at most {} are allowed",
+                field,
+                value.chars().count(),
+                max
+            )),
+            _ => {}
+        }
+    }
+    if !problems.is_empty() {
+        return Err(ApiError::invalid(problems.join("; ")));
+    }
+
+    Ok(Json(repo.update(id, &update).await?))
+}
+
+async fn delete_user(
+    State(repo): State<UserRepository>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    repo.delete(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn user_stats(State(repo): State<UserRepository>) -> Result<Json<UserStats>, ApiError> {
+    let stats = retry_as(repo.retry_policy(), "user_stats", || {
+        stats::user_statistics(repo.pool())
+    })
+    .await
+    .map_err(RepositoryError::from)?;
+
+    Ok(Json(stats.value))
+}
+
+async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Ready once a pooled connection answers a query, so load balancers hold traffic back
+/// while the cluster is unreachable or tokens cannot be minted
+async fn readyz(State(repo): State<UserRepository>) -> Response {
+    let report = doctor::check_pool(repo.pool()).await;
+    let (status, label) = if report.is_healthy() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    let body = Json(serde_json::json!({ "status": label, "checks": report.checks }));
+    (status, body).into_response()
+}
+
+/// An error response: a status code and a `{"error": "..."}` body
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    /// 422 for a request that is well-formed but breaks the users table's rules
+    fn invalid(message: impl Into<String>) -> Self {
+        ApiError {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<RepositoryError> for ApiError {
+    fn from(err: RepositoryError) -> Self {
+        let status = match &err {
+            RepositoryError::DuplicateEmail(_) | RepositoryError::DuplicateId(_) => {
+                StatusCode::CONFLICT
+            }
+            RepositoryError::NotFound(_) => StatusCode::NOT_FOUND,
+            // Still conflicting or unreachable after every retry: worth trying again later
+            RepositoryError::Database(err) if err.is_exhausted() => StatusCode::SERVICE_UNAVAILABLE,
+            RepositoryError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        if status.is_server_error() {
+            error!("request failed: {}", err);
+        }
+
+        ApiError {
+            status,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(serde_json::json!({ "error": self.message }));
+        if self.status == StatusCode::SERVICE_UNAVAILABLE {
+            (
+                self.status,
+                [(header::RETRY_AFTER, RETRY_AFTER_SECONDS)],
+                body,
+            )
+                .into_response()
+        } else {
+            (self.status, body).into_response()
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use crate::output::OutputFormat;
+use crate::query::{self, ResultSet};
+use crate::retry::{classify, ErrorClass};
+use crate::sql;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgPool, Postgres};
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Help for the meta commands, shown by `\?`
+const HELP: &str = "\
+Statements end with a semicolon and may span several lines.
+
+  \\dt              list tables
+  \\d [NAME]        describe table NAME (or list tables)
+  \\timing [on|off] toggle reporting how long each statement took
+  \\?               show this help
+  \\q               quit (Ctrl-D also works; Ctrl-C clears the current statement)";
+
+/// Location of the shell history: `~/.rust_dsql_history`
+pub fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(|home| PathBuf::from(home).join(".rust_dsql_history"))
+}
+
+/// Settings for an interactive session
+#[derive(Debug, Clone)]
+pub struct ShellOptions {
+    /// Shown at the start of the prompt, usually the database name
+    pub prompt: String,
+    /// File to load and save line history from; `None` keeps history in memory only
+    pub history: Option<PathBuf>,
+    /// How result rows are printed
+    pub format: OutputFormat,
+    /// Whether to report each statement's duration
+    pub timing: bool,
+}
+
+/// Run an interactive SQL session on `pool` until the user quits
+///
+/// Statements outside a transaction each borrow a pooled connection, so the pool's
+/// token refresh keeps the session usable for as long as it stays open. After `BEGIN`
+/// the session holds on to one connection until the transaction ends.
+///
+/// Reading input blocks, so this must run on a multi-threaded Tokio runtime.
+pub async fn run(pool: &PgPool, options: ShellOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut editor = DefaultEditor::new()?;
+    if let Some(path) = &options.history {
+        // A missing history file just means this is the first session
+        let _ = editor.load_history(path);
+    }
+
+    let mut session = Session {
+        pool: pool.clone(),
+        transaction: None,
+        format: options.format,
+        timing: options.timing,
+    };
+    let mut buffer = String::new();
+
+    eprintln!("Type \\? for help, \\q to quit.");
+
+    loop {
+        let prompt = format!(
+            "{}{}{}> ",
+            options.prompt,
+            if buffer.is_empty() { "=" } else { "-" },
+            if session.transaction.is_some() {
+                "*"
+            } else {
+                ""
+            }
+        );
+        let line = match tokio::task::block_in_place(|| editor.readline(&prompt)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+
+        // Meta commands are only recognised at the start of a statement
+        if buffer.is_empty() {
+            let trimmed = line.trim();
+            if let Some(command) = trimmed.strip_prefix('\\') {
+                if !session.meta_command(command).await {
+                    break;
+                }
+                continue;
+            }
+            if trimmed == "quit" || trimmed == "exit" {
+                break;
+            }
+        }
+
+        buffer.push_str(&line);
+        buffer.push('\n');
+
+        if !sql::has_code(&buffer) {
+            buffer.clear();
+        } else if sql::is_terminated(&buffer) {
+            for statement in sql::split_statements(&buffer) {
+                if !session.run_statement(&statement).await {
+                    break;
+                }
+            }
+            buffer.clear();
+        }
+    }
+
+    session.finish().await;
+
+    if let Some(path) = &options.history {
+        if let Err(e) = editor.save_history(path) {
+            warn!("could not save history to {}: {}", path.display(), e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Connection and display state of a shell session
+struct Session {
+    pool: PgPool,
+    /// The connection an explicit transaction is running on
+    transaction: Option<PoolConnection<Postgres>>,
+    format: OutputFormat,
+    timing: bool,
+}
+
+impl Session {
+    /// The transaction's connection, or a fresh one from the pool
+    async fn connection(&mut self) -> Result<PoolConnection<Postgres>, sqlx::Error> {
+        match self.transaction.take() {
+            Some(conn) => Ok(conn),
+            None => self.pool.acquire().await,
+        }
+    }
+
+    /// Run one statement and print its result; returns false if it failed
+    async fn run_statement(&mut self, statement: &str) -> bool {
+        let keyword = sql::keywords(statement)
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        let was_in_transaction = self.transaction.is_some();
+
+        let mut conn = match self.connection().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                self.report_error(&e, false, false);
+                return false;
+            }
+        };
+
+        let started = Instant::now();
+        let result = query::execute(&mut conn, statement).await;
+        let elapsed = started.elapsed();
+
+        let in_transaction = match &result {
+            Err(e) if classify(e) == ErrorClass::Connection => false,
+            _ => match keyword.as_str() {
+                "BEGIN" | "START" => was_in_transaction || result.is_ok(),
+                // A failed COMMIT (e.g. an OCC conflict) still ends the transaction
+                "COMMIT" | "END" | "ROLLBACK" | "ABORT" => false,
+                _ => was_in_transaction,
+            },
+        };
+        if in_transaction {
+            self.transaction = Some(conn);
+        }
+
+        match result {
+            Ok(result) => {
+                self.print_result(statement, &result);
+                if self.timing {
+                    println!("Time: {:.3} ms", elapsed.as_secs_f64() * 1000.0);
+                }
+                true
+            }
+            Err(e) => {
+                self.report_error(&e, was_in_transaction, in_transaction);
+                false
+            }
+        }
+    }
+
+    /// Print a statement's rows, or its command tag
+    fn print_result(&self, statement: &str, result: &ResultSet) {
+        if result.returns_rows() {
+            self.print_rows(result);
+        } else {
+            println!("{}", result.command_tag(statement));
+        }
+    }
+
+    fn print_rows(&self, result: &ResultSet) {
+        if let Err(e) = result.write(std::io::stdout().lock(), self.format) {
+            warn!("could not print result: {}", e);
+        }
+    }
+
+    /// Print a failed statement's error, with advice for errors that a retry would fix
+    fn report_error(&self, err: &sqlx::Error, was_in_transaction: bool, in_transaction: bool) {
+        match err.as_database_error() {
+            Some(db_err) => match db_err.code() {
+                Some(code) => eprintln!("ERROR: {} (SQLSTATE {})", db_err.message(), code),
+                None => eprintln!("ERROR: {}", db_err.message()),
+            },
+            None => eprintln!("ERROR: {}", err),
+        }
+
+        match classify(err) {
+            ErrorClass::Conflict if in_transaction => eprintln!(
+                "HINT: Another transaction changed the same data first. \
+                 ROLLBACK, then run the transaction again."
+            ),
+            ErrorClass::Conflict if was_in_transaction => eprintln!(
+                "HINT: Another transaction changed the same data first, so this one was \
+                 rolled back. Run it again from BEGIN."
+            ),
+            ErrorClass::Conflict => eprintln!(
+                "HINT: Another transaction changed the same data first. \
+                 Run the statement again."
+            ),
+            ErrorClass::Connection if was_in_transaction => eprintln!(
+                "HINT: The connection was lost and the open transaction with it. \
+                 The next statement reconnects with a fresh token."
+            ),
+            ErrorClass::Connection => eprintln!(
+                "HINT: The connection was lost. The next statement reconnects with a fresh token."
+            ),
+            ErrorClass::Fatal => {}
+        }
+    }
+
+    /// Handle a backslash command; returns false if the session should end
+    async fn meta_command(&mut self, command: &str) -> bool {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let argument = words.next();
+
+        match (name, argument) {
+            ("q" | "quit", _) => return false,
+            ("?", _) => println!("{}", HELP),
+            ("timing", argument) => {
+                self.timing = match argument {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => !self.timing,
+                };
+                println!("Timing is {}.", if self.timing { "on" } else { "off" });
+            }
+            ("dt", _) | ("d", None) => {
+                let was_in_transaction = self.transaction.is_some();
+                let Some(mut conn) = self.meta_connection().await else {
+                    return true;
+                };
+                let result = query::list_tables(&mut conn).await;
+                self.release(conn, was_in_transaction, &result);
+                match result {
+                    Ok(tables) if tables.rows.is_empty() => println!("Did not find any tables."),
+                    Ok(tables) => self.print_rows(&tables),
+                    Err(e) => self.report_error(&e, false, false),
+                }
+            }
+            ("d", Some(table)) => {
+                let was_in_transaction = self.transaction.is_some();
+                let Some(mut conn) = self.meta_connection().await else {
+                    return true;
+                };
+                let result = query::describe_table(&mut conn, table).await;
+                self.release(conn, was_in_transaction, &result);
+                match result {
+                    Ok(Some((columns, indexes))) => {
+                        println!("Table \"{}\"", table);
+                        self.print_rows(&columns);
+                        if !indexes.rows.is_empty() {
+                            println!("Indexes:");
+                            self.print_rows(&indexes);
+                        }
+                    }
+                    Ok(None) => println!("Did not find any table named \"{}\".", table),
+                    Err(e) => self.report_error(&e, false, false),
+                }
+            }
+            _ => println!("Invalid command \\{}. Try \\? for help.", name),
+        }
+
+        true
+    }
+
+    /// A connection for a catalog query, reporting failure to get one
+    ///
+    /// Inside a transaction this is the transaction's connection, so catalog queries see
+    /// its uncommitted schema changes.
+    async fn meta_connection(&mut self) -> Option<PoolConnection<Postgres>> {
+        match self.connection().await {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                self.report_error(&e, false, false);
+                None
+            }
+        }
+    }
+
+    /// Hand a connection used for a catalog query back to the transaction, if it is
+    /// still usable
+    fn release<T>(
+        &mut self,
+        conn: PoolConnection<Postgres>,
+        was_in_transaction: bool,
+        result: &Result<T, sqlx::Error>,
+    ) {
+        let lost = matches!(result, Err(e) if classify(e) == ErrorClass::Connection);
+        if was_in_transaction && !lost {
+            self.transaction = Some(conn);
+        }
+    }
+
+    /// Roll back a transaction left open when the session ends
+    async fn finish(&mut self) {
+        if let Some(mut conn) = self.transaction.take() {
+            info!("rolling back the open transaction");
+            if let Err(e) = query::execute(&mut conn, "ROLLBACK").await {
+                warn!("ROLLBACK failed: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+/// Broad category of a SQL statement, used to enforce DSQL's transaction rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatementKind {
+    /// Schema changes: CREATE, ALTER, DROP, TRUNCATE, COMMENT, GRANT, REVOKE
+    Ddl,
+    /// Data changes: INSERT, UPDATE, DELETE, MERGE, COPY
+    Dml,
+    /// Read-only statements: SELECT, WITH, VALUES, SHOW, EXPLAIN, TABLE
+    Query,
+    /// Transaction control: BEGIN, START, COMMIT, END, ROLLBACK, ABORT, SAVEPOINT, RELEASE
+    Transaction,
+    /// Anything else (SET, ANALYZE, ...)
+    Other,
+}
+
+impl StatementKind {
+    /// Classify a statement by its leading keyword
+    pub fn of(statement: &str) -> Self {
+        let keyword = keywords(statement).into_iter().next().unwrap_or_default();
+
+        match keyword.as_str() {
+            "CREATE" | "ALTER" | "DROP" | "TRUNCATE" | "COMMENT" | "GRANT" | "REVOKE" => {
+                StatementKind::Ddl
+            }
+            "INSERT" | "UPDATE" | "DELETE" | "MERGE" | "COPY" => StatementKind::Dml,
+            "SELECT" | "WITH" | "VALUES" | "SHOW" | "EXPLAIN" | "TABLE" => StatementKind::Query,
+            "BEGIN" | "START" | "COMMIT" | "END" | "ROLLBACK" | "ABORT" | "SAVEPOINT"
+            | "RELEASE" => StatementKind::Transaction,
+            _ => StatementKind::Other,
+        }
+    }
+}
+
+/// Split a SQL script into individual statements
+///
+/// Statements are separated by semicolons outside of string literals (including `E'...'`
+/// escape strings), quoted identifiers, dollar-quoted bodies and comments. Comments are
+/// kept as part of the statement that follows them; empty statements are dropped and the
+/// terminating semicolon is removed.
+pub fn split_statements(script: &str) -> Vec<String> {
+    let (mut statements, rest) = scan_statements(script);
+    push_statement(&mut statements, &rest);
+    statements
+}
+
+/// Whether every statement in `script` has been terminated with a semicolon
+///
+/// Trailing comments and whitespace don't count; an unterminated string literal or
+/// comment does. The shell uses this to keep reading lines until a statement is complete.
+pub fn is_terminated(script: &str) -> bool {
+    let (_, rest) = scan_statements(script);
+    !has_code(&rest)
+}
+
+/// Split off the terminated statements of `script`, returning them and the unterminated rest
+fn scan_statements(script: &str) -> (Vec<String>, String) {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut chars = script.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ';' => {
+                push_statement(&mut statements, &current);
+                current.clear();
+                continue;
+            }
+            '\'' | '"' => {
+                // Quoted literal or identifier; a doubled quote is an escaped quote, and
+                // in an escape string so is a backslashed one
+                let escapes = c == '\'' && is_escape_string_prefix(&script[..i]);
+                current.push(c);
+                while let Some((_, q)) = chars.next() {
+                    current.push(q);
+                    if escapes && q == '\\' {
+                        if let Some((_, escaped)) = chars.next() {
+                            current.push(escaped);
+                        }
+                    } else if q == c {
+                        if chars.peek().map(|&(_, n)| n) == Some(c) {
+                            current.push(chars.next().unwrap().1);
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                continue;
+            }
+            '-' if chars.peek().map(|&(_, n)| n) == Some('-') => {
+                // Line comment
+                current.push(c);
+                for (_, n) in chars.by_ref() {
+                    current.push(n);
+                    if n == '\n' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '/' if chars.peek().map(|&(_, n)| n) == Some('*') => {
+                // Block comment (these nest in Postgres)
+                current.push(c);
+                current.push(chars.next().unwrap().1);
+                let mut depth = 1;
+                while depth > 0 {
+                    let Some((_, n)) = chars.next() else { break };
+                    current.push(n);
+                    let next = chars.peek().map(|&(_, n)| n);
+                    if n == '*' && next == Some('/') {
+                        current.push(chars.next().unwrap().1);
+                        depth -= 1;
+                    } else if n == '/' && next == Some('*') {
+                        current.push(chars.next().unwrap().1);
+                        depth += 1;
+                    }
+                }
+                continue;
+            }
+            '$' => {
+                if let Some(tag) = dollar_quote_tag(&script[i..]) {
+                    // Dollar-quoted body: copy everything up to the matching closing tag
+                    let body_start = i + tag.len();
+                    let end = script[body_start..]
+                        .find(tag)
+                        .map(|pos| body_start + pos + tag.len())
+                        .unwrap_or(script.len());
+                    current.push_str(&script[i..end]);
+                    while chars.peek().is_some_and(|&(j, _)| j < end) {
+                        chars.next();
+                    }
+                    continue;
+                }
+            }
+            _ => {}
+        }
+
+        current.push(c);
+    }
+
+    (statements, current)
+}
+
+/// Uppercase words of a statement, skipping comments and punctuation
+pub fn keywords(statement: &str) -> Vec<String> {
+    strip_comments(statement)
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_uppercase())
+        .collect()
+}
+
+/// Whether the statement contains any SQL beyond comments and whitespace
+pub fn has_code(statement: &str) -> bool {
+    !strip_comments(statement).trim().is_empty()
+}
+
+/// Remove `--` and `/* */` comments from a statement
+fn strip_comments(statement: &str) -> String {
+    let mut out = String::with_capacity(statement.len());
+    let mut chars = statement.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '-' if chars.peek() == Some(&'-') => {
+                for n in chars.by_ref() {
+                    if n == '\n' {
+                        out.push('\n');
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut depth = 1;
+                while depth > 0 {
+                    match chars.next() {
+                        Some('*') if chars.peek() == Some(&'/') => {
+                            chars.next();
+                            depth -= 1;
+                        }
+                        Some('/') if chars.peek() == Some(&'*') => {
+                            chars.next();
+                            depth += 1;
+                        }
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+                out.push(' ');
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Whether the text before a quote ends with the `E` of an escape string (`E'...'`),
+/// rather than with an identifier ending in `e`
+fn is_escape_string_prefix(before: &str) -> bool {
+    let mut chars = before.chars().rev();
+    matches!(chars.next(), Some('E' | 'e'))
+        && !chars
+            .next()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '$')
+}
+
+/// If `text` starts with a dollar-quote opening tag (`$$` or `$name$`), return the tag
+fn dollar_quote_tag(text: &str) -> Option<&str> {
+    let rest = &text[1..];
+    let close = rest.find('$')?;
+    let name = &rest[..close];
+
+    let valid = name
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c == '_' || c.is_alphabetic() || (i > 0 && c.is_ascii_digit()));
+
+    valid.then(|| &text[..close + 2])
+}
+
+fn push_statement(statements: &mut Vec<String>, statement: &str) {
+    let statement = statement.trim();
+    if has_code(statement) {
+        statements.push(statement.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_semicolons() {
+        assert_eq!(
+            split_statements("SELECT 1;\nSELECT 2 ;; \n  SELECT 3"),
+            ["SELECT 1", "SELECT 2", "SELECT 3"]
+        );
+        assert!(split_statements(" ;\n; ").is_empty());
+    }
+
+    #[test]
+    fn ignores_semicolons_in_quotes() {
+        assert_eq!(
+            split_statements(r#"SELECT 'a;b', 'it''s; fine'; SELECT "odd;name" FROM t"#),
+            [
+                r#"SELECT 'a;b', 'it''s; fine'"#,
+                r#"SELECT "odd;name" FROM t"#
+            ]
+        );
+    }
+
+    #[test]
+    fn handles_backslashes_in_escape_strings_only() {
+        assert_eq!(
+            split_statements(r"SELECT E'it\'s; fine'; SELECT e'\\'; SELECT 2"),
+            [r"SELECT E'it\'s; fine'", r"SELECT e'\\'", "SELECT 2"]
+        );
+        // Standard strings treat a backslash literally
+        assert_eq!(
+            split_statements(r"SELECT 'C:\'; SELECT 2"),
+            [r"SELECT 'C:\'", "SELECT 2"]
+        );
+        // An identifier ending in e is not an escape string prefix
+        assert_eq!(
+            split_statements(r"SELECT name'\'; SELECT 2"),
+            [r"SELECT name'\'", "SELECT 2"]
+        );
+    }
+
+    #[test]
+    fn ignores_semicolons_in_dollar_quotes() {
+        let function = "CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql";
+        let tagged = "DO $body$ BEGIN RAISE NOTICE '$$;'; END $body$";
+        let script = format!("{};\n{};\nSELECT $1;", function, tagged);
+        assert_eq!(split_statements(&script), [function, tagged, "SELECT $1"]);
+    }
+
+    #[test]
+    fn keeps_comments_with_the_next_statement() {
+        let script = "-- first; still a comment\nSELECT 1;\n/* outer /* nested; */ ; */ SELECT 2;\n-- trailing";
+        assert_eq!(
+            split_statements(script),
+            [
+                "-- first; still a comment\nSELECT 1",
+                "/* outer /* nested; */ ; */ SELECT 2"
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_unterminated_statements() {
+        assert!(is_terminated("SELECT 1;"));
+        assert!(is_terminated("SELECT 1; -- done"));
+        assert!(is_terminated(""));
+        assert!(!is_terminated("SELECT 1"));
+        assert!(!is_terminated("SELECT 'open;"));
+        assert!(!is_terminated("SELECT E'it\\'s;"));
+        assert!(!is_terminated("SELECT $$ body;"));
+        assert!(!is_terminated("SELECT 1 /* comment;"));
+    }
+
+    #[test]
+    fn classifies_statements_by_leading_keyword() {
+        for (statement, kind) in [
+            ("create table t (id int)", StatementKind::Ddl),
+            ("-- comment\nALTER TABLE t ADD c int", StatementKind::Ddl),
+            ("/* x */ INSERT INTO t VALUES (1)", StatementKind::Dml),
+            ("WITH x AS (SELECT 1) SELECT * FROM x", StatementKind::Query),
+            ("begin", StatementKind::Transaction),
+            ("START TRANSACTION", StatementKind::Transaction),
+            ("SET search_path = public", StatementKind::Other),
+            ("", StatementKind::Other),
+        ] {
+            assert_eq!(StatementKind::of(statement), kind, "{}", statement);
+        }
+    }
+
+    #[test]
+    fn keywords_skip_comments_and_punctuation() {
+        assert_eq!(
+            keywords("-- note\nCREATE INDEX ASYNC idx ON t(c); /* done */"),
+            ["CREATE", "INDEX", "ASYNC", "IDX", "ON", "T", "C"]
+        );
+        assert!(!has_code("-- only a comment\n/* and another */"));
+    }
+}
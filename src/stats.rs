@@ -0,0 +1,352 @@
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use sqlx::types::chrono;
+use sqlx::Row;
+use tracing::instrument;
+
+/// Summary statistics about the `users` table
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UserStats {
+    pub total_users: i64,
+    /// User count per role, most common first
+    pub roles: Vec<RoleCount>,
+    pub newest_user: Option<UserSummary>,
+    pub oldest_user: Option<UserSummary>,
+    /// Users created per day, most recent 7 days with activity first
+    pub creation_trends: Vec<DailyCount>,
+    /// Users created per hour of the day (UTC)
+    pub hour_distribution: Vec<HourCount>,
+    /// Name length statistics (absent when the table is empty)
+    pub name_lengths: Option<NameLengthStats>,
+    /// Most common first names
+    pub popular_first_names: Vec<NameCount>,
+    /// Most common email domains
+    pub email_domains: Vec<DomainCount>,
+}
+
+impl UserStats {
+    /// Share of all users represented by `count`, as a percentage
+    pub fn percentage(&self, count: i64) -> f64 {
+        if self.total_users == 0 {
+            0.0
+        } else {
+            count as f64 / self.total_users as f64 * 100.0
+        }
+    }
+
+    /// Flatten the statistics into one row per value, e.g. for CSV output
+    pub fn rows(&self) -> Vec<StatRow> {
+        let mut rows = vec![StatRow::new(
+            "total_users",
+            "",
+            self.total_users.to_string(),
+        )];
+
+        for role in &self.roles {
+            rows.push(StatRow::new("role", &role.role, role.count.to_string()));
+        }
+        for (metric, user) in [
+            ("newest_user", &self.newest_user),
+            ("oldest_user", &self.oldest_user),
+        ] {
+            if let Some(user) = user {
+                rows.push(StatRow::new(
+                    metric,
+                    &user.email,
+                    user.created_at.to_rfc3339(),
+                ));
+            }
+        }
+        for day in &self.creation_trends {
+            rows.push(StatRow::new(
+                "created_on",
+                &day.date.to_string(),
+                day.count.to_string(),
+            ));
+        }
+        for hour in &self.hour_distribution {
+            rows.push(StatRow::new(
+                "created_at_hour",
+                &hour.hour.to_string(),
+                hour.count.to_string(),
+            ));
+        }
+        if let Some(names) = &self.name_lengths {
+            rows.push(StatRow::new(
+                "name_length",
+                "longest",
+                names.longest_name.clone(),
+            ));
+            rows.push(StatRow::new(
+                "name_length",
+                "shortest",
+                names.shortest_name.clone(),
+            ));
+            rows.push(StatRow::new(
+                "name_length",
+                "average",
+                names.average_length.to_string(),
+            ));
+        }
+        for name in &self.popular_first_names {
+            rows.push(StatRow::new(
+                "first_name",
+                name.first_name.trim(),
+                name.count.to_string(),
+            ));
+        }
+        for domain in &self.email_domains {
+            rows.push(StatRow::new(
+                "email_domain",
+                &domain.domain,
+                domain.count.to_string(),
+            ));
+        }
+
+        rows
+    }
+}
+
+/// One value of [`UserStats`] in flattened form
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatRow {
+    /// What is measured, e.g. "role" or "email_domain"
+    pub metric: String,
+    /// Which instance of the metric, e.g. the role name (empty for totals)
+    pub key: String,
+    pub value: String,
+}
+
+impl StatRow {
+    fn new(metric: &str, key: &str, value: String) -> Self {
+        Self {
+            metric: metric.to_string(),
+            key: key.to_string(),
+            value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoleCount {
+    pub role: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserSummary {
+    pub name: String,
+    pub email: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DailyCount {
+    pub date: chrono::NaiveDate,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HourCount {
+    pub hour: i32,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NameLengthStats {
+    pub longest_name: String,
+    pub shortest_name: String,
+    pub average_length: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NameCount {
+    pub first_name: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DomainCount {
+    pub domain: String,
+    pub count: i64,
+}
+
+/// Gather statistics about users in the database
+#[instrument(level = "debug", skip_all)]
+pub async fn user_statistics(pool: &PgPool) -> Result<UserStats, sqlx::Error> {
+    // Total user count
+    let total_users = sqlx::query("SELECT COUNT(*) as count FROM users")
+        .fetch_one(pool)
+        .await?
+        .get::<i64, _>("count");
+
+    // Count by role
+    let roles = sqlx::query(
+        r#"
+        SELECT role, COUNT(*) as count
+        FROM users
+        GROUP BY role
+        ORDER BY count DESC
+        "#,
+    )
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| RoleCount {
+        role: row.get("role"),
+        count: row.get("count"),
+    })
+    .collect();
+
+    // Get newest and oldest user
+    let newest_user = sqlx::query(
+        r#"
+        SELECT name, email, created_at
+        FROM users
+        ORDER BY created_at DESC
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| UserSummary {
+        name: row.get("name"),
+        email: row.get("email"),
+        created_at: row.get("created_at"),
+    });
+
+    let oldest_user = sqlx::query(
+        r#"
+        SELECT name, email, created_at
+        FROM users
+        ORDER BY created_at ASC
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|row| UserSummary {
+        name: row.get("name"),
+        email: row.get("email"),
+        created_at: row.get("created_at"),
+    });
+
+    // Get popular name prefixes
+    let popular_first_names = sqlx::query(
+        r#"
+        SELECT LEFT(name, POSITION(' ' IN name)) as first_name, COUNT(*) as count
+        FROM users
+        WHERE POSITION(' ' IN name) > 0
+        GROUP BY first_name
+        ORDER BY count DESC
+        LIMIT 5
+        "#,
+    )
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| NameCount {
+        first_name: row.get("first_name"),
+        count: row.get("count"),
+    })
+    .collect();
+
+    // Get user creation trends (users created by day)
+    let creation_trends = sqlx::query(
+        r#"
+        SELECT
+            DATE(created_at) as date,
+            COUNT(*) as count
+        FROM users
+        GROUP BY date
+        ORDER BY date DESC
+        LIMIT 7
+        "#,
+    )
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| DailyCount {
+        date: row.get("date"),
+        count: row.get("count"),
+    })
+    .collect();
+
+    // Get creation time distribution (by hour of day)
+    let hour_distribution = sqlx::query(
+        r#"
+        SELECT
+            EXTRACT(HOUR FROM created_at)::INT as hour,
+            COUNT(*) as count
+        FROM users
+        GROUP BY hour
+        ORDER BY hour
+        "#,
+    )
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| HourCount {
+        hour: row.get("hour"),
+        count: row.get("count"),
+    })
+    .collect();
+
+    // Get longest and shortest names
+    let name_extremes = sqlx::query(
+        r#"
+        SELECT
+            (SELECT name FROM users ORDER BY LENGTH(name) DESC LIMIT 1) as longest_name,
+            (SELECT name FROM users ORDER BY LENGTH(name) ASC LIMIT 1) as shortest_name,
+            (SELECT AVG(LENGTH(name))::FLOAT8 FROM users) as avg_length
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let name_lengths = match (
+        name_extremes.get::<Option<String>, _>("longest_name"),
+        name_extremes.get::<Option<String>, _>("shortest_name"),
+        name_extremes.get::<Option<f64>, _>("avg_length"),
+    ) {
+        (Some(longest_name), Some(shortest_name), Some(average_length)) => Some(NameLengthStats {
+            longest_name,
+            shortest_name,
+            average_length,
+        }),
+        _ => None,
+    };
+
+    // Email domain statistics
+    let email_domains = sqlx::query(
+        r#"
+        SELECT
+            SUBSTRING(email FROM POSITION('@' IN email) + 1) as domain,
+            COUNT(*) as count
+        FROM users
+        GROUP BY domain
+        ORDER BY count DESC
+        LIMIT 5
+        "#,
+    )
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| DomainCount {
+        domain: row.get("domain"),
+        count: row.get("count"),
+    })
+    .collect();
+
+    Ok(UserStats {
+        total_users,
+        roles,
+        newest_user,
+        oldest_user,
+        creation_trends,
+        hour_distribution,
+        name_lengths,
+        popular_first_names,
+        email_domains,
+    })
+}
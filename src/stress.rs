@@ -0,0 +1,921 @@
+use crate::retry::{retry_transaction, ErrorClass, Retried};
+use crate::users::{NewUser, RepositoryError, UserRepository, UserUpdate};
+use crate::workload::{Operation, Workload};
+use hdrhistogram::Histogram;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::types::uuid::Uuid;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Roles assigned round-robin to generated users
+const ROLES: [&str; 5] = ["User", "Admin", "Manager", "Guest", "Developer"];
+
+/// Medieval first names
+const MEDIEVAL_FIRST_NAMES: [&str; 40] = [
+    "Aelfric",
+    "Aldwin",
+    "Baldwin",
+    "Cedric",
+    "Edmund",
+    "Godfrey",
+    "Harold",
+    "Leofric",
+    "Oswald",
+    "Wilfrid",
+    "Adelina",
+    "Beatrice",
+    "Cecily",
+    "Eleanor",
+    "Guinevere",
+    "Isolde",
+    "Matilda",
+    "Rohesia",
+    "Sybil",
+    "Yvonne",
+    "William",
+    "Richard",
+    "Robert",
+    "Hugh",
+    "Roland",
+    "Giles",
+    "Walter",
+    "Henry",
+    "Thomas",
+    "John",
+    "Agnes",
+    "Alice",
+    "Elaine",
+    "Emma",
+    "Joan",
+    "Margaret",
+    "Marian",
+    "Edith",
+    "Godiva",
+    "Maud",
+];
+
+/// Shakespearean last names
+const SHAKESPEAREAN_LAST_NAMES: [&str; 45] = [
+    "Montague",
+    "Capulet",
+    "Othello",
+    "Hamlet",
+    "Macbeth",
+    "Lear",
+    "Prospero",
+    "Oberon",
+    "Puck",
+    "Lysander",
+    "Demetrius",
+    "Titania",
+    "Portia",
+    "Shylock",
+    "Malvolio",
+    "Orsino",
+    "Orlando",
+    "Rosalind",
+    "Falstaff",
+    "Petruchio",
+    "Ariel",
+    "Caliban",
+    "Polonius",
+    "Laertes",
+    "Ophelia",
+    "Macduff",
+    "Banquo",
+    "Desdemona",
+    "Cordelia",
+    "Goneril",
+    "Regan",
+    "Kent",
+    "Gloucester",
+    "Albany",
+    "Cornwall",
+    "Feste",
+    "Viola",
+    "Sebastian",
+    "Antonio",
+    "Benvolio",
+    "Mercutio",
+    "Tybalt",
+    "Horatio",
+    "Fortinbras",
+    "Bottom",
+];
+
+/// Parameters of a stress test run
+#[derive(Debug, Clone)]
+pub struct StressConfig {
+    /// Number of operations to run (ignored when `duration` is set)
+    pub total_operations: usize,
+    /// Number of workers running operations concurrently
+    pub concurrency: usize,
+    /// Keep running operations until this much time has passed instead of stopping after
+    /// `total_operations`
+    pub duration: Option<Duration>,
+    /// Target operations per second across all workers (as fast as possible if `None`)
+    pub rate: Option<f64>,
+    /// Mix of operations to run
+    pub workload: Workload,
+    /// Users inserted by each insert operation; above 1 they are inserted with
+    /// multi-row statements, chunked to DSQL's per-transaction limits
+    pub batch_size: usize,
+}
+
+impl StressConfig {
+    /// Whether the `i`-th operation should still be started, `elapsed` into the run
+    fn should_start(&self, i: usize, elapsed: Duration) -> bool {
+        match self.duration {
+            Some(duration) => elapsed < duration,
+            None => i < self.total_operations,
+        }
+    }
+
+    /// When the `i`-th operation may start, measured from the start of the run, or `None`
+    /// to start it right away when no rate is set
+    fn start_offset(&self, i: usize) -> Option<Duration> {
+        self.rate
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f64(i as f64 / rate))
+    }
+}
+
+/// At most this many error messages are kept in a [`StressReport`]
+pub const MAX_RECORDED_ERRORS: usize = 100;
+
+/// Workloads that read, update or delete existing users are given at least this many
+/// users to work on, inserting generated users if the table has fewer
+pub const MIN_EXISTING_USERS: usize = 100;
+
+/// At most this many existing user ids are loaded as targets before a run
+const MAX_LOADED_USERS: i64 = 10_000;
+
+/// Highest latency the histogram can record; slower operations are clamped to it
+const MAX_TRACKED_LATENCY: Duration = Duration::from_secs(60);
+
+/// Measurements for one type of operation
+#[derive(Debug, Clone)]
+pub struct OperationStats {
+    pub successful: u64,
+    pub failed: u64,
+    /// Latency of successful operations in microseconds, including time spent retrying
+    pub latency: Histogram<u64>,
+    /// Attempts beyond the first, over all operations
+    pub retries: u64,
+    /// Most retries needed by a single operation
+    pub max_retries: u64,
+    /// Attempts that failed with an OCC conflict, over all operations
+    pub conflicts: u64,
+    /// Attempts made, including retries
+    pub attempts: u64,
+    /// Number of operations by how many retries they needed
+    pub retry_depths: BTreeMap<u64, u64>,
+    /// Number of failed operations by kind of error, e.g. "not_found" or "sqlstate 40001"
+    pub error_kinds: BTreeMap<String, u64>,
+    /// Rows written or returned by successful operations
+    pub rows: u64,
+    /// Statements run by successful operations, not counting retries
+    pub statements: u64,
+}
+
+impl Default for OperationStats {
+    fn default() -> Self {
+        Self {
+            successful: 0,
+            failed: 0,
+            latency: Histogram::new_with_bounds(1, micros(MAX_TRACKED_LATENCY), 3)
+                .expect("latency histogram bounds are valid"),
+            retries: 0,
+            max_retries: 0,
+            conflicts: 0,
+            attempts: 0,
+            retry_depths: BTreeMap::new(),
+            error_kinds: BTreeMap::new(),
+            rows: 0,
+            statements: 0,
+        }
+    }
+}
+
+impl OperationStats {
+    /// Number of operations run, successful or not
+    pub fn total(&self) -> u64 {
+        self.successful + self.failed
+    }
+
+    /// Average number of retries per operation
+    pub fn retries_per_operation(&self) -> f64 {
+        if self.total() == 0 {
+            0.0
+        } else {
+            self.retries as f64 / self.total() as f64
+        }
+    }
+
+    /// Share of attempts that failed with an OCC conflict, which under DSQL happens at commit
+    pub fn conflict_rate(&self) -> f64 {
+        if self.attempts == 0 {
+            0.0
+        } else {
+            self.conflicts as f64 / self.attempts as f64
+        }
+    }
+
+    /// Latency of successful operations at the given percentile (0-100)
+    pub fn latency_percentile(&self, percentile: f64) -> Duration {
+        Duration::from_micros(self.latency.value_at_percentile(percentile))
+    }
+
+    fn record_success(&mut self, latency: Duration, retried: &Retried<Work>) {
+        self.successful += 1;
+        self.rows += retried.value.rows;
+        self.statements += retried.value.statements;
+        self.latency.saturating_record(micros(latency));
+        self.record_attempts(retried.attempts, retried.conflicts);
+    }
+
+    fn record_failure(&mut self, err: &RepositoryError) {
+        self.failed += 1;
+        *self.error_kinds.entry(error_kind(err)).or_default() += 1;
+        match err {
+            RepositoryError::Database(err) => self.record_attempts(err.attempts, err.conflicts),
+            _ => self.record_attempts(1, 0),
+        }
+    }
+
+    fn record_attempts(&mut self, attempts: u32, conflicts: u32) {
+        let retries = u64::from(attempts.saturating_sub(1));
+        self.attempts += u64::from(attempts);
+        self.retries += retries;
+        self.max_retries = self.max_retries.max(retries);
+        self.conflicts += u64::from(conflicts);
+        *self.retry_depths.entry(retries).or_default() += 1;
+    }
+
+    /// Add another set of measurements to this one
+    pub fn merge(&mut self, other: &OperationStats) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.successful += other.successful;
+        self.failed += other.failed;
+        self.latency.add(&other.latency)?;
+        self.retries += other.retries;
+        self.max_retries = self.max_retries.max(other.max_retries);
+        self.conflicts += other.conflicts;
+        self.attempts += other.attempts;
+        for (retries, count) in &other.retry_depths {
+            *self.retry_depths.entry(*retries).or_default() += count;
+        }
+        for (kind, count) in &other.error_kinds {
+            *self.error_kinds.entry(kind.clone()).or_default() += count;
+        }
+        self.rows += other.rows;
+        self.statements += other.statements;
+        Ok(())
+    }
+
+    /// The figures of these measurements, labelled with what they measure
+    pub fn summary(&self, operation: &str, elapsed: Duration) -> OperationSummary {
+        let millis = |micros: u64| micros as f64 / 1000.0;
+
+        OperationSummary {
+            operation: operation.to_string(),
+            successful: self.successful,
+            failed: self.failed,
+            rate: self.successful as f64 / elapsed.as_secs_f64(),
+            attempts: self.attempts,
+            attempt_rate: self.attempts as f64 / elapsed.as_secs_f64(),
+            latency_mean_ms: self.latency.mean() / 1000.0,
+            latency_p50_ms: millis(self.latency.value_at_percentile(50.0)),
+            latency_p90_ms: millis(self.latency.value_at_percentile(90.0)),
+            latency_p99_ms: millis(self.latency.value_at_percentile(99.0)),
+            latency_p999_ms: millis(self.latency.value_at_percentile(99.9)),
+            latency_max_ms: millis(self.latency.max()),
+            retries: self.retries,
+            retries_per_operation: self.retries_per_operation(),
+            max_retries: self.max_retries,
+            conflicts: self.conflicts,
+            conflict_rate: self.conflict_rate(),
+            rows: self.rows,
+            row_rate: self.rows as f64 / elapsed.as_secs_f64(),
+            statements: self.statements,
+            statement_rate: self.statements as f64 / elapsed.as_secs_f64(),
+        }
+    }
+}
+
+/// Outcome of a stress test run
+#[derive(Debug, Clone)]
+pub struct StressReport {
+    /// Name of the workload that was run
+    pub workload: String,
+    pub elapsed: Duration,
+    /// Measurements per type of operation
+    pub operations: BTreeMap<Operation, OperationStats>,
+    /// Error messages of the first [`MAX_RECORDED_ERRORS`] failed operations, prefixed
+    /// with the operation
+    pub errors: Vec<String>,
+}
+
+impl StressReport {
+    /// Measurements over all operations
+    pub fn total(&self) -> OperationStats {
+        let mut total = OperationStats::default();
+        for stats in self.operations.values() {
+            total
+                .merge(stats)
+                .expect("histograms with equal bounds always merge");
+        }
+        total
+    }
+
+    /// Successful operations per second
+    pub fn operation_rate(&self) -> f64 {
+        self.total().successful as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// One summary row for all operations ("total"), followed by one per operation type
+    pub fn summary(&self) -> Vec<OperationSummary> {
+        std::iter::once(self.total().summary("total", self.elapsed))
+            .chain(
+                self.operations
+                    .iter()
+                    .map(|(operation, stats)| stats.summary(&operation.to_string(), self.elapsed)),
+            )
+            .collect()
+    }
+}
+
+/// Serialized as the workload, elapsed time, the [`OperationSummary`] rows, the retry
+/// depth distribution per operation and the errors
+impl Serialize for StressReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Report<'a> {
+            workload: &'a str,
+            elapsed_seconds: f64,
+            operations: Vec<OperationSummary>,
+            retry_depths: BTreeMap<String, BTreeMap<u64, u64>>,
+            errors: &'a [String],
+        }
+
+        let total = self.total();
+        let retry_depths = std::iter::once(("total".to_string(), total.retry_depths))
+            .chain(
+                self.operations
+                    .iter()
+                    .map(|(operation, stats)| (operation.to_string(), stats.retry_depths.clone())),
+            )
+            .collect();
+
+        Report {
+            workload: &self.workload,
+            elapsed_seconds: self.elapsed.as_secs_f64(),
+            operations: self.summary(),
+            retry_depths,
+            errors: &self.errors,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Flat summary of an [`OperationStats`], e.g. for a CSV row
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OperationSummary {
+    /// Operation type, or "total"
+    pub operation: String,
+    pub successful: u64,
+    pub failed: u64,
+    /// Successful operations per second (goodput)
+    pub rate: f64,
+    /// Attempts made, including retries
+    pub attempts: u64,
+    /// Attempts per second, including retries (throughput)
+    pub attempt_rate: f64,
+    pub latency_mean_ms: f64,
+    pub latency_p50_ms: f64,
+    pub latency_p90_ms: f64,
+    pub latency_p99_ms: f64,
+    pub latency_p999_ms: f64,
+    pub latency_max_ms: f64,
+    pub retries: u64,
+    pub retries_per_operation: f64,
+    pub max_retries: u64,
+    pub conflicts: u64,
+    /// Share of attempts that failed with an OCC conflict
+    pub conflict_rate: f64,
+    /// Rows written or returned by successful operations
+    #[serde(default)]
+    pub rows: u64,
+    /// Rows per second
+    #[serde(default)]
+    pub row_rate: f64,
+    /// Statements run by successful operations, not counting retries
+    #[serde(default)]
+    pub statements: u64,
+    /// Statements per second
+    #[serde(default)]
+    pub statement_rate: f64,
+}
+
+/// A generated test user
+#[derive(Debug, Clone)]
+pub struct GeneratedUser {
+    pub id: Uuid,
+    pub name: String,
+    pub email: String,
+    pub role: &'static str,
+}
+
+impl From<GeneratedUser> for NewUser {
+    fn from(user: GeneratedUser) -> Self {
+        NewUser {
+            id: user.id,
+            name: user.name,
+            email: user.email,
+            role: user.role.to_string(),
+        }
+    }
+}
+
+/// Generate the `i`-th test user
+///
+/// Names combine a medieval first name with a Shakespearean last name; the email includes
+/// the user's UUID so repeated runs never conflict.
+pub fn generate_user(i: usize) -> GeneratedUser {
+    let id = Uuid::new_v4();
+
+    // Use a different multiplier for the last name to vary combinations
+    let first_name = MEDIEVAL_FIRST_NAMES[i % MEDIEVAL_FIRST_NAMES.len()];
+    let last_name = SHAKESPEAREAN_LAST_NAMES[(i * 7) % SHAKESPEAREAN_LAST_NAMES.len()];
+
+    GeneratedUser {
+        id,
+        name: format!("{} {}", first_name, last_name),
+        email: format!(
+            "{}.{}.{}@kingdommail.com",
+            first_name.to_lowercase(),
+            last_name.to_lowercase(),
+            id.simple()
+        ),
+        role: ROLES[i % ROLES.len()],
+    }
+}
+
+/// Ids of existing users that reads, updates and deletes pick from
+///
+/// Inserted users are added and deleted users removed, so workers rarely target a row
+/// that no longer exists. With `hot_keys`, only the first that many ids are picked.
+struct Targets {
+    ids: Mutex<Vec<Uuid>>,
+    hot_keys: Option<usize>,
+}
+
+impl Targets {
+    fn candidates(&self, len: usize) -> usize {
+        self.hot_keys.map_or(len, |hot| hot.min(len))
+    }
+
+    /// A random target, left in place
+    fn pick(&self) -> Option<Uuid> {
+        let ids = self.ids.lock().expect("targets lock poisoned");
+        let candidates = self.candidates(ids.len());
+        ids[..candidates].choose(&mut rand::thread_rng()).copied()
+    }
+
+    /// A random target, removed so no other worker picks it again
+    fn take(&self) -> Option<Uuid> {
+        let mut ids = self.ids.lock().expect("targets lock poisoned");
+        let candidates = self.candidates(ids.len());
+        if candidates == 0 {
+            return None;
+        }
+        let index = rand::thread_rng().gen_range(0..candidates);
+        Some(ids.swap_remove(index))
+    }
+
+    fn add(&self, id: Uuid) {
+        self.ids.lock().expect("targets lock poisoned").push(id);
+    }
+}
+
+/// Load existing user ids as targets, topping the table up with generated users if the
+/// workload needs more than there are
+async fn prepare_targets(
+    repo: &UserRepository,
+    workload: &Workload,
+) -> Result<Targets, Box<dyn Error + Send + Sync>> {
+    let mut ids = Vec::new();
+
+    if workload.needs_existing_users() {
+        ids = repo
+            .list_page(None, MAX_LOADED_USERS)
+            .await?
+            .into_iter()
+            .map(|user| user.id)
+            .collect();
+
+        let wanted = workload.hot_keys.unwrap_or(MIN_EXISTING_USERS);
+        for i in ids.len()..wanted {
+            let user = generate_user(i);
+            repo.insert(user.id, &user.name, &user.email, user.role)
+                .await?;
+            ids.push(user.id);
+        }
+    }
+
+    Ok(Targets {
+        ids: Mutex::new(ids),
+        hot_keys: workload.hot_keys,
+    })
+}
+
+/// Stress test the database with a mix of concurrent operations
+///
+/// `concurrency` workers pull operation numbers from a shared counter until
+/// `total_operations` have been started or `duration` has passed, so a slow operation
+/// only holds up its own worker. Each operation is drawn from the workload's weighted
+/// mix. With a target `rate`, operation `i` is not started before `i / rate` seconds
+/// into the run.
+///
+/// Workloads that read, update or delete users first load existing user ids to target,
+/// inserting up to [`MIN_EXISTING_USERS`] (or `hot_keys`) generated users if needed.
+pub async fn run_stress_test(
+    repo: &UserRepository,
+    config: &StressConfig,
+) -> Result<StressReport, Box<dyn Error + Send + Sync>> {
+    let targets = Arc::new(prepare_targets(repo, &config.workload).await?);
+    let next = Arc::new(AtomicUsize::new(0));
+    let start_time = Instant::now();
+
+    let workers: Vec<_> = (0..config.concurrency.max(1))
+        .map(|_| {
+            tokio::spawn(worker(
+                repo.clone(),
+                config.clone(),
+                targets.clone(),
+                next.clone(),
+                start_time,
+            ))
+        })
+        .collect();
+
+    let mut operations = BTreeMap::<Operation, OperationStats>::new();
+    let mut errors = Vec::new();
+    for worker in workers {
+        let stats = worker
+            .await
+            .map_err(|e| format!("Stress test worker failed: {}", e))?;
+        for (operation, stats) in &stats.operations {
+            operations.entry(*operation).or_default().merge(stats)?;
+        }
+        let room = MAX_RECORDED_ERRORS.saturating_sub(errors.len());
+        errors.extend(stats.errors.into_iter().take(room));
+    }
+
+    Ok(StressReport {
+        workload: config.workload.name.clone(),
+        elapsed: start_time.elapsed(),
+        operations,
+        errors,
+    })
+}
+
+/// What one stress test worker measured
+#[derive(Default)]
+struct WorkerStats {
+    operations: BTreeMap<Operation, OperationStats>,
+    errors: Vec<String>,
+}
+
+/// Run operations until the run is over
+async fn worker(
+    repo: UserRepository,
+    config: StressConfig,
+    targets: Arc<Targets>,
+    next: Arc<AtomicUsize>,
+    start_time: Instant,
+) -> WorkerStats {
+    let mut stats = WorkerStats::default();
+
+    loop {
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        if !config.should_start(i, start_time.elapsed()) {
+            break;
+        }
+
+        if let Some(offset) = config.start_offset(i) {
+            tokio::time::sleep_until(start_time + offset).await;
+            // The run may have ended while waiting for this operation's slot
+            if !config.should_start(i, start_time.elapsed()) {
+                break;
+            }
+        }
+
+        let operation = config.workload.choose(&mut rand::thread_rng());
+        let started = Instant::now();
+        let result = run_operation(&repo, &targets, operation, i, config.batch_size).await;
+        let elapsed = started.elapsed();
+
+        let op_stats = stats.operations.entry(operation).or_default();
+        match result {
+            Ok(retried) => op_stats.record_success(elapsed, &retried),
+            Err(e) => {
+                op_stats.record_failure(&e);
+                if stats.errors.len() < MAX_RECORDED_ERRORS {
+                    stats.errors.push(format!("{}: {}", operation, e));
+                }
+            }
+        }
+    }
+
+    stats
+}
+
+/// Work done by a successful operation
+struct Work {
+    rows: u64,
+    statements: u64,
+}
+
+/// Run the `i`-th operation of the test
+async fn run_operation(
+    repo: &UserRepository,
+    targets: &Targets,
+    operation: Operation,
+    i: usize,
+    batch_size: usize,
+) -> Result<Retried<Work>, RepositoryError> {
+    let done = |attempts: u32, conflicts: u32| Retried {
+        value: Work {
+            rows: 1,
+            statements: 1,
+        },
+        attempts,
+        conflicts,
+    };
+    // Targets only run out if deletes removed every user; report it like a missing row
+    let no_target = || RepositoryError::NotFound(Uuid::nil());
+
+    match operation {
+        Operation::Insert if batch_size > 1 => {
+            let users: Vec<NewUser> = (i * batch_size..(i + 1) * batch_size)
+                .map(|n| generate_user(n).into())
+                .collect();
+            let batch = repo.insert_users_batch(&users).await?;
+            for id in &batch.inserted {
+                targets.add(*id);
+            }
+            // Count the retries of every chunk as retries of the one operation
+            let retries = batch.attempts - batch.statements;
+            Ok(Retried {
+                value: Work {
+                    rows: batch.inserted.len() as u64,
+                    statements: batch.statements,
+                },
+                attempts: u32::try_from(retries + 1).unwrap_or(u32::MAX),
+                conflicts: u32::try_from(batch.conflicts).unwrap_or(u32::MAX),
+            })
+        }
+        Operation::Insert => {
+            let user = generate_user(i);
+            let inserted = repo
+                .insert_retried(user.id, &user.name, &user.email, user.role)
+                .await?;
+            targets.add(user.id);
+            Ok(done(inserted.attempts, inserted.conflicts))
+        }
+        Operation::GetById => {
+            let id = targets.pick().ok_or_else(no_target)?;
+            let user = repo.get_by_id_retried(id).await?;
+            Ok(done(user.attempts, user.conflicts))
+        }
+        Operation::UpdateRole => {
+            let id = targets.pick().ok_or_else(no_target)?;
+            let update = UserUpdate {
+                name: None,
+                role: Some(ROLES[i % ROLES.len()].to_string()),
+            };
+            let updated = repo.update_retried(id, &update).await?;
+            Ok(done(updated.attempts, updated.conflicts))
+        }
+        Operation::Delete => {
+            let id = targets.take().ok_or_else(no_target)?;
+            let deleted = repo.delete_retried(id).await?;
+            Ok(done(deleted.attempts, deleted.conflicts))
+        }
+        Operation::Count => {
+            let count = repo.count_retried().await?;
+            Ok(done(count.attempts, count.conflicts))
+        }
+        Operation::ContendedUpdate => {
+            let id = targets.pick().ok_or_else(no_target)?;
+            let rotated = rotate_role(repo, id).await?;
+            Ok(Retried {
+                value: Work {
+                    rows: 1,
+                    statements: 2,
+                },
+                attempts: rotated.attempts,
+                conflicts: rotated.conflicts,
+            })
+        }
+    }
+}
+
+/// Read a user's role and change it to the next one, in one explicit transaction
+///
+/// Transactions rotating the same user conflict when they commit, and are then re-run
+/// as a whole according to the repository's retry policy.
+async fn rotate_role(repo: &UserRepository, id: Uuid) -> Result<Retried<()>, RepositoryError> {
+    let rotated = retry_transaction(repo.pool(), repo.retry_policy(), move |conn| {
+        Box::pin(async move {
+            let role: Option<String> = sqlx::query_scalar("SELECT role FROM users WHERE id = $1")
+                .bind(id)
+                .fetch_optional(&mut *conn)
+                .await?;
+            let Some(role) = role else {
+                return Ok(false);
+            };
+
+            let next = ROLES
+                .iter()
+                .position(|r| *r == role)
+                .map_or(ROLES[0], |i| ROLES[(i + 1) % ROLES.len()]);
+            sqlx::query("UPDATE users SET role = $2 WHERE id = $1")
+                .bind(id)
+                .bind(next)
+                .execute(&mut *conn)
+                .await?;
+            Ok(true)
+        })
+    })
+    .await?;
+
+    if rotated.value {
+        Ok(Retried {
+            value: (),
+            attempts: rotated.attempts,
+            conflicts: rotated.conflicts,
+        })
+    } else {
+        Err(RepositoryError::NotFound(id))
+    }
+}
+
+/// Category of an error for [`OperationStats::error_kinds`]
+fn error_kind(err: &RepositoryError) -> String {
+    match err {
+        RepositoryError::DuplicateEmail(_) => "duplicate_email".to_string(),
+        RepositoryError::DuplicateId(_) => "duplicate_id".to_string(),
+        RepositoryError::NotFound(_) => "not_found".to_string(),
+        RepositoryError::Database(err) => {
+            match err.source.as_database_error().and_then(|e| e.code()) {
+                Some(code) => format!("sqlstate {}", code),
+                None => match err.class {
+                    ErrorClass::Conflict => "conflict".to_string(),
+                    ErrorClass::Connection => "connection".to_string(),
+                    ErrorClass::Fatal => "other".to_string(),
+                },
+            }
+        }
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::RetryError;
+
+    fn config(
+        total_operations: usize,
+        duration: Option<Duration>,
+        rate: Option<f64>,
+    ) -> StressConfig {
+        StressConfig {
+            total_operations,
+            concurrency: 4,
+            duration,
+            rate,
+            workload: Workload::new("test", [(Operation::Insert, 1)], None).unwrap(),
+            batch_size: 1,
+        }
+    }
+
+    fn stats(latencies_ms: impl IntoIterator<Item = u64>, attempts: u32) -> OperationStats {
+        let mut stats = OperationStats::default();
+        for latency in latencies_ms {
+            let retried = Retried {
+                value: Work {
+                    rows: 1,
+                    statements: 1,
+                },
+                attempts,
+                conflicts: attempts - 1,
+            };
+            stats.record_success(Duration::from_millis(latency), &retried);
+        }
+        stats
+    }
+
+    #[test]
+    fn count_runs_stop_after_the_last_operation() {
+        let config = config(3, None, None);
+        assert!(config.should_start(0, Duration::ZERO));
+        assert!(config.should_start(2, Duration::from_secs(3600)));
+        assert!(!config.should_start(3, Duration::ZERO));
+    }
+
+    #[test]
+    fn timed_runs_ignore_the_operation_count() {
+        let config = config(3, Some(Duration::from_secs(10)), None);
+        assert!(config.should_start(1_000_000, Duration::from_secs(9)));
+        assert!(!config.should_start(0, Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn rate_spaces_out_start_times() {
+        let paced = config(100, None, Some(4.0));
+        let offsets: Vec<_> = (0..5).map(|i| paced.start_offset(i).unwrap()).collect();
+        assert_eq!(offsets, [0, 250, 500, 750, 1000].map(Duration::from_millis));
+
+        assert_eq!(config(100, None, None).start_offset(7), None);
+        assert_eq!(config(100, None, Some(0.0)).start_offset(7), None);
+    }
+
+    #[test]
+    fn merged_stats_add_up() {
+        let mut merged = stats(1..=50, 1);
+        merged.record_failure(&RepositoryError::NotFound(Uuid::nil()));
+        let other = stats(51..=100, 3);
+        merged.merge(&other).unwrap();
+
+        assert_eq!((merged.successful, merged.failed), (100, 1));
+        assert_eq!(merged.total(), 101);
+        assert_eq!(merged.attempts, 50 + 1 + 150);
+        assert_eq!((merged.retries, merged.max_retries), (100, 2));
+        assert_eq!(merged.conflicts, 100);
+        assert_eq!(merged.retry_depths, BTreeMap::from([(0, 51), (2, 50)]));
+        assert_eq!(
+            merged.error_kinds,
+            BTreeMap::from([("not_found".to_string(), 1)])
+        );
+        assert_eq!((merged.rows, merged.statements), (100, 100));
+        assert_eq!(merged.latency.len(), 100);
+    }
+
+    #[test]
+    fn percentiles_come_from_the_latency_histogram() {
+        let stats = stats(1..=100, 1);
+        // The histogram keeps three significant digits
+        let close = |actual: Duration, ms: u64| {
+            let expected = Duration::from_millis(ms);
+            actual.abs_diff(expected) <= expected / 100
+        };
+        assert!(close(stats.latency_percentile(50.0), 50));
+        assert!(close(stats.latency_percentile(99.0), 99));
+        assert!(close(stats.latency_percentile(100.0), 100));
+
+        let summary = stats.summary("insert", Duration::from_secs(10));
+        assert_eq!(summary.successful, 100);
+        assert_eq!(summary.rate, 10.0);
+        assert!((summary.latency_p90_ms - 90.0).abs() <= 0.9);
+        assert!((summary.latency_max_ms - 100.0).abs() <= 1.0);
+        assert!((summary.latency_mean_ms - 50.5).abs() <= 0.5);
+    }
+
+    #[test]
+    fn contention_figures_count_the_attempts_of_failed_operations() {
+        // One transaction committed on its third attempt, one gave up after five conflicts
+        let mut stats = stats([10], 3);
+        stats.record_failure(&RepositoryError::Database(RetryError {
+            source: sqlx::Error::Protocol("serialization failure".to_string()),
+            class: ErrorClass::Conflict,
+            attempts: 5,
+            conflicts: 5,
+            ambiguous: false,
+        }));
+
+        assert_eq!((stats.attempts, stats.conflicts), (8, 7));
+        assert_eq!(stats.conflict_rate(), 7.0 / 8.0);
+        assert_eq!(stats.retry_depths, BTreeMap::from([(2, 1), (4, 1)]));
+        assert_eq!(stats.max_retries, 4);
+        assert_eq!(
+            stats.error_kinds,
+            BTreeMap::from([("conflict".to_string(), 1)])
+        );
+
+        let summary = stats.summary("contended_update", Duration::from_secs(2));
+        assert_eq!(summary.rate, 0.5);
+        assert_eq!(summary.attempt_rate, 4.0);
+        assert_eq!(summary.retries_per_operation, 3.0);
+    }
+}
@@ -0,0 +1,1041 @@
+use crate::connection;
+use crate::retry::{retry_as, retry_write_as, Attempts, Retried, RetryError, RetryPolicy};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgArguments, PgPool, Postgres};
+use sqlx::query::QueryAs;
+use sqlx::types::{chrono, uuid::Uuid};
+use std::error::Error;
+use std::fmt;
+use tracing::instrument;
+
+/// Columns selected for [`User`], in table order
+const USER_COLUMNS: &str = "id, name, email, role, created_at";
+
+/// A row of the `users` table
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow)]
+pub struct User {
+    pub id: Uuid,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    /// Creation time; DateTime<Utc> rather than NaiveDateTime to match the TIMESTAMPTZ type
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Changes to apply to an existing user; `None` fields are left untouched
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserUpdate {
+    pub name: Option<String>,
+    pub role: Option<String>,
+}
+
+impl UserUpdate {
+    /// Whether the update would change nothing
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.role.is_none()
+    }
+}
+
+/// Column users are listed by; ties are broken by id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum UserOrder {
+    #[default]
+    Id,
+    CreatedAt,
+    Email,
+    Name,
+}
+
+impl UserOrder {
+    fn column(self) -> &'static str {
+        match self {
+            UserOrder::Id => "id",
+            UserOrder::CreatedAt => "created_at",
+            UserOrder::Email => "email",
+            UserOrder::Name => "name",
+        }
+    }
+
+    /// The value `user` is sorted by, or `None` when ordering by id
+    fn key(self, user: &User) -> Option<SortKey> {
+        match self {
+            UserOrder::Id => None,
+            UserOrder::CreatedAt => Some(SortKey::Time(user.created_at)),
+            UserOrder::Email => Some(SortKey::Text(user.email.clone())),
+            UserOrder::Name => Some(SortKey::Text(user.name.clone())),
+        }
+    }
+}
+
+/// Value of the column a listing is ordered by, for continuing after a user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum SortKey {
+    /// `created_at`
+    Time(chrono::DateTime<chrono::Utc>),
+    /// `email` or `name`
+    Text(String),
+}
+
+/// Filters, ordering and page bounds for [`UserRepository::for_each`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UserQuery {
+    /// Only users with exactly this role
+    pub role: Option<String>,
+    /// Only users whose email matches this SQL `LIKE` pattern, e.g. `%@example.com`
+    pub email_like: Option<String>,
+    /// Only users created at or after this time
+    pub created_since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only users created before this time
+    pub created_until: Option<chrono::DateTime<chrono::Utc>>,
+    pub order_by: UserOrder,
+    pub descending: bool,
+    /// Start after the user with this id in the chosen order (keyset pagination)
+    pub after: Option<Uuid>,
+    /// Sort key of the `after` user, as returned in [`ListSummary::next_key`]
+    ///
+    /// Without it the key is looked up by id, which fails if the user has been deleted
+    /// since the previous page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after_key: Option<SortKey>,
+    /// Maximum number of users to return
+    pub limit: Option<i64>,
+}
+
+impl UserQuery {
+    /// The SELECT statement for this query's ordering
+    ///
+    /// Filters are bound as parameters: $1 role, $2 email pattern, $3 created since,
+    /// $4 created until, $5 cursor id, $6 limit and, unless ordering by id, $7 the cursor's
+    /// sort key (see [`UserQuery::bind_key`]).
+    fn sql(&self) -> String {
+        let column = self.order_by.column();
+        let (direction, comparison) = if self.descending {
+            ("DESC", "<")
+        } else {
+            ("ASC", ">")
+        };
+
+        // Without the cursor row's sort key it is looked up by id, so a page can resume
+        // from any user without the caller knowing the key's value
+        let key = match self.order_by {
+            UserOrder::CreatedAt => "$7::timestamptz",
+            _ => "$7::text",
+        };
+        let cursor = match self.order_by {
+            UserOrder::Id => format!("id {} $5", comparison),
+            _ => format!(
+                "({column}, id) {comparison} (COALESCE({key}, (SELECT {column} FROM users WHERE id = $5)), $5)",
+                column = column,
+                comparison = comparison,
+                key = key
+            ),
+        };
+
+        format!(
+            r#"
+            SELECT {columns} FROM users
+            WHERE ($1::text IS NULL OR role = $1)
+              AND ($2::text IS NULL OR email LIKE $2)
+              AND ($3::timestamptz IS NULL OR created_at >= $3)
+              AND ($4::timestamptz IS NULL OR created_at < $4)
+              AND ($5::uuid IS NULL OR {cursor})
+            ORDER BY {column} {direction}, id {direction}
+            LIMIT $6::bigint
+            "#,
+            columns = USER_COLUMNS,
+            cursor = cursor,
+            column = column,
+            direction = direction
+        )
+    }
+
+    /// Bind the cursor's sort key as $7, typed for the ordering's column
+    ///
+    /// A key of the wrong type is bound as NULL, so the key is looked up by id instead.
+    fn bind_key<'q>(
+        &'q self,
+        statement: QueryAs<'q, Postgres, User, PgArguments>,
+    ) -> QueryAs<'q, Postgres, User, PgArguments> {
+        match (self.order_by, &self.after_key) {
+            (UserOrder::Id, _) => statement,
+            (UserOrder::CreatedAt, Some(SortKey::Time(time))) => statement.bind(Some(*time)),
+            (UserOrder::CreatedAt, _) => statement.bind(None::<chrono::DateTime<chrono::Utc>>),
+            (_, Some(SortKey::Text(text))) => statement.bind(Some(text.as_str())),
+            (_, _) => statement.bind(None::<&str>),
+        }
+    }
+}
+
+/// Outcome of [`UserRepository::for_each`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListSummary {
+    /// Number of users passed to the callback
+    pub count: u64,
+    /// If the limit cut the listing short, the id to pass as `after` for the next page
+    pub next_after: Option<Uuid>,
+    /// The sort key to pass as `after_key` with `next_after` (absent when ordering by id)
+    pub next_key: Option<SortKey>,
+}
+
+/// Aurora DSQL rejects transactions that modify more than this many rows
+pub const MAX_TRANSACTION_ROWS: usize = 3000;
+
+/// Aurora DSQL rejects transactions that modify more than this much data
+pub const MAX_TRANSACTION_BYTES: usize = 10 * 1024 * 1024;
+
+/// Storage assumed per row on top of its column values when sizing batches
+const ROW_OVERHEAD_BYTES: usize = 64;
+
+/// A user to insert with [`UserRepository::insert_users_batch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewUser {
+    pub id: Uuid,
+    pub name: String,
+    pub email: String,
+    pub role: String,
+}
+
+/// Longest name, in characters, that fits the `VARCHAR(100)` column
+pub const MAX_NAME_LENGTH: usize = 100;
+
+/// Longest email address, in characters, that fits the `VARCHAR(100)` column
+pub const MAX_EMAIL_LENGTH: usize = 100;
+
+/// Longest role, in characters, that fits the `VARCHAR(50)` column
+pub const MAX_ROLE_LENGTH: usize = 50;
+
+impl NewUser {
+    /// Check the user against the constraints of the `users` table
+    ///
+    /// Returns:
+    ///   Ok, or every problem found, separated by "; "
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        let mut check_length = |field: &str, value: &str, max: usize| {
+            let length = value.chars().count();
+            if value.trim().is_empty() {
+                problems.push(format!("{} is empty", field));
+            } else if length > max {
+                problems.push(format!(
+                    "{} is {} characters long, at most {} are allowed",
+                    field, length, max
+                ));
+            }
+        };
+
+        check_length("name", &self.name, MAX_NAME_LENGTH);
+        check_length("email", &self.email, MAX_EMAIL_LENGTH);
+        check_length("role", &self.role, MAX_ROLE_LENGTH);
+        if !self.email.trim().is_empty() && !is_valid_email(&self.email) {
+            problems.push(format!("'{}' is not a valid email address", self.email));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; "))
+        }
+    }
+
+    /// Estimated size of the stored row: the column values (16-byte id, 8-byte
+    /// timestamp) plus a fixed per-row overhead
+    fn estimated_size(&self) -> usize {
+        16 + self.name.len() + self.email.len() + self.role.len() + 8 + ROW_OVERHEAD_BYTES
+    }
+}
+
+/// Whether an email address looks deliverable: `local@domain.tld` without whitespace
+fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+
+    !local.is_empty()
+        && !domain.contains('@')
+        && !email.chars().any(char::is_whitespace)
+        && domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty())
+}
+
+/// Split users into chunks that each fit in one Aurora DSQL transaction
+///
+/// A chunk holds at most `max_rows` users (capped at [`MAX_TRANSACTION_ROWS`]) whose
+/// estimated size stays under [`MAX_TRANSACTION_BYTES`].
+pub fn batch_chunks(users: &[NewUser], max_rows: usize) -> Vec<&[NewUser]> {
+    let max_rows = max_rows.clamp(1, MAX_TRANSACTION_ROWS);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+
+    for (i, user) in users.iter().enumerate() {
+        let size = user.estimated_size();
+        if i > start && (i - start == max_rows || bytes + size > MAX_TRANSACTION_BYTES) {
+            chunks.push(&users[start..i]);
+            start = i;
+            bytes = 0;
+        }
+        bytes += size;
+    }
+    if start < users.len() {
+        chunks.push(&users[start..]);
+    }
+
+    chunks
+}
+
+/// Outcome of [`UserRepository::insert_users_batch`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchInsert {
+    /// Ids of the users that were inserted
+    pub inserted: Vec<Uuid>,
+    /// Number of users skipped because their email or id was already taken
+    pub duplicates: u64,
+    /// Number of INSERT statements run, one per chunk
+    pub statements: u64,
+    /// Statement executions, including retries
+    pub attempts: u64,
+    /// Executions that failed with an OCC conflict
+    pub conflicts: u64,
+}
+
+/// Errors returned by [`UserRepository`]
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// A user with this email address already exists
+    DuplicateEmail(String),
+    /// A user with this id already exists (only possible when the caller picks the id)
+    DuplicateId(Uuid),
+    /// No user has this id
+    NotFound(Uuid),
+    /// The database operation failed (after retrying, if the error was retryable)
+    Database(RetryError),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::DuplicateEmail(email) => {
+                write!(f, "User with email '{}' already exists", email)
+            }
+            RepositoryError::DuplicateId(id) => write!(f, "User with ID '{}' already exists", id),
+            RepositoryError::NotFound(id) => write!(f, "User with ID '{}' not found", id),
+            RepositoryError::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for RepositoryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RepositoryError::Database(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<RetryError> for RepositoryError {
+    fn from(err: RetryError) -> Self {
+        RepositoryError::Database(err)
+    }
+}
+
+impl RepositoryError {
+    /// Error for a failed insert of `user_id`
+    ///
+    /// Inserts resolve email conflicts with `ON CONFLICT (email)`, so a unique violation
+    /// that still gets through is on the primary key: the id is taken.
+    fn from_insert(err: RetryError, user_id: Uuid) -> Self {
+        let duplicate_id = err
+            .source
+            .as_database_error()
+            .is_some_and(|db_err| db_err.is_unique_violation());
+        if duplicate_id {
+            RepositoryError::DuplicateId(user_id)
+        } else {
+            RepositoryError::Database(err)
+        }
+    }
+}
+
+/// Access to the `users` table, with every operation retried on OCC conflicts
+///
+/// Reads, updates and upserts are also retried when the connection is lost. Inserts and
+/// deletes are only retried when no connection could be had, since running them again
+/// after the connection dropped mid-statement could apply them twice (see
+/// [`retry_write_as`]).
+#[derive(Debug, Clone)]
+pub struct UserRepository {
+    pool: PgPool,
+    policy: RetryPolicy,
+}
+
+impl UserRepository {
+    /// Create a repository using the default retry policy
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Use a custom retry policy for all operations
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The underlying connection pool
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// The retry policy used for all operations
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Insert a new user
+    ///
+    /// Args:
+    ///   user_id: Primary key for the new row
+    ///   name: Display name
+    ///   email: Email address (must be unique)
+    ///   role: Role such as "Admin", "User" or "Manager"
+    ///
+    /// Returns:
+    ///   The inserted user, or `DuplicateEmail` if the email is taken and `DuplicateId`
+    ///   if the id is. If the connection was lost after the insert was sent, the user is
+    ///   looked up by id: finding it with this email means the insert committed, and
+    ///   otherwise the error (with [`RetryError::ambiguous`] set) is returned.
+    pub async fn insert(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        email: &str,
+        role: &str,
+    ) -> Result<User, RepositoryError> {
+        self.insert_retried(user_id, name, email, role)
+            .await
+            .map(|inserted| inserted.value)
+    }
+
+    /// Insert a new user, also reporting how many attempts it took
+    ///
+    /// Same as [`UserRepository::insert`], for callers such as the stress test that
+    /// measure retries and OCC conflicts.
+    #[instrument(level = "debug", skip_all, fields(id = %user_id))]
+    pub async fn insert_retried(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        email: &str,
+        role: &str,
+    ) -> Result<Retried<User>, RepositoryError> {
+        let query = format!(
+            r#"
+            INSERT INTO users (id, name, email, role)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (email) DO NOTHING
+            RETURNING {}
+            "#,
+            USER_COLUMNS
+        );
+
+        let query = &query;
+        let inserted = retry_write_as(
+            &self.pool,
+            &self.policy,
+            "insert_user",
+            |mut conn| async move {
+                sqlx::query_as::<_, User>(query)
+                    .bind(user_id)
+                    .bind(name)
+                    .bind(email)
+                    .bind(role)
+                    .fetch_optional(&mut *conn)
+                    .await
+            },
+        )
+        .await;
+
+        let inserted = match inserted {
+            Ok(inserted) => inserted,
+            Err(err) if err.ambiguous => {
+                return self.resolve_ambiguous_insert(err, user_id, email).await;
+            }
+            Err(err) => return Err(RepositoryError::from_insert(err, user_id)),
+        };
+
+        match inserted.value {
+            Some(user) => Ok(Retried {
+                value: user,
+                attempts: inserted.attempts,
+                conflicts: inserted.conflicts,
+            }),
+            None => Err(RepositoryError::DuplicateEmail(email.to_string())),
+        }
+    }
+
+    /// Settle an insert whose connection was lost after it was sent
+    ///
+    /// The id is the caller's own, so a row with this id and email can only be the one the
+    /// insert committed. Otherwise the insert's outcome stays unknown and the error is
+    /// returned.
+    async fn resolve_ambiguous_insert(
+        &self,
+        err: RetryError,
+        user_id: Uuid,
+        email: &str,
+    ) -> Result<Retried<User>, RepositoryError> {
+        match self.get_by_id(user_id).await {
+            Ok(Some(user)) if user.email == email => Ok(Retried {
+                value: user,
+                attempts: err.attempts,
+                conflicts: err.conflicts,
+            }),
+            _ => Err(RepositoryError::Database(err)),
+        }
+    }
+
+    /// Insert a user, or update the name and role of the user with the same email
+    ///
+    /// Running the same upsert twice leaves the table unchanged, which makes it safe for
+    /// seeding. `user_id` is only used when a new row is inserted; an existing user keeps
+    /// its id.
+    ///
+    /// Returns:
+    ///   The user as stored after the upsert
+    #[instrument(level = "debug", skip_all, fields(id = %user_id))]
+    pub async fn upsert(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        email: &str,
+        role: &str,
+    ) -> Result<User, RepositoryError> {
+        let query = format!(
+            r#"
+            INSERT INTO users (id, name, email, role)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (email) DO UPDATE SET name = EXCLUDED.name, role = EXCLUDED.role
+            RETURNING {}
+            "#,
+            USER_COLUMNS
+        );
+
+        let user = retry_as(&self.policy, "upsert_user", || async {
+            let mut conn = connection::acquire(&self.pool).await?;
+            sqlx::query_as::<_, User>(&query)
+                .bind(user_id)
+                .bind(name)
+                .bind(email)
+                .bind(role)
+                .fetch_one(&mut *conn)
+                .await
+        })
+        .await
+        .map_err(|err| RepositoryError::from_insert(err, user_id))?;
+
+        Ok(user.value)
+    }
+
+    /// Insert many users in as few statements as DSQL's transaction limits allow
+    ///
+    /// The users are split with [`batch_chunks`], and each chunk is inserted by a single
+    /// `INSERT ... SELECT * FROM UNNEST(...)` statement committing on its own. A chunk
+    /// failing with a retryable error is retried by itself, without repeating the chunks
+    /// already committed. Users whose email or id is already taken are skipped and counted
+    /// as duplicates rather than failing the batch.
+    ///
+    /// If a chunk fails for good, its error is returned and the chunks before it stay
+    /// inserted; callers that need to resume can run the chunks themselves with
+    /// [`UserRepository::insert_chunk_retried`].
+    pub async fn insert_users_batch(
+        &self,
+        users: &[NewUser],
+    ) -> Result<BatchInsert, RepositoryError> {
+        let mut batch = BatchInsert::default();
+
+        for chunk in batch_chunks(users, MAX_TRANSACTION_ROWS) {
+            let inserted = self.insert_chunk_retried(chunk).await?;
+            batch.duplicates += (chunk.len() - inserted.value.len()) as u64;
+            batch.inserted.extend(inserted.value);
+            batch.statements += 1;
+            batch.attempts += u64::from(inserted.attempts);
+            batch.conflicts += u64::from(inserted.conflicts);
+        }
+
+        Ok(batch)
+    }
+
+    /// Insert a chunk of users with one statement, also reporting how many attempts it took
+    ///
+    /// The chunk must fit in one transaction, as the chunks from [`batch_chunks`] do.
+    /// `ON CONFLICT DO NOTHING` has no conflict target, so a user clashing with an existing
+    /// row (or an earlier one in the chunk) on the email or the id is skipped instead of
+    /// failing the statement.
+    ///
+    /// Returns:
+    ///   The ids of the inserted users; users whose email or id is taken are left out.
+    ///   If the connection was lost after the statement was sent, the error has
+    ///   [`RetryError::ambiguous`] set: the chunk may have committed, and inserting it
+    ///   again would count its users as duplicates.
+    #[instrument(level = "debug", skip_all, fields(rows = users.len()))]
+    pub async fn insert_chunk_retried(
+        &self,
+        users: &[NewUser],
+    ) -> Result<Retried<Vec<Uuid>>, RepositoryError> {
+        let ids: Vec<Uuid> = users.iter().map(|user| user.id).collect();
+        let names: Vec<&str> = users.iter().map(|user| user.name.as_str()).collect();
+        let emails: Vec<&str> = users.iter().map(|user| user.email.as_str()).collect();
+        let roles: Vec<&str> = users.iter().map(|user| user.role.as_str()).collect();
+
+        let (ids, names, emails, roles) = (&ids, &names, &emails, &roles);
+        let inserted = retry_write_as(
+            &self.pool,
+            &self.policy,
+            "insert_users",
+            |mut conn| async move {
+                sqlx::query_scalar::<_, Uuid>(
+                    r#"
+                    INSERT INTO users (id, name, email, role)
+                    SELECT * FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::text[])
+                    ON CONFLICT DO NOTHING
+                    RETURNING id
+                    "#,
+                )
+                .bind(ids)
+                .bind(names)
+                .bind(emails)
+                .bind(roles)
+                .fetch_all(&mut *conn)
+                .await
+            },
+        )
+        .await?;
+
+        Ok(inserted)
+    }
+
+    /// Fetch a user by primary key
+    pub async fn get_by_id(&self, user_id: Uuid) -> Result<Option<User>, RepositoryError> {
+        self.get_by_id_retried(user_id).await.map(|user| user.value)
+    }
+
+    /// Fetch a user by primary key, also reporting how many attempts it took
+    #[instrument(level = "debug", skip_all, fields(id = %user_id))]
+    pub async fn get_by_id_retried(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Retried<Option<User>>, RepositoryError> {
+        let query = format!("SELECT {} FROM users WHERE id = $1", USER_COLUMNS);
+
+        let user = retry_as(&self.policy, "get_user", || async {
+            let mut conn = connection::acquire(&self.pool).await?;
+            sqlx::query_as::<_, User>(&query)
+                .bind(user_id)
+                .fetch_optional(&mut *conn)
+                .await
+        })
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Fetch a user by email address
+    #[instrument(level = "debug", skip_all)]
+    pub async fn get_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let query = format!("SELECT {} FROM users WHERE email = $1", USER_COLUMNS);
+
+        let user = retry_as(&self.policy, "get_user_by_email", || async {
+            let mut conn = connection::acquire(&self.pool).await?;
+            sqlx::query_as::<_, User>(&query)
+                .bind(email)
+                .fetch_optional(&mut *conn)
+                .await
+        })
+        .await?;
+
+        Ok(user.value)
+    }
+
+    /// Change a user's name and/or role
+    ///
+    /// Returns:
+    ///   The updated user, or `NotFound` if no user has this id
+    pub async fn update(
+        &self,
+        user_id: Uuid,
+        update: &UserUpdate,
+    ) -> Result<User, RepositoryError> {
+        self.update_retried(user_id, update)
+            .await
+            .map(|user| user.value)
+    }
+
+    /// Change a user's name and/or role, also reporting how many attempts it took
+    #[instrument(level = "debug", skip_all, fields(id = %user_id))]
+    pub async fn update_retried(
+        &self,
+        user_id: Uuid,
+        update: &UserUpdate,
+    ) -> Result<Retried<User>, RepositoryError> {
+        let query = format!(
+            r#"
+            UPDATE users
+            SET name = COALESCE($2, name), role = COALESCE($3, role)
+            WHERE id = $1
+            RETURNING {}
+            "#,
+            USER_COLUMNS
+        );
+
+        let updated = retry_as(&self.policy, "update_user", || async {
+            let mut conn = connection::acquire(&self.pool).await?;
+            sqlx::query_as::<_, User>(&query)
+                .bind(user_id)
+                .bind(update.name.as_deref())
+                .bind(update.role.as_deref())
+                .fetch_optional(&mut *conn)
+                .await
+        })
+        .await?;
+
+        match updated.value {
+            Some(user) => Ok(Retried {
+                value: user,
+                attempts: updated.attempts,
+                conflicts: updated.conflicts,
+            }),
+            None => Err(RepositoryError::NotFound(user_id)),
+        }
+    }
+
+    /// Delete a user
+    ///
+    /// Returns:
+    ///   Ok if the user was deleted, `NotFound` if no user has this id. A delete whose
+    ///   connection was lost after it was sent is not retried, as it would then report
+    ///   `NotFound` for the user it deleted; its error has [`RetryError::ambiguous`] set.
+    pub async fn delete(&self, user_id: Uuid) -> Result<(), RepositoryError> {
+        self.delete_retried(user_id)
+            .await
+            .map(|deleted| deleted.value)
+    }
+
+    /// Delete a user, also reporting how many attempts it took
+    #[instrument(level = "debug", skip_all, fields(id = %user_id))]
+    pub async fn delete_retried(&self, user_id: Uuid) -> Result<Retried<()>, RepositoryError> {
+        let result = retry_write_as(
+            &self.pool,
+            &self.policy,
+            "delete_user",
+            |mut conn| async move {
+                sqlx::query("DELETE FROM users WHERE id = $1")
+                    .bind(user_id)
+                    .execute(&mut *conn)
+                    .await
+            },
+        )
+        .await?;
+
+        if result.value.rows_affected() > 0 {
+            Ok(Retried {
+                value: (),
+                attempts: result.attempts,
+                conflicts: result.conflicts,
+            })
+        } else {
+            Err(RepositoryError::NotFound(user_id))
+        }
+    }
+
+    /// Count all users
+    pub async fn count(&self) -> Result<i64, RepositoryError> {
+        self.count_retried().await.map(|count| count.value)
+    }
+
+    /// Count all users, also reporting how many attempts it took
+    #[instrument(level = "debug", skip_all)]
+    pub async fn count_retried(&self) -> Result<Retried<i64>, RepositoryError> {
+        let count = retry_as(&self.policy, "count_users", || async {
+            let mut conn = connection::acquire(&self.pool).await?;
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users")
+                .fetch_one(&mut *conn)
+                .await
+        })
+        .await?;
+
+        Ok(count)
+    }
+
+    /// Fetch all users
+    #[instrument(level = "debug", skip_all)]
+    pub async fn list(&self) -> Result<Vec<User>, RepositoryError> {
+        let query = format!("SELECT {} FROM users", USER_COLUMNS);
+
+        let users = retry_as(&self.policy, "list_users", || async {
+            let mut conn = connection::acquire(&self.pool).await?;
+            sqlx::query_as::<_, User>(&query)
+                .fetch_all(&mut *conn)
+                .await
+        })
+        .await?;
+
+        Ok(users.value)
+    }
+
+    /// Stream the users matching `query` to a callback, one row at a time
+    ///
+    /// Rows are fetched with a cursor rather than collected, so memory use does not grow
+    /// with the size of the table. A failure before the first row is retried like any
+    /// other operation; once rows have been handed to the callback the error is returned
+    /// instead, since retrying would repeat them.
+    ///
+    /// Args:
+    ///   query: Filters, ordering and page bounds
+    ///   on_user: Called for every user; an error stops the listing and is returned
+    ///
+    /// Returns:
+    ///   How many users were listed and where the next page starts
+    #[instrument(level = "debug", skip_all, fields(order_by = ?query.order_by, limit = query.limit))]
+    pub async fn for_each<E>(
+        &self,
+        query: &UserQuery,
+        mut on_user: impl FnMut(User) -> Result<(), E>,
+    ) -> Result<ListSummary, E>
+    where
+        E: From<RepositoryError>,
+    {
+        if let (Some(after), None, UserOrder::CreatedAt | UserOrder::Email | UserOrder::Name) =
+            (query.after, &query.after_key, query.order_by)
+        {
+            // Otherwise the cursor subquery yields NULL and the listing is silently empty
+            if self.get_by_id(after).await?.is_none() {
+                return Err(RepositoryError::NotFound(after).into());
+            }
+        }
+
+        let sql = query.sql();
+        // One extra row tells whether there is a next page
+        let limit = query.limit.map(|limit| limit.max(0).saturating_add(1));
+        let mut attempts = Attempts::new(&self.policy, "list_users");
+
+        loop {
+            attempts.start();
+
+            let mut conn = match connection::acquire(&self.pool).await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    attempts.failed(err).await.map_err(RepositoryError::from)?;
+                    continue;
+                }
+            };
+            let rows = sqlx::query_as::<_, User>(&sql)
+                .bind(query.role.as_deref())
+                .bind(query.email_like.as_deref())
+                .bind(query.created_since)
+                .bind(query.created_until)
+                .bind(query.after)
+                .bind(limit);
+            let mut rows = query.bind_key(rows).fetch(&mut *conn);
+
+            let mut count = 0;
+            let mut last = None;
+            let err = loop {
+                match rows.try_next().await {
+                    Ok(Some(user)) => {
+                        if query
+                            .limit
+                            .is_some_and(|limit| count >= limit.max(0) as u64)
+                        {
+                            return Ok(attempts
+                                .succeeded(ListSummary {
+                                    count,
+                                    next_after: last.as_ref().map(|(id, _)| *id),
+                                    next_key: last.and_then(|(_, key)| key),
+                                })
+                                .value);
+                        }
+                        count += 1;
+                        last = Some((user.id, query.order_by.key(&user)));
+                        on_user(user)?;
+                    }
+                    Ok(None) => {
+                        return Ok(attempts
+                            .succeeded(ListSummary {
+                                count,
+                                next_after: None,
+                                next_key: None,
+                            })
+                            .value)
+                    }
+                    Err(err) => break err,
+                }
+            };
+
+            if count > 0 {
+                return Err(RepositoryError::from(attempts.give_up(err)).into());
+            }
+            attempts.failed(err).await.map_err(RepositoryError::from)?;
+        }
+    }
+
+    /// Fetch one page of users ordered by id
+    ///
+    /// Pages are addressed by keyset rather than offset: pass the id of the last user of
+    /// the previous page as `after` to get the next one.
+    ///
+    /// Args:
+    ///   after: Return only users whose id sorts after this one
+    ///   limit: Maximum number of users to return
+    #[instrument(level = "debug", skip_all, fields(limit))]
+    pub async fn list_page(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<User>, RepositoryError> {
+        let query = format!(
+            r#"
+            SELECT {} FROM users
+            WHERE $1::uuid IS NULL OR id > $1
+            ORDER BY id
+            LIMIT $2
+            "#,
+            USER_COLUMNS
+        );
+
+        let users = retry_as(&self.policy, "list_users_page", || async {
+            let mut conn = connection::acquire(&self.pool).await?;
+            sqlx::query_as::<_, User>(&query)
+                .bind(after)
+                .bind(limit)
+                .fetch_all(&mut *conn)
+                .await
+        })
+        .await?;
+
+        Ok(users.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// Numbers of the `$n` placeholders in `sql`
+    fn placeholders(sql: &str) -> BTreeSet<usize> {
+        sql.split('$')
+            .skip(1)
+            .filter_map(|rest| {
+                let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+                digits.parse().ok()
+            })
+            .collect()
+    }
+
+    fn new_users(count: usize, name_length: usize) -> Vec<NewUser> {
+        (0..count)
+            .map(|i| NewUser {
+                id: Uuid::new_v4(),
+                name: "n".repeat(name_length),
+                email: format!("user{}@example.com", i),
+                role: "User".to_string(),
+            })
+            .collect()
+    }
+
+    fn chunk_sizes(chunks: &[&[NewUser]]) -> Vec<usize> {
+        chunks.iter().map(|chunk| chunk.len()).collect()
+    }
+
+    #[test]
+    fn placeholders_match_the_binds() {
+        // `for_each` binds $1 to $6, and `bind_key` $7 unless ordering by id
+        for order_by in [
+            UserOrder::Id,
+            UserOrder::CreatedAt,
+            UserOrder::Email,
+            UserOrder::Name,
+        ] {
+            let sql = UserQuery {
+                order_by,
+                ..UserQuery::default()
+            }
+            .sql();
+            let binds = if order_by == UserOrder::Id { 6 } else { 7 };
+            assert_eq!(placeholders(&sql), (1..=binds).collect(), "{:?}", order_by);
+        }
+    }
+
+    #[test]
+    fn ordering_and_cursor_direction() {
+        let ascending = UserQuery {
+            order_by: UserOrder::Email,
+            ..UserQuery::default()
+        };
+        let sql = ascending.sql();
+        assert!(sql.contains("ORDER BY email ASC, id ASC"));
+        assert!(sql.contains("(email, id) > (COALESCE($7::text,"));
+
+        let descending = UserQuery {
+            order_by: UserOrder::CreatedAt,
+            descending: true,
+            ..UserQuery::default()
+        };
+        let sql = descending.sql();
+        assert!(sql.contains("ORDER BY created_at DESC, id DESC"));
+        assert!(sql.contains("(created_at, id) < (COALESCE($7::timestamptz,"));
+
+        let by_id = UserQuery {
+            descending: true,
+            ..UserQuery::default()
+        };
+        assert!(by_id.sql().contains("id < $5"));
+    }
+
+    #[test]
+    fn chunks_hold_at_most_max_rows() {
+        let users = new_users(10, 10);
+        assert_eq!(chunk_sizes(&batch_chunks(&users, 4)), [4, 4, 2]);
+        assert_eq!(chunk_sizes(&batch_chunks(&users, 10)), [10]);
+        assert_eq!(chunk_sizes(&batch_chunks(&users, 1)), [1; 10]);
+        // Zero is treated as one row per chunk
+        assert_eq!(chunk_sizes(&batch_chunks(&users, 0)), [1; 10]);
+    }
+
+    #[test]
+    fn chunks_never_exceed_the_row_limit() {
+        let users = new_users(MAX_TRANSACTION_ROWS * 2 + 1, 10);
+        assert_eq!(
+            chunk_sizes(&batch_chunks(&users, 10_000)),
+            [MAX_TRANSACTION_ROWS, MAX_TRANSACTION_ROWS, 1]
+        );
+    }
+
+    #[test]
+    fn chunks_never_exceed_the_byte_limit() {
+        // Rows of about 100 KiB, so about 100 fit in a transaction
+        let users = new_users(250, 100 * 1024);
+        let chunks = batch_chunks(&users, MAX_TRANSACTION_ROWS);
+        assert!(chunks.len() >= 3);
+        for chunk in &chunks {
+            let bytes: usize = chunk.iter().map(NewUser::estimated_size).sum();
+            assert!(bytes <= MAX_TRANSACTION_BYTES, "{} bytes", bytes);
+        }
+        assert_eq!(chunk_sizes(&chunks).iter().sum::<usize>(), users.len());
+
+        // A row too big for any transaction still gets a chunk of its own
+        let huge = new_users(2, MAX_TRANSACTION_BYTES);
+        assert_eq!(chunk_sizes(&batch_chunks(&huge, 10)), [1, 1]);
+    }
+
+    #[test]
+    fn no_users_make_no_chunks() {
+        assert!(batch_chunks(&[], 100).is_empty());
+    }
+}
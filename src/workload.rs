@@ -0,0 +1,379 @@
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A single kind of request a stress test worker can send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// Insert a new generated user
+    Insert,
+    /// Point read of an existing user by primary key
+    GetById,
+    /// Change the role of an existing user
+    UpdateRole,
+    /// Delete an existing user
+    Delete,
+    /// `SELECT COUNT(*)` over the whole table
+    Count,
+    /// Read an existing user's role and change it in one explicit transaction
+    ContendedUpdate,
+}
+
+impl Operation {
+    /// Whether the operation works on a user that already exists
+    pub fn needs_existing_user(self) -> bool {
+        matches!(
+            self,
+            Operation::GetById
+                | Operation::UpdateRole
+                | Operation::Delete
+                | Operation::ContendedUpdate
+        )
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Operation::Insert => "insert",
+            Operation::GetById => "get_by_id",
+            Operation::UpdateRole => "update_role",
+            Operation::Delete => "delete",
+            Operation::Count => "count",
+            Operation::ContendedUpdate => "contended_update",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Built-in workload mixes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WorkloadProfile {
+    /// Only inserts (the classic stress test)
+    #[default]
+    InsertOnly,
+    /// Mostly point reads with some updates, inserts and counts
+    ReadHeavy,
+    /// Mostly inserts and updates with some deletes and reads
+    WriteHeavy,
+    /// Updates and reads concentrated on a handful of rows
+    HotKeyContention,
+}
+
+impl WorkloadProfile {
+    /// The operation mix of this profile
+    pub fn workload(self) -> Workload {
+        use Operation::*;
+
+        let (name, weights, hot_keys): (&str, &[(Operation, u32)], _) = match self {
+            WorkloadProfile::InsertOnly => ("insert-only", &[(Insert, 1)], None),
+            WorkloadProfile::ReadHeavy => (
+                "read-heavy",
+                &[(GetById, 80), (UpdateRole, 10), (Insert, 5), (Count, 5)],
+                None,
+            ),
+            WorkloadProfile::WriteHeavy => (
+                "write-heavy",
+                &[(Insert, 50), (UpdateRole, 30), (Delete, 10), (GetById, 10)],
+                None,
+            ),
+            WorkloadProfile::HotKeyContention => (
+                "hot-key-contention",
+                &[(UpdateRole, 80), (GetById, 20)],
+                Some(10),
+            ),
+        };
+
+        Workload::new(name, weights.iter().copied(), hot_keys)
+            .expect("built-in workloads are valid")
+    }
+}
+
+/// A weighted mix of operations for the stress test
+#[derive(Debug, Clone)]
+pub struct Workload {
+    pub name: String,
+    /// Relative weight of each operation; operations with weight zero are left out
+    pub weights: Vec<(Operation, u32)>,
+    /// Restrict reads, updates and deletes to this many existing users
+    pub hot_keys: Option<usize>,
+    chooser: WeightedIndex<u32>,
+}
+
+impl Workload {
+    /// Create a workload from operation weights
+    ///
+    /// Returns:
+    ///   The workload, or `NoOperations` if no operation has a positive weight
+    pub fn new(
+        name: impl Into<String>,
+        weights: impl IntoIterator<Item = (Operation, u32)>,
+        hot_keys: Option<usize>,
+    ) -> Result<Self, WorkloadError> {
+        let weights: Vec<_> = weights
+            .into_iter()
+            .filter(|(_, weight)| *weight > 0)
+            .collect();
+        let chooser = WeightedIndex::new(weights.iter().map(|(_, weight)| *weight))
+            .map_err(|_| WorkloadError::NoOperations)?;
+
+        Ok(Self {
+            name: name.into(),
+            weights,
+            hot_keys: hot_keys.filter(|keys| *keys > 0),
+            chooser,
+        })
+    }
+
+    /// Load a custom workload from a TOML file
+    ///
+    /// ```toml
+    /// name = "checkout"
+    /// hot_keys = 50
+    ///
+    /// [weights]
+    /// get_by_id = 70
+    /// update_role = 20
+    /// insert = 9
+    /// count = 1
+    /// ```
+    pub fn from_file(path: &Path) -> Result<Self, WorkloadError> {
+        let contents = fs::read_to_string(path).map_err(|source| WorkloadError::ReadFile {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let file: WorkloadFile =
+            toml::from_str(&contents).map_err(|source| WorkloadError::ParseFile {
+                path: path.to_path_buf(),
+                source,
+            })?;
+
+        let name = file.name.unwrap_or_else(|| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "custom".to_string())
+        });
+        Self::new(name, file.weights, file.hot_keys)
+    }
+
+    /// Transactions that read and update a small set of hot users
+    ///
+    /// Under Aurora DSQL's optimistic concurrency control, transactions touching the same
+    /// row only conflict when they commit, so this measures conflict and retry behaviour.
+    pub fn contention(hot_rows: usize) -> Self {
+        Self::new(
+            "contention",
+            [(Operation::ContendedUpdate, 1)],
+            Some(hot_rows.max(1)),
+        )
+        .expect("contention workload is valid")
+    }
+
+    /// Whether any operation works on users that already exist
+    pub fn needs_existing_users(&self) -> bool {
+        self.weights
+            .iter()
+            .any(|(operation, _)| operation.needs_existing_user())
+    }
+
+    /// Pick the next operation according to the weights
+    pub fn choose<R: Rng + ?Sized>(&self, rng: &mut R) -> Operation {
+        self.weights[self.chooser.sample(rng)].0
+    }
+}
+
+/// On-disk layout of a workload file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WorkloadFile {
+    name: Option<String>,
+    hot_keys: Option<usize>,
+    weights: BTreeMap<Operation, u32>,
+}
+
+/// Problems loading a workload definition
+#[derive(Debug)]
+pub enum WorkloadError {
+    /// The workload file could not be read
+    ReadFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The workload file is not valid TOML or contains unknown keys
+    ParseFile {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    /// No operation has a positive weight
+    NoOperations,
+}
+
+impl fmt::Display for WorkloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkloadError::ReadFile { path, source } => {
+                write!(
+                    f,
+                    "cannot read workload file {}: {}",
+                    path.display(),
+                    source
+                )
+            }
+            WorkloadError::ParseFile { path, source } => {
+                write!(f, "invalid workload file {}: {}", path.display(), source)
+            }
+            WorkloadError::NoOperations => {
+                write!(f, "workload has no operation with a positive weight")
+            }
+        }
+    }
+}
+
+impl Error for WorkloadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WorkloadError::ReadFile { source, .. } => Some(source),
+            WorkloadError::ParseFile { source, .. } => Some(source),
+            WorkloadError::NoOperations => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use uuid::Uuid;
+
+    /// Write `contents` to a workload file of its own and load it
+    fn load(contents: &str) -> Result<Workload, WorkloadError> {
+        let path = std::env::temp_dir().join(format!("rust_dsql_workload_{}.toml", Uuid::new_v4()));
+        fs::write(&path, contents).unwrap();
+        let workload = Workload::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        workload
+    }
+
+    #[test]
+    fn loads_a_workload_file() {
+        let workload = load(
+            "name = \"checkout\"\nhot_keys = 50\n\n[weights]\nget_by_id = 70\ninsert = 30\ncount = 0\n",
+        )
+        .unwrap();
+        assert_eq!(workload.name, "checkout");
+        assert_eq!(workload.hot_keys, Some(50));
+        assert_eq!(
+            workload.weights,
+            [(Operation::Insert, 30), (Operation::GetById, 70)]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        for contents in [
+            "nmae = \"typo\"\n[weights]\ninsert = 1\n",
+            "[weights]\ninsert = 1\nupsert = 1\n",
+        ] {
+            assert!(
+                matches!(load(contents), Err(WorkloadError::ParseFile { .. })),
+                "{}",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn needs_an_operation_with_a_positive_weight() {
+        assert!(matches!(
+            load("[weights]\ninsert = 0\ncount = 0\n"),
+            Err(WorkloadError::NoOperations)
+        ));
+        assert!(matches!(
+            Workload::new("empty", [], None),
+            Err(WorkloadError::NoOperations)
+        ));
+    }
+
+    fn assert_profile(
+        profile: WorkloadProfile,
+        name: &str,
+        weights: &[(Operation, u32)],
+        hot_keys: Option<usize>,
+    ) {
+        let workload = profile.workload();
+        assert_eq!(workload.name, name);
+        assert_eq!(workload.weights, weights);
+        assert_eq!(workload.hot_keys, hot_keys);
+        assert_eq!(
+            workload.needs_existing_users(),
+            profile != WorkloadProfile::InsertOnly
+        );
+    }
+
+    #[test]
+    fn built_in_profiles() {
+        use Operation::*;
+
+        assert_profile(
+            WorkloadProfile::InsertOnly,
+            "insert-only",
+            &[(Insert, 1)],
+            None,
+        );
+        assert_profile(
+            WorkloadProfile::ReadHeavy,
+            "read-heavy",
+            &[(GetById, 80), (UpdateRole, 10), (Insert, 5), (Count, 5)],
+            None,
+        );
+        assert_profile(
+            WorkloadProfile::WriteHeavy,
+            "write-heavy",
+            &[(Insert, 50), (UpdateRole, 30), (Delete, 10), (GetById, 10)],
+            None,
+        );
+        assert_profile(
+            WorkloadProfile::HotKeyContention,
+            "hot-key-contention",
+            &[(UpdateRole, 80), (GetById, 20)],
+            Some(10),
+        );
+    }
+
+    #[test]
+    fn choose_follows_the_weights() {
+        let workload = Workload::new(
+            "mix",
+            [
+                (Operation::GetById, 70),
+                (Operation::Insert, 20),
+                (Operation::Count, 10),
+                (Operation::Delete, 0),
+            ],
+            None,
+        )
+        .unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut counts = BTreeMap::<Operation, u32>::new();
+        for _ in 0..10_000 {
+            *counts.entry(workload.choose(&mut rng)).or_default() += 1;
+        }
+
+        assert_eq!(counts.get(&Operation::Delete), None);
+        for (operation, share) in [
+            (Operation::GetById, 0.7),
+            (Operation::Insert, 0.2),
+            (Operation::Count, 0.1),
+        ] {
+            let actual = f64::from(counts[&operation]) / 10_000.0;
+            assert!((actual - share).abs() < 0.02, "{}: {}", operation, actual);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Helpers shared by the integration tests
+//!
+//! Tests that need a database connect to `DATABASE_URL`, e.g. a local Postgres started
+//! with `docker run -e POSTGRES_HOST_AUTH_METHOD=trust -p 5432:5432 postgres`:
+//!
+//! ```text
+//! DATABASE_URL=postgres://postgres@localhost:5432/postgres cargo test
+//! ```
+//!
+//! Without it they print a note and pass, so `cargo test` works offline.
+#![allow(dead_code)]
+
+use rust_dsql::migrate;
+use rust_dsql::retry::RetryPolicy;
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
+use sqlx::types::uuid::Uuid;
+use std::time::Duration;
+
+/// Environment variable with the connection string of the test database
+pub const DATABASE_URL_ENV: &str = "DATABASE_URL";
+
+/// Tests in one binary run in parallel; only one of them should apply the migrations
+static MIGRATE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+fn database_url() -> Option<String> {
+    match std::env::var(DATABASE_URL_ENV) {
+        Ok(url) if !url.is_empty() => Some(url),
+        _ => {
+            eprintln!("{} is not set, skipping", DATABASE_URL_ENV);
+            None
+        }
+    }
+}
+
+/// A pool for the test database with the schema migrated, or `None` to skip the test
+pub async fn pool() -> Option<PgPool> {
+    let pool = connect(&database_url()?).await;
+
+    let _guard = MIGRATE.lock().await;
+    migrate::up(&pool, &RetryPolicy::default(), false)
+        .await
+        .expect("migrate the test database");
+
+    Some(pool)
+}
+
+async fn connect(url: &str) -> PgPool {
+    PgPoolOptions::new()
+        .max_connections(5)
+        .connect(url)
+        .await
+        .expect("connect to DATABASE_URL")
+}
+
+/// An empty database of its own, for tests that change the schema
+///
+/// Call [`ScratchDatabase::drop`] at the end of the test; a failed test leaves the
+/// database behind for inspection.
+pub struct ScratchDatabase {
+    pub pool: PgPool,
+    name: String,
+    options: PgConnectOptions,
+    admin: PgPool,
+}
+
+impl ScratchDatabase {
+    /// Create the database, or return `None` to skip the test
+    pub async fn create() -> Option<Self> {
+        let url = database_url()?;
+        let admin = connect(&url).await;
+        let name = format!("rust_dsql_test_{}", Uuid::new_v4().simple());
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&admin)
+            .await
+            .expect("create a scratch database");
+
+        let options = url
+            .parse::<PgConnectOptions>()
+            .expect("DATABASE_URL is a valid connection string")
+            .database(&name);
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect_with(options.clone())
+            .await
+            .expect("connect to the scratch database");
+
+        Some(Self {
+            pool,
+            name,
+            options,
+            admin,
+        })
+    }
+
+    /// Options for connecting to the database, e.g. for a pool with other settings
+    pub fn connect_options(&self) -> PgConnectOptions {
+        self.options.clone()
+    }
+
+    /// Close the pool and drop the database; `FORCE` because backends exit asynchronously
+    /// after their connections close
+    pub async fn drop(self) {
+        self.pool.close().await;
+        sqlx::query(&format!(
+            "DROP DATABASE IF EXISTS {} WITH (FORCE)",
+            self.name
+        ))
+        .execute(&self.admin)
+        .await
+        .expect("drop the scratch database");
+    }
+}
+
+/// A pool whose connections always fail: nothing listens on port 1
+pub fn unreachable_pool() -> PgPool {
+    PgPoolOptions::new()
+        .acquire_timeout(Duration::from_millis(200))
+        .connect_lazy("postgres://postgres@127.0.0.1:1/postgres")
+        .expect("valid connection string")
+}
+
+/// A retry policy that gives up quickly, for tests of exhausted retries
+pub fn fast_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 2,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+        multiplier: 1.0,
+        jitter: false,
+    }
+}
+
+/// An email address no other test uses
+pub fn unique_email() -> String {
+    format!("{}@example.com", Uuid::new_v4().simple())
+}
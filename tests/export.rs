@@ -0,0 +1,155 @@
+//! Paged exports: resuming after users deleted mid-export, and cleaning up after failures
+
+mod common;
+
+use rust_dsql::export::{export_users, Compression, ExportFormat, ExportOptions};
+use rust_dsql::users::{RepositoryError, User, UserOrder, UserQuery, UserRepository};
+use sqlx::types::uuid::Uuid;
+use std::fs;
+use std::path::PathBuf;
+
+fn scratch_file(extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rust_dsql_export_{}.{}", Uuid::new_v4(), extension))
+}
+
+/// Insert `count` users with a role of their own, returning the role
+async fn insert_users(repo: &UserRepository, count: usize) -> String {
+    let role = format!("Export{}", Uuid::new_v4().simple());
+    for i in 0..count {
+        repo.insert(
+            Uuid::new_v4(),
+            &format!("User {}", i),
+            &common::unique_email(),
+            &role,
+        )
+        .await
+        .unwrap();
+    }
+    role
+}
+
+async fn page(repo: &UserRepository, query: &UserQuery) -> Result<Vec<User>, RepositoryError> {
+    let mut users = Vec::new();
+    repo.for_each(query, |user| {
+        users.push(user);
+        Ok::<_, RepositoryError>(())
+    })
+    .await?;
+    Ok(users)
+}
+
+#[tokio::test]
+async fn pages_continue_after_a_deleted_user() {
+    let Some(pool) = common::pool().await else {
+        return;
+    };
+    let repo = UserRepository::new(pool);
+    let role = insert_users(&repo, 4).await;
+
+    for order_by in [UserOrder::Email, UserOrder::Name, UserOrder::CreatedAt] {
+        let mut query = UserQuery {
+            role: Some(role.clone()),
+            order_by,
+            limit: Some(2),
+            ..UserQuery::default()
+        };
+        let all = page(
+            &repo,
+            &UserQuery {
+                limit: None,
+                ..query.clone()
+            },
+        )
+        .await
+        .unwrap();
+
+        let mut first = Vec::new();
+        let listed = repo
+            .for_each(&query, |user| {
+                first.push(user);
+                Ok::<_, RepositoryError>(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(first, all[..2]);
+        assert_eq!(listed.next_after, Some(all[1].id));
+        assert!(listed.next_key.is_some());
+
+        // Temporarily remove the cursor row
+        let cursor = &all[1];
+        repo.delete(cursor.id).await.unwrap();
+
+        query.after = listed.next_after;
+        let err = page(&repo, &query).await.unwrap_err();
+        assert!(matches!(err, RepositoryError::NotFound(id) if id == cursor.id));
+
+        query.after_key = listed.next_key;
+        assert_eq!(page(&repo, &query).await.unwrap(), all[2..]);
+
+        repo.insert(cursor.id, &cursor.name, &cursor.email, &cursor.role)
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn exports_every_page_in_order() {
+    let Some(pool) = common::pool().await else {
+        return;
+    };
+    let repo = UserRepository::new(pool);
+    let role = insert_users(&repo, 5).await;
+    let path = scratch_file("ndjson");
+    let options = ExportOptions {
+        format: ExportFormat::Ndjson,
+        compression: Compression::None,
+        page_size: 2,
+        query: UserQuery {
+            role: Some(role),
+            order_by: UserOrder::Email,
+            descending: true,
+            ..UserQuery::default()
+        },
+    };
+
+    let manifest = export_users(&repo, &path, &options, |_| {}).await.unwrap();
+    assert_eq!((manifest.rows, manifest.pages), (5, 3));
+
+    let emails: Vec<String> = fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let user: serde_json::Value = serde_json::from_str(line).unwrap();
+            user["email"].as_str().unwrap().to_string()
+        })
+        .collect();
+    let mut sorted = emails.clone();
+    sorted.sort_by(|a, b| b.cmp(a));
+    assert_eq!(emails, sorted);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(rust_dsql::export::Manifest::path_for(&path)).unwrap();
+}
+
+#[tokio::test]
+async fn failed_exports_leave_no_partial_file() {
+    // Needs no database: every connection attempt fails
+    let repo =
+        UserRepository::new(common::unreachable_pool()).with_retry_policy(common::fast_policy());
+    let path = scratch_file("csv");
+    let options = ExportOptions {
+        format: ExportFormat::Csv,
+        compression: Compression::None,
+        page_size: 10,
+        query: UserQuery::default(),
+    };
+
+    export_users(&repo, &path, &options, |_| {})
+        .await
+        .unwrap_err();
+
+    let mut partial = path.clone().into_os_string();
+    partial.push(".partial");
+    assert!(!PathBuf::from(partial).exists());
+    assert!(!path.exists());
+}
@@ -0,0 +1,124 @@
+//! Importing users whose email or id is already taken, and resuming an interrupted import
+
+mod common;
+
+use rust_dsql::import::{import_users, ImportError, ImportFormat, ImportOptions};
+use rust_dsql::users::UserRepository;
+use serde_json::{json, Value};
+use sqlx::types::uuid::Uuid;
+use std::fs;
+use std::path::Path;
+
+/// Lines of the import file that were rejected, in the order they were written
+fn reject_lines(path: &Path) -> Vec<u64> {
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<Value>(line).unwrap()["line"]
+                .as_u64()
+                .unwrap()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn taken_emails_and_ids_are_rejected() {
+    let Some(pool) = common::pool().await else {
+        return;
+    };
+    let repo = UserRepository::new(pool);
+    let existing = repo
+        .insert(Uuid::new_v4(), "Existing", &common::unique_email(), "User")
+        .await
+        .unwrap();
+    let repeated = Uuid::new_v4();
+
+    let records = [
+        json!({ "name": "New", "email": common::unique_email() }),
+        json!({ "name": "Same email", "email": existing.email }),
+        json!({ "id": existing.id, "name": "Same id", "email": common::unique_email() }),
+        json!({ "id": repeated, "name": "First", "email": common::unique_email() }),
+        json!({ "id": repeated, "name": "Second", "email": common::unique_email() }),
+        json!({ "name": "", "email": "invalid" }),
+    ];
+    let path = std::env::temp_dir().join(format!("rust_dsql_import_{}.ndjson", Uuid::new_v4()));
+    let lines: Vec<String> = records.iter().map(Value::to_string).collect();
+    fs::write(&path, lines.join("\n")).unwrap();
+    let options = ImportOptions::for_file(&path, ImportFormat::Ndjson);
+
+    let summary = import_users(&repo, &path, &options, |_| {}).await.unwrap();
+    assert_eq!(summary.records, 6);
+    assert_eq!(summary.inserted, 2);
+    assert_eq!(summary.duplicates, 3);
+    assert_eq!(summary.invalid, 1);
+    assert!(!options.checkpoint.exists());
+    assert_eq!(reject_lines(&options.rejects), [6, 2, 3, 5]);
+
+    let second = repo.get_by_id(repeated).await.unwrap().unwrap();
+    assert_eq!(second.name, "First");
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&options.rejects).unwrap();
+}
+
+#[tokio::test]
+async fn an_interrupted_import_resumes_from_its_checkpoint() {
+    let Some(pool) = common::pool().await else {
+        return;
+    };
+    let repo = UserRepository::new(pool);
+    let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+
+    // Chunks of two records, a valid one followed by an invalid one
+    let records = [
+        json!({ "id": ids[0], "name": "First", "email": common::unique_email() }),
+        json!({ "name": "", "email": "invalid" }),
+        json!({ "id": ids[1], "name": "Second", "email": common::unique_email() }),
+        json!({ "name": "", "email": "invalid" }),
+        json!({ "id": ids[2], "name": "Third", "email": common::unique_email() }),
+    ];
+    let path = std::env::temp_dir().join(format!("rust_dsql_import_{}.ndjson", Uuid::new_v4()));
+    let lines: Vec<String> = records.iter().map(Value::to_string).collect();
+    fs::write(&path, lines.join("\n")).unwrap();
+    let options = ImportOptions {
+        chunk_size: 2,
+        ..ImportOptions::for_file(&path, ImportFormat::Ndjson)
+    };
+
+    // The first run dies once its first chunk is checkpointed
+    let interrupted = tokio::spawn({
+        let (repo, path, options) = (repo.clone(), path.clone(), options.clone());
+        async move { import_users(&repo, &path, &options, |_| panic!("interrupted")).await }
+    })
+    .await;
+    assert!(interrupted.unwrap_err().is_panic());
+    assert!(options.checkpoint.exists());
+    assert_eq!(reject_lines(&options.rejects), [2]);
+
+    // Without --resume the checkpoint is refused and the rejects are left alone
+    let err = import_users(&repo, &path, &options, |_| {})
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ImportError::CheckpointExists(_)), "{}", err);
+    assert_eq!(reject_lines(&options.rejects), [2]);
+
+    let resume = ImportOptions {
+        resume: true,
+        ..options.clone()
+    };
+    let summary = import_users(&repo, &path, &resume, |_| {}).await.unwrap();
+    assert_eq!(summary.resumed_after, 2);
+    assert_eq!(summary.records, 5);
+    assert_eq!((summary.inserted, summary.duplicates), (3, 0));
+    assert_eq!(summary.invalid, 2);
+    assert!(!options.checkpoint.exists());
+    assert_eq!(reject_lines(&options.rejects), [2, 4]);
+
+    for id in ids {
+        assert!(repo.get_by_id(id).await.unwrap().is_some());
+        repo.delete(id).await.unwrap();
+    }
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&options.rejects).unwrap();
+}
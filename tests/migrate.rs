@@ -0,0 +1,109 @@
+//! The migration runner against a scratch database: status, dry runs, applying,
+//! checksum mismatches and concurrent runs
+
+mod common;
+
+use rust_dsql::migrate::{self, MigrationError, MIGRATIONS};
+use rust_dsql::retry::RetryPolicy;
+use sqlx::PgPool;
+
+async fn tracking_table_exists(pool: &PgPool) -> bool {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT FROM information_schema.tables WHERE table_name = 'schema_migrations')",
+    )
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn status_dry_run_and_up() {
+    let Some(db) = common::ScratchDatabase::create().await else {
+        return;
+    };
+    let policy = RetryPolicy::default();
+
+    let status = migrate::status(&db.pool, &policy).await.unwrap();
+    assert_eq!(status.len(), MIGRATIONS.len());
+    assert!(status.iter().all(|status| status.applied_at.is_none()));
+    assert!(!tracking_table_exists(&db.pool).await);
+
+    let pending = migrate::up(&db.pool, &policy, true).await.unwrap();
+    assert_eq!(pending.len(), MIGRATIONS.len());
+    assert!(!tracking_table_exists(&db.pool).await);
+
+    let applied = migrate::up(&db.pool, &policy, false).await.unwrap();
+    let versions: Vec<i64> = applied.iter().map(|a| a.migration.version).collect();
+    let expected: Vec<i64> = MIGRATIONS.iter().map(|m| m.version).collect();
+    assert_eq!(versions, expected);
+
+    let status = migrate::status(&db.pool, &policy).await.unwrap();
+    assert!(status
+        .iter()
+        .all(|status| status.applied_at.is_some() && status.checksum_matches));
+
+    assert!(migrate::up(&db.pool, &policy, false)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(migrate::up(&db.pool, &policy, true)
+        .await
+        .unwrap()
+        .is_empty());
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn modified_migrations_are_detected() {
+    let Some(db) = common::ScratchDatabase::create().await else {
+        return;
+    };
+    let policy = RetryPolicy::default();
+    migrate::up(&db.pool, &policy, false).await.unwrap();
+
+    sqlx::query("UPDATE schema_migrations SET checksum = 'modified' WHERE version = $1")
+        .bind(MIGRATIONS[0].version)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+    let status = migrate::status(&db.pool, &policy).await.unwrap();
+    assert!(!status[0].checksum_matches);
+
+    let err = migrate::up(&db.pool, &policy, false).await.unwrap_err();
+    assert!(
+        matches!(err, MigrationError::ChecksumMismatch { version, .. } if version == MIGRATIONS[0].version),
+        "{}",
+        err
+    );
+    let err = migrate::up(&db.pool, &policy, true).await.unwrap_err();
+    assert!(matches!(err, MigrationError::ChecksumMismatch { .. }));
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn concurrent_runs_apply_each_migration_once() {
+    let Some(db) = common::ScratchDatabase::create().await else {
+        return;
+    };
+    let policy = RetryPolicy::default();
+
+    let run = || migrate::up(&db.pool, &policy, false);
+    let results = tokio::join!(run(), run(), run(), run());
+
+    let applied: usize = [results.0, results.1, results.2, results.3]
+        .into_iter()
+        .map(|result| result.unwrap().len())
+        .sum();
+    assert_eq!(applied, MIGRATIONS.len());
+
+    let recorded: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM schema_migrations")
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+    assert_eq!(recorded, MIGRATIONS.len() as i64);
+
+    db.drop().await;
+}
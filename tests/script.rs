@@ -0,0 +1,58 @@
+//! Which failures of a script's steps are retried: queries after any lost connection,
+//! other statements only when they were never sent
+
+mod common;
+
+use rust_dsql::retry::{ErrorClass, RetryPolicy};
+use rust_dsql::script::{run_step, Script, ScriptError, Step};
+
+fn step(sql: &str) -> Step {
+    Script::parse(sql).unwrap().steps.remove(0)
+}
+
+fn policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        ..common::fast_policy()
+    }
+}
+
+fn failed(err: ScriptError) -> rust_dsql::retry::RetryError {
+    match err {
+        ScriptError::Failed { source, .. } => source,
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[tokio::test]
+async fn writes_are_not_retried_after_their_backend_dies() {
+    let Some(pool) = common::pool().await else {
+        return;
+    };
+
+    let insert = step(
+        "INSERT INTO users (id, name, email, role) \
+         SELECT gen_random_uuid(), 'Killed', 'killed@example.com', 'User' \
+         WHERE pg_terminate_backend(pg_backend_pid())",
+    );
+    let err = failed(run_step(&pool, &policy(), &insert).await.unwrap_err());
+    assert_eq!((err.class, err.attempts), (ErrorClass::Connection, 1));
+    assert!(err.ambiguous);
+
+    let query = step("SELECT pg_terminate_backend(pg_backend_pid())");
+    let err = failed(run_step(&pool, &policy(), &query).await.unwrap_err());
+    assert_eq!((err.class, err.attempts), (ErrorClass::Connection, 3));
+    assert!(!err.ambiguous);
+}
+
+#[tokio::test]
+async fn writes_are_retried_while_no_connection_can_be_had() {
+    // Needs no database: every connection attempt fails
+    let pool = common::unreachable_pool();
+
+    for sql in ["DELETE FROM users", "CREATE TABLE t (id INT)", "SELECT 1"] {
+        let err = failed(run_step(&pool, &policy(), &step(sql)).await.unwrap_err());
+        assert_eq!(err.attempts, 3, "{}", sql);
+        assert!(err.is_exhausted() && !err.ambiguous, "{}", sql);
+    }
+}
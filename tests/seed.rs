@@ -0,0 +1,82 @@
+//! Seeding and clearing the users table of a scratch database
+
+mod common;
+
+use rust_dsql::migrate;
+use rust_dsql::retry::RetryPolicy;
+use rust_dsql::seed::{seed_sample_users, truncate_users, SAMPLE_USERS};
+use rust_dsql::users::{User, UserRepository};
+use sqlx::types::uuid::Uuid;
+
+async fn all_users(db: &common::ScratchDatabase) -> Vec<User> {
+    sqlx::query_as("SELECT id, name, email, role, created_at FROM users ORDER BY email")
+        .fetch_all(&db.pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn seeding_twice_leaves_the_same_rows() {
+    let Some(db) = common::ScratchDatabase::create().await else {
+        return;
+    };
+    let policy = RetryPolicy::default();
+    migrate::up(&db.pool, &policy, false).await.unwrap();
+    let repo = UserRepository::new(db.pool.clone());
+    let other = repo
+        .insert(Uuid::new_v4(), "Other", &common::unique_email(), "User")
+        .await
+        .unwrap();
+
+    let first = seed_sample_users(&repo).await.unwrap();
+    assert_eq!(first.len(), SAMPLE_USERS.len());
+    assert!(first.iter().all(|seeded| seeded.created));
+    let seeded = all_users(&db).await;
+    assert_eq!(seeded.len(), SAMPLE_USERS.len() + 1);
+
+    let second = seed_sample_users(&repo).await.unwrap();
+    assert!(second.iter().all(|seeded| !seeded.created));
+    // Same ids, names, roles and creation times, and the other user untouched
+    assert_eq!(all_users(&db).await, seeded);
+    assert!(seeded.contains(&other));
+
+    db.drop().await;
+}
+
+#[tokio::test]
+async fn truncating_deletes_in_batches_until_no_rows_are_left() {
+    let Some(db) = common::ScratchDatabase::create().await else {
+        return;
+    };
+    let policy = RetryPolicy::default();
+    migrate::up(&db.pool, &policy, false).await.unwrap();
+    let repo = UserRepository::new(db.pool.clone());
+    for i in 0..25 {
+        repo.insert(
+            Uuid::new_v4(),
+            &format!("User {}", i),
+            &common::unique_email(),
+            "User",
+        )
+        .await
+        .unwrap();
+    }
+
+    let mut batches = Vec::new();
+    let deleted = truncate_users(&db.pool, &policy, 10, |total| batches.push(total))
+        .await
+        .unwrap();
+    assert_eq!(deleted, 25);
+    assert_eq!(batches, [10, 20, 25]);
+    assert!(all_users(&db).await.is_empty());
+
+    // An empty table takes one DELETE and no batches
+    let mut batches = Vec::new();
+    let deleted = truncate_users(&db.pool, &policy, 10, |total| batches.push(total))
+        .await
+        .unwrap();
+    assert_eq!(deleted, 0);
+    assert!(batches.is_empty());
+
+    db.drop().await;
+}
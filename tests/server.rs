@@ -0,0 +1,255 @@
+//! The REST API's routes and status codes, called through `server::router`
+
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::Router;
+use rust_dsql::server;
+use rust_dsql::users::UserRepository;
+use serde_json::{json, Value};
+use sqlx::types::uuid::Uuid;
+use tower::ServiceExt;
+
+/// Send a request to the router, returning the status, headers and JSON body (`Null` if
+/// the body is empty, a string if it is not JSON)
+async fn send(
+    app: &Router,
+    method: &str,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, HeaderMap, Value) {
+    let request = Request::builder().method(method).uri(uri);
+    let request = match body {
+        Some(body) => request
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    }
+    .unwrap();
+
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body = if bytes.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()))
+    };
+
+    (status, headers, body)
+}
+
+async fn app() -> Option<Router> {
+    let pool = common::pool().await?;
+    Some(server::router(UserRepository::new(pool)))
+}
+
+#[tokio::test]
+async fn create_get_update_delete() {
+    let Some(app) = app().await else { return };
+    let email = common::unique_email();
+
+    let (status, headers, created) = send(
+        &app,
+        "POST",
+        "/users",
+        Some(json!({ "name": "Ada Lovelace", "email": email })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["role"], "User");
+    let id = created["id"].as_str().unwrap().to_string();
+    assert_eq!(headers[header::LOCATION], format!("/users/{}", id));
+
+    let (status, _, user) = send(&app, "GET", &format!("/users/{}", id), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(user["email"], email.as_str());
+
+    let (status, _, user) = send(
+        &app,
+        "PATCH",
+        &format!("/users/{}", id),
+        Some(json!({ "role": "Admin" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(user["role"], "Admin");
+    assert_eq!(user["name"], "Ada Lovelace");
+
+    let (status, _, body) = send(&app, "DELETE", &format!("/users/{}", id), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(body, Value::Null);
+
+    let (status, _, _) = send(&app, "GET", &format!("/users/{}", id), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn duplicate_email_is_conflict() {
+    let Some(app) = app().await else { return };
+    let user = json!({ "name": "Grace Hopper", "email": common::unique_email() });
+
+    let (status, _, _) = send(&app, "POST", "/users", Some(user.clone())).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, _, body) = send(&app, "POST", "/users", Some(user)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body["error"].as_str().unwrap().contains("already"));
+}
+
+#[tokio::test]
+async fn duplicate_id_is_conflict() {
+    let Some(app) = app().await else { return };
+    let id = Uuid::new_v4();
+
+    let (status, _, _) = send(
+        &app,
+        "POST",
+        "/users",
+        Some(json!({ "id": id, "name": "First", "email": common::unique_email() })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, _, body) = send(
+        &app,
+        "POST",
+        "/users",
+        Some(json!({ "id": id, "name": "Second", "email": common::unique_email() })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body["error"].as_str().unwrap().contains(&id.to_string()));
+}
+
+#[tokio::test]
+async fn invalid_requests_are_unprocessable() {
+    let Some(app) = app().await else { return };
+
+    let (status, _, body) = send(
+        &app,
+        "POST",
+        "/users",
+        Some(json!({ "name": " ", "email": "not-an-email" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let error = body["error"].as_str().unwrap();
+    assert!(error.contains("name is empty"), "{}", error);
+    assert!(error.contains("not a valid email"), "{}", error);
+
+    let (status, _, _) = send(&app, "GET", "/users?limit=0", None).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let (status, _, _) = send(&app, "GET", "/users?limit=1001", None).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let id = Uuid::new_v4();
+    let (status, _, _) = send(&app, "PATCH", &format!("/users/{}", id), Some(json!({}))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn missing_users_are_not_found() {
+    let Some(app) = app().await else { return };
+    let id = Uuid::new_v4();
+
+    let (status, _, _) = send(&app, "GET", &format!("/users/{}", id), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _, _) = send(
+        &app,
+        "PATCH",
+        &format!("/users/{}", id),
+        Some(json!({ "name": "Nobody" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _, body) = send(&app, "DELETE", &format!("/users/{}", id), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(body["error"].is_string());
+}
+
+#[tokio::test]
+async fn list_users_pages_by_cursor() {
+    let Some(app) = app().await else { return };
+    let role = format!("Pager{}", Uuid::new_v4().simple());
+    for _ in 0..3 {
+        let (status, _, _) = send(
+            &app,
+            "POST",
+            "/users",
+            Some(json!({ "name": "Paged", "email": common::unique_email(), "role": role })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    let (status, _, first) =
+        send(&app, "GET", &format!("/users?role={}&limit=2", role), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(first["users"].as_array().unwrap().len(), 2);
+    let after = first["next_after"].as_str().unwrap();
+
+    let (status, _, second) = send(
+        &app,
+        "GET",
+        &format!("/users?role={}&limit=2&after={}", role, after),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(second["users"].as_array().unwrap().len(), 1);
+    assert!(second.get("next_after").is_none());
+}
+
+#[tokio::test]
+async fn stats_health_and_metrics() {
+    let Some(app) = app().await else { return };
+
+    let (status, _, stats) = send(&app, "GET", "/users/stats", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(stats.is_object());
+
+    let (status, _, body) = send(&app, "GET", "/healthz", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "status": "ok" }));
+
+    let (status, _, body) = send(&app, "GET", "/readyz", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "ok");
+
+    let (status, _, body) = send(&app, "GET", "/metrics", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.as_str().unwrap().contains("dsql_operations_total"));
+}
+
+#[tokio::test]
+async fn unreachable_database_is_unavailable() {
+    // Needs no database: every connection attempt fails
+    let repo =
+        UserRepository::new(common::unreachable_pool()).with_retry_policy(common::fast_policy());
+    let app = server::router(repo);
+
+    let (status, headers, body) =
+        send(&app, "GET", &format!("/users/{}", Uuid::new_v4()), None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(headers[header::RETRY_AFTER], "1");
+    assert!(body["error"]
+        .as_str()
+        .unwrap()
+        .contains("gave up after 2 attempts"));
+
+    let (status, _, body) = send(&app, "GET", "/readyz", None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "unavailable");
+
+    let (status, _, _) = send(&app, "GET", "/healthz", None).await;
+    assert_eq!(status, StatusCode::OK);
+}
@@ -0,0 +1,89 @@
+//! The contention workload against a scratch database: conflict rate, retry depth and
+//! goodput reported from the attempts of each transaction
+//!
+//! Postgres only reports write conflicts as serialization failures under `REPEATABLE
+//! READ`, so the run uses connections with that isolation level, like DSQL's.
+
+mod common;
+
+use rust_dsql::migrate;
+use rust_dsql::retry::RetryPolicy;
+use rust_dsql::stress::{run_stress_test, StressConfig};
+use rust_dsql::users::UserRepository;
+use rust_dsql::workload::{Operation, Workload};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::Executor;
+
+const OPERATIONS: u64 = 200;
+
+#[tokio::test]
+async fn contention_is_measured_from_the_attempts() {
+    let Some(db) = common::ScratchDatabase::create().await else {
+        return;
+    };
+    let pool = PgPoolOptions::new()
+        .max_connections(8)
+        .after_connect(|conn, _| {
+            Box::pin(async move {
+                conn.execute(
+                    "SET SESSION CHARACTERISTICS AS TRANSACTION ISOLATION LEVEL REPEATABLE READ",
+                )
+                .await?;
+                Ok(())
+            })
+        })
+        .connect_with(db.connect_options())
+        .await
+        .unwrap();
+    migrate::up(&pool, &RetryPolicy::default(), false)
+        .await
+        .unwrap();
+
+    // Few attempts, so some transactions give up and count as failed
+    let repo = UserRepository::new(pool.clone()).with_retry_policy(RetryPolicy {
+        max_attempts: 3,
+        ..common::fast_policy()
+    });
+    let config = StressConfig {
+        total_operations: OPERATIONS as usize,
+        concurrency: 8,
+        duration: None,
+        rate: None,
+        workload: Workload::contention(1),
+        batch_size: 1,
+    };
+    let report = run_stress_test(&repo, &config).await.unwrap();
+
+    let stats = &report.operations[&Operation::ContendedUpdate];
+    assert_eq!(stats.total(), OPERATIONS);
+    assert!(stats.conflicts > 0, "no conflicts on a single hot row");
+
+    // Every operation counts its attempts once, successful or not
+    let depths: u64 = stats.retry_depths.values().sum();
+    let attempts: u64 = stats
+        .retry_depths
+        .iter()
+        .map(|(retries, count)| (retries + 1) * count)
+        .sum();
+    assert_eq!(depths, OPERATIONS);
+    assert_eq!(attempts, stats.attempts);
+    assert!(stats.max_retries <= 2);
+    assert!(stats.conflicts <= stats.attempts - stats.successful);
+    if stats.failed > 0 {
+        assert_eq!(stats.error_kinds["sqlstate 40001"], stats.failed);
+    }
+
+    let summary = &report.summary()[1];
+    let seconds = report.elapsed.as_secs_f64();
+    assert_eq!(summary.operation, "contended_update");
+    assert_eq!(
+        summary.conflict_rate,
+        stats.conflicts as f64 / stats.attempts as f64
+    );
+    assert_eq!(summary.rate, stats.successful as f64 / seconds);
+    assert_eq!(summary.attempt_rate, stats.attempts as f64 / seconds);
+    assert!(summary.attempt_rate > summary.rate);
+
+    pool.close().await;
+    db.drop().await;
+}
@@ -0,0 +1,176 @@
+//! `UserRepository::for_each`: filters, ordering and keyset pages
+
+mod common;
+
+use chrono::{Duration, Utc};
+use rust_dsql::users::{RepositoryError, User, UserOrder, UserQuery, UserRepository};
+use sqlx::types::uuid::Uuid;
+
+async fn list(repo: &UserRepository, query: &UserQuery) -> Vec<User> {
+    let mut users = Vec::new();
+    repo.for_each(query, |user| {
+        users.push(user);
+        Ok::<_, RepositoryError>(())
+    })
+    .await
+    .unwrap();
+    users
+}
+
+/// All pages of `query`, `page_size` users at a time
+async fn list_pages(repo: &UserRepository, query: &UserQuery, page_size: i64) -> Vec<User> {
+    let mut query = UserQuery {
+        limit: Some(page_size),
+        ..query.clone()
+    };
+    let mut users = Vec::new();
+    loop {
+        let mut page = Vec::new();
+        let summary = repo
+            .for_each(&query, |user| {
+                page.push(user);
+                Ok::<_, RepositoryError>(())
+            })
+            .await
+            .unwrap();
+        assert!(page.len() as i64 <= page_size);
+        users.extend(page);
+
+        match summary.next_after {
+            Some(after) => {
+                query.after = Some(after);
+                query.after_key = summary.next_key;
+            }
+            None => return users,
+        }
+    }
+}
+
+#[tokio::test]
+async fn filters() {
+    let Some(pool) = common::pool().await else {
+        return;
+    };
+    let repo = UserRepository::new(pool);
+    let started = Utc::now() - Duration::seconds(1);
+    let role = format!("Filter{}", Uuid::new_v4().simple());
+    let domain = format!("{}.example.com", Uuid::new_v4().simple());
+
+    let mut inserted = Vec::new();
+    for (i, role) in [role.as_str(), role.as_str(), "User"]
+        .into_iter()
+        .enumerate()
+    {
+        let email = format!("user{}@{}", i, domain);
+        inserted.push(
+            repo.insert(Uuid::new_v4(), "Filtered", &email, role)
+                .await
+                .unwrap(),
+        );
+    }
+
+    let by_role = list(
+        &repo,
+        &UserQuery {
+            role: Some(role.clone()),
+            ..UserQuery::default()
+        },
+    )
+    .await;
+    assert_eq!(by_role.len(), 2);
+    assert!(by_role.iter().all(|user| user.role == role));
+
+    let by_email = UserQuery {
+        email_like: Some(format!("%@{}", domain)),
+        order_by: UserOrder::Email,
+        ..UserQuery::default()
+    };
+    assert_eq!(list(&repo, &by_email).await, inserted);
+
+    let descending = UserQuery {
+        descending: true,
+        ..by_email.clone()
+    };
+    let mut reversed = inserted.clone();
+    reversed.reverse();
+    assert_eq!(list(&repo, &descending).await, reversed);
+
+    let since = UserQuery {
+        created_since: Some(started),
+        ..by_email.clone()
+    };
+    assert_eq!(list(&repo, &since).await, inserted);
+    let until = UserQuery {
+        created_until: Some(started),
+        ..by_email.clone()
+    };
+    assert!(list(&repo, &until).await.is_empty());
+
+    let first_page = UserQuery {
+        limit: Some(2),
+        ..by_email.clone()
+    };
+    let summary = repo
+        .for_each(&first_page, |_| Ok::<_, RepositoryError>(()))
+        .await
+        .unwrap();
+    assert_eq!(summary.count, 2);
+    assert_eq!(summary.next_after, Some(inserted[1].id));
+    let rest = UserQuery {
+        after: summary.next_after,
+        ..by_email.clone()
+    };
+    assert_eq!(list(&repo, &rest).await, inserted[2..]);
+
+    for user in inserted {
+        repo.delete(user.id).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn pages_cross_sort_key_ties() {
+    let Some(pool) = common::pool().await else {
+        return;
+    };
+    let repo = UserRepository::new(pool);
+    let role = format!("Tie{}", Uuid::new_v4().simple());
+
+    // Five users share a name, so only the id tells them apart
+    for name in ["Same", "Same", "Same", "Same", "Same", "Other"] {
+        repo.insert(Uuid::new_v4(), name, &common::unique_email(), &role)
+            .await
+            .unwrap();
+    }
+
+    for descending in [false, true] {
+        let query = UserQuery {
+            role: Some(role.clone()),
+            order_by: UserOrder::Name,
+            descending,
+            ..UserQuery::default()
+        };
+        let all = list(&repo, &query).await;
+        assert_eq!(all.len(), 6);
+        for page_size in [1, 2, 4] {
+            assert_eq!(
+                list_pages(&repo, &query, page_size).await,
+                all,
+                "descending: {}, page size: {}",
+                descending,
+                page_size
+            );
+        }
+    }
+
+    for user in list(
+        &repo,
+        &UserQuery {
+            role: Some(role),
+            ..UserQuery::default()
+        },
+    )
+    .await
+    {
+        repo.delete(user.id).await.unwrap();
+    }
+}